@@ -10,7 +10,7 @@ fn main() {
     app.add_plugins(MinimalPlugins);
     app.add_plugins(bevy::log::LogPlugin::default());
 
-    app.add_plugins(NetworkingPlugin);
+    app.add_plugins(NetworkingPlugin::default());
 
     app.add_systems(Startup, setup);
     app.add_systems(Update, (
@@ -2,10 +2,11 @@
 use nifty_net_bevy::prelude::*;
 use serde::{Serialize, Deserialize};
 
-pub fn typed_plugin() -> TypedMessagePlugin {
-    TypedMessagePlugin::default()
-    .with_message::<Ping>()
-    .with_message::<Pong>()
+define_messages! {
+    pub fn typed_plugin {
+        Ping,
+        Pong,
+    }
 }
 
 
@@ -9,6 +9,7 @@ fn main() {
         "0.0.0.0:3000".parse().unwrap(),
         Config::default(),
     ).expect("failed to bind address");
+    socket.set_report_ignored_handshakes(true);
 
     let start_time = Instant::now();
 
@@ -19,20 +20,52 @@ fn main() {
                     println!("socket error {:?}", err);
                 },
 
-                SocketEvent::NewConnection { addr, } => {
-                    println!("new connection with {}", addr);
+                SocketEvent::NewConnection { addr, id, .. } => {
+                    println!("new connection with {} {:?}", addr, id);
                 },
 
                 SocketEvent::ConnectionRequest { accept_connection, .. } => {
                     *accept_connection = true;
                 },
 
-                SocketEvent::Received { addr, data } => {
+                SocketEvent::QualityChanged { addr, quality } => {
+                    println!("connection quality with {} is now {:?}", addr, quality);
+                },
+
+                SocketEvent::ConnectionStalled { addr } => {
+                    println!("connection stalled {}", addr);
+                },
+
+                SocketEvent::SendBufferFull { addr } => {
+                    println!("send buffer full sending to {}", addr);
+                },
+
+                SocketEvent::ConnectionRejected { addr, reason } => {
+                    println!("connection rejected by {} {:?}", addr, reason);
+                },
+
+                SocketEvent::ConnectionMigrated { addr, previous_addr } => {
+                    println!("connection {} moved to {}", previous_addr, addr);
+                },
+
+                SocketEvent::MessageDropped { addr, id } => {
+                    println!("gave up on message {} to {}", id, addr);
+                },
+
+                SocketEvent::HandshakeIgnored { addr, reason } => {
+                    println!("ignored handshake from {} {:?}", addr, reason);
+                },
+
+                SocketEvent::Received { addr, data, .. } => {
                     println!("received data from {} {:?}", addr, data);
                 },
 
-                SocketEvent::ClosedConnection { addr } => {
-                    println!("connection closed {}", addr);
+                SocketEvent::ReceivedChunk { addr, sequence, offset, total_size, data } => {
+                    println!("received {} bytes at {} of {} in message {} from {}", data.len(), offset, total_size, sequence, addr);
+                },
+
+                SocketEvent::ClosedConnection { addr, id } => {
+                    println!("connection closed {} {:?}", addr, id);
                 }
             }
         });
@@ -0,0 +1,138 @@
+use std::{net::SocketAddr, time::{Duration, Instant}};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use nifty_net::{bench::*, prelude::*};
+
+
+const FRAGMENT_SIZE: usize = 1024;
+const MESSAGE_SIZE: usize = 1 << 20;
+
+
+fn fragment(start: usize) -> Fragment {
+    Fragment {
+        send_ack: true,
+        ordered: false,
+        tagged: false,
+        fragmentation_id: 0,
+        total_size: MESSAGE_SIZE as u32,
+        start: start as u32,
+        data: vec![0; FRAGMENT_SIZE].into(),
+    }
+}
+
+fn fragment_serialization(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fragment serialization");
+    group.throughput(Throughput::Bytes(FRAGMENT_SIZE as u64));
+
+    let mut packet = Packet::new();
+    packet.push(Blob::Fragment(fragment(0)));
+
+    let mut bytes = Vec::new();
+    group.bench_function("serialize", |b| b.iter(|| {
+        bytes.clear();
+        packet.serialize_into(&mut bytes);
+    }));
+
+    packet.serialize_into(&mut bytes);
+    group.bench_function("deserialize", |b| b.iter(|| Packet::deserialize(&bytes).unwrap()));
+
+    group.finish();
+}
+
+/// builds a message out of fragments arriving in some order, which is tracked with `DeliveredIntervals`
+fn delivered_intervals(c: &mut Criterion) {
+    let mut group = c.benchmark_group("delivered intervals");
+    group.throughput(Throughput::Bytes(MESSAGE_SIZE as u64));
+
+    let in_order: Vec<usize> = (0..MESSAGE_SIZE).step_by(FRAGMENT_SIZE).collect();
+
+    // every other fragment, then the ones in between, leaving the most gaps to merge
+    let out_of_order: Vec<usize> = in_order.iter().step_by(2)
+    .chain(in_order.iter().skip(1).step_by(2))
+    .copied()
+    .collect();
+
+    for (name, starts) in [("in order", in_order), ("out of order", out_of_order)] {
+        group.bench_function(name, |b| b.iter_batched(
+            || starts.iter().map(|&start| fragment(start)).collect::<Vec<_>>(),
+            |fragments| {
+                let mut fragments = fragments.into_iter();
+                let mut message = ReceiveMessage::new(Duration::ZERO, fragments.next().unwrap()).unwrap();
+
+                for fragment in fragments {
+                    message.add_fragment(Duration::ZERO, fragment).unwrap();
+                }
+
+                assert!(message.complete());
+            },
+            BatchSize::LargeInput,
+        ));
+    }
+
+    group.finish();
+}
+
+/// opens a connection between two sockets on the loopback interface
+fn connected_sockets() -> (Socket, SocketAddr, Socket, SocketAddr) {
+    let mut client = Socket::bind("127.0.0.1:0".parse().unwrap(), Config::default()).unwrap();
+    let mut server = Socket::bind("127.0.0.1:0".parse().unwrap(), Config::default()).unwrap();
+    let client_addr = client.local_addr().unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    client.open_connection(Duration::ZERO, server_addr).unwrap();
+    client.update(Duration::ZERO, |_| ());
+    server.update(Duration::ZERO, |_| ());
+    server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+
+    // the server's first heartbeat establishes the connection
+    let time = Config::default().heartbeat_interval;
+    server.update(time, |_| ());
+    client.update(time, |_| ());
+
+    (client, client_addr, server, server_addr)
+}
+
+fn throughput(c: &mut Criterion) {
+    const MESSAGES: usize = 64;
+    const SIZE: usize = 16 * 1024;
+
+    let mut group = c.benchmark_group("throughput");
+    group.throughput(Throughput::Bytes((MESSAGES * SIZE) as u64));
+
+    for reliable in [false, true] {
+        let name = if reliable { "reliable" } else { "unreliable" };
+
+        let (mut client, _, mut server, server_addr) = connected_sockets();
+        let start = Instant::now();
+
+        group.bench_function(name, |b| b.iter(|| {
+            for _ in 0..MESSAGES {
+                client.send(server_addr, reliable, vec![0; SIZE].into()).unwrap();
+            }
+
+            // unreliable messages can be lost, so stop once nothing more is arriving
+            let mut received = 0;
+            let mut idle_updates = 0;
+            while received < MESSAGES && idle_updates < 100 {
+                let time = start.elapsed();
+                client.update(time, |_| ());
+
+                let received_before = received;
+                server.update(time, |event| if let SocketEvent::Received { .. } = event {
+                    received += 1;
+                });
+
+                if received == received_before {
+                    idle_updates += 1;
+                } else {
+                    idle_updates = 0;
+                }
+            }
+        }));
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, fragment_serialization, delivered_intervals, throughput);
+criterion_main!(benches);
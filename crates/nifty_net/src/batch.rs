@@ -0,0 +1,256 @@
+use std::{ffi::{c_uint, c_void}, io::ErrorKind, mem::{size_of, zeroed}, net::{SocketAddr, UdpSocket}, ops::Range, os::fd::AsRawFd};
+#[cfg(feature = "recvmmsg")]
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV6};
+
+#[cfg(feature = "sendmmsg")]
+use crate::packet::send_buffer_full;
+
+/// the most datagrams `sendmmsg` accepts in one call, `UIO_MAXIOV` on linux
+#[cfg(feature = "sendmmsg")]
+const MAX_BATCH_SIZE: usize = 1024;
+
+/// the smallest space each datagram is given by [receive_batch], so handshakes and rejections
+/// still fit when the mtu is tiny
+#[cfg(feature = "recvmmsg")]
+const MIN_RECEIVE_SLOT_SIZE: usize = 1024;
+
+/// datagrams held to be sent with as few `sendmmsg` syscalls as possible,
+/// see [start_batch](crate::packet::FilteredSocket::start_batch)
+#[cfg(feature = "sendmmsg")]
+#[derive(Default)]
+pub struct Batch {
+    /// if datagrams are currently being held
+    pub active: bool,
+    /// where each datagram is sent, it's range in `bytes` and it's range in `unfiltered`
+    datagrams: Vec<(SocketAddr, Range<usize>, Range<usize>)>,
+    /// the bytes of every datagram, one after the other
+    bytes: Vec<u8>,
+    /// the bytes of every datagram before the outbound filter, handed back if it doesn't fit in the send buffer
+    unfiltered: Vec<u8>,
+    /// acts as if the send buffer fills up after this many datagrams
+    #[cfg(test)]
+    pub full_after: Option<usize>,
+}
+
+#[cfg(feature = "sendmmsg")]
+impl Batch {
+    pub fn push(&mut self, addr: SocketAddr, bytes: &[u8], unfiltered: &[u8]) {
+        let start = self.bytes.len();
+        self.bytes.extend_from_slice(bytes);
+        let unfiltered_start = self.unfiltered.len();
+        self.unfiltered.extend_from_slice(unfiltered);
+        self.datagrams.push((addr, start..self.bytes.len(), unfiltered_start..self.unfiltered.len()));
+    }
+
+    /// sends every held datagram, calling `sent` with each one that was sent
+    ///
+    /// once the send buffer is full `unsent` is called with the rest, before the outbound filter and
+    /// with how many bytes they were counted as when held, so they can be sent later.
+    /// a datagram that couldn't be sent for any other reason is skipped and the first error is returned
+    pub fn send(
+        &mut self,
+        socket: &UdpSocket,
+        mut sent: impl FnMut(SocketAddr, &[u8]),
+        mut unsent: impl FnMut(SocketAddr, &[u8], usize),
+    ) -> Result<(), std::io::Error> {
+        let mut first_error = None;
+
+        #[cfg(test)]
+        let mut full_from = self.full_after.take().unwrap_or(self.datagrams.len()).min(self.datagrams.len());
+        #[cfg(not(test))]
+        let mut full_from = self.datagrams.len();
+
+        'chunks: for (chunk_index, chunk) in self.datagrams[..full_from].chunks(MAX_BATCH_SIZE).enumerate() {
+            let mut addrs = chunk.iter().map(|&(addr, _, _)| sockaddr(addr)).collect::<Vec<_>>();
+            let mut iovecs = chunk.iter().map(|(_, range, _)| libc::iovec {
+                // never written to, `sendmmsg` only reads the buffers
+                iov_base: self.bytes[range.clone()].as_ptr() as *mut c_void,
+                iov_len: range.len(),
+            }).collect::<Vec<_>>();
+
+            let mut headers = addrs.iter_mut().zip(iovecs.iter_mut()).map(|((addr, addr_len), iovec)| {
+                // safety: all zeroes is a valid `mmsghdr`, with no control messages or flags
+                let mut header: libc::mmsghdr = unsafe { zeroed() };
+                header.msg_hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut c_void;
+                header.msg_hdr.msg_namelen = *addr_len;
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+                header
+            }).collect::<Vec<_>>();
+
+            let mut start = 0;
+            while start < headers.len() {
+                // safety: every header points to an address and buffer that outlive the call
+                let result = unsafe {
+                    libc::sendmmsg(socket.as_raw_fd(), headers[start..].as_mut_ptr(), (headers.len() - start) as c_uint, 0)
+                };
+
+                if result < 0 {
+                    let err = std::io::Error::last_os_error();
+
+                    if err.kind() == ErrorKind::Interrupted {
+                        continue;
+                    }
+
+                    if send_buffer_full(&err) {
+                        full_from = chunk_index * MAX_BATCH_SIZE + start;
+                        break 'chunks;
+                    }
+
+                    // only reported when the first datagram fails, skip it
+                    first_error.get_or_insert(err);
+                    start += 1;
+                    continue;
+                }
+
+                let end = start + result as usize;
+                for (header, (addr, range, _)) in headers[start..end].iter().zip(chunk[start..end].iter()) {
+                    if (header.msg_len as usize) < range.len() {
+                        first_error.get_or_insert(std::io::Error::new(
+                            ErrorKind::WriteZero,
+                            format!("only sent {} of {} bytes in datagram", header.msg_len, range.len()),
+                        ));
+                    } else {
+                        sent(*addr, &self.bytes[range.clone()]);
+                    }
+                }
+
+                start = end;
+            }
+        }
+
+        for (addr, range, unfiltered) in self.datagrams[full_from..].iter() {
+            unsent(*addr, &self.unfiltered[unfiltered.clone()], range.len());
+        }
+
+        self.datagrams.clear();
+        self.bytes.clear();
+        self.unfiltered.clear();
+
+        match first_error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+/// receives as many waiting datagrams as fit in `buffer` with one `recvmmsg` syscall,
+/// returning the address each came from, where it is in `buffer` and if it was cut short
+///
+/// each datagram gets a slot of `slot_size`, anything past the end of it is lost.
+/// returns a [WouldBlock](ErrorKind::WouldBlock) error if nothing was waiting
+#[cfg(feature = "recvmmsg")]
+pub fn receive_batch(socket: &UdpSocket, buffer: &mut [u8], slot_size: usize) -> Result<Vec<(SocketAddr, Range<usize>, bool)>, std::io::Error> {
+    let slot_size = slot_size.max(MIN_RECEIVE_SLOT_SIZE).min(buffer.len());
+
+    // safety: all zeroes is a valid `sockaddr_storage`
+    let mut addrs = (0..buffer.len() / slot_size).map(|_| unsafe { zeroed::<libc::sockaddr_storage>() }).collect::<Vec<_>>();
+    let mut iovecs = buffer.chunks_exact_mut(slot_size).map(|slot| libc::iovec {
+        iov_base: slot.as_mut_ptr() as *mut c_void,
+        iov_len: slot.len(),
+    }).collect::<Vec<_>>();
+
+    let mut headers = addrs.iter_mut().zip(iovecs.iter_mut()).map(|(addr, iovec)| {
+        // safety: all zeroes is a valid `mmsghdr`, with no control messages or flags
+        let mut header: libc::mmsghdr = unsafe { zeroed() };
+        header.msg_hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut c_void;
+        header.msg_hdr.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        header.msg_hdr.msg_iov = iovec;
+        header.msg_hdr.msg_iovlen = 1;
+        header
+    }).collect::<Vec<_>>();
+
+    let received = loop {
+        // safety: every header points to an address and slot that outlive the call, the socket is non blocking
+        let result = unsafe {
+            libc::recvmmsg(socket.as_raw_fd(), headers.as_mut_ptr(), headers.len() as c_uint, 0, std::ptr::null_mut())
+        };
+
+        if result >= 0 {
+            break result as usize;
+        }
+
+        let err = std::io::Error::last_os_error();
+        if err.kind() != ErrorKind::Interrupted {
+            return Err(err);
+        }
+    };
+
+    Ok(headers[..received].iter().enumerate().filter_map(|(index, header)| {
+        // safety: the os wrote an address of the size it says into the storage
+        let addr = unsafe { socket_addr(&*(header.msg_hdr.msg_name as *const libc::sockaddr_storage)) }?;
+        let start = index * slot_size;
+        let truncated = header.msg_hdr.msg_flags & libc::MSG_TRUNC != 0;
+
+        Some((addr, start..start + header.msg_len as usize, truncated))
+    }).collect())
+}
+
+/// converts an address from the form the os gives, `None` if it isn't an ip address
+#[cfg(feature = "recvmmsg")]
+fn socket_addr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // safety: the family says this is a `sockaddr_in`
+            let addr = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+
+            Some(SocketAddr::new(
+                Ipv4Addr::from(addr.sin_addr.s_addr.to_ne_bytes()).into(),
+                u16::from_be(addr.sin_port),
+            ))
+        },
+        libc::AF_INET6 => {
+            // safety: the family says this is a `sockaddr_in6`
+            let addr = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+
+            Some(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            ).into())
+        },
+        _ => None,
+    }
+}
+
+/// converts an address to the form the os expects
+#[cfg(feature = "sendmmsg")]
+fn sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // safety: all zeroes is a valid `sockaddr_storage`
+    let mut storage: libc::sockaddr_storage = unsafe { zeroed() };
+
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sockaddr = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                },
+                sin_zero: [0; 8],
+            };
+
+            // safety: `sockaddr_storage` is large enough and aligned for any address
+            unsafe { (&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in).write(sockaddr) };
+            size_of::<libc::sockaddr_in>()
+        },
+        SocketAddr::V6(addr) => {
+            let sockaddr = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+            };
+
+            // safety: `sockaddr_storage` is large enough and aligned for any address
+            unsafe { (&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6).write(sockaddr) };
+            size_of::<libc::sockaddr_in6>()
+        },
+    };
+
+    (storage, len as libc::socklen_t)
+}
@@ -0,0 +1,139 @@
+use std::{io::{ErrorKind, Read, Write}, net::SocketAddr, time::Duration};
+
+use crate::packet::{deserialize_addr, serialize_addr};
+
+/// whether a captured datagram was sent or received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// a datagram recorded by [start_capture](crate::socket::Socket::start_capture)
+///
+/// serialization layout:
+/// - 8 bytes: the time passed to the update it was sent or received in, in microseconds
+/// - 1 byte: `0` if it was sent or `1` if it was received
+/// - the peer's address: `4` or `6` for the ip version, the ip's bytes then a 2 byte port
+/// - 2 bytes: the length of the datagram
+/// - the datagram's bytes, as they were on the wire
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureRecord {
+    pub time: Duration,
+    pub direction: Direction,
+    /// the address the datagram was sent to or received from
+    pub addr: SocketAddr,
+    pub bytes: Box<[u8]>,
+}
+
+impl CaptureRecord {
+    /// writes the record in the same layout as a capture
+    pub fn write_to(&self, sink: &mut impl Write) -> Result<(), std::io::Error> {
+        write_capture_record(sink, self.time, self.direction, self.addr, &self.bytes)
+    }
+
+    /// reads the next record from a capture, returns `Ok(None)` at the end of the capture
+    pub fn read_from(source: &mut impl Read) -> Result<Option<Self>, std::io::Error> {
+        let mut time = [0; 8];
+        match source.read_exact(&mut time) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        let time = Duration::from_micros(u64::from_be_bytes(time));
+
+        let mut header = [0; 2];
+        source.read_exact(&mut header)?;
+        let [direction, ip_version] = header;
+
+        let direction = match direction {
+            0 => Direction::Sent,
+            1 => Direction::Received,
+            _ => return Err(invalid_data("unknown direction")),
+        };
+
+        let ip_size = match ip_version {
+            4 => 4,
+            6 => 16,
+            _ => return Err(invalid_data("unknown ip version")),
+        };
+
+        let mut addr = vec![0; 1 + ip_size + 2];
+        addr[0] = ip_version;
+        source.read_exact(&mut addr[1..])?;
+        // can't fail, the ip version and size are checked above
+        let addr = deserialize_addr(&addr).unwrap();
+
+        let mut len = [0; 2];
+        source.read_exact(&mut len)?;
+
+        let mut bytes = vec![0; u16::from_be_bytes(len) as usize];
+        source.read_exact(&mut bytes)?;
+
+        Ok(Some(CaptureRecord {
+            time,
+            direction,
+            addr,
+            bytes: bytes.into(),
+        }))
+    }
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, format!("malformed capture record, {}", message))
+}
+
+/// writes a [CaptureRecord] without needing to own the bytes
+pub(crate) fn write_capture_record(sink: &mut (impl Write + ?Sized), time: Duration, direction: Direction, addr: SocketAddr, bytes: &[u8]) -> Result<(), std::io::Error> {
+    let mut header = Vec::with_capacity(8 + 1 + 19 + 2);
+    header.extend_from_slice(&(time.as_micros() as u64).to_be_bytes());
+    header.push(match direction {
+        Direction::Sent => 0,
+        Direction::Received => 1,
+    });
+    serialize_addr(addr, &mut header);
+    // datagrams are never larger than `u16::MAX`
+    header.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+
+    sink.write_all(&header)?;
+    sink.write_all(bytes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let records = [
+            CaptureRecord {
+                time: Duration::from_millis(1500),
+                direction: Direction::Sent,
+                addr: "127.0.0.1:4000".parse().unwrap(),
+                bytes: [1, 2, 3].into(),
+            },
+            CaptureRecord {
+                time: Duration::from_secs(2),
+                direction: Direction::Received,
+                addr: "[::1]:5000".parse().unwrap(),
+                bytes: [].into(),
+            },
+        ];
+
+        let mut capture = Vec::new();
+        for record in records.iter() {
+            record.write_to(&mut capture).unwrap();
+        }
+
+        let mut source = capture.as_slice();
+        for record in records.iter() {
+            assert_eq!(CaptureRecord::read_from(&mut source).unwrap().as_ref(), Some(record));
+        }
+        assert_eq!(CaptureRecord::read_from(&mut source).unwrap(), None);
+
+        // cut off part way through a record
+        let mut source = &capture[..capture.len() - 1];
+        CaptureRecord::read_from(&mut source).unwrap();
+        assert!(CaptureRecord::read_from(&mut source).is_err());
+    }
+}
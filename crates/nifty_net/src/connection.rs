@@ -1,11 +1,15 @@
-use std::{collections::{hash_map::Entry, HashMap, VecDeque}, net::{SocketAddr, UdpSocket}, time::Duration};
+use std::{any::Any, collections::{hash_map::{Entry, RandomState}, HashMap, VecDeque}, hash::{BuildHasher, Hasher}, net::SocketAddr, sync::Arc, time::Duration};
 
-use crate::{
+use crate::{ConnectionId, 
     message::*,
     packet::*,
     metrics::*,
+    BlacklistMode,
     Config,
+    ControlBlob,
     Error,
+    Reliability,
+    SendScheduling,
 };
 
 
@@ -13,6 +17,11 @@ use crate::{
 
 pub struct Connection {
     addr: SocketAddr,
+    id: ConnectionId,
+    /// overrides the socket's [Config] for this connection if `Some`
+    config: Option<Arc<Config>>,
+    /// overrides the mtu of the connection's [Config] if `Some`
+    mtu: Option<u16>,
 
     /// this will be some whilst trying to establish a connection
     ///
@@ -20,10 +29,41 @@ pub struct Connection {
     ///
     /// contains the time the last heartbeat was sent at
     last_handshake: Option<Option<Duration>>,
+    /// the token sent in the handshake, by us if we opened the connection or by the peer if they did
+    token: Box<[u8]>,
+    /// if we opened the connection, rather than accepting it
+    opening_party: bool,
+    /// an application defined value stored with the connection
+    user_data: Option<Box<dyn Any + Send + Sync>>,
+    /// the time the connection was established
+    ///
+    /// `None` whilst trying to establish a connection
+    established_time: Option<Duration>,
 
     last_heartbeat: Duration,
+    /// if a heartbeat should be sent in the next update regardless of the heartbeat interval
+    ping_requested: bool,
+    /// the time the connection was paused at, `None` if it isn't paused
+    ///
+    /// nothing but the pause blob is sent and the timeout clock is suspended whilst paused
+    paused: Option<Duration>,
+    /// a pause or resume blob to tell the peer about in the next update
+    send_pause: Option<Blob>,
+    /// the time the peer told us it paused, suspending the timeout clock until it resumes
+    /// or [max_peer_pause](Config::max_peer_pause) runs out
+    peer_paused: Option<Duration>,
     /// a queue of heartbeats to respond to
     heartbeat_responses: Vec<Heartbeat>,
+    /// an application message to send with the next heartbeat
+    heartbeat_payload: Option<Box<[u8]>>,
+    /// application messages received with heartbeats and their fragmentation ids, returned with the next flush
+    received_heartbeat_payloads: Vec<CompleteMessage>,
+    /// from the last heartbeat received, how many packets the peer had received from us
+    /// and how many we had sent when it arrived
+    last_peer_counts: Option<(u64, u64)>,
+    /// estimated fraction of our packets lost on the way to the peer,
+    /// from the change in counts between the last two heartbeats
+    packet_loss: Option<f32>,
     rtt_samples: VecDeque<Duration>,
     /// round trip time
     ///
@@ -33,50 +73,171 @@ pub struct Connection {
     ///
     /// recalculated when `rtt_samples` changes
     cached_rtv: Option<f32>,
+    /// estimates of how far the peer's clock is ahead of ours (seconds), one from each heartbeat
+    clock_offset_samples: VecDeque<f32>,
+    /// the average of `clock_offset_samples`
+    cached_clock_offset: Option<f32>,
     last_keep_alive: Duration,
 
     next_fragmentation_id: u16,
     send_messages: Vec<SendMessage>,
+    /// unreliable messages waiting to be sent as `(priority, fragmentation id, data)`, ordered by priority
+    ///
+    /// these skip [SendMessage] bookkeeping if they fit in a single fragment
+    unreliable_messages: Vec<(u8, u16, Box<[u8]>)>,
 
     receive_messages: Vec<ReceiveMessage>,
+    /// the most recent fragmentation id received, used for replay protection
+    latest_fragmentation_id: Option<u16>,
     /// acknowledgements to send
     acknowledgements: Vec<Acknowledgement>,
-    reliable_blacklist: Vec<(Duration, u16)>,
+    /// negative acknowledgements to send, for gaps found in reliable messages
+    negative_acknowledgements: Vec<NegativeAcknowledgement>,
+    /// the receive window last advertised to the peer, `None` if one was never sent
+    advertised_window: Option<u32>,
+    /// how many bytes of reliable messages the peer is willing to buffer,
+    /// `None` until it advertises a window
+    peer_window: Option<u32>,
+    /// the mtu last advertised to the peer, `None` if one was never sent
+    advertised_mtu: Option<u16>,
+    /// the mtu of the peer, packets are kept within the smaller of this and our own mtu,
+    /// `None` until it's received in a handshake or advertised
+    peer_mtu: Option<u16>,
+    reliable_blacklist: VecDeque<(Duration, u16)>,
+    /// the sequence number of the next [ReliableOrdered](Reliability::ReliableOrdered) message to send
+    next_ordered_sequence: u16,
+    /// the sequence number of the next ordered message to be received
+    next_ordered_delivery: u16,
+    /// complete ordered messages waiting for the ones before them, by their sequence number
+    held_ordered_messages: Vec<(u16, CompleteMessage)>,
+    /// the sequence number of the next [UnreliableSequenced](Reliability::UnreliableSequenced) message to send
+    next_sequenced_sequence: u16,
+    /// the sequence number of the newest [UnreliableSequenced](Reliability::UnreliableSequenced) message received
+    latest_sequenced: Option<u16>,
+    /// the address the peer knew this connection by before it was handed off to this socket,
+    /// announced with every update until the peer sends something to the new address
+    handoff_from: Option<SocketAddr>,
+    /// a random key the peer has to send back for us to move the connection to a new address,
+    /// so that only the peer itself can hand it's end of the connection off
+    handoff_key: u64,
+    /// if our handoff key has been sent, it's sent again with every heartbeat
+    handoff_key_sent: bool,
+    /// the peer's handoff key, sent back when announcing a handoff. `None` until it's received
+    peer_handoff_key: Option<u64>,
 
     /// when set to true the connection will continue to function
     /// but be removed at the end of the next update
     drop_connection: bool,
     /// set to true to signal that a connection socket event needs to be fired
     just_connected: bool,
+    /// set to true to signal that a stalled socket event needs to be fired
+    just_stalled: bool,
+    /// set to `Some` with the reason given, if any, to signal that a rejected socket event needs to be fired
+    just_rejected: Option<Option<Box<str>>>,
+    /// set to true to signal that a send buffer full socket event needs to be fired
+    just_blocked: bool,
+    /// fragmentation ids of reliable messages given up on after their maximum number of resends,
+    /// a message dropped socket event needs to be fired for each
+    dropped_messages: Vec<u16>,
+    /// the last quality reported with a quality changed socket event
+    reported_quality: Option<ConnectionQuality>,
+
+    /// serialized packets that couldn't be sent because the socket's send buffer was full,
+    /// retried in the next update
+    blocked_packets: VecDeque<Box<[u8]>>,
+    /// kept between updates to serialize packets into without allocating
+    send_buffer: Vec<u8>,
 
     // metrics
     sent_packets: u64,
     sent_bytes: u64,
+    received_packets: u64,
+    received_bytes: u64,
     reliable_message_count: u64,
     unreliable_message_count: u64,
+    reordered_fragments: u64,
+    metrics_history: MetricsHistory,
+}
+
+/// a message ready to be passed on as `(fragmentation id, tag, data)`
+pub type CompleteMessage = (u16, Option<u8>, Box<[u8]>);
+
+/// sorts messages by fragmentation id, accounting for wrap around of the 15 bit id
+/// by ordering relative to a point half the id space behind the first message
+fn sort_by_fragmentation_id(messages: &mut [CompleteMessage]) {
+    if let Some(&(first, _, _)) = messages.first() {
+        let reference = first.wrapping_sub(1 << 14);
+        messages.sort_by_key(|&(fragmentation_id, _, _)| fragmentation_id.wrapping_sub(reference) & !(1 << 15));
+    }
+}
+
+/// [UnreliableSequenced](Reliability::UnreliableSequenced) and [ReliableOrdered](Reliability::ReliableOrdered)
+/// messages start with a sequence number of this many bytes
+pub const ORDERED_SEQUENCE_SIZE: usize = 2;
+
+/// tagged messages start with a tag of this many bytes, after the sequence number of an ordered message
+pub const TAG_SIZE: usize = 1;
+
+/// splits the tag off the front of a message if it has one, `None` if it should have but is empty
+fn split_tag(tagged: bool, data: &[u8]) -> Option<(Option<u8>, Box<[u8]>)> {
+    if !tagged {
+        return Some((None, data.into()));
+    }
+
+    let (&tag, data) = data.split_first()?;
+    Some((Some(tag), data.into()))
+}
+
+/// the most message data that fits in a single fragment in a packet with some mtu
+fn max_fragment_data_size(mtu: u16) -> usize {
+    Packet::new().space_left(mtu)
+    .saturating_sub((Blob::HEADER_SIZE + Fragment::HEADER_SIZE) as u16) as usize
 }
 
+/// the most packets a connection keeps while the socket's send buffer is full
+const MAX_BLOCKED_PACKETS: usize = 64;
+
+/// the most acknowledgements a paused connection keeps to send once it's resumed,
+/// the peer resends anything else and it's acknowledged then
+const MAX_PAUSED_ACKNOWLEDGEMENTS: usize = 1024;
+
 pub struct Connections {
     connections: HashMap<SocketAddr, Connection>,
+    /// the address of each connection by it's id
+    addrs: HashMap<ConnectionId, SocketAddr>,
+    next_id: u64,
 }
 
 struct PacketGrouper<'a> {
     addr: SocketAddr,
-    socket: &'a UdpSocket,
+    socket: &'a FilteredSocket,
     mtu: u16,
     current_packet: Packet,
+    /// reused to serialize packets into
+    send_buffer: &'a mut Vec<u8>,
+    blocked_packets: &'a mut VecDeque<Box<[u8]>>,
     sent_packets: &'a mut u64,
     sent_bytes: &'a mut u64,
 }
 
 
 impl Connections {
-    pub fn new() -> Self {
+    /// creates an empty set of connections with space for at least `capacity` connections
+    pub fn with_capacity(capacity: usize) -> Self {
         Connections {
-            connections: HashMap::new(),
+            connections: HashMap::with_capacity(capacity),
+            addrs: HashMap::with_capacity(capacity),
+            next_id: 0,
         }
     }
 
+    /// generates a new [ConnectionId] to create a connection with
+    pub fn next_id(&mut self) -> ConnectionId {
+        let id = ConnectionId(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
     /// tries to create a new connection
     ///
     /// will return [Ok] if the connection didn't exist with a mutable reference to the [Connection]
@@ -87,10 +248,18 @@ impl Connections {
 
         match entry {
             Entry::Occupied(_) => Err(()),
-            Entry::Vacant(entry) => Ok(entry.insert(connection)),
+            Entry::Vacant(entry) => {
+                self.addrs.insert(connection.id, connection.addr);
+                Ok(entry.insert(connection))
+            },
         }
     }
 
+    /// gets the address of a connection by it's id
+    pub fn get_addr(&self, id: ConnectionId) -> Option<SocketAddr> {
+        self.addrs.get(&id).copied()
+    }
+
     pub fn get_connection(&self, addr: SocketAddr) -> Option<&Connection> {
         self.connections.get(&addr)
     }
@@ -99,52 +268,141 @@ impl Connections {
         self.connections.get_mut(&addr)
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = &Connection> + '_ {
+        self.connections.values()
+    }
+
     pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Connection> + '_ {
         self.connections.values_mut()
     }
 
-    pub fn remove_connection(&mut self, addr: SocketAddr) {
-        self.connections.remove(&addr);
+    /// removes a connection and returns it, so it's state can be moved to another [Connections]
+    pub fn take_connection(&mut self, addr: SocketAddr) -> Option<Box<Connection>> {
+        let connection = self.connections.remove(&addr)?;
+        self.addrs.remove(&connection.id);
+        Some(Box::new(connection))
+    }
+
+    /// inserts a connection taken from another [Connections] with [take_connection](Connections::take_connection),
+    /// giving it a new id from this set
+    ///
+    /// will return [Err] with the connection if there is already a connection with it's address
+    pub fn insert_connection(&mut self, mut connection: Box<Connection>) -> Result<&mut Connection, Box<Connection>> {
+        if self.connections.contains_key(&connection.addr) {
+            return Err(connection);
+        }
+
+        connection.id = self.next_id();
+        // unwrap is safe, connection doesn't exist
+        Ok(self.new_connection(*connection).unwrap())
+    }
+
+    /// moves a connection to a new address after it's peer handed it off to another socket
+    ///
+    /// will return [Err] if there is no connection at `previous_addr` or there is already one at `addr`
+    pub fn migrate_connection(&mut self, previous_addr: SocketAddr, addr: SocketAddr) -> Result<&mut Connection, ()> {
+        if self.connections.contains_key(&addr) {
+            return Err(());
+        }
+
+        let mut connection = self.connections.remove(&previous_addr).ok_or(())?;
+        connection.addr = addr;
+        self.addrs.insert(connection.id, addr);
+        Ok(self.connections.entry(addr).or_insert(connection))
     }
 }
 
 
 impl Connection {
-    /// creates a new connection at some `time` to some `addr`
+    /// creates a new connection at some `time` to some `addr` with an `id` from [Connections::next_id]
     ///
     /// `opening_party` should be true if this socket is the one responsible for creating the connection,
     /// meaning it has to wait before knowing that the connection is established
-    pub fn new(time: Duration, addr: SocketAddr, opening_party: bool) -> Self {
+    ///
+    /// `config` is used to preallocate buffers
+    pub fn new(time: Duration, addr: SocketAddr, id: ConnectionId, opening_party: bool, config: &Config) -> Self {
         Connection {
             addr,
+            id,
+            config: None,
+            mtu: None,
 
             last_handshake: if opening_party {
                 Some(None)
             } else {
                 None
             },
+            token: Box::new([]),
+            opening_party,
+            user_data: None,
+            established_time: if opening_party {
+                None
+            } else {
+                Some(time)
+            },
 
             last_heartbeat: Duration::ZERO,
+            // the accepting party sends a heartbeat straight away,
+            // the opening party only knows it's been accepted once one arrives
+            ping_requested: !opening_party,
+            paused: None,
+            send_pause: None,
+            peer_paused: None,
             heartbeat_responses: Vec::new(),
-            rtt_samples: VecDeque::new(),
+            heartbeat_payload: None,
+            received_heartbeat_payloads: Vec::new(),
+            last_peer_counts: None,
+            packet_loss: None,
+            rtt_samples: VecDeque::with_capacity(config.rtt_memory + 1),
             cached_rtt: None,
             cached_rtv: None,
+            clock_offset_samples: VecDeque::with_capacity(config.rtt_memory + 1),
+            cached_clock_offset: None,
             last_keep_alive: time,
 
             next_fragmentation_id: 0,
-            send_messages: Vec::new(),
+            send_messages: Vec::with_capacity(config.expected_messages_in_transit),
+            unreliable_messages: Vec::new(),
 
             receive_messages: Vec::new(),
+            latest_fragmentation_id: None,
             acknowledgements: Vec::new(),
-            reliable_blacklist: Vec::new(),
+            negative_acknowledgements: Vec::new(),
+            advertised_window: None,
+            peer_window: None,
+            advertised_mtu: None,
+            peer_mtu: None,
+            reliable_blacklist: VecDeque::new(),
+            next_ordered_sequence: 0,
+            next_ordered_delivery: 0,
+            held_ordered_messages: Vec::new(),
+            next_sequenced_sequence: 0,
+            latest_sequenced: None,
+            handoff_from: None,
+            // std's hashers are keyed randomly, which is enough to make the key unguessable
+            handoff_key: RandomState::new().build_hasher().finish(),
+            handoff_key_sent: false,
+            peer_handoff_key: None,
 
             drop_connection: false,
             just_connected: !opening_party,
+            just_stalled: false,
+            just_rejected: None,
+            just_blocked: false,
+            dropped_messages: Vec::new(),
+            reported_quality: None,
+
+            blocked_packets: VecDeque::new(),
+            send_buffer: Vec::new(),
 
             sent_packets: 0,
             sent_bytes: 0,
+            received_packets: 0,
+            received_bytes: 0,
             reliable_message_count: 0,
             unreliable_message_count: 0,
+            reordered_fragments: 0,
+            metrics_history: MetricsHistory::default(),
         }
     }
 
@@ -152,23 +410,299 @@ impl Connection {
         self.addr
     }
 
-    pub fn send(&mut self, reliable: bool, data: Box<[u8]>) {
-        let fragmentation_id = self.next_fragmentation_id;
-        self.next_fragmentation_id = self.next_fragmentation_id.wrapping_add(1);
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    /// sets the token sent in handshakes, or received in the handshake that opened the connection
+    pub fn set_token(&mut self, token: Box<[u8]>) {
+        self.token = token;
+    }
+
+    /// gets the token sent in the handshake that opened the connection
+    pub fn token(&self) -> &[u8] {
+        &self.token
+    }
+
+    /// if we opened the connection, rather than accepting it
+    pub fn opening_party(&self) -> bool {
+        self.opening_party
+    }
+
+    /// sets the address the peer knows this connection by, to announce after handing it off to another socket
+    pub fn set_handoff_from(&mut self, previous_addr: SocketAddr) {
+        self.handoff_from = Some(previous_addr);
+    }
+
+    /// the key the peer has to send back to move the connection to a new address
+    pub fn handoff_key(&self) -> u64 {
+        self.handoff_key
+    }
+
+    /// stores an application defined value with the connection, replacing any previous one
+    pub fn set_user_data(&mut self, data: impl Any + Send + Sync) {
+        self.user_data = Some(Box::new(data));
+    }
+
+    /// gets the value stored with [set_user_data](Connection::set_user_data),
+    /// `None` if there isn't one or it isn't a `T`
+    pub fn user_data<T: Any>(&self) -> Option<&T> {
+        self.user_data.as_ref()?.downcast_ref()
+    }
+
+    /// mutably gets the value stored with [set_user_data](Connection::set_user_data),
+    /// `None` if there isn't one or it isn't a `T`
+    pub fn user_data_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.user_data.as_mut()?.downcast_mut()
+    }
+
+    /// returns true whilst the connection is still sending handshakes and waiting for a response
+    pub fn connecting(&self) -> bool {
+        self.last_handshake.is_some()
+    }
+
+    /// overrides the socket's [Config] for this connection, or removes the override if `None`
+    pub fn set_config(&mut self, config: Option<Config>) {
+        self.config = config.map(Arc::new);
+    }
+
+    /// gets the [Config] overriding the socket's for this connection, if any
+    pub fn config(&self) -> Option<&Config> {
+        self.config.as_deref()
+    }
+
+    /// overrides the mtu for this connection, or removes the override if `None`
+    pub fn set_mtu(&mut self, mtu: Option<u16>) {
+        self.mtu = mtu;
+    }
+
+    /// gets the mtu used by this connection given the socket's [Config],
+    /// the smaller of it's own mtu and the peer's once it's known
+    pub fn mtu(&self, config: &Config) -> u16 {
+        self.mtu.unwrap_or(self.config.as_deref().unwrap_or(config).mtu)
+        .min(self.peer_mtu.unwrap_or(u16::MAX))
+    }
+
+    /// sets the peer's mtu, from the handshake that opened the connection or a [Mtu](Blob::Mtu) blob
+    ///
+    /// ignored if it leaves no space for message data, or if a queued message would no longer fit,
+    /// the same as a new mtu is refused by [set_config](crate::socket::Socket::set_config)
+    pub fn set_peer_mtu(&mut self, mtu: u16, config: &Config) {
+        let previous = self.peer_mtu.replace(mtu);
+
+        if max_fragment_data_size(self.mtu(config)) == 0 || self.largest_queued_message() > self.max_message_size(config) {
+            self.peer_mtu = previous;
+        }
+    }
+
+    /// the sequence numbers to carry on from in a new connection as `(send, receive)`,
+    /// see [resume_ordered_sequences](Connection::resume_ordered_sequences)
+    ///
+    /// ordered messages that haven't been acknowledged keep their sequence numbers,
+    /// see [take_unacknowledged_ordered](Connection::take_unacknowledged_ordered)
+    pub fn ordered_sequences(&self) -> (u16, u16) {
+        (self.next_ordered_sequence, self.next_ordered_delivery)
+    }
+
+    /// takes the [ReliableOrdered](Reliability::ReliableOrdered) messages the peer hasn't acknowledged,
+    /// so that they can be sent again in a new connection with [requeue_ordered](Connection::requeue_ordered)
+    ///
+    /// they are given in the order they were sent, so they keep it when queued again
+    pub fn take_unacknowledged_ordered(&mut self) -> Vec<SendMessage> {
+        let (mut ordered, rest): (Vec<_>, _) = std::mem::take(&mut self.send_messages).into_iter()
+        .partition(|message| !message.delivered() && message.ordered_sequence().is_some());
+
+        // every sequence number is behind the next one, the furthest behind was sent first
+        let next_sequence = self.next_ordered_sequence;
+        ordered.sort_by_key(|message| message.ordered_sequence().map(|sequence| sequence.wrapping_sub(next_sequence)));
+
+        self.send_messages = rest;
+        ordered
+    }
+
+    /// queues ordered messages taken from a previous connection,
+    /// they keep their sequence numbers so the peer drops any it already received
+    pub fn requeue_ordered(&mut self, messages: Vec<SendMessage>) {
+        for message in messages {
+            let fragmentation_id = self.take_fragmentation_id();
+            self.reliable_message_count += 1;
+            self.insert_send_message(message.restart(fragmentation_id));
+        }
+    }
+
+    /// carries on the sequence numbers of [ReliableOrdered](Reliability::ReliableOrdered) messages
+    /// from a previous connection, see [ordered_sequences](Connection::ordered_sequences)
+    pub fn resume_ordered_sequences(&mut self, send: u16, receive: u16) {
+        self.next_ordered_sequence = send;
+        self.next_ordered_delivery = receive;
+        self.held_ordered_messages.clear();
+    }
+
+    /// the size of the largest message that can be sent within
+    /// [max_message_fragments](Config::max_message_fragments) given the socket's [Config]
+    pub fn max_message_size(&self, config: &Config) -> usize {
+        let max_fragments = self.config.as_deref().unwrap_or(config).max_message_fragments;
+        max_fragment_data_size(self.mtu(config)).saturating_mul(max_fragments as usize)
+        // the top bit of a fragment's total size is used for the ordered flag
+        .min((1 << 31) - 1)
+    }
+
+    /// the size of the largest message queued to be sent, `0` if there are none
+    pub fn largest_queued_message(&self) -> usize {
+        let largest_send_message = self.send_messages.iter().map(SendMessage::size).max().unwrap_or(0);
+        let largest_unreliable_message = self.unreliable_messages.iter().map(|(_, _, data)| data.len()).max().unwrap_or(0);
+        largest_send_message.max(largest_unreliable_message)
+    }
+
+    /// how many bytes of reliable messages are queued but not acknowledged yet
+    pub fn pending_reliable_bytes(&self) -> usize {
+        self.send_messages.iter()
+        .filter(|message| message.is_reliable())
+        .map(SendMessage::remaining_bytes)
+        .sum()
+    }
+
+    /// the [max_pending_reliable_bytes](Config::max_pending_reliable_bytes) of the connection given the socket's [Config]
+    pub fn max_pending_reliable_bytes(&self, config: &Config) -> usize {
+        self.config.as_deref().unwrap_or(config).max_pending_reliable_bytes
+    }
+
+    /// queues a message to be sent, returning it's fragmentation id
+    ///
+    /// messages with a higher `priority` are sent before those with a lower one,
+    /// messages with the same priority are sent in the order they were queued
+    pub fn send(&mut self, reliable: bool, priority: u8, data: Box<[u8]>) -> u16 {
+        self.send_with_reliability(reliable.into(), priority, data)
+    }
+
+    /// the same as [send](Connection::send) but with any [Reliability]
+    ///
+    /// [UnreliableSequenced](Reliability::UnreliableSequenced) and [ReliableOrdered](Reliability::ReliableOrdered)
+    /// messages are [ORDERED_SEQUENCE_SIZE] bytes larger once sent
+    pub fn send_with_reliability(&mut self, reliability: Reliability, priority: u8, data: Box<[u8]>) -> u16 {
+        self.send_with_tag(reliability, priority, None, data)
+    }
+
+    /// the same as [send_with_reliability](Connection::send_with_reliability) but optionally with a tag
+    /// that is received alongside the message, making it [TAG_SIZE] bytes larger once sent
+    pub fn send_with_tag(&mut self, reliability: Reliability, priority: u8, tag: Option<u8>, data: Box<[u8]>) -> u16 {
+        // the tag goes in front of the data, after an ordered message's sequence number
+        let data = match tag {
+            Some(tag) => [tag].iter().chain(data.iter()).copied().collect(),
+            None => data,
+        };
+
+        // sequenced and ordered messages count separately, so that other messages don't affect them
+        let next_sequence = match reliability {
+            Reliability::UnreliableSequenced => Some(&mut self.next_sequenced_sequence),
+            Reliability::ReliableOrdered => Some(&mut self.next_ordered_sequence),
+            Reliability::Unreliable | Reliability::Reliable => None,
+        };
+
+        let data = match next_sequence {
+            Some(next_sequence) => {
+                // prefix the sequence number so the peer can tell which messages came before it
+                let mut sequenced_data = Vec::with_capacity(ORDERED_SEQUENCE_SIZE + data.len());
+                sequenced_data.extend_from_slice(&next_sequence.to_be_bytes());
+                sequenced_data.extend_from_slice(&data);
+                *next_sequence = next_sequence.wrapping_add(1);
+                sequenced_data.into()
+            },
+            None => data,
+        };
+
+        // the fast path has no room for the tagged or ordered flags
+        if reliability == Reliability::Unreliable && tag.is_none() {
+            let fragmentation_id = self.take_fragmentation_id();
+            self.unreliable_message_count += 1;
 
-        self.send_messages.push(SendMessage::new(reliable, fragmentation_id, data));
+            // keep messages ordered by priority
+            let index = self.unreliable_messages.partition_point(|&(message_priority, _, _)| message_priority >= priority);
+            self.unreliable_messages.insert(index, (priority, fragmentation_id, data));
+
+            return fragmentation_id;
+        }
+
+        self.queue_message(reliability.is_reliable(), priority, data, |message| {
+            message.set_ordered(reliability.is_ordered());
+            message.set_tagged(tag.is_some());
+        })
+    }
+
+    /// queues a message that isn't sent through the fast path, returning it's fragmentation id
+    fn queue_message(&mut self, reliable: bool, priority: u8, data: Box<[u8]>, configure: impl FnOnce(&mut SendMessage)) -> u16 {
+        let fragmentation_id = self.take_fragmentation_id();
+
+        let mut message = SendMessage::new(reliable, fragmentation_id, priority, data);
+        configure(&mut message);
 
         if reliable {
             self.reliable_message_count += 1;
         } else {
             self.unreliable_message_count += 1;
         }
+
+        self.insert_send_message(message);
+
+        fragmentation_id
+    }
+
+    /// sets an unreliable message to send with the next heartbeat instead of in a packet of it's own,
+    /// replacing any message that hasn't been sent yet
+    pub fn set_heartbeat_payload(&mut self, data: Box<[u8]>) {
+        self.heartbeat_payload = Some(data);
+    }
+
+    /// queues a reliable message to be sent, returning it's fragmentation id
+    ///
+    /// if `max_resends` is `Some` the message is dropped instead of being resent more than that many times
+    pub fn send_with_max_resends(&mut self, priority: u8, max_resends: Option<u32>, data: Box<[u8]>) -> u16 {
+        self.queue_message(true, priority, data, |message| message.set_max_resends(max_resends))
+    }
+
+    /// queues a reliable message that has it's first transmission sent twice, returning it's fragmentation id
+    ///
+    /// both copies share the fragmentation id, so the peer receives whichever arrives first
+    /// and ignores the other as a retransmission
+    pub fn send_redundant(&mut self, priority: u8, data: Box<[u8]>) -> u16 {
+        self.queue_message(true, priority, data, |message| message.set_redundant(true))
+    }
+
+    /// how many more bytes of reliable messages can be buffered whilst receiving them, given the socket's [Config]
+    ///
+    /// only bytes that have been received are counted, the peer counts those it has sent that we haven't acknowledged
+    pub fn receive_window(&self, config: &Config) -> u32 {
+        let receive_window = self.config.as_deref().unwrap_or(config).receive_window;
+
+        let buffered = self.receive_messages.iter()
+        .filter(|message| message.is_reliable())
+        .map(ReceiveMessage::buffered_bytes)
+        .sum::<usize>() +
+            self.held_ordered_messages.iter().map(|(_, (_, _, data))| data.len()).sum::<usize>();
+
+        receive_window.saturating_sub(buffered.min(u32::MAX as usize) as u32)
+    }
+
+    /// takes the next fragmentation id, ids are 15 bits on the wire so they wrap after `0x7fff`
+    fn take_fragmentation_id(&mut self) -> u16 {
+        let fragmentation_id = self.next_fragmentation_id;
+        self.next_fragmentation_id = self.next_fragmentation_id.wrapping_add(1) & !(1 << 15);
+        fragmentation_id
     }
 
-    pub fn update(&mut self, time: Duration, config: &Config, socket: &UdpSocket) -> Result<(), Error> {
+    /// inserts a message into `send_messages`, keeping them ordered by priority
+    fn insert_send_message(&mut self, message: SendMessage) {
+        let index = self.send_messages.partition_point(|queued| queued.priority() >= message.priority());
+        self.send_messages.insert(index, message);
+    }
+
+    pub fn update(&mut self, time: Duration, config: &Config, socket: &FilteredSocket) -> Result<(), Error> {
+        let config_override = self.config.clone();
+        let config = config_override.as_deref().unwrap_or(config);
 
-        // timeout connection
-        if self.last_keep_alive + config.timeout_delay < time {
+        // timeout connection, the clock is suspended whilst either side is paused
+        let peer_paused = self.peer_paused.is_some_and(|paused_at| paused_at + config.max_peer_pause >= time);
+        if self.paused.is_none() && !peer_paused && self.last_keep_alive + config.timeout_delay < time {
             self.drop_connection = true;
         }
 
@@ -183,92 +717,246 @@ impl Connection {
             } {
                 *last_handshake = Some(time);
 
-                let sent_bytes = Handshake {
+                let handshake = Handshake {
                     protocol_id: config.protocol_id,
-                }.send(self.addr, socket).map_err(|err| Error::IoError(err))?;
+                    mtu: self.mtu.unwrap_or(config.mtu),
+                    token: self.token.clone(),
+                };
 
-                // update metrics
-                self.sent_packets += 1;
-                self.sent_bytes += sent_bytes as u64;
+                // if the send buffer is full the handshake is simply sent again after the interval
+                if let Some(sent_bytes) = handshake.send(self.addr, socket).map_err(|err| Error::IoError(err))? {
+                    // update metrics
+                    self.sent_packets += 1;
+                    self.sent_bytes += sent_bytes as u64;
+                } else {
+                    self.just_blocked = true;
+                }
             }
 
             return Ok(());
         }
 
-        let mut grouper = PacketGrouper::new(self.addr, socket, config.mtu, &mut self.sent_packets, &mut self.sent_bytes);
+        let local_mtu = self.mtu.unwrap_or(config.mtu);
+        let mtu = local_mtu.min(self.peer_mtu.unwrap_or(u16::MAX));
 
-        let resend_delay = self.cached_rtt.map(|rtt| Duration::from_secs_f32(
-            rtt.as_secs_f32() * config.reliable_resend_threshold
-        ));
+        // only tell the peer we're paused, unless closing the connection
+        if self.paused.is_some() && !self.drop_connection {
+            if let Some(blob) = self.send_pause.take() {
+                let mut grouper = PacketGrouper::new(self.addr, socket, mtu, &mut self.send_buffer, &mut self.blocked_packets, &mut self.sent_packets, &mut self.sent_bytes);
+                grouper.ensure_space(ControlBlob::Pause, blob.size())?;
+                grouper.push(blob);
+                grouper.send_remaining()?;
+            }
 
-        // send message fragments
-        for message in self.send_messages.iter_mut() {
+            return Ok(());
+        }
 
-            // decide whether to send fragments
-            let send_fragments = 'b: {
-                let Some(last_sent) = message.reliable() else {
-                    // unreliable, always send
-                    break 'b true;
-                };
+        // unreliable messages that need more than one fragment go through the regular send path
+        let max_data_size = max_fragment_data_size(mtu);
+        let mut index = 0;
+        while index < self.unreliable_messages.len() {
+            if self.unreliable_messages[index].2.len() > max_data_size {
+                let (priority, fragmentation_id, data) = self.unreliable_messages.remove(index);
+                self.insert_send_message(SendMessage::new(false, fragmentation_id, priority, data));
+            } else {
+                index += 1;
+            }
+        }
 
-                let Some(last_sent) = last_sent else {
-                    // reliable but have never sent
-                    break 'b true;
-                };
+        // retry packets from when the send buffer was full
+        while let Some(bytes) = self.blocked_packets.front() {
+            let Some(sent_bytes) = send_datagram(bytes, self.addr, socket).map_err(Error::IoError)? else {
+                // still full
+                break;
+            };
 
-                let Some(resend_delay) = resend_delay else {
-                    // have sent once but no rtt calculated, wait for rtt
-                    break 'b false;
-                };
+            self.blocked_packets.pop_front();
+            self.sent_packets += 1;
+            self.sent_bytes += sent_bytes as u64;
+        }
 
-                if *last_sent + resend_delay <= time {
-                    // send if resend threshold has been reached
-                    break 'b true;
-                }
+        let receive_window = self.receive_window(config);
 
-                false
-            };
+        // decided before packets are grouped, the payload's fragmentation id can't be taken whilst grouping
+        let heartbeat_sent = self.ping_requested || self.last_heartbeat + config.heartbeat_interval <= time;
+        let heartbeat_payload = if heartbeat_sent {
+            // the payload is counted as an unreliable message with an id of it's own
+            self.heartbeat_payload.take().map(|data| {
+                self.unreliable_message_count += 1;
+                (self.take_fragmentation_id(), data)
+            })
+        } else {
+            None
+        };
 
-            if !send_fragments {
-                continue;
+        let mut grouper = PacketGrouper::new(self.addr, socket, mtu, &mut self.send_buffer, &mut self.blocked_packets, &mut self.sent_packets, &mut self.sent_bytes);
+
+        // the peer drops packets from the new address unless they announce the handoff,
+        // which can't be done until we know it's key
+        if let (Some(previous_addr), Some(key)) = (self.handoff_from, self.peer_handoff_key) {
+            let blob = Blob::Handoff(previous_addr, key);
+            grouper.ensure_space(ControlBlob::Handoff, blob.size())?;
+            grouper.push(blob);
+        }
+
+        // single fragment unreliable messages, interleaved with `send_messages` by priority
+        let mut unreliable_messages = self.unreliable_messages.drain(..).peekable();
+
+        // use the initial estimate until there are samples
+        let resend_delay = Duration::from_secs_f32(
+            self.cached_rtt.unwrap_or(config.initial_rtt).as_secs_f32() * config.reliable_resend_threshold
+        );
+
+        // reliable bytes already being sent, new reliable messages are held back
+        // once these would go over the peer's receive window
+        let mut in_flight = self.send_messages.iter()
+        .filter(|message| message.in_flight())
+        .map(SendMessage::remaining_bytes)
+        .sum::<usize>();
+        let mut window_full = false;
+
+        // redundant messages sent for the first time, sent again once everything else has been
+        let mut redundant_copies = Vec::new();
+
+        // send message fragments, one group of messages with the same priority at a time
+        let mut group_start = 0;
+        while group_start < self.send_messages.len() {
+            let priority = self.send_messages[group_start].priority();
+            let group_end = group_start + self.send_messages[group_start..].partition_point(|message| message.priority() == priority);
+
+            while let Some((_, fragmentation_id, data)) = unreliable_messages.next_if(
+                |&(unreliable_priority, _, _)| unreliable_priority > priority
+            ) {
+                grouper.push_unreliable(fragmentation_id, data)?;
             }
 
-            let mut deliverd_intervals = message.get_deliverd_intervals();
+            // messages in the group that are due to be sent, with the intervals they will have sent
+            let mut sending = Vec::new();
+            // messages that aren't due but have ranges the peer reported missing
+            let mut repairing = Vec::new();
+
+            for message in self.send_messages[group_start..group_end].iter_mut() {
+                // decide whether to send fragments
+                let send_fragments = 'b: {
+                    let Some(last_sent) = message.reliable() else {
+                        // unreliable, always send
+                        break 'b true;
+                    };
+
+                    let Some(last_sent) = last_sent else {
+                        // reliable but have never sent, start sending if it fits in the peer's window.
+                        // a message is always allowed when nothing else is in flight so larger ones can't get stuck
+                        if let Some(peer_window) = self.peer_window {
+                            window_full |= in_flight > 0 && in_flight + message.remaining_bytes() > peer_window as usize;
+                        }
 
-            loop {
-                let available_space = grouper.space_left();
+                        if window_full {
+                            break 'b false;
+                        }
 
-                let Some(blob) = message.create_blob(&mut deliverd_intervals, available_space) else {
-                    // no more blobs to send
-                    break;
-                };
+                        in_flight += message.remaining_bytes();
+                        break 'b true;
+                    };
 
-                let Some(blob) = blob else {
-                    // not enough space for a blob
-                    grouper.create_space()?;
-                    continue;
+                    if *last_sent + resend_delay <= time {
+                        if message.resends_exhausted() {
+                            // give up instead of resending again
+                            self.dropped_messages.push(message.fragmentation_id());
+                            break 'b false;
+                        }
+
+                        // send if resend threshold has been reached
+                        break 'b true;
+                    }
+
+                    false
                 };
 
-                grouper.push(blob);
+                // a full resend already covers anything reported missing
+                let missing = message.take_missing();
+
+                if send_fragments {
+                    let deliverd_intervals = message.get_deliverd_intervals();
+                    sending.push((message, deliverd_intervals));
+                } else if let Some(missing) = missing {
+                    repairing.push((message, missing));
+                }
             }
 
-            if let Some(last_sent) = message.reliable() {
-                // if reliable, mark now as the last sent time
-                *last_sent = Some(time);
-            } else {
-                // if unreliable assume that the packets were delivered
-                message.set_delivered_intervals(deliverd_intervals);
+            // resend missing ranges first without touching the resend timer,
+            // anything still missing after that is left to it
+            for (message, missing) in repairing.iter_mut() {
+                while grouper.push_fragment(message, missing)? {}
+            }
+
+            match config.send_scheduling {
+                SendScheduling::FirstComplete => {
+                    for (message, deliverd_intervals) in sending.iter_mut() {
+                        while grouper.push_fragment(message, deliverd_intervals)? {}
+                    }
+                },
+                SendScheduling::RoundRobin => {
+                    let mut pushed = true;
+                    while pushed {
+                        pushed = false;
+                        for (message, deliverd_intervals) in sending.iter_mut() {
+                            pushed |= grouper.push_fragment(message, deliverd_intervals)?;
+                        }
+                    }
+                },
+            }
+
+            for (message, deliverd_intervals) in sending {
+                if let Some(last_sent) = message.reliable() {
+                    let resent = last_sent.is_some();
+
+                    // if reliable, mark now as the last sent time
+                    *last_sent = Some(time);
+
+                    if resent {
+                        message.mark_resent();
+
+                        if config.stall_resend_threshold.checked_add(1) == Some(message.resends_without_progress()) {
+                            self.just_stalled = true;
+                        }
+                    } else if message.is_redundant() {
+                        redundant_copies.push(message.fragmentation_id());
+                    }
+                } else {
+                    // if unreliable assume that the packets were delivered
+                    message.set_delivered_intervals(deliverd_intervals);
+                }
+            }
+
+            group_start = group_end;
+        }
+        for (_, fragmentation_id, data) in unreliable_messages {
+            grouper.push_unreliable(fragmentation_id, data)?;
+        }
+
+        // the copies are sent after everything else, in a packet of their own so they aren't lost with the original
+        if !redundant_copies.is_empty() && grouper.current_packet.blob_count() > 0 {
+            grouper.send_packet()?;
+        }
+        for fragmentation_id in redundant_copies {
+            if let Some(message) = self.send_messages.iter_mut().find(|message| message.fragmentation_id() == fragmentation_id) {
+                let mut deliverd_intervals = message.get_deliverd_intervals();
+                while grouper.push_fragment(message, &mut deliverd_intervals)? {}
             }
         }
-        self.send_messages.retain(|message| !message.delivered());
+
+        self.send_messages.retain(|message| {
+            !message.delivered() && !self.dropped_messages.contains(&message.fragmentation_id())
+        });
 
 
         // send heartbeats
-        if self.last_heartbeat + config.heartbeat_interval <= time {
+        if heartbeat_sent {
             self.last_heartbeat = time;
+            self.ping_requested = false;
 
-            let blob = Blob::Heartbeat(Heartbeat::new(time));
-            grouper.ensure_space(blob.size())?;
+            let blob = Blob::Heartbeat(Heartbeat::new(time, self.received_packets).with_payload(heartbeat_payload));
+            grouper.ensure_space(ControlBlob::Heartbeat, blob.size())?;
             grouper.push(blob);
         }
 
@@ -276,7 +964,7 @@ impl Connection {
         // send heartbeat responses
         for heartbeat in self.heartbeat_responses.drain(..) {
             let blob = Blob::HeartbeatResponse(heartbeat);
-            grouper.ensure_space(blob.size())?;
+            grouper.ensure_space(ControlBlob::HeartbeatResponse, blob.size())?;
             grouper.push(blob);
         }
 
@@ -284,7 +972,53 @@ impl Connection {
         // send acknowledgements
         for ack in self.acknowledgements.drain(..) {
             let blob = Blob::Acknowledgement(ack);
-            grouper.ensure_space(blob.size())?;
+            grouper.ensure_space(ControlBlob::Acknowledgement, blob.size())?;
+            grouper.push(blob);
+        }
+
+
+        // send negative acknowledgements
+        for nak in self.negative_acknowledgements.drain(..) {
+            let blob = Blob::NegativeAcknowledgement(nak);
+            grouper.ensure_space(ControlBlob::NegativeAcknowledgement, blob.size())?;
+            grouper.push(blob);
+        }
+
+
+        // advertise the receive window with heartbeats, or when it has changed
+        if heartbeat_sent || self.advertised_window != Some(receive_window) {
+            self.advertised_window = Some(receive_window);
+
+            let blob = Blob::ReceiveWindow(receive_window);
+            grouper.ensure_space(ControlBlob::ReceiveWindow, blob.size())?;
+            grouper.push(blob);
+        }
+
+
+        // advertise our mtu with heartbeats, or when it has changed
+        if heartbeat_sent || self.advertised_mtu != Some(local_mtu) {
+            self.advertised_mtu = Some(local_mtu);
+
+            let blob = Blob::Mtu(local_mtu);
+            grouper.ensure_space(ControlBlob::Mtu, blob.size())?;
+            grouper.push(blob);
+        }
+
+
+        // tell the peer our handoff key with heartbeats, or straight away if it's never been sent
+        if heartbeat_sent || !self.handoff_key_sent {
+            self.handoff_key_sent = true;
+
+            let blob = Blob::HandoffKey(self.handoff_key);
+            grouper.ensure_space(ControlBlob::HandoffKey, blob.size())?;
+            grouper.push(blob);
+        }
+
+
+        // tell the peer we've paused or resumed
+        if let Some(blob) = self.send_pause.take() {
+            let control_blob = if self.paused.is_some() { ControlBlob::Pause } else { ControlBlob::Resume };
+            grouper.ensure_space(control_blob, blob.size())?;
             grouper.push(blob);
         }
 
@@ -292,19 +1026,31 @@ impl Connection {
         // send disconnect message if just decided to drop
         if self.drop_connection {
             let blob = Blob::Disconnect;
-            grouper.ensure_space(blob.size())?;
+            grouper.ensure_space(ControlBlob::Disconnect, blob.size())?;
             grouper.push(blob);
         }
 
 
         grouper.send_remaining()?;
 
+        // packets left over means the send buffer filled up during this update
+        if !self.blocked_packets.is_empty() {
+            self.just_blocked = true;
+        }
+
+        self.metrics_history.sample(
+            time,
+            config,
+            (self.sent_packets, self.sent_bytes),
+            (self.received_packets, self.received_bytes),
+            self.cached_rtt,
+        );
 
         // drop incomplete unreliable messages
         if let Some(rtt) = self.round_trip_time() {
             let drop_delay = Duration::from_secs_f32(
                 rtt.as_secs_f32() * config.unreliable_drop_threshhold
-            );
+            ).max(config.min_unreliable_drop_delay);
 
             self.receive_messages.retain(|message| {
                 message.is_reliable() || // message is reliable, never drop
@@ -314,52 +1060,118 @@ impl Connection {
 
 
         // trim reliable message blacklist
-        if let Some(rtt) = self.round_trip_time() {
-            let trim_delay = Duration::from_secs_f32(
-                rtt.as_secs_f32() * config.reliable_message_blacklist_memory
-            );
-
-            self.trim_blacklist(time.saturating_sub(trim_delay));
+        match config.reliable_blacklist {
+            BlacklistMode::TimeBased(memory) => {
+                // ids are kept for at least the timeout delay
+                // since a sender could keep resending a message for as long as it hears from us
+                let trim_delay = self.round_trip_time().map_or(Duration::ZERO, |rtt| Duration::from_secs_f32(
+                    rtt.as_secs_f32() * memory
+                )).max(config.timeout_delay);
+
+                self.trim_blacklist(time.saturating_sub(trim_delay));
+            },
+            BlacklistMode::FixedSize(size) => {
+                let excess = self.reliable_blacklist.len().saturating_sub(size);
+                self.reliable_blacklist.drain(..excess);
+            },
+            BlacklistMode::Disabled => self.reliable_blacklist.clear(),
         }
 
 
         Ok(())
     }
 
+    /// counts a received UDP packet of some size in the connection's metrics
+    pub fn record_received(&mut self, bytes: usize) {
+        self.received_packets += 1;
+        self.received_bytes += bytes as u64;
+    }
+
     /// processes a [Packet]
     ///
     /// fails if the packet had malformed data
     pub fn receive(&mut self, time: Duration, config: &Config, packet: Packet) -> Result<(), ()> {
+        let config_override = self.config.clone();
+        let config = config_override.as_deref().unwrap_or(config);
         self.last_keep_alive = time;
+        // the peer has learned our new address
+        self.handoff_from = None;
 
         for blob in packet.into_iter() {
             match blob {
                 Blob::Fragment(fragment) => {
+                    // drop fragments with ids too far behind the most recent one
+                    if !self.in_replay_window(fragment.fragmentation_id, config.replay_window) {
+                        continue;
+                    }
+
                     let ack = fragment.acknowledgement();
+                    let fragmentation_id = fragment.fragmentation_id;
 
                     // ignore blacklisted reliable ids
                     if !(fragment.send_ack && self.is_blacklisted(fragment.fragmentation_id)) {
                         if let Some(message) = self.receive_messages.iter_mut().find(
                             |message| message.fragmentation_id() == fragment.fragmentation_id
                         ) {
-                            message.add_fragment(time, fragment)?;
+                            if message.add_fragment(time, fragment)? {
+                                self.reordered_fragments += 1;
+                            }
+                        } else if fragment.send_ack && !fragment.ordered && !fragment.tagged && fragment.total_size > 0
+                            && config.min_streamed_message_size.is_some_and(|min_size| fragment.total_size as usize >= min_size)
+                        {
+                            self.receive_messages.push(ReceiveMessage::new_streamed(time, fragment)?);
                         } else {
                             self.receive_messages.push(ReceiveMessage::new(time, fragment)?);
                         }
                     }
 
                     if let Some(ack) = ack {
-                        self.acknowledgements.push(ack);
+                        if self.paused.is_none() || self.acknowledgements.len() < MAX_PAUSED_ACKNOWLEDGEMENTS {
+                            self.acknowledgements.push(ack);
+                        }
+
+                        // a later fragment arriving can reveal gaps, report them so they are resent sooner
+                        if let Some(message) = self.receive_messages.iter_mut().find(
+                            |message| message.fragmentation_id() == fragmentation_id
+                        ) {
+                            let gaps = message.unreported_gaps();
+
+                            for ranges in gaps.chunks(NegativeAcknowledgement::MAX_RANGES) {
+                                self.negative_acknowledgements.push(NegativeAcknowledgement {
+                                    fragmentation_id,
+                                    ranges: ranges.to_vec(),
+                                });
+                            }
+                        }
                     }
                 },
 
-                Blob::Heartbeat(heartbeat) => {
+                Blob::Heartbeat(mut heartbeat) => {
+                    // peers don't send heartbeats whilst paused,
+                    // so this also recovers from a lost resume blob
+                    self.peer_paused = None;
+
                     if self.last_handshake.is_some() {
                         self.just_connected = true;
                         self.last_handshake = None;
+                        self.established_time = Some(time);
+                    }
+
+                    self.estimate_packet_loss(heartbeat.received_packets());
+                    self.estimate_clock_offset(time, config, heartbeat.time());
+
+                    // the payload isn't sent back with the response
+                    if let Some((fragmentation_id, data)) = heartbeat.take_payload() {
+                        if self.in_replay_window(fragmentation_id, config.replay_window) {
+                            self.received_heartbeat_payloads.push((fragmentation_id, None, data));
+                        }
                     }
 
-                    self.heartbeat_responses.push(heartbeat);
+                    // a response sent after resuming would be as late as the pause was long,
+                    // throwing off the peer's round trip time
+                    if self.paused.is_none() {
+                        self.heartbeat_responses.push(heartbeat);
+                    }
                 },
 
                 Blob::HeartbeatResponse(heartbeat) => {
@@ -395,29 +1207,220 @@ impl Connection {
                     }
                 },
 
+                Blob::NegativeAcknowledgement(nak) => {
+                    if let Some(message) = self.send_messages.iter_mut().find(
+                        |message| message.fragmentation_id() == nak.fragmentation_id
+                    ) {
+                        // skip ranges outside the message rather than dropping the rest of the packet
+                        for (start, len) in nak.ranges {
+                            let start = start as usize;
+                            if let Some(end) = start.checked_add(len as usize) {
+                                let _ = message.set_missing(start..end);
+                            }
+                        }
+                    }
+                },
+
                 Blob::Disconnect => {
                     self.drop_connection = true;
                 },
+
+                Blob::Pause => {
+                    // repeated pause blobs don't extend it
+                    self.peer_paused.get_or_insert(time);
+                },
+
+                Blob::Resume => {
+                    self.peer_paused = None;
+                },
+
+                Blob::ReceiveWindow(window) => {
+                    self.peer_window = Some(window);
+                },
+
+                Blob::Mtu(mtu) => {
+                    self.set_peer_mtu(mtu, config);
+                },
+
+                Blob::HandshakeRejected(reason) => {
+                    // only meaningful whilst trying to establish the connection
+                    if self.last_handshake.is_some() {
+                        self.just_rejected = Some(reason);
+                        self.drop_connection = true;
+                    }
+                },
+
+                Blob::HandoffKey(key) => {
+                    self.peer_handoff_key = Some(key);
+                },
+
+                // handled by the socket before the packet gets here
+                Blob::Handoff(..) => (),
             }
         }
 
         Ok(())
     }
 
-    /// flushes any complete messages, returning them
-    pub fn flush_messages(&mut self, time: Duration, mut flush: impl FnMut(Box<[u8]>)) {
-        let mut i = 0;
-        while let Some(message) = self.receive_messages.get(i) {
-            if message.complete() {
-                if message.is_reliable() {
+    /// the fragmentation ids of partially received messages and the fraction of each that has been received
+    pub fn incoming_progress(&self) -> impl Iterator<Item = (u16, f32)> + '_ {
+        self.receive_messages.iter()
+        .filter(|message| !message.complete())
+        .map(|message| (message.fragmentation_id(), message.progress()))
+    }
+
+    /// takes the data that's ready from streamed messages, as `(fragmentation id, offset, total size, data)`,
+    /// see [min_streamed_message_size](Config::min_streamed_message_size)
+    ///
+    /// call this before [flush_into](Connection::flush_into), which removes complete messages
+    pub fn flush_chunks_into(&mut self, chunks: &mut Vec<(u16, usize, usize, Box<[u8]>)>) {
+        for message in self.receive_messages.iter_mut() {
+            if let Some((offset, data)) = message.take_chunk() {
+                chunks.push((message.fragmentation_id(), offset, message.size(), data));
+            }
+        }
+    }
+
+    /// flushes any complete messages, appending them to `messages` along with their fragmentation id
+    ///
+    /// messages completed in the same flush are returned in the order they were sent,
+    /// followed by released [ReliableOrdered](Reliability::ReliableOrdered) messages in their own order,
+    /// but messages can still be returned out of order across flushes
+    pub fn flush_into(&mut self, time: Duration, messages: &mut Vec<CompleteMessage>) {
+        let mut complete_messages = Vec::new();
+        let mut sequenced_messages = Vec::new();
+
+        let mut i = 0;
+        while let Some(message) = self.receive_messages.get(i) {
+            if message.complete() {
+                if message.is_reliable() {
                     self.blacklist_id(time, message.fragmentation_id());
                 }
 
-                flush(self.receive_messages.remove(i).data());
+                // the order of incomplete messages doesn't matter, so avoid shifting them all down
+                let message = self.receive_messages.swap_remove(i);
+                let fragmentation_id = message.fragmentation_id();
+
+                if message.is_streamed() {
+                    // already passed on by `flush_chunks_into`
+                    continue;
+                }
+
+                let tagged = message.is_tagged();
+
+                match (message.is_reliable(), message.is_ordered()) {
+                    (true, true) => {
+                        let data = message.data();
+
+                        // ordered messages start with their sequence number, drop any without one
+                        if let Some(sequence) = data.get(..ORDERED_SEQUENCE_SIZE) {
+                            let sequence = u16::from_be_bytes(sequence.try_into().unwrap());
+
+                            // after resuming sequence numbers the peer can resend a message we already
+                            // received but didn't acknowledge, it would never be released
+                            let behind = self.next_ordered_delivery.wrapping_sub(sequence);
+                            if behind == 0 || behind > u16::MAX / 2 {
+                                // a tagged message without a tag is passed on empty,
+                                // so that the ones after it aren't held back
+                                let (tag, data) = split_tag(tagged, &data[ORDERED_SEQUENCE_SIZE..]).unwrap_or_default();
+                                self.held_ordered_messages.push((sequence, (fragmentation_id, tag, data)));
+                            }
+                        }
+                    },
+                    (false, true) => {
+                        let data = message.data();
+
+                        // sequenced messages start with their sequence number,
+                        // drop any without one and tagged messages without a tag
+                        if let Some(sequence) = data.get(..ORDERED_SEQUENCE_SIZE) {
+                            let sequence = u16::from_be_bytes(sequence.try_into().unwrap());
+
+                            if let Some((tag, data)) = split_tag(tagged, &data[ORDERED_SEQUENCE_SIZE..]) {
+                                sequenced_messages.push((sequence, (fragmentation_id, tag, data)));
+                            }
+                        }
+                    },
+                    _ => if let Some((tag, data)) = split_tag(tagged, &message.data()) {
+                        complete_messages.push((fragmentation_id, tag, data));
+                    },
+                }
             } else {
                 i += 1;
             }
         }
+
+        complete_messages.append(&mut self.received_heartbeat_payloads);
+
+        sort_by_fragmentation_id(&mut complete_messages);
+        messages.append(&mut complete_messages);
+
+        // release ordered messages once all the ones before them have been received,
+        // their fragmentation ids can be out of order after being sent again in a resumed session
+        while let Some(index) = self.held_ordered_messages.iter().position(|&(sequence, _)| sequence == self.next_ordered_delivery) {
+            let (_, message) = self.held_ordered_messages.swap_remove(index);
+            messages.push(message);
+            self.next_ordered_delivery = self.next_ordered_delivery.wrapping_add(1);
+        }
+
+        // only keep sequenced messages newer than the last one received
+        if let Some(&(first, _)) = sequenced_messages.first() {
+            let reference = first.wrapping_sub(u16::MAX / 2);
+            sequenced_messages.sort_by_key(|&(sequence, _)| sequence.wrapping_sub(reference));
+        }
+        for (sequence, message) in sequenced_messages {
+            let newer = self.latest_sequenced.is_none_or(|latest| {
+                (1..=u16::MAX / 2).contains(&sequence.wrapping_sub(latest))
+            });
+
+            if newer {
+                self.latest_sequenced = Some(sequence);
+                messages.push(message);
+            }
+        }
+    }
+
+    /// takes a clock offset sample from a heartbeat the peer sent at `peer_time` by it's clock,
+    /// assuming it took half the round trip time to arrive
+    fn estimate_clock_offset(&mut self, time: Duration, config: &Config, peer_time: Duration) {
+        let Some(rtt) = self.cached_rtt else {
+            return;
+        };
+
+        // subtract in f64 so that long running clocks don't lose precision
+        let sample = peer_time.as_secs_f64() + rtt.as_secs_f64() / 2. - time.as_secs_f64();
+        self.clock_offset_samples.push_back(sample as f32);
+
+        while self.clock_offset_samples.len() > config.rtt_memory {
+            self.clock_offset_samples.pop_front();
+        }
+
+        self.cached_clock_offset = Some(
+            self.clock_offset_samples.iter().sum::<f32>() / self.clock_offset_samples.len() as f32
+        );
+    }
+
+    /// estimates packet loss from how many packets the peer says it has received
+    /// compared to how many were sent since the previous heartbeat
+    ///
+    /// packets still in flight are assumed to roughly cancel out between heartbeats
+    fn estimate_packet_loss(&mut self, peer_received_packets: u64) {
+        let counts = (peer_received_packets, self.sent_packets);
+
+        if let Some((last_received, last_sent)) = self.last_peer_counts {
+            if peer_received_packets < last_received {
+                // heartbeats arrived out of order, ignore the older one
+                return;
+            }
+
+            let sent = self.sent_packets - last_sent;
+            let received = peer_received_packets - last_received;
+
+            if sent > 0 {
+                self.packet_loss = Some((1. - received as f32 / sent as f32).clamp(0., 1.));
+            }
+        }
+
+        self.last_peer_counts = Some(counts);
     }
 
     /// gets the round trip time
@@ -433,8 +1436,37 @@ impl Connection {
     }
 
 
+    /// gets how long the connection has been established for
+    ///
+    /// returns `None` if the connection hasn't been established yet
+    pub fn uptime(&self, time: Duration) -> Option<Duration> {
+        self.established_time.map(|established_time| time.saturating_sub(established_time))
+    }
+
+
+    /// checks if a fragmentation id is within the replay window,
+    /// updating the most recent id if it is newer
+    ///
+    /// ids are 15 bits and wrap around, so an id up to half the id space ahead is considered newer
+    fn in_replay_window(&mut self, id: u16, replay_window: u16) -> bool {
+        let Some(latest) = self.latest_fragmentation_id else {
+            self.latest_fragmentation_id = Some(id);
+            return true;
+        };
+
+        let behind = latest.wrapping_sub(id) & !(1 << 15);
+
+        if behind >= 1 << 14 {
+            // id is newer
+            self.latest_fragmentation_id = Some(id);
+            return true;
+        }
+
+        behind <= replay_window
+    }
+
     fn blacklist_id(&mut self, time: Duration, id: u16) {
-        self.reliable_blacklist.push((time, id));
+        self.reliable_blacklist.push_back((time, id));
     }
 
     fn is_blacklisted(&self, id: u16) -> bool {
@@ -462,26 +1494,184 @@ impl Connection {
         }
     }
 
-    pub fn metrics(&self) -> ConnectionMetrics {
+    /// sends a heartbeat in the next update outside of the regular interval,
+    /// getting a new round trip time sample once it's response is received
+    pub fn ping(&mut self) {
+        self.ping_requested = true;
+    }
+
+    /// pauses the connection, nothing is sent and the timeout clock is suspended until it is resumed
+    ///
+    /// the peer is told so that it doesn't time out either,
+    /// but if that packet is lost it will time out as usual
+    pub fn pause(&mut self, time: Duration) {
+        if self.paused.is_some() {
+            return;
+        }
+
+        self.paused = Some(time);
+        self.send_pause = Some(Blob::Pause);
+    }
+
+    /// resumes a paused connection, timers continue from where they were when it was paused
+    pub fn resume(&mut self, time: Duration) {
+        let Some(paused_at) = self.paused.take() else {
+            return;
+        };
+
+        let paused_for = time.saturating_sub(paused_at);
+
+        self.last_keep_alive = (self.last_keep_alive + paused_for).min(time);
+        self.last_heartbeat += paused_for;
+
+        for message in self.send_messages.iter_mut() {
+            if let Some(Some(last_sent)) = message.reliable() {
+                *last_sent += paused_for;
+            }
+        }
+
+        // the peer never found out if the pause wasn't sent yet
+        self.send_pause = match self.send_pause {
+            Some(Blob::Pause) => None,
+            _ => Some(Blob::Resume),
+        };
+    }
+
+    /// returns true if the connection is paused
+    pub fn paused(&self) -> bool {
+        self.paused.is_some()
+    }
+
+    /// returns true once after a reliable message has been resent more than
+    /// the [stall_resend_threshold](Config::stall_resend_threshold) without any progress
+    pub fn just_stalled(&mut self) -> bool {
+        if self.just_stalled {
+            self.just_stalled = false;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// returns `Some` once after the other party rejected the connection whilst it was being established,
+    /// with the reason they gave if any
+    pub fn just_rejected(&mut self) -> Option<Option<Box<str>>> {
+        self.just_rejected.take()
+    }
+
+    /// takes the packets held back because the send buffer was full, so they can
+    /// still be sent after the connection is removed
+    pub fn take_blocked_packets(&mut self) -> VecDeque<Box<[u8]>> {
+        std::mem::take(&mut self.blocked_packets)
+    }
+
+    /// holds back a packet that was counted as sent in a batch, but didn't fit in the send buffer
+    /// when the batch was flushed, see [flush_batch](crate::packet::FilteredSocket::flush_batch)
+    pub fn unsend_packet(&mut self, bytes: &[u8], sent_bytes: usize) {
+        self.sent_packets = self.sent_packets.saturating_sub(1);
+        self.sent_bytes = self.sent_bytes.saturating_sub(sent_bytes as u64);
+        self.just_blocked = true;
+
+        // handshakes are sent again after the interval anyway
+        if self.connecting() {
+            return;
+        }
+
+        if self.blocked_packets.len() >= MAX_BLOCKED_PACKETS {
+            self.blocked_packets.pop_front();
+        }
+
+        self.blocked_packets.push_back(bytes.into());
+    }
+
+    /// returns true once after an update in which the socket's send buffer was full
+    /// and packets had to be held back
+    pub fn just_blocked(&mut self) -> bool {
+        if self.just_blocked {
+            self.just_blocked = false;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// takes the fragmentation ids of reliable messages given up on after their maximum number of resends
+    pub fn drain_dropped_messages(&mut self) -> impl Iterator<Item = u16> + '_ {
+        self.dropped_messages.drain(..)
+    }
+
+    /// returns the new quality of the connection if it has changed since the last call
+    pub fn quality_changed(&mut self, config: &Config) -> Option<ConnectionQuality> {
+        let quality = self.metrics(config).quality_with_hysteresis(self.reported_quality)?;
+
+        if Some(quality) == self.reported_quality {
+            return None;
+        }
+
+        self.reported_quality = Some(quality);
+        Some(quality)
+    }
+
+    pub fn metrics(&self, config: &Config) -> ConnectionMetrics {
+        let config = self.config.as_deref().unwrap_or(config);
+
         ConnectionMetrics {
             sent_packets: self.sent_packets,
             sent_bytes: self.sent_bytes,
+            received_packets: self.received_packets,
+            received_bytes: self.received_bytes,
             rtt: self.cached_rtt,
+            packet_loss: self.packet_loss,
             rtv: self.cached_rtv,
+            clock_offset: self.cached_clock_offset,
             unreliable_message_count: self.unreliable_message_count,
             reliable_message_count: self.reliable_message_count,
-            messages_in_transit: self.send_messages.len(),
+            messages_in_transit: self.send_messages.len() + self.unreliable_messages.len(),
+            reordered_fragments: self.reordered_fragments,
+            pending_send_bytes: self.send_messages.iter().map(SendMessage::remaining_bytes).sum::<usize>() +
+                self.unreliable_messages.iter().map(|(_, _, data)| data.len()).sum::<usize>(),
+            active_send_id_count: self.active_send_id_count(),
+            active_receive_id_count: self.active_receive_id_count(),
+            mtu: self.mtu(config),
+            peer_mtu: self.peer_mtu,
+            quality_thresholds: config.quality_thresholds,
         }
     }
+
+    /// how many of our fragmentation ids can't be reused yet because their messages are still being sent
+    pub fn active_send_id_count(&self) -> usize {
+        self.send_messages.len() + self.unreliable_messages.len()
+    }
+
+    /// how many of the peer's fragmentation ids are for received messages that are still being assembled
+    /// or remembered in the reliable blacklist
+    pub fn active_receive_id_count(&self) -> usize {
+        self.receive_messages.len() + self.reliable_blacklist.len()
+    }
+
+    /// gets the recent [MetricsHistory] of the connection
+    pub fn metrics_history(&self) -> &MetricsHistory {
+        &self.metrics_history
+    }
 }
 
 impl<'a> PacketGrouper<'a> {
-    fn new(addr: SocketAddr, socket: &'a UdpSocket, mtu: u16, sent_packets: &'a mut u64, sent_bytes: &'a mut u64) -> Self {
+    fn new(
+        addr: SocketAddr,
+        socket: &'a FilteredSocket,
+        mtu: u16,
+        send_buffer: &'a mut Vec<u8>,
+        blocked_packets: &'a mut VecDeque<Box<[u8]>>,
+        sent_packets: &'a mut u64,
+        sent_bytes: &'a mut u64,
+    ) -> Self {
         PacketGrouper {
             addr,
             socket,
             mtu,
             current_packet: Packet::new(),
+            send_buffer,
+            blocked_packets,
             sent_packets,
             sent_bytes,
         }
@@ -498,14 +1688,64 @@ impl<'a> PacketGrouper<'a> {
         self.current_packet.push(blob);
     }
 
-    /// either garuntees that there is enough space for a blob, or errors
-    fn ensure_space(&mut self, space_needed: u16) -> Result<(), Error> {
-        if self.space_left() < space_needed {
+    /// adds an unreliable message that fits in a single fragment, sending the current packet if needed
+    fn push_unreliable(&mut self, fragmentation_id: u16, data: Box<[u8]>) -> Result<(), Error> {
+        let blob = Blob::Fragment(Fragment {
+            send_ack: false,
+            ordered: false,
+            tagged: false,
+            fragmentation_id,
+            total_size: data.len() as u32,
+            start: 0,
+            data,
+        });
+
+        if self.space_left() < blob.size() {
             self.create_space()?;
         }
 
-        if self.current_packet.space_left(self.mtu) < space_needed {
-            return Err(Error::MtuTooSmall);
+        self.push(blob);
+
+        Ok(())
+    }
+
+    /// adds the next fragment of a message that hasn't been sent in this wave, sending the current packet if needed
+    ///
+    /// returns false once there are no fragments left to send
+    fn push_fragment(&mut self, message: &mut SendMessage, delivered: &mut DeliveredIntervals) -> Result<bool, Error> {
+        loop {
+            let available_space = self.space_left();
+
+            let Some(blob) = message.create_blob(delivered, available_space) else {
+                // no more blobs to send
+                return Ok(false);
+            };
+
+            let Some(blob) = blob else {
+                // not enough space for a blob
+                self.create_space()?;
+                continue;
+            };
+
+            self.push(blob);
+
+            return Ok(true);
+        }
+    }
+
+    /// either garuntees that there is enough space for a control blob, or errors
+    fn ensure_space(&mut self, blob: ControlBlob, space_needed: u16) -> Result<(), Error> {
+        if self.space_left() < space_needed && self.current_packet.blob_count() > 0 {
+            self.create_space()?;
+        }
+
+        if self.space_left() < space_needed {
+            return Err(Error::MtuTooSmallForControlBlob {
+                addr: self.addr,
+                blob,
+                // blob and it's 2 byte length header
+                min_mtu: space_needed + 2,
+            });
         }
 
         Ok(())
@@ -519,22 +1759,1189 @@ impl<'a> PacketGrouper<'a> {
             return Err(Error::MtuTooSmall);
         }
 
-        let sent_bytes = self.current_packet.send(self.addr, &self.socket).map_err(|err| Error::IoError(err))?;
-        self.current_packet = Packet::new();
+        self.send_packet()
+    }
 
-        *self.sent_packets += 1;
-        *self.sent_bytes += sent_bytes as u64;
+    fn send_remaining(mut self) -> Result<(), Error> {
+        if self.current_packet.blob_count() > 0 {
+            self.send_packet()?;
+        }
 
         Ok(())
     }
 
-    fn send_remaining(self) -> Result<(), Error> {
-        if self.current_packet.blob_count() > 0 {
-            if let Err(err) = self.current_packet.send(self.addr, &self.socket) {
-                return Err(Error::IoError(err));
+    /// sends the current packet and starts a new one
+    ///
+    /// if the send buffer is full, or there are already packets waiting for it,
+    /// the packet is kept to be sent in the next update
+    fn send_packet(&mut self) -> Result<(), Error> {
+        self.send_buffer.clear();
+        self.current_packet.serialize_into(self.send_buffer);
+        self.current_packet.clear();
+
+        if self.blocked_packets.is_empty() {
+            if let Some(sent_bytes) = send_datagram(self.send_buffer, self.addr, self.socket).map_err(Error::IoError)? {
+                *self.sent_packets += 1;
+                *self.sent_bytes += sent_bytes as u64;
+                return Ok(());
             }
         }
 
+        if self.blocked_packets.len() >= MAX_BLOCKED_PACKETS {
+            // drop the oldest, reliable data will be resent anyway
+            self.blocked_packets.pop_front();
+        }
+
+        self.blocked_packets.push_back(self.send_buffer.as_slice().into());
+
         Ok(())
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+
+    /// creates a socket to send from and a peer socket to receive on
+    fn sockets() -> (FilteredSocket, FilteredSocket) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_nonblocking(true).unwrap();
+
+        (FilteredSocket::new(socket), FilteredSocket::new(peer))
+    }
+
+    /// receives all waiting packets on a socket
+    fn receive_packets(socket: &UdpSocket) -> Vec<Packet> {
+        let mut buffer = [0; 1500];
+        let mut packets = Vec::new();
+
+        while let Ok((len, _)) = socket.recv_from(&mut buffer) {
+            packets.push(Packet::deserialize(&buffer[..len]).unwrap());
+        }
+
+        packets
+    }
+
+    fn fragment_count(packets: Vec<Packet>) -> usize {
+        packets.into_iter()
+        .flat_map(Packet::into_iter)
+        .filter(|blob| matches!(blob, Blob::Fragment(_)))
+        .count()
+    }
+
+    /// sends four messages of about a packet each between two connections over loopback,
+    /// losing the fragments of the second message and delivering the packets in reverse order,
+    /// then updates both again after the resend threshold
+    ///
+    /// returns the first byte of each message in the order they were received
+    fn send_with_loss(reliability: Reliability) -> Vec<u8> {
+        let (socket, peer) = sockets();
+        socket.set_nonblocking(true).unwrap();
+        let config = Config {
+            mtu: 100,
+            ..Default::default()
+        };
+
+        let mut sender = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+        let mut receiver = Connection::new(Duration::ZERO, socket.local_addr().unwrap(), ConnectionId(1), false, &config);
+
+        for i in 0..4 {
+            sender.send_with_reliability(reliability, 0, [i; 60].into());
+        }
+
+        let mut received = Vec::new();
+        for time in [Duration::ZERO, Duration::from_secs(1)] {
+            sender.update(time, &config, &socket).unwrap();
+
+            let mut packets = receive_packets(&peer);
+            if time == Duration::ZERO {
+                packets = packets.into_iter().rev().map(|packet| {
+                    let mut kept = Packet::new();
+                    for blob in packet.into_iter() {
+                        if !matches!(&blob, Blob::Fragment(fragment) if fragment.fragmentation_id == 1) {
+                            kept.push(blob);
+                        }
+                    }
+                    kept
+                }).collect();
+            }
+
+            for packet in packets {
+                receiver.receive(time, &config, packet).unwrap();
+                receiver.flush_into(time, &mut received);
+            }
+
+            // send acknowledgements back
+            receiver.update(time, &config, &peer).unwrap();
+            for packet in receive_packets(&socket) {
+                sender.receive(time, &config, packet).unwrap();
+            }
+        }
+
+        received.into_iter().map(|(_, _, data)| data[0]).collect()
+    }
+
+    #[test]
+    fn unreliable_with_loss() {
+        assert_eq!(send_with_loss(Reliability::Unreliable), [3, 2, 0]);
+    }
+
+    #[test]
+    fn unreliable_sequenced_with_loss() {
+        // older messages arriving after a newer one are dropped
+        assert_eq!(send_with_loss(Reliability::UnreliableSequenced), [3]);
+    }
+
+    #[test]
+    fn sequenced_ignores_fragmentation_ids() {
+        let (socket, peer) = sockets();
+        let config = Config::default();
+
+        let mut sender = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+        let mut receiver = Connection::new(Duration::ZERO, socket.local_addr().unwrap(), ConnectionId(1), false, &config);
+
+        // as far apart in fragmentation ids as the replay window still accepts as newer,
+        // which used to be too far for sequenced messages
+        sender.send_with_reliability(Reliability::UnreliableSequenced, 0, [0].into());
+        for _ in 1..1 << 14 {
+            sender.take_fragmentation_id();
+        }
+        sender.send_with_reliability(Reliability::UnreliableSequenced, 0, [1].into());
+
+        let mut received = Vec::new();
+        sender.update(Duration::ZERO, &config, &socket).unwrap();
+        for packet in receive_packets(&peer) {
+            receiver.receive(Duration::ZERO, &config, packet).unwrap();
+        }
+        receiver.flush_into(Duration::ZERO, &mut received);
+
+        let mut received = received.into_iter().map(|(_, _, data)| data[0]).collect::<Vec<_>>();
+        received.sort();
+        assert_eq!(received, [0, 1]);
+    }
+
+    #[test]
+    fn reliable_with_loss() {
+        assert_eq!(send_with_loss(Reliability::Reliable), [3, 2, 0, 1]);
+    }
+
+    #[test]
+    fn ordered_sequences() {
+        let config = Config::default();
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let mut connection = Connection::new(Duration::ZERO, addr, ConnectionId(0), true, &config);
+
+        connection.resume_ordered_sequences(u16::MAX, 7);
+        for _ in 0..3 {
+            connection.send_with_reliability(Reliability::ReliableOrdered, 0, [0].into());
+        }
+
+        // carries on after the last message sent, across the wrap around
+        assert_eq!(connection.ordered_sequences(), (2, 7));
+
+        // the unacknowledged messages keep their sequence numbers in the next connection
+        let messages = connection.take_unacknowledged_ordered();
+        let mut resumed = Connection::new(Duration::ZERO, addr, ConnectionId(1), true, &config);
+        resumed.resume_ordered_sequences(2, 7);
+        resumed.requeue_ordered(messages);
+        let sequences: Vec<_> = resumed.send_messages.iter().filter_map(SendMessage::ordered_sequence).collect();
+        assert_eq!(sequences, [u16::MAX, 0, 1]);
+        assert_eq!(resumed.ordered_sequences(), (2, 7));
+    }
+
+    #[test]
+    fn reliable_ordered_with_loss() {
+        // held back until the lost message is resent
+        assert_eq!(send_with_loss(Reliability::ReliableOrdered), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn redundant_message() {
+        let (socket, peer) = sockets();
+        socket.set_nonblocking(true).unwrap();
+        let config = Config::default();
+
+        for drop_first in [false, true] {
+            let mut sender = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+            let mut receiver = Connection::new(Duration::ZERO, socket.local_addr().unwrap(), ConnectionId(1), false, &config);
+
+            let fragmentation_id = sender.send_redundant(0, [1, 2, 3].into());
+            sender.update(Duration::ZERO, &config, &socket).unwrap();
+
+            let mut copies = 0;
+            let mut copy_packets = Vec::new();
+            for (index, packet) in receive_packets(&peer).into_iter().enumerate() {
+                for blob in packet.into_iter() {
+                    if let Blob::Fragment(fragment) = blob {
+                        assert_eq!(fragment.fragmentation_id, fragmentation_id);
+                        copies += 1;
+                        copy_packets.push(index);
+
+                        if !(drop_first && copies == 1) {
+                            let mut packet = Packet::new();
+                            packet.push(Blob::Fragment(fragment));
+                            receiver.receive(Duration::ZERO, &config, packet).unwrap();
+                        }
+                    }
+                }
+            }
+            assert_eq!(copies, 2);
+            // the copies are sent in different datagrams
+            assert_ne!(copy_packets[0], copy_packets[1]);
+
+            let mut received = Vec::new();
+            receiver.flush_into(Duration::ZERO, &mut received);
+            assert_eq!(received, [(fragmentation_id, None, [1, 2, 3].into())] as [CompleteMessage; 1]);
+
+            // a copy arriving later is ignored like any retransmission
+            let mut packet = Packet::new();
+            packet.push(Blob::Fragment(Fragment {
+                send_ack: true,
+                ordered: false,
+                tagged: false,
+                fragmentation_id,
+                total_size: 3,
+                start: 0,
+                data: [1, 2, 3].into(),
+            }));
+            receiver.receive(Duration::ZERO, &config, packet).unwrap();
+            receiver.flush_into(Duration::ZERO, &mut received);
+            assert_eq!(received.len(), 1);
+
+            // the second copy isn't sent again after the first transmission
+            sender.update(Duration::ZERO, &config, &socket).unwrap();
+            assert!(receive_packets(&peer).into_iter().flat_map(Packet::into_iter).all(|blob| !matches!(blob, Blob::Fragment(_))));
+        }
+    }
+
+    #[test]
+    fn large_message_round_trip() {
+        let (socket, peer) = sockets();
+        socket.set_nonblocking(true).unwrap();
+        let config = Config {
+            mtu: 64,
+            ..Default::default()
+        };
+
+        let mut sender = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+        let mut receiver = Connection::new(Duration::ZERO, socket.local_addr().unwrap(), ConnectionId(1), false, &config);
+
+        let message: Box<[u8]> = (0..8192u32).map(|i| (i * 7 + i / 251) as u8).collect();
+        let fragmentation_id = sender.send(true, 0, message.clone());
+        let empty_id = sender.send(true, 0, [].into());
+
+        let mut received = Vec::new();
+        let mut dropped = 0;
+        for step in 0..100 {
+            let time = Duration::from_secs(step);
+            sender.update(time, &config, &socket).unwrap();
+
+            // drop every third packet and deliver the rest in reverse
+            let packets = receive_packets(&peer);
+            let sent = packets.len();
+            for (i, packet) in packets.into_iter().enumerate().rev() {
+                if (dropped + i) % 3 == 0 {
+                    continue;
+                }
+
+                receiver.receive(time, &config, packet).unwrap();
+            }
+            dropped += sent;
+
+            receiver.flush_into(time, &mut received);
+            if received.len() == 2 {
+                // lost fragments had to be resent
+                assert!(step > 0);
+                break;
+            }
+
+            // acknowledgements are dropped too
+            receiver.update(time, &config, &peer).unwrap();
+            for (i, packet) in receive_packets(&socket).into_iter().enumerate() {
+                if i % 4 != 3 {
+                    sender.receive(time, &config, packet).unwrap();
+                }
+            }
+        }
+
+        received.sort_by_key(|&(id, _, _)| id);
+        assert_eq!(received, [(fragmentation_id, None, message), (empty_id, None, [].into())]);
+    }
+
+    #[test]
+    fn fragmentation_id_wraps() {
+        let (socket, peer) = sockets();
+        socket.set_nonblocking(true).unwrap();
+        let config = Config::default();
+
+        let mut sender = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+        let mut receiver = Connection::new(Duration::ZERO, socket.local_addr().unwrap(), ConnectionId(1), false, &config);
+        sender.next_fragmentation_id = 0x7ffe;
+
+        let reliabilities = [Reliability::Unreliable, Reliability::Unreliable, Reliability::Unreliable, Reliability::ReliableOrdered];
+        let ids: Vec<_> = reliabilities.into_iter().enumerate()
+        .map(|(i, reliability)| sender.send_with_reliability(reliability, 0, [i as u8].into()))
+        .collect();
+        assert_eq!(ids, [0x7ffe, 0x7fff, 0, 1]);
+
+        sender.update(Duration::ZERO, &config, &socket).unwrap();
+        let mut received = Vec::new();
+        for packet in receive_packets(&peer) {
+            let mut checked = Packet::new();
+            for blob in packet.into_iter() {
+                if let Blob::Fragment(fragment) = &blob {
+                    // only the reliable message asks for an acknowledgement
+                    assert_eq!(fragment.send_ack, fragment.fragmentation_id == 1);
+                }
+                checked.push(blob);
+            }
+
+            receiver.receive(Duration::ZERO, &config, checked).unwrap();
+        }
+        receiver.flush_into(Duration::ZERO, &mut received);
+
+        let mut received: Vec<_> = received.into_iter().map(|(id, _, _)| id).collect();
+        received.sort();
+        assert_eq!(received, [0, 1, 0x7ffe, 0x7fff]);
+    }
+
+    #[test]
+    fn peer_mtu_floor() {
+        let config = Config::default();
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let mut connection = Connection::new(Duration::ZERO, addr, ConnectionId(0), false, &config);
+
+        let receive_mtu = |connection: &mut Connection, mtu| {
+            let mut packet = Packet::new();
+            packet.push(Blob::Mtu(mtu));
+            connection.receive(Duration::ZERO, &config, packet).unwrap();
+        };
+
+        // too small to hold any message data
+        receive_mtu(&mut connection, 1);
+        assert_eq!(connection.peer_mtu, None);
+
+        receive_mtu(&mut connection, 600);
+        assert_eq!(connection.peer_mtu, Some(600));
+
+        // a queued message would no longer fit
+        let config = Config {
+            max_message_fragments: 2,
+            ..Default::default()
+        };
+        connection.send(true, 0, vec![0; 1000].into());
+        connection.set_peer_mtu(200, &config);
+        assert_eq!(connection.peer_mtu, Some(600));
+
+        connection.set_peer_mtu(550, &config);
+        assert_eq!(connection.peer_mtu, Some(550));
+    }
+
+    #[test]
+    fn reliable_resend_threshold() {
+        let (socket, peer) = sockets();
+        let config = Config::default();
+
+        let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+        connection.cached_rtt = Some(Duration::from_millis(100));
+
+        connection.send(true, 0, [1, 2, 3].into());
+
+        connection.update(Duration::ZERO, &config, &socket).unwrap();
+        assert_eq!(fragment_count(receive_packets(&peer)), 1);
+
+        // resend threshold is 1.25 rtt
+        connection.update(Duration::from_millis(120), &config, &socket).unwrap();
+        assert_eq!(fragment_count(receive_packets(&peer)), 0);
+
+        connection.update(Duration::from_millis(130), &config, &socket).unwrap();
+        assert_eq!(fragment_count(receive_packets(&peer)), 1);
+        assert_eq!(connection.send_messages.len(), 1);
+
+        // acknowledge the message
+        let mut packet = Packet::new();
+        packet.push(Blob::Acknowledgement(Acknowledgement {
+            fragmentation_id: 0,
+            start: 0,
+            len: 3,
+        }));
+        connection.receive(Duration::from_millis(140), &config, packet).unwrap();
+
+        connection.update(Duration::from_millis(300), &config, &socket).unwrap();
+        assert_eq!(fragment_count(receive_packets(&peer)), 0);
+        assert_eq!(connection.send_messages.len(), 0);
+        assert_eq!(connection.metrics(&config).pending_send_bytes, 0);
+    }
+
+    #[test]
+    fn initial_rtt() {
+        let (socket, peer) = sockets();
+        let config = Config {
+            initial_rtt: Duration::from_millis(100),
+            ..Default::default()
+        };
+
+        let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+
+        connection.send(true, 0, [1, 2, 3].into());
+
+        connection.update(Duration::ZERO, &config, &socket).unwrap();
+        assert_eq!(fragment_count(receive_packets(&peer)), 1);
+
+        // resends without any rtt samples
+        connection.update(Duration::from_millis(130), &config, &socket).unwrap();
+        assert_eq!(fragment_count(receive_packets(&peer)), 1);
+    }
+
+    #[test]
+    fn stalled() {
+        let (socket, peer) = sockets();
+        let config = Config {
+            stall_resend_threshold: 2,
+            ..Default::default()
+        };
+
+        let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+        connection.cached_rtt = Some(Duration::from_millis(100));
+
+        connection.send(true, 0, [1, 2, 3].into());
+
+        // first send and two resends
+        for i in 0..3 {
+            connection.update(Duration::from_millis(200 * i), &config, &socket).unwrap();
+            assert!(!connection.just_stalled());
+        }
+
+        connection.update(Duration::from_millis(600), &config, &socket).unwrap();
+        assert!(connection.just_stalled());
+        assert!(!connection.just_stalled());
+
+        // partially acknowledge the message
+        let mut packet = Packet::new();
+        packet.push(Blob::Acknowledgement(Acknowledgement {
+            fragmentation_id: 0,
+            start: 0,
+            len: 1,
+        }));
+        connection.receive(Duration::from_millis(700), &config, packet).unwrap();
+
+        for i in 4..6 {
+            connection.update(Duration::from_millis(200 * i), &config, &socket).unwrap();
+            assert!(!connection.just_stalled());
+        }
+
+        connection.update(Duration::from_millis(1200), &config, &socket).unwrap();
+        assert!(connection.just_stalled());
+
+        // the largest threshold never stalls
+        let config = Config {
+            stall_resend_threshold: u32::MAX,
+            ..config
+        };
+        connection.update(Duration::from_millis(1400), &config, &socket).unwrap();
+        assert!(!connection.just_stalled());
+    }
+
+    #[test]
+    fn max_resends() {
+        let (socket, peer) = sockets();
+        let config = Config::default();
+
+        let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+        connection.cached_rtt = Some(Duration::from_millis(100));
+
+        let id = connection.send_with_max_resends(0, Some(2), [1, 2, 3].into());
+
+        // first send and two resends
+        for i in 0..3 {
+            connection.update(Duration::from_millis(200 * i), &config, &socket).unwrap();
+            assert_eq!(fragment_count(receive_packets(&peer)), 1);
+            assert_eq!(connection.drain_dropped_messages().count(), 0);
+        }
+
+        // given up on instead of being resent a third time
+        connection.update(Duration::from_millis(600), &config, &socket).unwrap();
+        assert_eq!(fragment_count(receive_packets(&peer)), 0);
+        assert_eq!(connection.drain_dropped_messages().collect::<Vec<_>>(), [id]);
+        assert_eq!(connection.send_messages.len(), 0);
+    }
+
+    #[test]
+    fn unreliable_sent_once() {
+        let (socket, peer) = sockets();
+        let config = Config::default();
+
+        let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+
+        connection.send(false, 0, [1, 2, 3].into());
+
+        connection.update(Duration::ZERO, &config, &socket).unwrap();
+        assert_eq!(fragment_count(receive_packets(&peer)), 1);
+        assert_eq!(connection.send_messages.len(), 0);
+    }
+
+    #[test]
+    fn unreliable_fast_path() {
+        let (socket, peer) = sockets();
+        let config = Config {
+            mtu: 20,
+            ..Default::default()
+        };
+
+        let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+
+        // fits in a single fragment
+        connection.send(false, 0, [0; 4].into());
+        // needs two fragments
+        connection.send(false, 0, [1; 12].into());
+        connection.send(true, 1, [2; 4].into());
+
+        assert_eq!(connection.unreliable_messages.len(), 2);
+        assert_eq!(connection.send_messages.len(), 1);
+        assert_eq!(connection.metrics(&config).messages_in_transit, 3);
+        assert_eq!(connection.metrics(&config).pending_send_bytes, 20);
+
+        connection.update(Duration::ZERO, &config, &socket).unwrap();
+        assert_eq!(fragment_count(receive_packets(&peer)), 4);
+
+        // only the reliable message is still in transit
+        assert_eq!(connection.unreliable_messages.len(), 0);
+        assert_eq!(connection.send_messages.len(), 1);
+        assert_eq!(connection.metrics(&config).pending_send_bytes, 4);
+    }
+
+    #[test]
+    fn send_priority() {
+        let (socket, peer) = sockets();
+        let config = Config {
+            mtu: 20,
+            ..Default::default()
+        };
+
+        let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+
+        connection.send(false, 0, [0; 4].into());
+        connection.send(false, 2, [2; 4].into());
+        connection.send(false, 1, [1; 4].into());
+        connection.send(false, 2, [3; 4].into());
+
+        connection.update(Duration::ZERO, &config, &socket).unwrap();
+
+        let sent: Vec<_> = receive_packets(&peer).into_iter()
+        .flat_map(Packet::into_iter)
+        .filter_map(|blob| match blob {
+            Blob::Fragment(fragment) => Some(fragment.data[0]),
+            _ => None,
+        })
+        .collect();
+
+        assert_eq!(sent, [2, 3, 1, 0]);
+    }
+
+    #[test]
+    fn send_scheduling() {
+        let (socket, peer) = sockets();
+
+        for (send_scheduling, expected) in [
+            (SendScheduling::FirstComplete, [1, 1, 2, 2]),
+            (SendScheduling::RoundRobin, [1, 2, 1, 2]),
+        ] {
+            // one fragment per packet
+            let config = Config {
+                mtu: 20,
+                send_scheduling,
+                ..Default::default()
+            };
+
+            let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+
+            connection.send(true, 0, [1; 14].into());
+            connection.send(true, 0, [2; 14].into());
+
+            connection.update(Duration::ZERO, &config, &socket).unwrap();
+
+            let sent: Vec<_> = receive_packets(&peer).into_iter()
+            .flat_map(Packet::into_iter)
+            .filter_map(|blob| match blob {
+                Blob::Fragment(fragment) => Some(fragment.data[0]),
+                _ => None,
+            })
+            .collect();
+
+            assert_eq!(sent, expected);
+        }
+    }
+
+    #[test]
+    fn packet_loss() {
+        let config = Config::default();
+        let mut connection = Connection::new(Duration::ZERO, "127.0.0.1:0".parse().unwrap(), ConnectionId(0), false, &config);
+
+        let heartbeat = |received_packets| {
+            let mut packet = Packet::new();
+            packet.push(Blob::Heartbeat(Heartbeat::new(Duration::ZERO, received_packets)));
+            packet
+        };
+
+        connection.sent_packets = 10;
+        connection.receive(Duration::ZERO, &config, heartbeat(8)).unwrap();
+        assert_eq!(connection.metrics(&config).packet_loss, None);
+
+        // 3 of the 4 packets sent since the last heartbeat arrived
+        connection.sent_packets = 14;
+        connection.receive(Duration::ZERO, &config, heartbeat(11)).unwrap();
+        assert_eq!(connection.metrics(&config).packet_loss, Some(0.25));
+
+        // older heartbeats are ignored
+        connection.sent_packets = 16;
+        connection.receive(Duration::ZERO, &config, heartbeat(10)).unwrap();
+        assert_eq!(connection.metrics(&config).packet_loss, Some(0.25));
+
+        connection.receive(Duration::ZERO, &config, heartbeat(13)).unwrap();
+        assert_eq!(connection.metrics(&config).packet_loss, Some(0.));
+    }
+
+    #[test]
+    fn clock_offset() {
+        let config = Config::default();
+        let mut connection = Connection::new(Duration::ZERO, "127.0.0.1:0".parse().unwrap(), ConnectionId(0), false, &config);
+
+        let heartbeat = |peer_time| {
+            let mut packet = Packet::new();
+            packet.push(Blob::Heartbeat(Heartbeat::new(Duration::from_millis(peer_time), 0)));
+            packet
+        };
+
+        // needs the round trip time
+        connection.receive(Duration::from_millis(400), &config, heartbeat(900)).unwrap();
+        assert_eq!(connection.metrics(&config).clock_offset, None);
+
+        connection.cached_rtt = Some(Duration::from_millis(100));
+
+        // sent half a round trip before it arrived, so the peer was 0.55 seconds ahead
+        connection.receive(Duration::from_millis(500), &config, heartbeat(1000)).unwrap();
+        let offset = connection.metrics(&config).clock_offset.unwrap();
+        assert!((offset - 0.55).abs() < 1e-4);
+
+        // averaged with the next sample of 0.53
+        connection.receive(Duration::from_millis(620), &config, heartbeat(1100)).unwrap();
+        let offset = connection.metrics(&config).clock_offset.unwrap();
+        assert!((offset - 0.54).abs() < 1e-4);
+    }
+
+    #[test]
+    fn ping() {
+        let (socket, peer) = sockets();
+        let config = Config::default();
+
+        let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+
+        let heartbeat_count = |packets: Vec<Packet>| packets.into_iter()
+        .flat_map(Packet::into_iter)
+        .filter(|blob| matches!(blob, Blob::Heartbeat(_)))
+        .count();
+
+        connection.update(config.heartbeat_interval, &config, &socket).unwrap();
+        assert_eq!(heartbeat_count(receive_packets(&peer)), 1);
+
+        let time = config.heartbeat_interval + Duration::from_millis(1);
+        connection.update(time, &config, &socket).unwrap();
+        assert_eq!(heartbeat_count(receive_packets(&peer)), 0);
+
+        connection.ping();
+        connection.update(time, &config, &socket).unwrap();
+        assert_eq!(heartbeat_count(receive_packets(&peer)), 1);
+
+        connection.update(time, &config, &socket).unwrap();
+        assert_eq!(heartbeat_count(receive_packets(&peer)), 0);
+    }
+
+    #[test]
+    fn blocked_packets_retried() {
+        let (socket, peer) = sockets();
+        let config = Config::default();
+
+        let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+
+        // as if the send buffer was full last update
+        let mut packet = Packet::new();
+        packet.push(Blob::Disconnect);
+        connection.blocked_packets.push_back(packet.serialize().into());
+
+        connection.send(false, 0, [1, 2, 3].into());
+        connection.update(Duration::ZERO, &config, &socket).unwrap();
+
+        let mut packets = receive_packets(&peer).into_iter();
+        assert!(matches!(packets.next().unwrap().into_iter().next(), Some(Blob::Disconnect)));
+        assert_eq!(fragment_count(packets.collect()), 1);
+
+        assert!(connection.blocked_packets.is_empty());
+        assert_eq!(connection.sent_packets, 2);
+        assert!(!connection.just_blocked());
+    }
+
+    #[test]
+    fn timeout() {
+        let (socket, peer) = sockets();
+        let config = Config::default();
+
+        let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+
+        connection.update(config.timeout_delay, &config, &socket).unwrap();
+        assert!(!connection.should_drop());
+
+        // receiving anything keeps the connection alive
+        connection.receive(config.timeout_delay, &config, Packet::new()).unwrap();
+
+        connection.update(config.timeout_delay * 2, &config, &socket).unwrap();
+        assert!(!connection.should_drop());
+
+        connection.update(config.timeout_delay * 2 + Duration::from_millis(1), &config, &socket).unwrap();
+        assert!(connection.should_drop());
+    }
+
+    #[test]
+    fn pause() {
+        let (socket, peer) = sockets();
+        let config = Config::default();
+
+        let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+        connection.send(true, 0, [1, 2, 3].into());
+        connection.update(Duration::ZERO, &config, &socket).unwrap();
+        receive_packets(&peer);
+
+        connection.pause(Duration::ZERO);
+
+        // only the pause is sent, and the connection doesn't time out
+        let time = config.timeout_delay * 2;
+        connection.update(time, &config, &socket).unwrap();
+        let packets = receive_packets(&peer);
+        assert_eq!(packets.len(), 1);
+        assert!(matches!(packets.into_iter().next().unwrap().into_iter().next(), Some(Blob::Pause)));
+        assert!(!connection.should_drop());
+
+        // timers continue from where they were paused
+        connection.resume(time);
+        connection.update(time, &config, &socket).unwrap();
+        let blobs: Vec<_> = receive_packets(&peer).into_iter().flat_map(Packet::into_iter).collect();
+        assert!(matches!(blobs[..], [Blob::Resume]));
+        assert!(!connection.should_drop());
+
+        connection.update(time + config.timeout_delay + Duration::from_millis(1), &config, &socket).unwrap();
+        assert!(connection.should_drop());
+    }
+
+    #[test]
+    fn paused_responses() {
+        let (socket, peer) = sockets();
+        let config = Config::default();
+
+        let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+        connection.pause(Duration::ZERO);
+
+        // heartbeats aren't responded to and only so many acknowledgements are kept
+        for fragmentation_id in 0..MAX_PAUSED_ACKNOWLEDGEMENTS as u16 + 10 {
+            let mut packet = Packet::new();
+            packet.push(Blob::Heartbeat(Heartbeat::new(Duration::ZERO, 0)));
+            packet.push(Blob::Fragment(Fragment {
+                send_ack: true,
+                ordered: false,
+                tagged: false,
+                fragmentation_id,
+                total_size: 1,
+                start: 0,
+                data: [0].into(),
+            }));
+            connection.receive(Duration::ZERO, &config, packet).unwrap();
+        }
+
+        assert!(connection.heartbeat_responses.is_empty());
+        assert_eq!(connection.acknowledgements.len(), MAX_PAUSED_ACKNOWLEDGEMENTS);
+
+        let time = Duration::from_secs(10);
+        connection.resume(time);
+        connection.update(time, &config, &socket).unwrap();
+        let blobs: Vec<_> = receive_packets(&peer).into_iter().flat_map(Packet::into_iter).collect();
+        assert!(!blobs.iter().any(|blob| matches!(blob, Blob::HeartbeatResponse(_))));
+    }
+
+    #[test]
+    fn peer_paused() {
+        let (socket, peer) = sockets();
+        let config = Config::default();
+
+        let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+
+        let mut packet = Packet::new();
+        packet.push(Blob::Pause);
+        connection.receive(Duration::ZERO, &config, packet).unwrap();
+
+        connection.update(config.timeout_delay * 2, &config, &socket).unwrap();
+        assert!(!connection.should_drop());
+
+        // a heartbeat means the peer has resumed
+        let mut packet = Packet::new();
+        packet.push(Blob::Heartbeat(Heartbeat::new(Duration::ZERO, 0)));
+        connection.receive(config.timeout_delay * 2, &config, packet).unwrap();
+
+        connection.update(config.timeout_delay * 3 + Duration::from_millis(1), &config, &socket).unwrap();
+        assert!(connection.should_drop());
+    }
+
+    #[test]
+    fn max_peer_pause() {
+        let (socket, peer) = sockets();
+        let config = Config::default();
+
+        let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+
+        for time in [Duration::ZERO, config.max_peer_pause] {
+            let mut packet = Packet::new();
+            packet.push(Blob::Pause);
+            connection.receive(time, &config, packet).unwrap();
+        }
+
+        connection.update(config.max_peer_pause, &config, &socket).unwrap();
+        assert!(!connection.should_drop());
+
+        // timed out once the pause runs out, the second pause blob didn't extend it
+        connection.update(config.max_peer_pause + config.timeout_delay + Duration::from_millis(1), &config, &socket).unwrap();
+        assert!(connection.should_drop());
+    }
+
+    #[test]
+    fn mtu_too_small_for_control_blob() {
+        let (socket, peer) = sockets();
+        let config = Config {
+            mtu: 18,
+            ..Default::default()
+        };
+
+        let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+
+        let Err(Error::MtuTooSmallForControlBlob { blob, min_mtu, .. }) = connection.update(config.heartbeat_interval, &config, &socket) else {
+            panic!();
+        };
+
+        assert_eq!(blob, ControlBlob::Heartbeat);
+        assert_eq!(min_mtu, 19);
+
+        let config = Config {
+            mtu: 19,
+            ..Default::default()
+        };
+
+        connection.update(config.heartbeat_interval * 2, &config, &socket).unwrap();
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut connection = Connection::new(Duration::ZERO, "127.0.0.1:0".parse().unwrap(), ConnectionId(0), false, &Config::default());
+
+        assert!(connection.in_replay_window(100, 50));
+        assert!(connection.in_replay_window(50, 50));
+        assert!(!connection.in_replay_window(49, 50));
+
+        // wrap around
+        assert!(connection.in_replay_window(16000, 50));
+        assert!(connection.in_replay_window(32000, 50));
+        assert!(connection.in_replay_window(20, 50));
+        assert_eq!(connection.latest_fragmentation_id, Some(20));
+        assert!(connection.in_replay_window(32767, 50));
+        assert!(!connection.in_replay_window(32000, 50));
+    }
+
+    #[test]
+    fn flush_into() {
+        let config = Config::default();
+        let mut connection = Connection::new(Duration::ZERO, "127.0.0.1:0".parse().unwrap(), ConnectionId(0), false, &config);
+
+        let mut packet = Packet::new();
+        for (fragmentation_id, total_size) in [(2, 1), (0, 1), (3, 2), (1, 1)] {
+            packet.push(Blob::Fragment(Fragment {
+                send_ack: false,
+                ordered: false,
+                tagged: false,
+                fragmentation_id,
+                total_size,
+                start: 0,
+                data: [fragmentation_id as u8].into(),
+            }));
+        }
+        connection.receive(Duration::ZERO, &config, packet).unwrap();
+
+        // complete messages come out in the order they were sent, incomplete ones are kept
+        let mut received = Vec::new();
+        connection.flush_into(Duration::ZERO, &mut received);
+        assert_eq!(received, [(0, None, [0].into()), (1, None, [1].into()), (2, None, [2].into())] as [CompleteMessage; 3]);
+        assert_eq!(connection.receive_messages.len(), 1);
+
+        // half of the incomplete message has been received
+        assert_eq!(connection.incoming_progress().collect::<Vec<_>>(), [(3, 0.5)]);
+    }
+
+    #[test]
+    fn reliable_blacklist() {
+        let (socket, peer) = sockets();
+        let config = Config {
+            timeout_delay: Duration::from_millis(500),
+            ..Default::default()
+        };
+
+        let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+        connection.cached_rtt = Some(Duration::from_millis(100));
+
+        let packet = || {
+            let mut packet = Packet::new();
+            packet.push(Blob::Fragment(Fragment {
+                send_ack: true,
+                ordered: false,
+                tagged: false,
+                fragmentation_id: 5,
+                total_size: 3,
+                start: 0,
+                data: [1, 2, 3].into(),
+            }));
+            packet
+        };
+
+        connection.receive(Duration::ZERO, &config, packet()).unwrap();
+
+        let mut received = Vec::new();
+        connection.flush_into(Duration::ZERO, &mut received);
+        assert_eq!(received.len(), 1);
+        assert_eq!(connection.reliable_blacklist.len(), 1);
+
+        // a retransmission is acknowledged again but not received twice
+        connection.receive(Duration::from_millis(10), &config, packet()).unwrap();
+        connection.flush_into(Duration::from_millis(10), &mut received);
+        assert_eq!(received.len(), 1);
+        assert_eq!(connection.acknowledgements.len(), 2);
+
+        // blacklist memory is 8 rtt, which is longer than the timeout delay
+        connection.update(Duration::from_millis(800), &config, &socket).unwrap();
+        assert_eq!(connection.reliable_blacklist.len(), 1);
+
+        connection.update(Duration::from_millis(801), &config, &socket).unwrap();
+        assert_eq!(connection.reliable_blacklist.len(), 0);
+    }
+
+    #[test]
+    fn reliable_blacklist_outlasts_timeout() {
+        let (socket, peer) = sockets();
+        let config = Config::default();
+
+        let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+        connection.cached_rtt = Some(Duration::from_millis(100));
+
+        connection.blacklist_id(Duration::ZERO, 5);
+
+        // 8 rtt is shorter than the timeout delay, so the id is kept for the timeout delay instead
+        connection.update(config.timeout_delay, &config, &socket).unwrap();
+        assert!(connection.is_blacklisted(5));
+
+        connection.update(config.timeout_delay + Duration::from_millis(1), &config, &socket).unwrap();
+        assert!(!connection.is_blacklisted(5));
+    }
+
+    #[test]
+    fn blacklist_modes() {
+        let (socket, peer) = sockets();
+
+        let config = Config {
+            reliable_blacklist: BlacklistMode::FixedSize(2),
+            ..Default::default()
+        };
+        let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+
+        for id in 0..4 {
+            connection.blacklist_id(Duration::ZERO, id);
+        }
+        connection.update(Duration::ZERO, &config, &socket).unwrap();
+        assert!(!connection.is_blacklisted(1));
+        assert!(connection.is_blacklisted(2));
+        assert!(connection.is_blacklisted(3));
+
+        let config = Config {
+            reliable_blacklist: BlacklistMode::Disabled,
+            ..Default::default()
+        };
+        connection.update(Duration::ZERO, &config, &socket).unwrap();
+        assert!(connection.reliable_blacklist.is_empty());
+    }
+
+    #[test]
+    fn unreliable_drop_delay_floor() {
+        let (socket, peer) = sockets();
+        let config = Config::default();
+
+        let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+        connection.cached_rtt = Some(Duration::from_millis(1));
+
+        let mut packet = Packet::new();
+        packet.push(Blob::Fragment(Fragment {
+            send_ack: false,
+            ordered: false,
+            tagged: false,
+            fragmentation_id: 0,
+            total_size: 10,
+            start: 0,
+            data: [0; 5].into(),
+        }));
+        connection.receive(Duration::ZERO, &config, packet).unwrap();
+
+        // 4 rtt is shorter than the floor, so the message is kept until the floor is reached
+        connection.update(config.min_unreliable_drop_delay - Duration::from_millis(1), &config, &socket).unwrap();
+        assert_eq!(connection.receive_messages.len(), 1);
+
+        connection.update(config.min_unreliable_drop_delay, &config, &socket).unwrap();
+        assert!(connection.receive_messages.is_empty());
+    }
+
+    #[test]
+    fn active_id_count() {
+        let (_, peer) = sockets();
+        let config = Config::default();
+
+        let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+        let metrics = connection.metrics(&config);
+        assert_eq!((metrics.active_send_id_count, metrics.active_receive_id_count), (0, 0));
+
+        connection.send(true, 0, [1, 2, 3].into());
+        connection.blacklist_id(Duration::ZERO, 5);
+
+        let mut packet = Packet::new();
+        packet.push(Blob::Fragment(Fragment {
+            send_ack: false,
+            ordered: false,
+            tagged: false,
+            fragmentation_id: 0,
+            total_size: 10,
+            start: 0,
+            data: [0; 5].into(),
+        }));
+        connection.receive(Duration::ZERO, &config, packet).unwrap();
+
+        // the send and receive ids are separate spaces
+        let metrics = connection.metrics(&config);
+        assert_eq!((metrics.active_send_id_count, metrics.active_receive_id_count), (1, 2));
+    }
+
+    #[test]
+    fn receive_window() {
+        let (socket, peer) = sockets();
+        let config = Config::default();
+
+        let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+        connection.cached_rtt = Some(Duration::from_millis(100));
+
+        let mut packet = Packet::new();
+        packet.push(Blob::ReceiveWindow(4));
+        connection.receive(Duration::ZERO, &config, packet).unwrap();
+        assert_eq!(connection.peer_window, Some(4));
+
+        // the first message is allowed even though it's larger than the window
+        connection.send(true, 0, [1, 2, 3, 4, 5].into());
+        connection.send(true, 0, [1].into());
+        connection.update(Duration::ZERO, &config, &socket).unwrap();
+
+        let blobs: Vec<_> = receive_packets(&peer).into_iter().flat_map(Packet::into_iter).collect();
+        assert!(blobs.iter().any(|blob| matches!(blob, Blob::ReceiveWindow(window) if *window == config.receive_window)));
+        assert_eq!(blobs.iter().filter(|blob| matches!(blob, Blob::Fragment(_))).count(), 1);
+
+        // the second message is sent once the first is acknowledged
+        let mut packet = Packet::new();
+        packet.push(Blob::Acknowledgement(Acknowledgement {
+            fragmentation_id: 0,
+            start: 0,
+            len: 5,
+        }));
+        connection.receive(Duration::from_millis(10), &config, packet).unwrap();
+
+        connection.update(Duration::from_millis(10), &config, &socket).unwrap();
+        assert_eq!(fragment_count(receive_packets(&peer)), 1);
+
+        // the received part of reliable messages shrinks the advertised window,
+        // the rest is still counted as in flight by the peer
+        let mut packet = Packet::new();
+        packet.push(Blob::Fragment(Fragment {
+            send_ack: true,
+            ordered: false,
+            tagged: false,
+            fragmentation_id: 0,
+            total_size: 100,
+            start: 0,
+            data: [0; 10].into(),
+        }));
+        connection.receive(Duration::from_millis(20), &config, packet).unwrap();
+        assert_eq!(connection.receive_window(&config), config.receive_window - 10);
+
+        connection.update(Duration::from_millis(20), &config, &socket).unwrap();
+        let blobs: Vec<_> = receive_packets(&peer).into_iter().flat_map(Packet::into_iter).collect();
+        assert!(blobs.iter().any(|blob| matches!(blob, Blob::ReceiveWindow(window) if *window == config.receive_window - 10)));
+    }
+
+    #[test]
+    fn negative_acknowledgement() {
+        let (socket, peer) = sockets();
+        let config = Config::default();
+
+        let mut connection = Connection::new(Duration::ZERO, peer.local_addr().unwrap(), ConnectionId(0), false, &config);
+        connection.cached_rtt = Some(Duration::from_millis(100));
+
+        // receiving a later fragment reports the gap before it, but only once
+        for start in [0, 20, 30] {
+            let mut packet = Packet::new();
+            packet.push(Blob::Fragment(Fragment {
+                send_ack: true,
+                ordered: false,
+                tagged: false,
+                fragmentation_id: 0,
+                total_size: 40,
+                start,
+                data: [0; 10].into(),
+            }));
+            connection.receive(Duration::ZERO, &config, packet).unwrap();
+        }
+
+        connection.update(Duration::ZERO, &config, &socket).unwrap();
+        let naks: Vec<_> = receive_packets(&peer).into_iter().flat_map(Packet::into_iter).filter_map(|blob| match blob {
+            Blob::NegativeAcknowledgement(nak) => Some(nak),
+            _ => None,
+        }).collect();
+        assert_eq!(naks.len(), 1);
+        assert_eq!(naks[0].fragmentation_id, 0);
+        assert_eq!(naks[0].ranges, vec![(10, 10)]);
+
+        // the sender resends only the missing range, before the resend threshold
+        connection.send(true, 0, [1; 40].into());
+        connection.update(Duration::ZERO, &config, &socket).unwrap();
+        receive_packets(&peer);
+
+        // a range outside the message is skipped without losing the rest of the packet
+        let mut packet = Packet::new();
+        packet.push(Blob::NegativeAcknowledgement(NegativeAcknowledgement {
+            fragmentation_id: 0,
+            ranges: vec![(30, 20), (10, 10)],
+        }));
+        packet.push(Blob::ReceiveWindow(1234));
+        connection.receive(Duration::from_millis(10), &config, packet).unwrap();
+        assert_eq!(connection.peer_window, Some(1234));
+
+        connection.update(Duration::from_millis(10), &config, &socket).unwrap();
+        let fragments: Vec<_> = receive_packets(&peer).into_iter().flat_map(Packet::into_iter).filter_map(|blob| match blob {
+            Blob::Fragment(fragment) => Some((fragment.start, fragment.data.len())),
+            _ => None,
+        }).collect();
+        assert_eq!(fragments, vec![(10, 10)]);
+
+        // not a resend, so the threshold is unchanged
+        connection.update(Duration::from_millis(120), &config, &socket).unwrap();
+        assert_eq!(fragment_count(receive_packets(&peer)), 0);
+        connection.update(Duration::from_millis(130), &config, &socket).unwrap();
+        assert_eq!(fragment_count(receive_packets(&peer)), 1);
+    }
+}
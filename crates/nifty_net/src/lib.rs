@@ -4,11 +4,22 @@ pub(crate) mod connection;
 pub(crate) mod packet;
 pub(crate) mod message;
 pub mod metrics;
+pub mod capture;
+#[cfg(all(any(feature = "sendmmsg", feature = "recvmmsg"), target_os = "linux"))]
+pub(crate) mod batch;
 
 pub mod prelude {
-    pub use crate::socket::{Socket, SocketEvent};
-    pub use crate::Config;
-    pub use crate::metrics::ConnectionMetrics;
+    pub use crate::socket::{Socket, SocketHandle, SocketEvent, OwnedSocketEvent, ConnectionChange, ConnectionMut, DetachedConnection, HandshakeIgnoredReason, OrderedSequences, ReceiveBuffer, Received, ReceivedChunk, SendError, ConnectError, ConfigError};
+    pub use crate::{BlacklistMode, Config, ConnectionId, Reliability, SendScheduling};
+    pub use crate::metrics::{ConnectionMetrics, ConnectionQuality, MetricsHistory, SocketStats};
+}
+
+/// internals used by the benchmarks, not part of the public api
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::packet::{Packet, Blob, Fragment};
+    pub use crate::message::ReceiveMessage;
 }
 
 #[derive(Clone)]
@@ -31,6 +42,11 @@ pub struct Config {
     pub handshake_interval: std::time::Duration,
     /// how many round trip time samples to keep to calculate an average from
     pub rtt_memory: usize,
+    /// the round trip time to assume until the first sample is received
+    ///
+    /// reliable messages are resent based on this estimate,
+    /// so they can still be delivered if the first heartbeats are lost
+    pub initial_rtt: std::time::Duration,
     /// what multiple of the round trip time to wait before resending unacknowledged fragments
     ///
     /// the lower this is the more likely an unecessary resend occurs
@@ -44,17 +60,91 @@ pub struct Config {
     /// if unreliable messages get fragmented and not all of the message is received
     /// then the incomplete message will sit in memory until this threshold is reached
     pub unreliable_drop_threshhold: f32,
-    /// what multiple of the round trip time to wait before forgetting the id of a reliable message
+    /// the shortest time to wait before dropping incomplete unreliable messages
+    ///
+    /// on links with a round trip time close to zero the [unreliable_drop_threshhold](Config::unreliable_drop_threshhold)
+    /// alone would drop messages before the rest of their fragments have a chance to arrive
+    pub min_unreliable_drop_delay: std::time::Duration,
+    /// how long to remember the ids of completed reliable messages
     ///
     /// when reliable message fragments get retransmitted because the ack wasn't received,
     /// they could be received twice after the message has been completed.
     /// in order to recognise that it is not a new message we need to keep a blacklist
-    /// of received messages. this option controls how long to wait before forgetting those ids.
-    /// if it is too low, then reliable message fragments won't be ignored and will be received twice at best
-    /// and at worst be a memory leak as it waits forever for other fragments to complete it
-    pub reliable_message_blacklist_memory: f32,
+    /// of received messages, see [BlacklistMode] for the trade-offs
+    pub reliable_blacklist: BlacklistMode,
+    /// how many fragmentation ids behind the most recent one received to accept fragments from
+    ///
+    /// fragments with older ids are dropped, which stops old fragments from being replayed
+    /// into a message that is reusing the same id.
+    /// this must be larger than the number of messages that could be sent whilst a reliable message
+    /// is still being retransmitted, else it will never be received.
+    /// ids are 15 bits, so the largest useful value is `16383`
+    pub replay_window: u16,
     /// how long to wait before dropping a connection because no packets were received
     pub timeout_delay: std::time::Duration,
+    /// the longest the peer can pause a connection for, after which it's timed out as if it hadn't paused
+    pub max_peer_pause: std::time::Duration,
+    /// how many times a reliable message can be resent without any of it being acknowledged
+    /// before the connection is considered stalled
+    ///
+    /// gives earlier warning of a dead connection than the timeout delay when there are reliable messages in transit.
+    /// `u32::MAX` to never consider a connection stalled
+    pub stall_resend_threshold: u32,
+    /// how many connections to allocate space for when the socket is bound
+    ///
+    /// avoids reallocating when lots of connections are made at once
+    pub expected_connections: usize,
+    /// how many in transit messages to allocate space for when a connection is made
+    pub expected_messages_in_transit: usize,
+    /// the most fragments a single message can be split into at the connection's mtu,
+    /// larger messages can't be sent
+    ///
+    /// bounds the work needed to keep track of the delivery of a message
+    pub max_message_fragments: u32,
+    /// how fragments are taken from messages of the same priority when sending
+    pub send_scheduling: SendScheduling,
+    /// how many bytes of reliable messages can be waiting to be acknowledged
+    /// before [try_send_reliable](socket::Socket::try_send_reliable) rejects new ones
+    ///
+    /// doesn't limit regular sends
+    pub max_pending_reliable_bytes: usize,
+    /// how many bytes of reliable messages a connection is willing to buffer whilst receiving them,
+    /// advertised to the peer so that it stops sending new reliable messages once this is reached
+    pub receive_window: u32,
+    /// reliable messages at least this many bytes large are passed on in chunks as they arrive,
+    /// with [ReceivedChunk](socket::SocketEvent::ReceivedChunk) events, instead of being buffered whole.
+    /// sequenced and ordered messages are never streamed
+    ///
+    /// `None` to always buffer whole messages
+    pub min_streamed_message_size: Option<usize>,
+    /// how long the [OrderedSequences](socket::OrderedSequences) of a closed connection with a token are remembered,
+    /// so that a connection opened or accepted with the same token carries on from them
+    /// and ordered messages stay in order across a brief disconnect
+    ///
+    /// sessions are only told apart by their token, so when this is set tokens must be unique to each session,
+    /// such as a random session id. any connection with the same token carries on the session,
+    /// including being sent the ordered messages the previous peer hadn't acknowledged
+    ///
+    /// `None` to start every connection's sequence numbers from zero
+    pub session_resume_delay: Option<std::time::Duration>,
+    /// the most connections to update each time timers are processed, taking turns in the order they were made
+    /// so that every connection is serviced fairly when there are too many to update at once.
+    /// incoming packets are still processed for every connection
+    ///
+    /// `None` to update every connection every time
+    pub max_connection_updates: Option<std::num::NonZeroUsize>,
+    /// the most pending and rejected connection requests to keep at once,
+    /// once full the request that last sent a handshake the longest ago is forgotten
+    ///
+    /// bounds the memory used by handshakes from lots of addresses, such as a flood of spoofed ones
+    pub max_connection_requests: usize,
+    /// how often to record a window of a connection's [MetricsHistory](metrics::MetricsHistory)
+    pub metrics_sample_interval: std::time::Duration,
+    /// how many windows of a connection's [MetricsHistory](metrics::MetricsHistory) to keep,
+    /// zero disables recording history
+    pub metrics_history_length: usize,
+    /// thresholds used to classify the [ConnectionQuality](metrics::ConnectionQuality) of connections
+    pub quality_thresholds: metrics::QualityThresholds,
 }
 
 impl Default for Config {
@@ -65,20 +155,147 @@ impl Default for Config {
             heartbeat_interval: std::time::Duration::from_millis(500),
             handshake_interval: std::time::Duration::from_millis(100),
             rtt_memory: 16,
+            initial_rtt: std::time::Duration::from_millis(500),
             reliable_resend_threshold: 1.25,
             unreliable_drop_threshhold: 4.,
-            reliable_message_blacklist_memory: 8.,
+            min_unreliable_drop_delay: std::time::Duration::from_millis(100),
+            reliable_blacklist: BlacklistMode::TimeBased(8.),
+            replay_window: 8192,
             timeout_delay: std::time::Duration::from_millis(10_000),
+            max_peer_pause: std::time::Duration::from_secs(300),
+            stall_resend_threshold: 8,
+            expected_connections: 0,
+            expected_messages_in_transit: 0,
+            max_message_fragments: 1 << 16,
+            send_scheduling: SendScheduling::FirstComplete,
+            max_pending_reliable_bytes: 1 << 20,
+            receive_window: 1 << 22,
+            min_streamed_message_size: None,
+            session_resume_delay: None,
+            max_connection_updates: None,
+            max_connection_requests: 1024,
+            metrics_sample_interval: std::time::Duration::from_secs(1),
+            metrics_history_length: 0,
+            quality_thresholds: Default::default(),
+        }
+    }
+}
+
+/// an opaque identifier for a connection, unique within a socket
+///
+/// assigned when the connection is created, unlike the address it stays the same for the life of the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    /// gets the raw value of the id, useful for logging
+    pub fn to_bits(self) -> u64 {
+        self.0
+    }
+}
+
+/// how a connection remembers the ids of completed reliable messages, see [reliable_blacklist](Config::reliable_blacklist)
+///
+/// if an id is forgotten too soon a retransmitted fragment won't be ignored, the message will be received twice at best
+/// and at worst be a memory leak as it waits forever for other fragments to complete it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlacklistMode {
+    /// forget ids after this multiple of the round trip time
+    ///
+    /// the memory has to outlast the longest time a sender could keep resending a message,
+    /// which is bounded by the [timeout_delay](Config::timeout_delay) as it stops once it stops hearing from us,
+    /// so ids are always kept for at least that long however short the round trip time is.
+    /// the safest choice, but the blacklist grows with the rate reliable messages are received
+    TimeBased(f32),
+    /// keep only the ids of this many of the most recent messages, trimmed every update
+    ///
+    /// memory use is bounded even during bursts, but if more messages than this complete
+    /// before a sender gives up on a lost acknowledgement, a message can be received twice
+    FixedSize(usize),
+    /// forget ids at the end of every update
+    ///
+    /// uses no memory between updates, for peers that can't afford it,
+    /// but any retransmission after the update a message completed in is received again as a new message
+    Disabled,
+}
+
+/// the order fragments are sent in when several messages of the same priority are waiting to be sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendScheduling {
+    /// send all of one message before moving onto the next, in the order they were sent.
+    /// gives the lowest latency to the first message being completed
+    FirstComplete,
+    /// take one fragment from each message in turn, sharing bandwidth fairly between them
+    RoundRobin,
+}
+
+/// how a message is delivered, see [send_with_reliability](socket::Socket::send_with_reliability)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Reliability {
+    /// the message may be lost and messages can arrive in any order
+    Unreliable,
+    /// the message may be lost, and is dropped if a newer sequenced message has already been received.
+    /// useful for state updates where only the latest matters
+    UnreliableSequenced,
+    /// the message is resent until it is acknowledged, but messages can arrive in any order
+    Reliable,
+    /// the message is resent until it is acknowledged and is received in the order it was sent
+    /// relative to other ordered messages, held back until the messages before it have been received
+    ReliableOrdered,
+}
+
+impl Reliability {
+    /// if the message is resent until it's acknowledged
+    pub fn is_reliable(self) -> bool {
+        matches!(self, Reliability::Reliable | Reliability::ReliableOrdered)
+    }
+
+    /// if the message is delivered in order, either by waiting for earlier messages or dropping them
+    pub fn is_ordered(self) -> bool {
+        matches!(self, Reliability::UnreliableSequenced | Reliability::ReliableOrdered)
+    }
+}
+
+/// `true` is [Reliable](Reliability::Reliable) and `false` is [Unreliable](Reliability::Unreliable)
+impl From<bool> for Reliability {
+    fn from(reliable: bool) -> Self {
+        if reliable {
+            Reliability::Reliable
+        } else {
+            Reliability::Unreliable
         }
     }
 }
 
+/// blobs sent by a connection to maintain itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlBlob {
+    Heartbeat,
+    HeartbeatResponse,
+    Acknowledgement,
+    Disconnect,
+    Pause,
+    Resume,
+    ReceiveWindow,
+    NegativeAcknowledgement,
+    Handoff,
+    Mtu,
+    HandoffKey,
+}
+
 #[derive(Debug)]
 pub enum Error {
     /// an io error occurred
     IoError(std::io::Error),
     /// the mtu in the config made it impossible to complete a task
     MtuTooSmall,
+    /// the mtu in the config is too small to fit a blob needed to maintain a connection,
+    /// the connection can't function until the mtu is at least `min_mtu`
+    MtuTooSmallForControlBlob {
+        addr: std::net::SocketAddr,
+        blob: ControlBlob,
+        min_mtu: u16,
+    },
     /// some part of a packet from `add` was malformed
     MalformedPacket {
         addr: std::net::SocketAddr,
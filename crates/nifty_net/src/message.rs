@@ -1,4 +1,4 @@
-use std::{ops::Range, time::Duration};
+use std::{collections::{btree_map::Entry, BTreeMap}, ops::Range, time::Duration};
 
 use crate::packet::{Blob, Fragment};
 
@@ -13,16 +13,53 @@ pub struct SendMessage {
     /// the inner option being `None` if data was never sent
     reliable: Option<Option<Duration>>,
     fragmentation_id: u16,
+    /// messages with a higher priority are sent first
+    priority: u8,
     /// how much of the message has been delivered
     delivered: DeliveredIntervals,
+    /// how much of the message has been sent at least once,
+    /// acknowledgements for anything else are ignored
+    sent: DeliveredIntervals,
+    /// how many times the message has been resent since part of it was last acknowledged
+    resends_without_progress: u32,
+    /// how many times the message has been resent in total
+    resends: u32,
+    /// if `Some` a reliable message is given up on instead of being resent more than this many times
+    max_resends: Option<u32>,
+    /// ranges the peer reported missing, resent in the next update instead of waiting for the resend threshold
+    missing: Vec<Range<usize>>,
+    /// if the message is sequenced or ordered, see [Reliability](crate::Reliability)
+    ordered: bool,
+    /// if the message starts with a tag, see [send_with_tag](crate::socket::Socket::send_with_tag)
+    tagged: bool,
+    /// if the first transmission of the message is sent twice, see [send_redundant](crate::socket::Socket::send_redundant)
+    redundant: bool,
 }
 
 pub struct ReceiveMessage {
+    /// empty if the message is streamed
     data: Box<[u8]>,
+    /// set if the message is passed on in chunks as it arrives instead of all at once
+    stream: Option<ReceiveStream>,
     reliable: bool,
+    ordered: bool,
+    tagged: bool,
     fragmentation_id: u16,
     delivered: DeliveredIntervals,
     last_received_time: Duration,
+    /// the highest fragment start index received so far
+    highest_start: u32,
+    /// gaps before this index have already been reported to the sender
+    reported_until: u32,
+}
+
+/// the state of a message that is passed on in chunks, see [new_streamed](ReceiveMessage::new_streamed)
+struct ReceiveStream {
+    /// bytes before this have been passed on
+    streamed_until: usize,
+    /// received data that can't be passed on yet, by where it starts in the message.
+    /// resends can overlap with each other or with data already passed on
+    pending: BTreeMap<usize, Box<[u8]>>,
 }
 
 /// what portion of a message is delivered
@@ -39,15 +76,50 @@ pub struct DeliveredIntervalsGaps<'a> {
 
 
 impl SendMessage {
-    pub fn new(reliable: bool, fragmentation_id: u16, data: Box<[u8]>) -> Self {
+    pub fn new(reliable: bool, fragmentation_id: u16, priority: u8, data: Box<[u8]>) -> Self {
         SendMessage {
             delivered: DeliveredIntervals::new(data.len()),
+            sent: DeliveredIntervals::new(data.len()),
             data,
             reliable: if reliable { Some(None) } else { None },
             fragmentation_id,
+            priority,
+            resends_without_progress: 0,
+            resends: 0,
+            max_resends: None,
+            missing: Vec::new(),
+            ordered: false,
+            tagged: false,
+            redundant: false,
         }
     }
 
+    /// marks the message as sequenced or ordered, see [Reliability](crate::Reliability)
+    pub fn set_ordered(&mut self, ordered: bool) {
+        self.ordered = ordered;
+    }
+
+    /// marks the message as starting with a tag, see [send_with_tag](crate::socket::Socket::send_with_tag)
+    pub fn set_tagged(&mut self, tagged: bool) {
+        self.tagged = tagged;
+    }
+
+    /// marks the message to have it's first transmission sent twice,
+    /// see [send_redundant](crate::socket::Socket::send_redundant)
+    pub fn set_redundant(&mut self, redundant: bool) {
+        self.redundant = redundant;
+    }
+
+    /// if the first transmission of the message is sent twice
+    pub fn is_redundant(&self) -> bool {
+        self.redundant
+    }
+
+    /// gets the priority of the message
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
     /// gets the fragmentation id of the message
     pub fn fragmentation_id(&self) -> u16 {
         self.fragmentation_id
@@ -60,6 +132,57 @@ impl SendMessage {
         self.reliable.as_mut()
     }
 
+    /// if the message needs to be acknowledged
+    pub fn is_reliable(&self) -> bool {
+        self.reliable.is_some()
+    }
+
+    /// returns true if the message is reliable and has been sent at least once
+    pub fn in_flight(&self) -> bool {
+        matches!(self.reliable, Some(Some(_)))
+    }
+
+    /// how many times the message has been resent since part of it was last acknowledged
+    pub fn resends_without_progress(&self) -> u32 {
+        self.resends_without_progress
+    }
+
+    /// records that the message is being resent
+    pub fn mark_resent(&mut self) {
+        self.resends_without_progress += 1;
+        self.resends += 1;
+    }
+
+    /// limits how many times a reliable message is resent before it is given up on, `None` for no limit
+    pub fn set_max_resends(&mut self, max_resends: Option<u32>) {
+        self.max_resends = max_resends;
+    }
+
+    /// returns true if the message has been resent as many times as it's limit allows
+    pub fn resends_exhausted(&self) -> bool {
+        self.max_resends.is_some_and(|max_resends| self.resends >= max_resends)
+    }
+
+    /// the same message with a new fragmentation id and none of it sent yet,
+    /// for sending it again in a new connection
+    pub fn restart(self, fragmentation_id: u16) -> Self {
+        let mut message = SendMessage::new(self.is_reliable(), fragmentation_id, self.priority, self.data);
+        message.ordered = self.ordered;
+        message.tagged = self.tagged;
+        message.max_resends = self.max_resends;
+        message
+    }
+
+    /// the sequence number a [ReliableOrdered](crate::Reliability::ReliableOrdered) message starts with,
+    /// `None` for any other message
+    pub fn ordered_sequence(&self) -> Option<u16> {
+        if !self.ordered || self.reliable.is_none() {
+            return None;
+        }
+
+        Some(u16::from_be_bytes(self.data.get(..2)?.try_into().unwrap()))
+    }
+
     /// gets this messages [DeliveredIntervals]
     pub fn get_deliverd_intervals(&self) -> DeliveredIntervals {
         self.delivered.clone()
@@ -74,17 +197,73 @@ impl SendMessage {
 
     /// sets that a range of data has been delivered, typically from an acknowledgement
     ///
+    /// ranges that haven't been sent yet are ignored, so a stale or spoofed acknowledgement
+    /// can't complete a message the peer never received
+    ///
     /// fails if range was outside the message
     pub fn set_delivered(&mut self, range: Range<usize>) -> Result<(), ()> {
         if range.end > self.data.len() {
             return Err(());
         }
 
+        if !self.sent.contains(&range) {
+            return Ok(());
+        }
+
+        let delivered_bytes = self.delivered.delivered_bytes();
+
         self.delivered.set_delivered(range);
 
+        if self.delivered.delivered_bytes() > delivered_bytes {
+            self.resends_without_progress = 0;
+        }
+
+        Ok(())
+    }
+
+    /// records that the peer is missing a range, typically from a negative acknowledgement
+    ///
+    /// ranges of unreliable messages or that haven't been sent yet are ignored
+    ///
+    /// fails if range was outside the message
+    pub fn set_missing(&mut self, range: Range<usize>) -> Result<(), ()> {
+        if range.start > range.end || range.end > self.data.len() {
+            return Err(());
+        }
+
+        if self.is_reliable() && self.sent.contains(&range) {
+            self.missing.push(range);
+        }
+
         Ok(())
     }
 
+    /// takes the ranges reported missing as [DeliveredIntervals] for [create_blob](SendMessage::create_blob)
+    /// that only has the missing ranges left to deliver
+    ///
+    /// returns `None` if nothing was reported missing
+    pub fn take_missing(&mut self) -> Option<DeliveredIntervals> {
+        if self.missing.is_empty() {
+            return None;
+        }
+
+        self.missing.sort_unstable_by_key(|range| range.start);
+
+        // everything outside the missing ranges is treated as delivered
+        let mut delivered = self.delivered.clone();
+        let mut end = 0;
+        for range in self.missing.drain(..) {
+            if end < range.start {
+                delivered.set_delivered(end..range.start);
+            }
+
+            end = end.max(range.end);
+        }
+        delivered.set_delivered(end..self.data.len());
+
+        Some(delivered)
+    }
+
     /// tries to create a blob to deliver, using the [DeliveredIntervals] supplied.
     /// if you are delivering messages unrealiably you can immediately reapply the given [DeliveredIntervals],
     /// otherwise don't and just use it within one resend wave
@@ -106,9 +285,12 @@ impl SendMessage {
         gap.end = gap.end.min(gap.start + available_space as usize);
 
         delivered.set_delivered(gap.clone());
+        self.sent.set_delivered(gap.clone());
 
         Some(Some(Blob::Fragment(Fragment {
             send_ack: self.reliable.is_some(),
+            ordered: self.ordered,
+            tagged: self.tagged,
             fragmentation_id: self.fragmentation_id,
             total_size: self.data.len() as u32,
             start: gap.start as u32,
@@ -119,16 +301,76 @@ impl SendMessage {
     pub fn delivered(&self) -> bool {
         self.delivered.finished()
     }
+
+    /// how many bytes of the message haven't been delivered yet
+    pub fn remaining_bytes(&self) -> usize {
+        self.delivered.remaining_bytes()
+    }
+
+    /// the total size of the message
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
 }
 
 impl ReceiveMessage {
     pub fn new(time: Duration, fragment: Fragment) -> Result<Self, ()> {
+        if fragment.start == 0 && fragment.data.len() == fragment.total_size as usize {
+            // the fragment is the whole message, take it's data instead of copying it
+            let mut delivered = DeliveredIntervals::new(fragment.data.len());
+            delivered.set_delivered(0..fragment.data.len());
+
+            return Ok(ReceiveMessage {
+                data: fragment.data,
+                stream: None,
+                reliable: fragment.send_ack,
+                ordered: fragment.ordered,
+                tagged: fragment.tagged,
+                fragmentation_id: fragment.fragmentation_id,
+                delivered,
+                last_received_time: time,
+                highest_start: 0,
+                reported_until: 0,
+            });
+        }
+
         let mut message = ReceiveMessage {
             data: vec![0; fragment.total_size as usize].into_boxed_slice(),
+            stream: None,
+            reliable: fragment.send_ack,
+            ordered: fragment.ordered,
+            tagged: fragment.tagged,
+            fragmentation_id: fragment.fragmentation_id,
+            delivered: DeliveredIntervals::new(fragment.total_size as usize),
+            last_received_time: Duration::ZERO,
+            highest_start: fragment.start,
+            reported_until: 0,
+        };
+
+        message.add_fragment(time, fragment)?;
+
+        Ok(message)
+    }
+
+    /// the same as [new](ReceiveMessage::new) but the message isn't buffered whole,
+    /// instead the received data is taken in order with [take_chunk](ReceiveMessage::take_chunk) as it arrives
+    ///
+    /// only data that arrives ahead of a gap is buffered
+    pub fn new_streamed(time: Duration, fragment: Fragment) -> Result<Self, ()> {
+        let mut message = ReceiveMessage {
+            data: Box::new([]),
+            stream: Some(ReceiveStream {
+                streamed_until: 0,
+                pending: BTreeMap::new(),
+            }),
             reliable: fragment.send_ack,
+            ordered: fragment.ordered,
+            tagged: fragment.tagged,
             fragmentation_id: fragment.fragmentation_id,
             delivered: DeliveredIntervals::new(fragment.total_size as usize),
             last_received_time: Duration::ZERO,
+            highest_start: fragment.start,
+            reported_until: 0,
         };
 
         message.add_fragment(time, fragment)?;
@@ -136,18 +378,102 @@ impl ReceiveMessage {
         Ok(message)
     }
 
-    pub fn add_fragment(&mut self, time: Duration, fragment: Fragment) -> Result<(), ()> {
+    /// adds a received fragment to the message
+    ///
+    /// returns `true` if the fragment arrived out of order,
+    /// meaning a fragment further into the message was already received
+    ///
+    /// fails if the fragment was outside the message
+    pub fn add_fragment(&mut self, time: Duration, fragment: Fragment) -> Result<bool, ()> {
         let target_range = (fragment.start as usize)..(fragment.start as usize + fragment.data.len());
 
-        let Some(target_bytes) = self.data.get_mut(target_range.clone()) else {
+        if target_range.end > self.delivered.size {
             return Err(());
-        };
+        }
+
+        if let Some(stream) = self.stream.as_mut() {
+            // anything entirely before `streamed_until` was already passed on
+            if target_range.end > stream.streamed_until {
+                // a resend starting at the same place may be shorter, keep the longer one
+                match stream.pending.entry(target_range.start) {
+                    Entry::Occupied(mut entry) if entry.get().len() < fragment.data.len() => {
+                        entry.insert(fragment.data);
+                    },
+                    Entry::Occupied(_) => (),
+                    Entry::Vacant(entry) => {
+                        entry.insert(fragment.data);
+                    },
+                }
+            }
+        } else {
+            // unwrap is safe, checked to be within the message above
+            self.data.get_mut(target_range.clone()).unwrap().copy_from_slice(&fragment.data);
+        }
 
-        target_bytes.copy_from_slice(&fragment.data);
         self.delivered.set_delivered(target_range);
         self.last_received_time = time;
 
-        Ok(())
+        let reordered = fragment.start < self.highest_start;
+        self.highest_start = self.highest_start.max(fragment.start);
+
+        Ok(reordered)
+    }
+
+    /// takes the gaps before the furthest fragment received that haven't been reported yet,
+    /// as `(start, length)` pairs
+    ///
+    /// each gap is only reported once, if the resent data is lost too it is left to the sender's resend threshold
+    pub fn unreported_gaps(&mut self) -> Vec<(u32, u32)> {
+        let reported_until = self.reported_until as usize;
+        let highest_start = self.highest_start as usize;
+        self.reported_until = self.highest_start;
+
+        // the furthest fragment is delivered, so gaps before it also end before it
+        self.delivered.gaps()
+        .take_while(|gap| gap.start < highest_start)
+        .filter(|gap| gap.end > reported_until)
+        .map(|gap| {
+            let start = gap.start.max(reported_until);
+            (start as u32, (gap.end - start) as u32)
+        })
+        .collect()
+    }
+
+    /// takes the data received after what has already been taken, up to the first gap,
+    /// along with where it starts in the message
+    ///
+    /// returns `None` if the message isn't streamed or no more data is ready
+    pub fn take_chunk(&mut self) -> Option<(usize, Box<[u8]>)> {
+        let stream = self.stream.as_mut()?;
+        let offset = stream.streamed_until;
+        let mut chunk = Vec::new();
+
+        while let Some(entry) = stream.pending.first_entry() {
+            let start = *entry.key();
+            if start > stream.streamed_until {
+                break;
+            }
+
+            let data = entry.remove();
+            let end = start + data.len();
+
+            // skip anything that overlaps with what was already taken
+            if end > stream.streamed_until {
+                chunk.extend_from_slice(&data[stream.streamed_until - start..]);
+                stream.streamed_until = end;
+            }
+        }
+
+        if chunk.is_empty() {
+            return None;
+        }
+
+        Some((offset, chunk.into()))
+    }
+
+    /// if the message is passed on in chunks, see [new_streamed](ReceiveMessage::new_streamed)
+    pub fn is_streamed(&self) -> bool {
+        self.stream.is_some()
     }
 
     pub fn fragmentation_id(&self) -> u16 {
@@ -158,6 +484,15 @@ impl ReceiveMessage {
         self.delivered.finished()
     }
 
+    /// the fraction of the message that has been received, between `0` and `1`
+    pub fn progress(&self) -> f32 {
+        if self.delivered.size == 0 {
+            return 1.;
+        }
+
+        self.delivered.delivered_bytes() as f32 / self.delivered.size as f32
+    }
+
     pub fn data(self) -> Box<[u8]> {
         self.data
     }
@@ -166,6 +501,30 @@ impl ReceiveMessage {
         self.reliable
     }
 
+    /// if the message is sequenced or ordered, see [Reliability](crate::Reliability)
+    pub fn is_ordered(&self) -> bool {
+        self.ordered
+    }
+
+    /// if the message starts with a tag, see [send_with_tag](crate::socket::Socket::send_with_tag)
+    pub fn is_tagged(&self) -> bool {
+        self.tagged
+    }
+
+    /// the total size of the message
+    pub fn size(&self) -> usize {
+        self.delivered.size
+    }
+
+    /// how many received bytes of the message are being held until they're flushed,
+    /// everything received so far unless it's streamed
+    pub fn buffered_bytes(&self) -> usize {
+        match &self.stream {
+            Some(stream) => stream.pending.values().map(|data| data.len()).sum(),
+            None => self.delivered.delivered_bytes(),
+        }
+    }
+
     pub fn last_received_time(&self) -> Duration {
         self.last_received_time
     }
@@ -180,10 +539,29 @@ impl DeliveredIntervals {
     }
 
     fn set_delivered(&mut self, range: Range<usize>) {
-        if range.start == range.end {
+        // an empty range only delivers something if the message is empty
+        if range.start == range.end && self.size != 0 {
             return;
         }
 
+        // fast path for ranges at or after the start of the last interval,
+        // which is the common case of fragments arriving in order
+        match self.intervals.last_mut() {
+            Some(last) if range.start >= last.start => {
+                if range.start <= last.end {
+                    last.end = last.end.max(range.end);
+                } else {
+                    self.intervals.push(range);
+                }
+                return;
+            },
+            None => {
+                self.intervals.push(range);
+                return;
+            },
+            _ => (),
+        }
+
         // insert the range whilst preserving order of start values
         let (Ok(index) | Err(index)) = self.intervals.binary_search_by(
             |element| element.start.cmp(&range.start)
@@ -191,8 +569,9 @@ impl DeliveredIntervals {
 
         self.intervals.insert(index, range);
 
-        // merge intersecting ranges
-        let mut pointer = 0usize;
+        // merge intersecting ranges, only the new range can intersect others
+        // so start at the range before it and stop once past it
+        let mut pointer = index.saturating_sub(1);
         loop {
             let Some(higher,) = self.intervals.get(pointer + 1).cloned() else {
                 break;
@@ -205,12 +584,34 @@ impl DeliveredIntervals {
             if higher.start <= lower.end {
                 lower.end = lower.end.max(higher.end);
                 self.intervals.remove(pointer + 1);
-            } else {
+            } else if pointer < index {
                 pointer += 1;
+            } else {
+                break;
             }
         }
     }
 
+    /// returns how many bytes of the message have been delivered
+    pub fn delivered_bytes(&self) -> usize {
+        self.intervals.iter().map(|range| range.end - range.start).sum()
+    }
+
+    /// returns how many bytes of the message are yet to be delivered
+    pub fn remaining_bytes(&self) -> usize {
+        self.size - self.delivered_bytes()
+    }
+
+    /// returns true if the whole range is within a single delivered interval
+    fn contains(&self, range: &Range<usize>) -> bool {
+        // intervals are merged, so a range can't span more than one of them
+        let index = self.intervals.partition_point(|interval| interval.start <= range.start);
+
+        index.checked_sub(1)
+        .and_then(|index| self.intervals.get(index))
+        .is_some_and(|interval| range.end <= interval.end)
+    }
+
     fn finished(&self) -> bool {
         let Some(range) = self.intervals.first() else {
             return false;
@@ -231,6 +632,13 @@ impl<'a> Iterator for DeliveredIntervalsGaps<'a> {
     type Item = Range<usize>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.intervals.size == 0 {
+            // an empty message is a single empty gap until it's delivered
+            let gap = (self.next == 0 && self.intervals.intervals.is_empty()).then_some(0..0);
+            self.next += 1;
+            return gap;
+        }
+
         let lower = if let Some(lower_index) = self.next.checked_sub(1) {
             let Some(lower) = self.intervals.intervals.get(lower_index) else {
                 return None;
@@ -264,6 +672,8 @@ impl<'a> Iterator for DeliveredIntervalsGaps<'a> {
 
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+
     use super::*;
 
     #[test]
@@ -281,6 +691,90 @@ mod tests {
         assert!(delivered.finished());
     }
 
+    #[test]
+    fn delivered_intervals_bytes() {
+        let mut delivered = DeliveredIntervals::new(10);
+        assert_eq!(delivered.delivered_bytes(), 0);
+        assert_eq!(delivered.remaining_bytes(), 10);
+
+        delivered.set_delivered(1..3);
+        delivered.set_delivered(2..5);
+        delivered.set_delivered(7..8);
+        assert_eq!(delivered.delivered_bytes(), 5);
+        assert_eq!(delivered.remaining_bytes(), 5);
+
+        delivered.set_delivered(0..10);
+        assert_eq!(delivered.delivered_bytes(), 10);
+        assert_eq!(delivered.remaining_bytes(), 0);
+    }
+
+    #[test]
+    fn delivered_intervals_contains() {
+        let mut delivered = DeliveredIntervals::new(10);
+        assert!(!delivered.contains(&(0..1)));
+
+        delivered.set_delivered(2..5);
+        delivered.set_delivered(7..9);
+        assert!(delivered.contains(&(2..5)));
+        assert!(delivered.contains(&(3..4)));
+        assert!(delivered.contains(&(7..9)));
+        assert!(!delivered.contains(&(1..3)));
+        assert!(!delivered.contains(&(4..8)));
+        assert!(!delivered.contains(&(8..10)));
+    }
+
+    #[test]
+    fn send_message_ignores_unsent_acks() {
+        let mut message = SendMessage::new(true, 0, 0, [0; 10].into());
+        let mut delivered = message.get_deliverd_intervals();
+
+        // sends 0..4
+        message.create_blob(&mut delivered, Fragment::HEADER_SIZE as u16 + 4).unwrap().unwrap();
+
+        assert_eq!(message.set_delivered(4..10), Ok(()));
+        assert_eq!(message.remaining_bytes(), 10);
+
+        assert_eq!(message.set_delivered(0..4), Ok(()));
+        assert_eq!(message.remaining_bytes(), 6);
+
+        assert_eq!(message.set_delivered(0..11), Err(()));
+    }
+
+    #[test]
+    fn receive_message_reordered_fragments() {
+        let fragment = |start: u32| Fragment {
+            send_ack: false,
+            ordered: false,
+            tagged: false,
+            fragmentation_id: 0,
+            total_size: 9,
+            start,
+            data: [0; 3].into(),
+        };
+
+        let mut message = ReceiveMessage::new(Duration::ZERO, fragment(3)).unwrap();
+
+        assert_eq!(message.add_fragment(Duration::ZERO, fragment(6)), Ok(false));
+        assert_eq!(message.add_fragment(Duration::ZERO, fragment(0)), Ok(true));
+        assert!(message.complete());
+    }
+
+    #[test]
+    fn receive_message_single_fragment() {
+        let message = ReceiveMessage::new(Duration::ZERO, Fragment {
+            send_ack: true,
+            ordered: false,
+            tagged: false,
+            fragmentation_id: 0,
+            total_size: 3,
+            start: 0,
+            data: [1, 2, 3].into(),
+        }).unwrap();
+
+        assert!(message.complete());
+        assert_eq!(message.data().as_ref(), [1, 2, 3]);
+    }
+
     #[test]
     fn deliverd_intervals_gaps() {
         let mut delivered = DeliveredIntervals::new(10);
@@ -296,4 +790,121 @@ mod tests {
         assert_eq!(gaps.next(), Some(8..10));
         assert_eq!(gaps.next(), None);
     }
+
+    #[test]
+    fn delivered_intervals_merge() {
+        let mut delivered = DeliveredIntervals::new(20);
+
+        // in order
+        delivered.set_delivered(0..2);
+        delivered.set_delivered(2..4);
+        delivered.set_delivered(3..6);
+        delivered.set_delivered(8..10);
+        assert_eq!(delivered.intervals, [0..6, 8..10]);
+
+        // out of order
+        delivered.set_delivered(12..14);
+        delivered.set_delivered(7..8);
+        assert_eq!(delivered.intervals, [0..6, 7..10, 12..14]);
+
+        delivered.set_delivered(5..13);
+        assert_eq!(delivered.intervals.len(), 1);
+        assert_eq!(delivered.intervals[0], 0..14);
+    }
+
+    fn fragment(start: usize, data: &[u8]) -> Fragment {
+        Fragment {
+            send_ack: true,
+            ordered: false,
+            tagged: false,
+            fragmentation_id: 0,
+            total_size: 10,
+            start: start as u32,
+            data: data.into(),
+        }
+    }
+
+    #[test]
+    fn streamed_message() {
+        let mut message = ReceiveMessage::new_streamed(Duration::ZERO, fragment(4, &[4, 5, 6])).unwrap();
+        assert!(message.is_streamed());
+        assert_eq!(message.take_chunk(), None);
+        assert_eq!(message.buffered_bytes(), 3);
+
+        message.add_fragment(Duration::ZERO, fragment(0, &[0, 1, 2])).unwrap();
+        assert_eq!(message.take_chunk(), Some((0, [0, 1, 2].into())));
+        assert_eq!(message.take_chunk(), None);
+
+        // a shorter resend doesn't replace what is already waiting
+        message.add_fragment(Duration::ZERO, fragment(4, &[4])).unwrap();
+        // overlaps with data already taken
+        message.add_fragment(Duration::ZERO, fragment(1, &[1, 2, 3])).unwrap();
+        assert_eq!(message.take_chunk(), Some((3, [3, 4, 5, 6].into())));
+        assert_eq!(message.buffered_bytes(), 0);
+        assert!(!message.complete());
+
+        assert!(message.add_fragment(Duration::ZERO, fragment(7, &[7, 8, 9, 10])).is_err());
+        message.add_fragment(Duration::ZERO, fragment(7, &[7, 8, 9])).unwrap();
+        assert!(message.complete());
+        assert_eq!(message.take_chunk(), Some((7, [7, 8, 9].into())));
+        assert_eq!(message.size(), 10);
+    }
+
+    proptest! {
+        #[test]
+        fn streamed_message_reassembles(ranges in proptest::collection::vec((0..60usize, 1..16usize), 0..32)) {
+            let data = (0..60).collect::<Vec<u8>>();
+            let fragment = |start: usize, end: usize| Fragment {
+                send_ack: true,
+                ordered: false,
+                tagged: false,
+                fragmentation_id: 0,
+                total_size: data.len() as u32,
+                start: start as u32,
+                data: data[start..end].into(),
+            };
+
+            let mut message = ReceiveMessage::new_streamed(Duration::ZERO, fragment(0, 1)).unwrap();
+            let mut received = Vec::new();
+
+            // random overlapping fragments, then the whole message to fill any gaps
+            let ranges = ranges.into_iter()
+            .map(|(start, len)| (start, (start + len).min(data.len())))
+            .chain([(0, data.len())]);
+
+            for (start, end) in ranges {
+                message.add_fragment(Duration::ZERO, fragment(start, end)).unwrap();
+
+                if let Some((offset, chunk)) = message.take_chunk() {
+                    prop_assert_eq!(offset, received.len());
+                    received.extend_from_slice(&chunk);
+                }
+            }
+
+            prop_assert!(message.complete());
+            prop_assert_eq!(received, data);
+        }
+
+        #[test]
+        fn delivered_intervals_match_bitmap(ranges in proptest::collection::vec((0..64usize, 0..16usize), 0..32)) {
+            let mut delivered = DeliveredIntervals::new(80);
+            let mut bitmap = [false; 80];
+
+            for (start, len) in ranges {
+                delivered.set_delivered(start..start + len);
+                bitmap[start..start + len].fill(true);
+            }
+
+            let mut expected: Vec<Range<usize>> = Vec::new();
+            for (i, &set) in bitmap.iter().enumerate() {
+                match expected.last_mut() {
+                    Some(range) if set && i == range.end => range.end += 1,
+                    _ if set => expected.push(i..i + 1),
+                    _ => (),
+                }
+            }
+
+            prop_assert_eq!(delivered.intervals, expected);
+        }
+    }
 }
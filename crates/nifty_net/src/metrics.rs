@@ -1,4 +1,6 @@
-use std::time::Duration;
+use std::{collections::VecDeque, time::Duration};
+
+use crate::Config;
 
 #[derive(Clone, Default)]
 pub struct ConnectionMetrics {
@@ -6,18 +8,447 @@ pub struct ConnectionMetrics {
     pub sent_packets: u64,
     /// total number of bytes that have been sent from this connection
     pub sent_bytes: u64,
+    /// how many UDP packets have been received by this connection
+    pub received_packets: u64,
+    /// total number of bytes that have been received by this connection
+    pub received_bytes: u64,
     /// the estimated round trip time (ping) of this connection
     ///
     /// is `None` if there have been zero samples to estimate from
     pub rtt: Option<Duration>,
+    /// the estimated fraction of packets sent from this connection that are lost before reaching the peer,
+    /// between `0` and `1`
+    ///
+    /// estimated from the packet counts in the peer's heartbeats, so it works without any reliable messages.
+    /// is `None` until two heartbeats have been received
+    pub packet_loss: Option<f32>,
     /// the estimated round trip variance (seconds)
     ///
     /// is `None` if there are less than two samples to estimate from
     pub rtv: Option<f32>,
+    /// the estimated offset of the peer's clock from ours (seconds),
+    /// positive if it is ahead
+    ///
+    /// add it to a local time to get the peer's time, such as for interpolation or lag compensation.
+    /// each heartbeat is assumed to take half the round trip time to arrive and the estimates are averaged.
+    /// the clocks are whatever times each side passes to it's socket's update.
+    /// is `None` until a heartbeat is received after the round trip time is known
+    pub clock_offset: Option<f32>,
     /// how many total unreliable messages have been sent
     pub unreliable_message_count: u64,
     /// how many total reliable messages have been sent
     pub reliable_message_count: u64,
-    /// how many in transit reliable messages have not been acknowledged as received yet
+    /// how many messages are queued or in transit, reliable ones until they are acknowledged
+    /// and unreliable ones until they have been sent
     pub messages_in_transit: usize,
+    /// how many message fragments have been received out of order
+    ///
+    /// a fragment is out of order if a fragment further into the same message was received before it,
+    /// this includes retransmitted fragments
+    pub reordered_fragments: u64,
+    /// how many bytes of messages are queued but not yet delivered
+    ///
+    /// for reliable messages this includes bytes that have been sent but not acknowledged,
+    /// if this keeps growing messages are being sent faster than the connection can deliver them
+    pub pending_send_bytes: usize,
+    /// how many of our fragmentation ids the connection is holding on to for messages being sent
+    ///
+    /// ids are 15 bits, so once this approaches `32768` new messages start colliding with old ones.
+    /// getting close means too many messages are in flight at once
+    pub active_send_id_count: usize,
+    /// how many of the peer's fragmentation ids the connection is holding on to, for received messages
+    /// that are still being assembled or remembered to ignore resends
+    ///
+    /// the peer's ids are separate from ours, this approaching `32768` means the peer has too many messages in flight
+    pub active_receive_id_count: usize,
+    /// the mtu packets are kept within, the smaller of this connection's mtu and the [peer's](ConnectionMetrics::peer_mtu)
+    pub mtu: u16,
+    /// the mtu the peer told us it uses, `None` until it has
+    pub peer_mtu: Option<u16>,
+    /// the thresholds used to classify the [quality](ConnectionMetrics::quality) of the connection
+    pub quality_thresholds: QualityThresholds,
+}
+
+/// totals across all of a socket's connections, along with counters for the socket itself
+///
+/// connection totals only include connections that currently exist
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SocketStats {
+    /// how many connections there are, including ones still being established
+    pub connections: usize,
+    /// how many UDP packets have been sent from all connections
+    pub sent_packets: u64,
+    /// total number of bytes that have been sent from all connections
+    pub sent_bytes: u64,
+    /// how many UDP packets have been received by all connections
+    pub received_packets: u64,
+    /// total number of bytes that have been received by all connections
+    pub received_bytes: u64,
+    /// the average round trip time of connections that have an estimate
+    ///
+    /// is `None` if no connection has a round trip time sample yet
+    pub average_rtt: Option<Duration>,
+    /// how many handshakes with the wrong protocol id were received since the socket was bound
+    ///
+    /// lots of these could mean something is probing the port or an incompatible version is connecting
+    pub wrong_protocol_handshakes: u64,
+    /// how many malformed packets were received from connections since the socket was bound
+    pub malformed_packets: u64,
+    /// how many packets that weren't handshakes were received from addresses without a connection
+    /// since the socket was bound
+    pub packets_from_unknown_peers: u64,
+    /// how many connection requests were rejected since the socket was bound
+    pub refused_connections: u64,
+    /// how many connection requests were forgotten to make space for newer ones since the socket was bound,
+    /// see [max_connection_requests](crate::Config::max_connection_requests)
+    pub dropped_connection_requests: u64,
+}
+
+/// a rough classification of how good a connection is, from best to worst
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ConnectionQuality {
+    Good,
+    Fair,
+    Poor,
+    Critical,
+}
+
+/// thresholds used to classify [ConnectionQuality]
+///
+/// a connection has the worst quality of any threshold it exceeds
+#[derive(Clone, Copy, Debug)]
+pub struct QualityThresholds {
+    /// the round trip time above which the quality is at best [Fair](ConnectionQuality::Fair)
+    pub fair_rtt: Duration,
+    /// the round trip time above which the quality is at best [Poor](ConnectionQuality::Poor)
+    pub poor_rtt: Duration,
+    /// the round trip time above which the quality is [Critical](ConnectionQuality::Critical)
+    pub critical_rtt: Duration,
+    /// the round trip jitter above which the quality is at best [Fair](ConnectionQuality::Fair)
+    pub fair_jitter: Duration,
+    /// the round trip jitter above which the quality is at best [Poor](ConnectionQuality::Poor)
+    pub poor_jitter: Duration,
+    /// the round trip jitter above which the quality is [Critical](ConnectionQuality::Critical)
+    pub critical_jitter: Duration,
+    /// the [packet loss](ConnectionMetrics::packet_loss) above which the quality is at best [Fair](ConnectionQuality::Fair)
+    pub fair_loss: f32,
+    /// the [packet loss](ConnectionMetrics::packet_loss) above which the quality is at best [Poor](ConnectionQuality::Poor)
+    pub poor_loss: f32,
+    /// the [packet loss](ConnectionMetrics::packet_loss) above which the quality is [Critical](ConnectionQuality::Critical)
+    pub critical_loss: f32,
+    /// how far below a threshold a connection has to get to be reported as improving past it,
+    /// as a fraction of the threshold
+    ///
+    /// stops quality changed events from flapping when a connection is near a threshold
+    pub hysteresis: f32,
+}
+
+impl Default for QualityThresholds {
+    fn default() -> Self {
+        QualityThresholds {
+            fair_rtt: Duration::from_millis(100),
+            poor_rtt: Duration::from_millis(200),
+            critical_rtt: Duration::from_millis(400),
+            fair_jitter: Duration::from_millis(20),
+            poor_jitter: Duration::from_millis(50),
+            critical_jitter: Duration::from_millis(100),
+            fair_loss: 0.02,
+            poor_loss: 0.05,
+            critical_loss: 0.1,
+            hysteresis: 0.2,
+        }
+    }
+}
+
+impl QualityThresholds {
+    /// all thresholds multiplied by `factor`
+    fn scaled(&self, factor: f32) -> Self {
+        QualityThresholds {
+            fair_rtt: self.fair_rtt.mul_f32(factor),
+            poor_rtt: self.poor_rtt.mul_f32(factor),
+            critical_rtt: self.critical_rtt.mul_f32(factor),
+            fair_jitter: self.fair_jitter.mul_f32(factor),
+            poor_jitter: self.poor_jitter.mul_f32(factor),
+            critical_jitter: self.critical_jitter.mul_f32(factor),
+            fair_loss: self.fair_loss * factor,
+            poor_loss: self.poor_loss * factor,
+            critical_loss: self.critical_loss * factor,
+            hysteresis: self.hysteresis,
+        }
+    }
+}
+
+impl ConnectionMetrics {
+    /// the round trip jitter, the standard deviation of round trip time samples
+    ///
+    /// is `None` if there are less than two samples to estimate from
+    pub fn jitter(&self) -> Option<Duration> {
+        self.rtv.map(|rtv| Duration::from_secs_f32(rtv.sqrt()))
+    }
+
+    /// classifies the quality of the connection from it's round trip time, jitter and packet loss
+    ///
+    /// is `None` if there have been zero round trip time samples
+    pub fn quality(&self) -> Option<ConnectionQuality> {
+        let thresholds = &self.quality_thresholds;
+
+        fn classify<T: PartialOrd>(value: T, fair: T, poor: T, critical: T) -> ConnectionQuality {
+            if value > critical {
+                ConnectionQuality::Critical
+            } else if value > poor {
+                ConnectionQuality::Poor
+            } else if value > fair {
+                ConnectionQuality::Fair
+            } else {
+                ConnectionQuality::Good
+            }
+        }
+
+        let rtt_quality = classify(
+            self.rtt?,
+            thresholds.fair_rtt,
+            thresholds.poor_rtt,
+            thresholds.critical_rtt,
+        );
+
+        let jitter_quality = self.jitter().map_or(ConnectionQuality::Good, |jitter| {
+            classify(
+                jitter,
+                thresholds.fair_jitter,
+                thresholds.poor_jitter,
+                thresholds.critical_jitter,
+            )
+        });
+
+        let loss_quality = self.packet_loss.map_or(ConnectionQuality::Good, |packet_loss| {
+            classify(
+                packet_loss,
+                thresholds.fair_loss,
+                thresholds.poor_loss,
+                thresholds.critical_loss,
+            )
+        });
+
+        Some(rtt_quality.max(jitter_quality).max(loss_quality))
+    }
+
+    /// classifies the quality of the connection given the `previous` quality
+    ///
+    /// the quality gets worse as soon as a threshold is crossed,
+    /// but only improves once it is below the threshold by the [hysteresis](QualityThresholds::hysteresis)
+    pub fn quality_with_hysteresis(&self, previous: Option<ConnectionQuality>) -> Option<ConnectionQuality> {
+        let quality = self.quality()?;
+
+        let Some(previous) = previous else {
+            return Some(quality);
+        };
+
+        if quality >= previous {
+            return Some(quality);
+        }
+
+        let strict_quality = ConnectionMetrics {
+            quality_thresholds: self.quality_thresholds.scaled(1. - self.quality_thresholds.hysteresis),
+            ..self.clone()
+        }.quality()?;
+
+        Some(strict_quality.min(previous))
+    }
+}
+
+/// the change in a connection's counters over one sample of it's [MetricsHistory]
+#[derive(Clone, Copy, Debug)]
+pub struct MetricsWindow {
+    /// how long the window covers
+    pub duration: Duration,
+    /// how many UDP packets were sent during the window
+    pub sent_packets: u64,
+    /// how many bytes were sent during the window
+    pub sent_bytes: u64,
+    /// how many UDP packets were received during the window
+    pub received_packets: u64,
+    /// how many bytes were received during the window
+    pub received_bytes: u64,
+    /// the estimated round trip time at the end of the window
+    pub rtt: Option<Duration>,
+}
+
+impl MetricsWindow {
+    /// the average number of packets sent per second during the window
+    pub fn sent_packets_per_second(&self) -> f32 {
+        self.sent_packets as f32 / self.duration.as_secs_f32()
+    }
+
+    /// the average number of bytes sent per second during the window
+    pub fn sent_bytes_per_second(&self) -> f32 {
+        self.sent_bytes as f32 / self.duration.as_secs_f32()
+    }
+
+    /// the average number of packets received per second during the window
+    pub fn received_packets_per_second(&self) -> f32 {
+        self.received_packets as f32 / self.duration.as_secs_f32()
+    }
+
+    /// the average number of bytes received per second during the window
+    pub fn received_bytes_per_second(&self) -> f32 {
+        self.received_bytes as f32 / self.duration.as_secs_f32()
+    }
+}
+
+/// a connection's total packets and bytes, sent or received
+type Totals = (u64, u64);
+
+/// the most recent [MetricsWindow]s of a connection, useful for graphing rates over time
+///
+/// a window is recorded every [metrics_sample_interval](Config::metrics_sample_interval),
+/// keeping up to [metrics_history_length](Config::metrics_history_length) of them
+#[derive(Clone, Default, Debug)]
+pub struct MetricsHistory {
+    windows: VecDeque<MetricsWindow>,
+    /// the time, sent packets and bytes and received packets and bytes at the end of the latest window
+    last_sample: Option<(Duration, Totals, Totals)>,
+}
+
+impl MetricsHistory {
+    /// the recorded windows, from oldest to newest
+    pub fn windows(&self) -> impl Iterator<Item = &MetricsWindow> + '_ {
+        self.windows.iter()
+    }
+
+    /// the most recently recorded window, if any
+    pub fn latest(&self) -> Option<&MetricsWindow> {
+        self.windows.back()
+    }
+
+    /// records a new window if the sample interval has passed since the last one,
+    /// `sent` and `received` are the connection's total packets and bytes
+    pub(crate) fn sample(&mut self, time: Duration, config: &Config, sent: Totals, received: Totals, rtt: Option<Duration>) {
+        if config.metrics_history_length == 0 {
+            self.windows.clear();
+            return;
+        }
+
+        let Some((last_time, last_sent, last_received)) = self.last_sample else {
+            self.last_sample = Some((time, sent, received));
+            return;
+        };
+
+        if last_time + config.metrics_sample_interval > time {
+            return;
+        }
+
+        // packets handed back when the send buffer fills up are no longer counted as sent,
+        // so the totals can go down
+        self.windows.push_back(MetricsWindow {
+            duration: time - last_time,
+            sent_packets: sent.0.saturating_sub(last_sent.0),
+            sent_bytes: sent.1.saturating_sub(last_sent.1),
+            received_packets: received.0.saturating_sub(last_received.0),
+            received_bytes: received.1.saturating_sub(last_received.1),
+            rtt,
+        });
+
+        while self.windows.len() > config.metrics_history_length {
+            self.windows.pop_front();
+        }
+
+        self.last_sample = Some((time, sent, received));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quality() {
+        let mut metrics = ConnectionMetrics::default();
+        assert_eq!(metrics.quality(), None);
+
+        metrics.rtt = Some(Duration::from_millis(50));
+        assert_eq!(metrics.quality(), Some(ConnectionQuality::Good));
+
+        metrics.rtt = Some(Duration::from_millis(150));
+        assert_eq!(metrics.quality(), Some(ConnectionQuality::Fair));
+
+        // 60ms of jitter
+        metrics.rtv = Some(0.06 * 0.06);
+        assert_eq!(metrics.quality(), Some(ConnectionQuality::Poor));
+
+        metrics.rtt = Some(Duration::from_millis(500));
+        assert_eq!(metrics.quality(), Some(ConnectionQuality::Critical));
+
+        // packet loss alone makes a connection worse
+        let mut metrics = ConnectionMetrics {
+            rtt: Some(Duration::from_millis(50)),
+            packet_loss: Some(0.03),
+            ..Default::default()
+        };
+        assert_eq!(metrics.quality(), Some(ConnectionQuality::Fair));
+
+        metrics.packet_loss = Some(0.2);
+        assert_eq!(metrics.quality(), Some(ConnectionQuality::Critical));
+    }
+
+    #[test]
+    fn quality_hysteresis() {
+        let mut metrics = ConnectionMetrics::default();
+        assert_eq!(metrics.quality_with_hysteresis(None), None);
+
+        metrics.rtt = Some(Duration::from_millis(110));
+        assert_eq!(metrics.quality_with_hysteresis(None), Some(ConnectionQuality::Fair));
+
+        // just below the threshold isn't enough to improve
+        metrics.rtt = Some(Duration::from_millis(90));
+        assert_eq!(metrics.quality_with_hysteresis(Some(ConnectionQuality::Fair)), Some(ConnectionQuality::Fair));
+
+        metrics.rtt = Some(Duration::from_millis(70));
+        assert_eq!(metrics.quality_with_hysteresis(Some(ConnectionQuality::Fair)), Some(ConnectionQuality::Good));
+
+        // getting worse is immediate
+        metrics.rtt = Some(Duration::from_millis(210));
+        assert_eq!(metrics.quality_with_hysteresis(Some(ConnectionQuality::Fair)), Some(ConnectionQuality::Poor));
+
+        // the loss thresholds have hysteresis too
+        metrics.rtt = Some(Duration::from_millis(50));
+        metrics.packet_loss = Some(0.045);
+        assert_eq!(metrics.quality_with_hysteresis(Some(ConnectionQuality::Poor)), Some(ConnectionQuality::Poor));
+
+        metrics.packet_loss = Some(0.035);
+        assert_eq!(metrics.quality_with_hysteresis(Some(ConnectionQuality::Poor)), Some(ConnectionQuality::Fair));
+    }
+
+    #[test]
+    fn metrics_history() {
+        let config = Config {
+            metrics_sample_interval: Duration::from_secs(1),
+            metrics_history_length: 2,
+            ..Default::default()
+        };
+
+        let mut history = MetricsHistory::default();
+
+        history.sample(Duration::ZERO, &config, (0, 0), (0, 0), None);
+        history.sample(Duration::from_millis(500), &config, (5, 500), (2, 200), None);
+        assert!(history.latest().is_none());
+
+        history.sample(Duration::from_secs(1), &config, (10, 1000), (4, 400), None);
+        assert_eq!(history.latest().unwrap().sent_bytes, 1000);
+        assert_eq!(history.latest().unwrap().sent_packets_per_second(), 10.);
+        assert_eq!(history.latest().unwrap().received_bytes, 400);
+        assert_eq!(history.latest().unwrap().received_packets_per_second(), 4.);
+
+        history.sample(Duration::from_secs(3), &config, (20, 1500), (8, 600), None);
+        history.sample(Duration::from_secs(4), &config, (20, 1500), (8, 600), None);
+
+        // only the last two windows are kept
+        let bytes_per_second: Vec<_> = history.windows().map(MetricsWindow::sent_bytes_per_second).collect();
+        assert_eq!(bytes_per_second, [250., 0.]);
+        let bytes_per_second: Vec<_> = history.windows().map(MetricsWindow::received_bytes_per_second).collect();
+        assert_eq!(bytes_per_second, [100., 0.]);
+
+        // totals going down, from packets being handed back, don't underflow
+        history.sample(Duration::from_secs(5), &config, (19, 1400), (8, 600), None);
+        assert_eq!(history.latest().unwrap().sent_bytes, 0);
+    }
 }
@@ -1,4 +1,12 @@
-use std::{mem::size_of, net::{SocketAddr, UdpSocket}, time::Duration};
+use std::{io::{ErrorKind, Write}, mem::size_of, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket}, ops::Deref, sync::Mutex, time::Duration};
+
+#[cfg(all(feature = "sendmmsg", target_os = "linux"))]
+use crate::batch::Batch;
+use crate::capture::{write_capture_record, Direction};
+use crate::socket::{MAX_HEARTBEAT_PAYLOAD_SIZE, MAX_REJECTION_REASON_SIZE, MAX_TOKEN_SIZE};
+
+/// set on the first byte of a packet with exactly one blob, see [Packet]
+const SINGLE_BLOB_FLAG: u8 = 0x80;
 
 /// a collection of data [Blob]s
 ///
@@ -7,13 +15,23 @@ use std::{mem::size_of, net::{SocketAddr, UdpSocket}, time::Duration};
 /// - then as many bytes as needed for that blob
 /// - repeat, starting with the length of the next blob
 ///
+/// a packet with exactly one blob leaves out the length and sets the most significant bit of the blob's type instead.
+/// lengths are kept below `0x8000` whenever a packet has more than one blob so the two can't be confused,
+/// see [space_left](Packet::space_left)
+///
 /// special case when deserializing where if the first two bytes are zero, the following 8 bytes are a [Handshake]
+/// followed by the sender's mtu and it's token
+#[derive(Debug)]
 pub struct Packet {
     blobs: Vec<Blob>,
 }
 
 pub struct Handshake {
     pub protocol_id: u64,
+    /// the mtu of the connection that sent the handshake
+    pub mtu: u16,
+    /// application defined data sent with the handshake, at most [MAX_TOKEN_SIZE](crate::socket::MAX_TOKEN_SIZE) bytes
+    pub token: Box<[u8]>,
 }
 
 /// a blob is a piece of data
@@ -23,6 +41,7 @@ pub struct Handshake {
 /// serialization layout:
 /// - first byte: blob type, commented on each variant
 /// - remaining bytes: fragment type layout
+#[derive(Debug)]
 pub enum Blob {
     /// `0`
     Fragment(Fragment),
@@ -34,6 +53,30 @@ pub enum Blob {
     Acknowledgement(Acknowledgement),
     /// `4`
     Disconnect,
+    /// `5`
+    Pause,
+    /// `6`
+    Resume,
+    /// `7`, sent in response to handshakes from an address whose connection request was rejected
+    ///
+    /// followed by the reason for the rejection as utf-8, if one was given,
+    /// at most [MAX_REJECTION_REASON_SIZE](crate::socket::MAX_REJECTION_REASON_SIZE) bytes
+    HandshakeRejected(Option<Box<str>>),
+    /// `8`, how many more bytes of reliable messages the sender is willing to buffer
+    ReceiveWindow(u32),
+    /// `9`
+    NegativeAcknowledgement(NegativeAcknowledgement),
+    /// `10`, sent by a socket a connection was handed off to,
+    /// with the address the peer knew the connection by before the handoff
+    /// and the peer's [HandoffKey](Blob::HandoffKey), proving the connection is ours to move
+    ///
+    /// followed by the 8 byte key, then `4` or `6` for the ip version, the ip's bytes and a 2 byte port
+    Handoff(SocketAddr, u64),
+    /// `11`, the mtu of the sender, the peer sends packets no larger than this
+    Mtu(u16),
+    /// `12`, a random key the peer has to send back in a [Handoff](Blob::Handoff)
+    /// for the connection to be moved to a new address
+    HandoffKey(u64),
 }
 
 /// used as heartbeat and it's response
@@ -44,11 +87,19 @@ pub enum Blob {
 /// serialization layout:
 /// - 1 bit: send ack
 /// - 15 bits: fragmentation_id
-/// - 4 bytes: total size of all fragments
-/// - 4 bytes: start index of data
+/// - 1 bit: ordered, see [Reliability](crate::Reliability)
+/// - 31 bits: total size of all fragments
+/// - 1 bit: tagged, see [send_with_tag](crate::socket::Socket::send_with_tag)
+/// - 31 bits: start index of data
 /// - remaining bytes: data
+#[derive(Debug)]
 pub struct Fragment {
     pub send_ack: bool,
+    /// set for [UnreliableSequenced](crate::Reliability::UnreliableSequenced)
+    /// and [ReliableOrdered](crate::Reliability::ReliableOrdered) messages
+    pub ordered: bool,
+    /// set for messages that start with a tag
+    pub tagged: bool,
     pub fragmentation_id: u16,
     pub total_size: u32,
     pub start: u32,
@@ -57,20 +108,39 @@ pub struct Fragment {
 
 /// serialization layout:
 /// - first 8 bytes: send time
+/// - next 8 bytes: how many packets the sender has received from the peer
+/// - optionally, 2 bytes: the fragmentation id of an application message sent with the heartbeat
+/// - remaining bytes: that message's data, at most [MAX_HEARTBEAT_PAYLOAD_SIZE](crate::socket::MAX_HEARTBEAT_PAYLOAD_SIZE) bytes
+#[derive(Debug)]
 pub struct Heartbeat {
     send_time: u64,
+    received_packets: u64,
+    payload: Option<(u16, Box<[u8]>)>,
 }
 
 /// serialization layout:
 /// - 2 bytes: fragmentation id
 /// - 4 bytes: acknowledgement range start
 /// - 2 bytes: acknowledgement range length
+#[derive(Debug)]
 pub struct Acknowledgement {
     pub fragmentation_id: u16,
     pub start: u32,
     pub len: u16,
 }
 
+/// tells the sender of a reliable message which ranges are missing,
+/// so they can be resent without waiting for the resend threshold
+///
+/// serialization layout:
+/// - 2 bytes: fragmentation id
+/// - remaining bytes: missing ranges, each a 4 byte start followed by a 4 byte length
+#[derive(Debug)]
+pub struct NegativeAcknowledgement {
+    pub fragmentation_id: u16,
+    pub ranges: Vec<(u32, u32)>,
+}
+
 
 impl Packet {
     pub fn new() -> Self {
@@ -83,16 +153,35 @@ impl Packet {
         self.blobs.push(blob);
     }
 
+    /// the size of the packet once serialized
+    #[cfg(test)]
     pub fn size(&self) -> u16 {
+        if let [blob] = self.blobs.as_slice() {
+            // single blob packets don't have a length
+            return blob.size();
+        }
+
+        self.multi_blob_size()
+    }
+
+    /// the size of the packet if every blob has a length
+    fn multi_blob_size(&self) -> u16 {
         size_of::<u16>() as u16 * self.blobs.len() as u16 +
         self.blobs.iter().map(Blob::size).sum::<u16>()
     }
 
     /// returns the size of the largest blob that could be added with a given max size
+    ///
+    /// always leaves room for the blob's length, even though a single blob packet won't need it
     pub fn space_left(&self, max_size: u16) -> u16 {
+        if self.blobs.first().is_some_and(|blob| blob.size() >= (SINGLE_BLOB_FLAG as u16) << 8) {
+            // a length this large would set the flag in it's first byte, looking like a single blob packet
+            return 0;
+        }
+
         max_size.saturating_sub(
             // current size + 2 byte header for next blob
-            self.size() + 2
+            self.multi_blob_size() + 2
         )
     }
 
@@ -105,18 +194,54 @@ impl Packet {
         self.blobs.into_iter()
     }
 
+    /// the previous address and key from the packet's [Handoff](Blob::Handoff) blob, if it has one
+    pub fn handoff(&self) -> Option<(SocketAddr, u64)> {
+        self.blobs.iter().find_map(|blob| match blob {
+            &Blob::Handoff(previous_addr, key) => Some((previous_addr, key)),
+            _ => None,
+        })
+    }
+
+    /// sending reuses a buffer with [serialize_into](Packet::serialize_into) instead
+    #[cfg(test)]
     pub fn serialize(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
+        self.serialize_into(&mut bytes);
+        bytes
+    }
+
+    /// the same as [serialize](Packet::serialize) but appends to an existing buffer,
+    /// so a buffer can be reused between packets
+    pub fn serialize_into(&self, bytes: &mut Vec<u8>) {
+        if let [blob] = self.blobs.as_slice() {
+            let start = bytes.len();
+            blob.serialize(bytes);
+            bytes[start] |= SINGLE_BLOB_FLAG;
+            return;
+        }
 
         for blob in self.blobs.iter() {
             bytes.extend_from_slice(&blob.size().to_be_bytes());
-            blob.serialize(&mut bytes);
+            blob.serialize(bytes);
         }
+    }
 
-        bytes
+    /// removes all blobs, keeping the allocation
+    pub fn clear(&mut self) {
+        self.blobs.clear();
     }
 
     pub fn deserialize(bytes: &[u8]) -> Option<Self> {
+        if let Some(&first_byte) = bytes.first() {
+            if first_byte & SINGLE_BLOB_FLAG != 0 {
+                let blob = Blob::deserialize_body(first_byte & !SINGLE_BLOB_FLAG, bytes.get(1..)?)?;
+
+                return Some(Packet {
+                    blobs: vec![blob],
+                });
+            }
+        }
+
         let mut blobs = Vec::new();
 
         let mut bytes = bytes;
@@ -136,10 +261,6 @@ impl Packet {
             blobs,
         })
     }
-
-    pub fn send(&self, addr: SocketAddr, socket: &UdpSocket) -> Result<usize, std::io::Error> {
-        socket.send_to(&self.serialize(), addr)
-    }
 }
 
 impl Handshake {
@@ -147,6 +268,8 @@ impl Handshake {
         let mut bytes = vec![0, 0];
 
         bytes.extend_from_slice(&self.protocol_id.to_be_bytes());
+        bytes.extend_from_slice(&self.mtu.to_be_bytes());
+        bytes.extend_from_slice(&self.token);
 
         bytes
     }
@@ -160,18 +283,284 @@ impl Handshake {
             return None;
         }
 
+        let mtu = u16::from_be_bytes(TryFrom::try_from(bytes.get(10..12)?).unwrap());
+        let token = bytes.get(12..)?;
+
+        if token.len() > MAX_TOKEN_SIZE {
+            return None;
+        }
+
         Some(Handshake {
             protocol_id,
+            mtu,
+            token: token.into(),
         })
     }
 
-    pub fn send(&self, addr: SocketAddr, socket: &UdpSocket) -> Result<usize, std::io::Error> {
-        socket.send_to(&self.serialize(), addr)
+    /// see [send_datagram]
+    pub fn send(&self, addr: SocketAddr, socket: &FilteredSocket) -> Result<Option<usize>, std::io::Error> {
+        send_datagram(&self.serialize(), addr, socket)
+    }
+}
+
+/// serializes an address as `4` or `6` for the ip version, the ip's bytes then a two byte port
+pub fn serialize_addr(addr: SocketAddr, buffer: &mut Vec<u8>) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buffer.push(4);
+            buffer.extend_from_slice(&ip.octets());
+        },
+        IpAddr::V6(ip) => {
+            buffer.push(6);
+            buffer.extend_from_slice(&ip.octets());
+        },
+    }
+
+    buffer.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// deserializes an address written by [serialize_addr], `bytes` must be exactly the address
+pub fn deserialize_addr(bytes: &[u8]) -> Option<SocketAddr> {
+    Some(match bytes {
+        [4, ip @ .., port_0, port_1] => SocketAddr::new(
+            Ipv4Addr::from(<[u8; 4]>::try_from(ip).ok()?).into(),
+            u16::from_be_bytes([*port_0, *port_1]),
+        ),
+        [6, ip @ .., port_0, port_1] => SocketAddr::new(
+            Ipv6Addr::from(<[u8; 16]>::try_from(ip).ok()?).into(),
+            u16::from_be_bytes([*port_0, *port_1]),
+        ),
+        _ => return None,
+    })
+}
+
+/// a function applied to the raw bytes of datagrams, see [set_outbound_filter](crate::socket::Socket::set_outbound_filter)
+pub type PacketFilter = Box<dyn FnMut(&mut Vec<u8>) + Send>;
+
+/// a `UdpSocket` along with the [PacketFilter]s applied to every datagram it sends and receives,
+/// and the capture they're recorded to
+pub struct FilteredSocket {
+    udp_socket: UdpSocket,
+    /// behind a mutex because connections send through a shared reference
+    hooks: Mutex<Hooks>,
+}
+
+#[derive(Default)]
+struct Hooks {
+    outbound_filter: Option<PacketFilter>,
+    inbound_filter: Option<PacketFilter>,
+    /// reused to filter outgoing datagrams into
+    buffer: Vec<u8>,
+    capture: Capture,
+    #[cfg(all(feature = "sendmmsg", target_os = "linux"))]
+    batch: Batch,
+    /// acts as if the send buffer fills up after this many more datagrams,
+    /// see [fill_send_buffer_after](FilteredSocket::fill_send_buffer_after)
+    #[cfg(test)]
+    full_after: Option<usize>,
+}
+
+#[derive(Default)]
+struct Capture {
+    sink: Option<Box<dyn Write + Send>>,
+    /// the time of the current update, recorded with captured datagrams
+    time: Duration,
+    /// the error that stopped the capture, reported in the next update
+    error: Option<std::io::Error>,
+}
+
+impl Capture {
+    fn record(&mut self, direction: Direction, addr: SocketAddr, bytes: &[u8]) {
+        let Some(sink) = self.sink.as_mut() else {
+            return;
+        };
+
+        if let Err(err) = write_capture_record(sink, self.time, direction, addr, bytes) {
+            self.sink = None;
+            self.error = Some(err);
+        }
+    }
+}
+
+impl FilteredSocket {
+    pub fn new(udp_socket: UdpSocket) -> Self {
+        FilteredSocket {
+            udp_socket,
+            hooks: Mutex::default(),
+        }
+    }
+
+    /// a panicking filter poisons the mutex, but the hooks are still usable
+    fn hooks(&mut self) -> &mut Hooks {
+        self.hooks.get_mut().unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub fn set_outbound_filter(&mut self, filter: Option<PacketFilter>) {
+        self.hooks().outbound_filter = filter;
+    }
+
+    pub fn set_inbound_filter(&mut self, filter: Option<PacketFilter>) {
+        self.hooks().inbound_filter = filter;
+    }
+
+    #[cfg(all(feature = "recvmmsg", target_os = "linux"))]
+    pub fn has_inbound_filter(&mut self) -> bool {
+        self.hooks().inbound_filter.is_some()
+    }
+
+    /// replaces the capture sink, returning the previous one
+    pub fn set_capture(&mut self, sink: Option<Box<dyn Write + Send>>) -> Option<Box<dyn Write + Send>> {
+        std::mem::replace(&mut self.hooks().capture.sink, sink)
+    }
+
+    /// sets the time captured datagrams are recorded with
+    pub fn set_time(&mut self, time: Duration) {
+        self.hooks().capture.time = time;
+    }
+
+    /// takes the error that stopped the capture, if there was one
+    pub fn take_capture_error(&mut self) -> Option<std::io::Error> {
+        self.hooks().capture.error.take()
+    }
+
+    /// holds datagrams sent from now on to be sent together by [flush_batch](FilteredSocket::flush_batch)
+    ///
+    /// only with the `sendmmsg` feature on linux, otherwise datagrams are still sent straight away
+    #[cfg(all(feature = "sendmmsg", target_os = "linux"))]
+    pub fn start_batch(&mut self) {
+        self.hooks().batch.active = true;
+    }
+
+    #[cfg(not(all(feature = "sendmmsg", target_os = "linux")))]
+    pub fn start_batch(&mut self) {}
+
+    /// sends the datagrams held since [start_batch](FilteredSocket::start_batch),
+    /// calling `unsent` with those that didn't fit in the send buffer, see [Batch::send](crate::batch::Batch::send)
+    #[cfg(all(feature = "sendmmsg", target_os = "linux"))]
+    pub fn flush_batch(&mut self, unsent: impl FnMut(SocketAddr, &[u8], usize)) -> Result<(), std::io::Error> {
+        // borrowed directly to also borrow the udp socket
+        let hooks = self.hooks.get_mut().unwrap_or_else(|err| err.into_inner());
+        hooks.batch.active = false;
+        #[cfg(test)]
+        {
+            hooks.batch.full_after = hooks.full_after.take();
+        }
+
+        let capture = &mut hooks.capture;
+        hooks.batch.send(&self.udp_socket, |addr, bytes| capture.record(Direction::Sent, addr, bytes), unsent)
+    }
+
+    #[cfg(not(all(feature = "sendmmsg", target_os = "linux")))]
+    pub fn flush_batch(&mut self, _unsent: impl FnMut(SocketAddr, &[u8], usize)) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    /// acts as if the send buffer fills up after this many more datagrams,
+    /// for the rest of the batch or for one datagram when not batching
+    #[cfg(test)]
+    pub fn fill_send_buffer_after(&mut self, datagrams: usize) {
+        self.hooks().full_after = Some(datagrams);
+    }
+
+    /// captures a received datagram then applies the inbound filter to it, using `buffer` if there is one
+    pub fn filter_inbound<'a>(&mut self, addr: SocketAddr, bytes: &'a [u8], buffer: &'a mut Vec<u8>) -> &'a [u8] {
+        let hooks = self.hooks();
+        hooks.capture.record(Direction::Received, addr, bytes);
+
+        let Some(filter) = hooks.inbound_filter.as_mut() else {
+            return bytes;
+        };
+
+        buffer.clear();
+        buffer.extend_from_slice(bytes);
+        filter(buffer);
+        buffer
+    }
+}
+
+impl Deref for FilteredSocket {
+    type Target = UdpSocket;
+
+    fn deref(&self) -> &UdpSocket {
+        &self.udp_socket
     }
 }
 
+/// sends bytes to an address as a single datagram after applying the socket's outbound filter,
+/// returning how many bytes were sent
+///
+/// returns `Ok(None)` if the socket's send buffer is full, in which case the bytes can be sent later
+///
+/// a datagram that was only partially sent is an error
+pub fn send_datagram(bytes: &[u8], addr: SocketAddr, socket: &FilteredSocket) -> Result<Option<usize>, std::io::Error> {
+    let mut hooks = socket.hooks.lock().unwrap_or_else(|err| err.into_inner());
+    let hooks = &mut *hooks;
+
+    #[cfg(all(feature = "sendmmsg", target_os = "linux"))]
+    let unfiltered = bytes;
+    let bytes = if let Some(filter) = hooks.outbound_filter.as_mut() {
+        hooks.buffer.clear();
+        hooks.buffer.extend_from_slice(bytes);
+        filter(&mut hooks.buffer);
+        &hooks.buffer
+    } else {
+        bytes
+    };
+
+    // counted as sent, any that don't fit in the send buffer are handed back when the batch is flushed
+    #[cfg(all(feature = "sendmmsg", target_os = "linux"))]
+    if hooks.batch.active {
+        hooks.batch.push(addr, bytes, unfiltered);
+        return Ok(Some(bytes.len()));
+    }
+
+    #[cfg(test)]
+    match hooks.full_after.as_mut() {
+        Some(0) => {
+            hooks.full_after = None;
+            return Ok(None);
+        },
+        Some(full_after) => *full_after -= 1,
+        None => (),
+    }
+
+    match socket.send_to(bytes, addr) {
+        Ok(sent_bytes) if sent_bytes < bytes.len() => Err(std::io::Error::new(
+            ErrorKind::WriteZero,
+            format!("only sent {} of {} bytes in datagram", sent_bytes, bytes.len()),
+        )),
+        Ok(sent_bytes) => {
+            hooks.capture.record(Direction::Sent, addr, bytes);
+            Ok(Some(sent_bytes))
+        },
+        Err(err) if send_buffer_full(&err) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// if an error from sending a datagram means that the send buffer is full
+pub fn send_buffer_full(err: &std::io::Error) -> bool {
+    if err.kind() == ErrorKind::WouldBlock {
+        return true;
+    }
+
+    // `ENOBUFS` doesn't have it's own `ErrorKind`
+    let enobufs = if cfg!(any(target_os = "linux", target_os = "android")) {
+        Some(105)
+    } else if cfg!(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd")) {
+        Some(55)
+    } else if cfg!(windows) {
+        // `WSAENOBUFS`
+        Some(10055)
+    } else {
+        None
+    };
+
+    enobufs.is_some() && err.raw_os_error() == enobufs
+}
+
 impl Blob {
-    const HEADER_SIZE: usize = 1;
+    pub const HEADER_SIZE: usize = 1;
 
     /// returns the size of the blob in bytes if it was serialized
     pub fn size(&self) -> u16 {
@@ -183,6 +572,15 @@ impl Blob {
                 Blob::HeartbeatResponse(heartbeat) => heartbeat.size(),
                 Blob::Acknowledgement(acknowledgement) => acknowledgement.size(),
                 Blob::Disconnect => 0,
+                Blob::Pause => 0,
+                Blob::Resume => 0,
+                Blob::HandshakeRejected(reason) => reason.as_ref().map_or(0, |reason| reason.len() as u16),
+                Blob::ReceiveWindow(_) => size_of::<u32>() as u16,
+                Blob::NegativeAcknowledgement(nak) => nak.size(),
+                Blob::Handoff(SocketAddr::V4(_), _) => size_of::<u64>() as u16 + 1 + 4 + size_of::<u16>() as u16,
+                Blob::Handoff(SocketAddr::V6(_), _) => size_of::<u64>() as u16 + 1 + 16 + size_of::<u16>() as u16,
+                Blob::Mtu(_) => size_of::<u16>() as u16,
+                Blob::HandoffKey(_) => size_of::<u64>() as u16,
             }
         ) as u16
     }
@@ -208,6 +606,40 @@ impl Blob {
             Blob::Disconnect => {
                 buffer.push(4);
             },
+            Blob::Pause => {
+                buffer.push(5);
+            },
+            Blob::Resume => {
+                buffer.push(6);
+            },
+            Blob::HandshakeRejected(reason) => {
+                buffer.push(7);
+
+                if let Some(reason) = reason {
+                    buffer.extend_from_slice(reason.as_bytes());
+                }
+            },
+            Blob::ReceiveWindow(window) => {
+                buffer.push(8);
+                buffer.extend_from_slice(&window.to_be_bytes());
+            },
+            Blob::NegativeAcknowledgement(nak) => {
+                buffer.push(9);
+                nak.serialize(buffer);
+            },
+            Blob::Handoff(previous_addr, key) => {
+                buffer.push(10);
+                buffer.extend_from_slice(&key.to_be_bytes());
+                serialize_addr(*previous_addr, buffer);
+            },
+            Blob::Mtu(mtu) => {
+                buffer.push(11);
+                buffer.extend_from_slice(&mtu.to_be_bytes());
+            },
+            Blob::HandoffKey(key) => {
+                buffer.push(12);
+                buffer.extend_from_slice(&key.to_be_bytes());
+            },
         }
     }
 
@@ -215,12 +647,32 @@ impl Blob {
         let blob_type = bytes.get(0)?;
         let bytes = bytes.get(1..)?;
 
+        Self::deserialize_body(*blob_type, bytes)
+    }
+
+    /// deserializes the bytes after the blob type
+    fn deserialize_body(blob_type: u8, bytes: &[u8]) -> Option<Self> {
         Some(match blob_type {
             0 => Blob::Fragment(Fragment::deserialize(bytes)?),
             1 => Blob::Heartbeat(Heartbeat::deserialize(bytes)?),
             2 => Blob::HeartbeatResponse(Heartbeat::deserialize(bytes)?),
             3 => Blob::Acknowledgement(Acknowledgement::deserialize(bytes)?),
             4 => Blob::Disconnect,
+            5 => Blob::Pause,
+            6 => Blob::Resume,
+            7 => Blob::HandshakeRejected(match bytes {
+                [] => None,
+                bytes if bytes.len() > MAX_REJECTION_REASON_SIZE => return None,
+                bytes => Some(std::str::from_utf8(bytes).ok()?.into()),
+            }),
+            8 => Blob::ReceiveWindow(u32::from_be_bytes(TryFrom::try_from(bytes).ok()?)),
+            9 => Blob::NegativeAcknowledgement(NegativeAcknowledgement::deserialize(bytes)?),
+            10 => Blob::Handoff(
+                deserialize_addr(bytes.get(size_of::<u64>()..)?)?,
+                u64::from_be_bytes(TryFrom::try_from(bytes.get(..size_of::<u64>())?).ok()?),
+            ),
+            11 => Blob::Mtu(u16::from_be_bytes(TryFrom::try_from(bytes).ok()?)),
+            12 => Blob::HandoffKey(u64::from_be_bytes(TryFrom::try_from(bytes).ok()?)),
             _ => return None,
         })
     }
@@ -256,8 +708,10 @@ impl Fragment {
         let first_16_bits = ((self.send_ack as u16) << 15) | self.fragmentation_id;
 
         buffer.extend_from_slice(&first_16_bits.to_be_bytes());
-        buffer.extend_from_slice(&self.total_size.to_be_bytes());
-        buffer.extend_from_slice(&self.start.to_be_bytes());
+        // the same again with the `ordered` bit, total sizes are kept below `1 << 31`
+        buffer.extend_from_slice(&(((self.ordered as u32) << 31) | self.total_size).to_be_bytes());
+        // and the `tagged` bit, starts are always less than the total size
+        buffer.extend_from_slice(&(((self.tagged as u32) << 31) | self.start).to_be_bytes());
         buffer.extend_from_slice(&self.data);
     }
 
@@ -268,13 +722,20 @@ impl Fragment {
         let send_ack = (first_16_bits & (1 << 15)) != 0;
         let fragmentation_id = first_16_bits & !(1 << 15);
 
-        let total_size = u32::from_be_bytes(TryFrom::try_from(bytes.get(2..6)?).unwrap());
-        let start = u32::from_be_bytes(TryFrom::try_from(bytes.get(6..10)?).unwrap());
+        let ordered_and_total_size = u32::from_be_bytes(TryFrom::try_from(bytes.get(2..6)?).unwrap());
+        let ordered = (ordered_and_total_size & (1 << 31)) != 0;
+        let total_size = ordered_and_total_size & !(1 << 31);
+
+        let tagged_and_start = u32::from_be_bytes(TryFrom::try_from(bytes.get(6..10)?).unwrap());
+        let tagged = (tagged_and_start & (1 << 31)) != 0;
+        let start = tagged_and_start & !(1 << 31);
 
         let data = bytes.get(10..)?.into();
 
         Some(Fragment {
             send_ack,
+            ordered,
+            tagged,
             fragmentation_id,
             total_size,
             start,
@@ -284,27 +745,65 @@ impl Fragment {
 }
 
 impl Heartbeat {
-    pub fn new(time: Duration) -> Self {
+    pub fn new(time: Duration, received_packets: u64) -> Self {
         Heartbeat {
             send_time: time.as_millis() as u64,
+            received_packets,
+            payload: None,
         }
     }
 
+    /// attaches an application message to the heartbeat, with it's fragmentation id
+    pub fn with_payload(mut self, payload: Option<(u16, Box<[u8]>)>) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    /// takes the application message sent with the heartbeat, if any, with it's fragmentation id
+    pub fn take_payload(&mut self) -> Option<(u16, Box<[u8]>)> {
+        self.payload.take()
+    }
+
     pub fn time(&self) -> Duration {
         Duration::from_millis(self.send_time)
     }
 
+    /// how many packets the sender of the heartbeat had received from us when it was sent
+    pub fn received_packets(&self) -> u64 {
+        self.received_packets
+    }
+
     pub fn size(&self) -> u16 {
-        size_of::<u64>() as u16
+        (
+            size_of::<u64>() +
+            size_of::<u64>() +
+            self.payload.as_ref().map_or(0, |(_, data)| size_of::<u16>() + data.len())
+        ) as u16
     }
 
     fn serialize(&self, buffer: &mut Vec<u8>) {
         buffer.extend_from_slice(&self.send_time.to_be_bytes());
+        buffer.extend_from_slice(&self.received_packets.to_be_bytes());
+
+        if let Some((fragmentation_id, data)) = &self.payload {
+            buffer.extend_from_slice(&fragmentation_id.to_be_bytes());
+            buffer.extend_from_slice(data);
+        }
     }
 
     fn deserialize(bytes: &[u8]) -> Option<Self> {
+        let payload = match bytes.get(16..)? {
+            [] => None,
+            [high, low, data @ ..] if data.len() <= MAX_HEARTBEAT_PAYLOAD_SIZE => {
+                Some((u16::from_be_bytes([*high, *low]), data.into()))
+            },
+            _ => return None,
+        };
+
         Some(Heartbeat {
-            send_time: u64::from_be_bytes(TryFrom::try_from(bytes).ok()?),
+            send_time: u64::from_be_bytes(TryFrom::try_from(&bytes[0..8]).unwrap()),
+            received_packets: u64::from_be_bytes(TryFrom::try_from(&bytes[8..16]).unwrap()),
+            payload,
         })
     }
 }
@@ -333,16 +832,58 @@ impl Acknowledgement {
     }
 }
 
+impl NegativeAcknowledgement {
+    /// the most ranges put in one blob, more are split across several
+    pub const MAX_RANGES: usize = 8;
+
+    pub fn size(&self) -> u16 {
+        (
+            size_of::<u16>() +
+            self.ranges.len() * (size_of::<u32>() + size_of::<u32>())
+        ) as u16
+    }
+
+    fn serialize(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.fragmentation_id.to_be_bytes());
+
+        for (start, len) in self.ranges.iter() {
+            buffer.extend_from_slice(&start.to_be_bytes());
+            buffer.extend_from_slice(&len.to_be_bytes());
+        }
+    }
+
+    fn deserialize(bytes: &[u8]) -> Option<Self> {
+        let fragmentation_id = u16::from_be_bytes(TryFrom::try_from(bytes.get(0..2)?).unwrap());
+
+        let ranges = bytes.get(2..)?;
+        if ranges.len() % 8 != 0 {
+            return None;
+        }
+
+        Some(NegativeAcknowledgement {
+            fragmentation_id,
+            ranges: ranges.chunks_exact(8).map(|range| (
+                u32::from_be_bytes(TryFrom::try_from(&range[0..4]).unwrap()),
+                u32::from_be_bytes(TryFrom::try_from(&range[4..8]).unwrap()),
+            )).collect(),
+        })
+    }
+}
+
 
 
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+
     use super::*;
 
     #[test]
     fn whole_fragment_size() {
         let fragment = Fragment {
             send_ack: true,
+            ordered: false,
+            tagged: false,
             fragmentation_id: 10,
             total_size: 15,
             start: 8,
@@ -355,10 +896,100 @@ mod tests {
         assert_eq!(fragment.size(), buffer.len() as u16);
     }
 
+    #[test]
+    fn single_blob_packet() {
+        let mut packet = Packet::new();
+        packet.push(Blob::Disconnect);
+        assert_eq!(packet.serialize(), [0x84]);
+
+        let deserialized = Packet::deserialize(&[0x84]).unwrap();
+        assert!(matches!(deserialized.blobs[..], [Blob::Disconnect]));
+
+        packet.push(Blob::Disconnect);
+        assert_eq!(packet.serialize(), [0, 1, 4, 0, 1, 4]);
+
+        // a first blob too large for it's length to be told apart from a single blob packet leaves no space
+        let mut packet = Packet::new();
+        packet.push(Blob::Fragment(Fragment {
+            send_ack: false,
+            ordered: false,
+            tagged: false,
+            fragmentation_id: 0,
+            total_size: 0x8000,
+            start: 0,
+            data: vec![0; 0x8000].into(),
+        }));
+        assert_eq!(packet.space_left(u16::MAX), 0);
+    }
+
+    #[test]
+    fn batched_datagrams() {
+        let mut socket = FilteredSocket::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+
+        socket.set_outbound_filter(Some(Box::new(|bytes| bytes.push(0xff))));
+
+        socket.start_batch();
+        for index in 0..3u8 {
+            assert_eq!(send_datagram(&[index; 4], peer_addr, &socket).unwrap(), Some(5));
+        }
+        socket.flush_batch(|_, _, _| panic!("nothing should be left unsent")).unwrap();
+
+        let mut buffer = [0; 16];
+        for index in 0..3u8 {
+            let (len, _) = peer.recv_from(&mut buffer).unwrap();
+            assert_eq!(buffer[..len], [index, index, index, index, 0xff]);
+        }
+    }
+
+    #[test]
+    fn grouped_after_large_blob() {
+        let mut packet = Packet::new();
+        packet.push(Blob::Fragment(Fragment {
+            send_ack: true,
+            ordered: false,
+            tagged: false,
+            fragmentation_id: 0,
+            total_size: 200,
+            start: 0,
+            data: vec![0; 200].into(),
+        }));
+
+        let ack = Blob::Acknowledgement(Acknowledgement {
+            fragmentation_id: 0,
+            start: 0,
+            len: 200,
+        });
+        assert!(packet.space_left(1500) >= ack.size());
+        packet.push(ack);
+
+        let deserialized = Packet::deserialize(&packet.serialize()).unwrap();
+        assert!(matches!(deserialized.blobs[..], [Blob::Fragment(_), Blob::Acknowledgement(_)]));
+    }
+
+    #[test]
+    fn panicking_filter() {
+        let mut socket = FilteredSocket::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+
+        socket.set_outbound_filter(Some(Box::new(|_| panic!("filter failed"))));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| send_datagram(&[1], peer_addr, &socket)));
+        assert!(result.is_err());
+
+        // the socket still works after the filter is replaced
+        socket.set_outbound_filter(None);
+        assert_eq!(send_datagram(&[1], peer_addr, &socket).unwrap(), Some(1));
+    }
+
     #[test]
     fn split_fragment_size() {
         let fragment = Fragment {
             send_ack: false,
+            ordered: false,
+            tagged: false,
             fragmentation_id: 50,
             total_size: 10,
             start: 5,
@@ -375,6 +1006,8 @@ mod tests {
     fn blob_size() {
         let blob = Blob::Fragment(Fragment {
             send_ack: true,
+            ordered: false,
+            tagged: false,
             fragmentation_id: 80,
             total_size: 10,
             start: 5,
@@ -391,6 +1024,8 @@ mod tests {
     fn fragment_serialization() {
         let fragment = Fragment {
             send_ack: true,
+            ordered: false,
+            tagged: false,
             fragmentation_id: 80,
             total_size: 10,
             start: 5,
@@ -413,6 +1048,8 @@ mod tests {
     fn blob_serialization() {
         let blob = Blob::Fragment(Fragment {
             send_ack: true,
+            ordered: false,
+            tagged: false,
             fragmentation_id: 80,
             total_size: 10,
             start: 5,
@@ -436,6 +1073,8 @@ mod tests {
             blobs: vec![
                 Blob::Fragment(Fragment {
                     send_ack: true,
+                    ordered: false,
+                    tagged: false,
                     fragmentation_id: 80,
                     total_size: 10,
                     start: 5,
@@ -443,6 +1082,8 @@ mod tests {
                 }),
                 Blob::Fragment(Fragment {
                     send_ack: true,
+                    ordered: false,
+                    tagged: false,
                     fragmentation_id: 80,
                     total_size: 10,
                     start: 5,
@@ -462,6 +1103,8 @@ mod tests {
             blobs: vec![
                 Blob::Fragment(Fragment {
                     send_ack: true,
+                    ordered: false,
+                    tagged: false,
                     fragmentation_id: 80,
                     total_size: 10,
                     start: 5,
@@ -469,6 +1112,8 @@ mod tests {
                 }),
                 Blob::Fragment(Fragment {
                     send_ack: true,
+                    ordered: false,
+                    tagged: false,
                     fragmentation_id: 80,
                     total_size: 10,
                     start: 5,
@@ -482,4 +1127,74 @@ mod tests {
 
         assert_eq!(packet.blobs.len(), deserialized.blobs.len());
     }
+
+    fn blob_strategy() -> impl Strategy<Value = Blob> {
+        prop_oneof![
+            (any::<bool>(), any::<bool>(), any::<bool>(), 0..(1u16 << 15), 0..(1u32 << 31), 0..(1u32 << 31), proptest::collection::vec(any::<u8>(), 0..64))
+            .prop_map(|(send_ack, ordered, tagged, fragmentation_id, total_size, start, data)| Blob::Fragment(Fragment {
+                send_ack,
+                ordered,
+                tagged,
+                fragmentation_id,
+                total_size,
+                start,
+                data: data.into(),
+            })),
+            (any::<u64>(), any::<u64>(), proptest::option::of((any::<u16>(), proptest::collection::vec(any::<u8>(), 0..MAX_HEARTBEAT_PAYLOAD_SIZE))))
+            .prop_map(|(send_time, received_packets, payload)| Blob::Heartbeat(Heartbeat {
+                send_time,
+                received_packets,
+                payload: payload.map(|(fragmentation_id, data)| (fragmentation_id, data.into())),
+            })),
+            (any::<u64>(), any::<u64>())
+            .prop_map(|(send_time, received_packets)| Blob::HeartbeatResponse(Heartbeat { send_time, received_packets, payload: None })),
+            (any::<u16>(), any::<u32>(), any::<u16>())
+            .prop_map(|(fragmentation_id, start, len)| Blob::Acknowledgement(Acknowledgement {
+                fragmentation_id,
+                start,
+                len,
+            })),
+            Just(()).prop_map(|()| Blob::Disconnect),
+            Just(()).prop_map(|()| Blob::Pause),
+            Just(()).prop_map(|()| Blob::Resume),
+            proptest::option::of("[a-z ]{1,32}")
+            .prop_map(|reason| Blob::HandshakeRejected(reason.map(String::into_boxed_str))),
+            any::<u32>().prop_map(Blob::ReceiveWindow),
+            (any::<u16>(), proptest::collection::vec(any::<(u32, u32)>(), 0..NegativeAcknowledgement::MAX_RANGES))
+            .prop_map(|(fragmentation_id, ranges)| Blob::NegativeAcknowledgement(NegativeAcknowledgement {
+                fragmentation_id,
+                ranges,
+            })),
+            (any::<IpAddr>(), any::<u16>(), any::<u64>())
+            .prop_map(|(ip, port, key)| Blob::Handoff(SocketAddr::new(ip, port), key)),
+            any::<u16>().prop_map(Blob::Mtu),
+            any::<u64>().prop_map(Blob::HandoffKey),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn packet_round_trip(blobs in proptest::collection::vec(blob_strategy(), 0..8)) {
+            let packet = Packet { blobs };
+
+            let bytes = packet.serialize();
+            prop_assert_eq!(packet.size() as usize, bytes.len());
+
+            let deserialized = Packet::deserialize(&bytes).unwrap();
+            prop_assert_eq!(deserialized.blob_count(), packet.blob_count());
+            prop_assert_eq!(deserialized.serialize(), bytes);
+        }
+
+        /// packets come straight from the network, so no input should cause a panic
+        #[test]
+        fn deserialize_arbitrary_bytes(bytes in proptest::collection::vec(any::<u8>(), 0..256)) {
+            Handshake::deserialize_handshake(&bytes);
+
+            if let Some(packet) = Packet::deserialize(&bytes) {
+                // anything accepted should be able to be sent back out
+                let serialized = packet.serialize();
+                prop_assert!(Packet::deserialize(&serialized).is_some());
+            }
+        }
+    }
 }
@@ -1,41 +1,339 @@
 use std::{
-    io::ErrorKind, net::{SocketAddr, UdpSocket}, time::Duration
+    any::Any, collections::{HashMap, VecDeque}, io::{ErrorKind, Write}, net::{SocketAddr, UdpSocket}, sync::{Arc, Mutex}, time::Duration
 };
 
-use crate::{connection::{Connection, Connections}, packet::{Handshake, Packet}, prelude::ConnectionMetrics, Config, Error};
+use crate::{connection::{CompleteMessage, Connection, Connections, ORDERED_SEQUENCE_SIZE, TAG_SIZE}, packet::{send_datagram, Blob, FilteredSocket, Handshake, Packet}, message::SendMessage, metrics::{ConnectionMetrics, ConnectionQuality, MetricsHistory, SocketStats}, Config, ConnectionId, Error, Reliability};
 
 
 const RECV_BUFFER_SIZE: usize = u16::MAX as usize;
 
+/// the largest token that can be sent in a handshake, see [open_connection_with_token](Socket::open_connection_with_token)
+pub const MAX_TOKEN_SIZE: usize = 256;
+
+/// the largest message that can be sent with a heartbeat, see [set_heartbeat_payload](Socket::set_heartbeat_payload)
+pub const MAX_HEARTBEAT_PAYLOAD_SIZE: usize = 64;
+
+/// the longest reason that can be given when rejecting a connection,
+/// see [reject_connection_with_reason](Socket::reject_connection_with_reason)
+pub const MAX_REJECTION_REASON_SIZE: usize = 256;
+
+/// the reason given to connection requests rejected by [shutdown_graceful](Socket::shutdown_graceful)
+pub const SHUTDOWN_REJECTION_REASON: &str = "shutting down";
+
+/// how long [shutdown_graceful](Socket::shutdown_graceful) waits between updates
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
 pub struct Socket {
     config: Config,
-    udp_socket: UdpSocket,
+    udp_socket: FilteredSocket,
     /// cached to not have constant reallocation
     receive_buffer: Option<Box<[u8; RECV_BUFFER_SIZE]>>,
     connections: Connections,
+    /// connection requests that weren't accepted when they were received
+    connection_requests: HashMap<SocketAddr, ConnectionRequest>,
+    /// messages queued by [SocketHandle]s, sent in the next update
+    handle_queue: Arc<Mutex<Vec<QueuedMessage>>>,
+    /// events kept by [update_buffered](Socket::update_buffered) until they are drained
+    buffered_events: BufferedEvents,
+    /// events held back by [poll_events_with_limit](Socket::poll_events_with_limit) for later calls
+    deferred_events: VecDeque<OwnedSocketEvent>,
+    /// if errors are kept for [drain_errors](Socket::drain_errors) instead of being passed to the event handler
+    buffer_errors: bool,
+    /// if new connection requests are rejected, see [shutdown_graceful](Socket::shutdown_graceful)
+    shutting_down: bool,
+    /// see [set_report_ignored_handshakes](Socket::set_report_ignored_handshakes)
+    report_ignored_handshakes: bool,
+    /// packets of closed connections that were held back because the send buffer was full,
+    /// with the time the connection was closed
+    closing_packets: VecDeque<(Duration, SocketAddr, Box<[u8]>)>,
+    /// the last connection updated when only some are, see [max_connection_updates](Config::max_connection_updates)
+    last_updated_connection: Option<ConnectionId>,
+    /// the sequence numbers of closed connections by their token, with the time they closed,
+    /// see [session_resume_delay](Config::session_resume_delay)
+    closed_sessions: Vec<ClosedSession>,
+    /// reused to flush complete messages from connections into
+    flushed_messages: Vec<CompleteMessage>,
+    /// reused to flush chunks of streamed messages from connections into
+    flushed_chunks: Vec<(u16, usize, usize, Box<[u8]>)>,
+    // counters reported in `SocketStats`
+    wrong_protocol_handshakes: u64,
+    malformed_packets: u64,
+    packets_from_unknown_peers: u64,
+    refused_connections: u64,
+    dropped_connection_requests: u64,
+}
+
+/// a buffer for receiving packets, which only needs to be held whilst a socket is receiving
+///
+/// by default every [Socket] allocates one for itself, this can be shared between many sockets
+/// with [update_with_buffer](Socket::update_with_buffer) to avoid that
+pub struct ReceiveBuffer(Box<[u8; RECV_BUFFER_SIZE]>);
+
+impl ReceiveBuffer {
+    pub fn new() -> Self {
+        ReceiveBuffer([0; RECV_BUFFER_SIZE].into())
+    }
+}
+
+impl Default for ReceiveBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Default)]
+struct BufferedEvents {
+    received: Vec<Received>,
+    received_chunks: Vec<ReceivedChunk>,
+    connection_changes: Vec<ConnectionChange>,
+    errors: Vec<Error>,
+}
+
+/// why a connection couldn't be opened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectError {
+    /// there is already an established connection with the address
+    AlreadyConnected,
+    /// a connection with the address was already opened and is still waiting for a response,
+    /// so opening it again changes nothing
+    AlreadyConnecting,
+    /// the address is IPv4 and the socket is bound to IPv6 or the other way around,
+    /// so nothing could ever be sent to it
+    AddressFamilyMismatch {
+        local_addr: SocketAddr,
+        addr: SocketAddr,
+    },
+    /// the token is larger than [MAX_TOKEN_SIZE]
+    TokenTooLarge {
+        size: usize,
+    },
+}
+
+/// why a [Config] couldn't be used, see [set_config](Socket::set_config)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// with the new mtu or [max_message_fragments](Config::max_message_fragments)
+    /// a message already queued to a connection would no longer fit
+    MtuTooSmall {
+        addr: SocketAddr,
+        message_size: usize,
+        max_message_size: usize,
+    },
+}
+
+/// why a handshake was ignored, see [set_report_ignored_handshakes](Socket::set_report_ignored_handshakes)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeIgnoredReason {
+    /// the handshake's protocol id didn't match our [protocol_id](Config::protocol_id),
+    /// the peer is likely running a different version
+    ProtocolMismatch {
+        received: u64,
+        expected: u64,
+    },
+    /// there is already a connection with the address.
+    /// normal whilst the peer waits for our first heartbeat, as it keeps sending handshakes until then
+    AlreadyConnected,
+}
+
+/// why a message couldn't be sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// there is no connection with the address
+    NoConnection,
+    /// the message would need more than [max_message_fragments](Config::max_message_fragments) fragments
+    /// at the connection's mtu
+    MessageTooLarge {
+        size: usize,
+        max_size: usize,
+    },
+    /// the connection already has more than [max_pending_reliable_bytes](Config::max_pending_reliable_bytes)
+    /// of reliable messages waiting to be acknowledged, see [try_send_reliable](Socket::try_send_reliable)
+    TooMuchPending {
+        pending_bytes: usize,
+        max_pending_bytes: usize,
+    },
+}
+
+/// a cheap handle to a [Socket] that can be cloned and sent to other threads to queue messages
+///
+/// messages are queued until the next [update](Socket::update) of the socket,
+/// messages that can't be sent at that point are dropped, see [SendError]
+#[derive(Clone)]
+pub struct SocketHandle {
+    queue: Arc<Mutex<Vec<QueuedMessage>>>,
+}
+
+/// a connection taken out of a socket with [take_connection](Socket::take_connection),
+/// to be moved into another with [insert_connection](Socket::insert_connection)
+///
+/// keeps all of the connection's state, such as round trip time samples, messages in transit and the reliable blacklist
+pub struct DetachedConnection {
+    connection: Box<Connection>,
+}
+
+/// mutable access to a connection whilst iterating over a socket's connections,
+/// see [connections_mut](Socket::connections_mut)
+pub struct ConnectionMut<'a> {
+    connection: &'a mut Connection,
+    config: &'a Config,
+}
+
+/// where a connection's [ReliableOrdered](Reliability::ReliableOrdered) messages are up to,
+/// see [ordered_sequences](Socket::ordered_sequences)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OrderedSequences {
+    /// the sequence number of the next ordered message to send
+    pub send: u16,
+    /// the sequence number of the next ordered message to receive
+    pub receive: u16,
+}
+
+/// a closed connection with a token, remembered so that a new connection with the same token carries on from it
+struct ClosedSession {
+    /// when the connection closed
+    time: Duration,
+    token: Box<[u8]>,
+    sequences: OrderedSequences,
+    /// ordered messages the peer hadn't acknowledged, sent again once resumed
+    messages: Vec<SendMessage>,
+}
+
+/// a message queued by a [SocketHandle]
+struct QueuedMessage {
+    addr: SocketAddr,
+    reliability: Reliability,
+    priority: u8,
+    data: Box<[u8]>,
+}
+
+/// a connection request waiting for [accept_connection](Socket::accept_connection)
+/// or [reject_connection](Socket::reject_connection)
+struct ConnectionRequest {
+    /// the time the last handshake was received from the address
+    ///
+    /// the request is forgotten if no handshakes are received within the timeout delay
+    last_handshake: Duration,
+    /// if the request was rejected, in which case handshakes are ignored
+    rejected: bool,
+    /// the reason given when the request was rejected, if any
+    rejection_reason: Option<Box<str>>,
+    /// the token sent in the first handshake
+    token: Box<[u8]>,
+    /// the mtu sent in the first handshake
+    mtu: u16,
 }
 
 pub enum SocketEvent<'a> {
     /// a message was received from a connection
+    ///
+    /// messages received in the same update are in the order they were sent,
+    /// but there is no guarantee of order between updates
+    ///
+    /// `sequence` is the fragmentation id of the message, counting up by one for every message
+    /// sent to the connection and wrapping around after 15 bits,
+    /// which can be used to detect gaps or reorder messages across updates
+    ///
+    /// `tag` is set if the message was sent with [send_with_tag](Socket::send_with_tag)
     Received {
         addr: SocketAddr,
+        sequence: u16,
+        tag: Option<u8>,
+        data: Box<[u8]>,
+    },
+    /// part of a message being streamed, see [min_streamed_message_size](Config::min_streamed_message_size)
+    ///
+    /// chunks of a message are passed on in order as soon as they are received, starting at `offset` in the message.
+    /// the message is complete once `offset + data.len()` reaches `total_size`
+    ///
+    /// `sequence` is the fragmentation id of the message, the same as for [Received](SocketEvent::Received)
+    ReceivedChunk {
+        addr: SocketAddr,
+        sequence: u16,
+        offset: usize,
+        total_size: usize,
         data: Box<[u8]>,
     },
     /// a new connection was established with an address
     ///
     /// if you are the initiating party, this even will only be fired
     /// after a response is received
+    ///
+    /// `token` is the one sent in the handshake, see [connection_token](Socket::connection_token)
     NewConnection {
         addr: SocketAddr,
+        id: ConnectionId,
+        token: &'a [u8],
     },
     /// received a request from an address to open a connection
     ///
     /// set `accept_connection` to true to open the connection
     ///
     /// simply do nothing if you never want to accept incoming connections
+    ///
+    /// set `config` to use a different [Config] for the connection than the socket's
+    ///
+    /// if the connection isn't accepted the request stays pending,
+    /// and can be accepted or rejected later with [accept_connection](Socket::accept_connection)
+    /// or [reject_connection](Socket::reject_connection).
+    /// the request is forgotten once the address stops sending handshakes
     ConnectionRequest {
         addr: SocketAddr,
+        /// the token sent with the handshake, see [open_connection_with_token](Socket::open_connection_with_token)
+        token: &'a [u8],
         accept_connection: &'a mut bool,
+        config: &'a mut Option<Config>,
+    },
+    /// the [quality](ConnectionMetrics::quality) of a connection changed
+    ///
+    /// fired once the first round trip time is known, and then whenever the quality changes
+    QualityChanged {
+        addr: SocketAddr,
+        quality: ConnectionQuality,
+    },
+    /// a reliable message to a connection has been resent more than the
+    /// [stall_resend_threshold](Config::stall_resend_threshold) without any of it being acknowledged
+    ///
+    /// the connection may have silently died, close it to give up early instead of waiting for the timeout
+    ConnectionStalled {
+        addr: SocketAddr,
+    },
+    /// the operating system's send buffer was full while sending to a connection
+    ///
+    /// the held back packets are retried in the next update, but producers should be
+    /// throttled before they start being dropped
+    SendBufferFull {
+        addr: SocketAddr,
+    },
+    /// the other party rejected a connection we opened, see [reject_connection](Socket::reject_connection)
+    ///
+    /// fired before the [ClosedConnection](SocketEvent::ClosedConnection) event,
+    /// so that a refused connection can be told apart from one that timed out
+    ConnectionRejected {
+        addr: SocketAddr,
+        /// the reason given by the other party, see [reject_connection_with_reason](Socket::reject_connection_with_reason)
+        reason: Option<&'a str>,
+    },
+    /// the peer handed a connection off to another of it's sockets, see [take_connection](Socket::take_connection)
+    ///
+    /// the connection keeps it's id and state, only it's address changed from `previous_addr` to `addr`
+    ConnectionMigrated {
+        addr: SocketAddr,
+        previous_addr: SocketAddr,
+    },
+    /// a reliable message sent with [send_with_max_resends](Socket::send_with_max_resends)
+    /// was given up on after being resent the maximum number of times
+    ///
+    /// `id` is the fragmentation id returned when it was sent
+    MessageDropped {
+        addr: SocketAddr,
+        id: u16,
+    },
+    /// a handshake was received but ignored, for diagnosing connections that never get established
+    ///
+    /// only fired once enabled with [set_report_ignored_handshakes](Socket::set_report_ignored_handshakes)
+    HandshakeIgnored {
+        addr: SocketAddr,
+        reason: HandshakeIgnoredReason,
     },
     /// a connection with an address was closed
     ///
@@ -44,189 +342,3065 @@ pub enum SocketEvent<'a> {
     /// received
     ClosedConnection {
         addr: SocketAddr,
+        id: ConnectionId,
     },
     /// some internal error occurred
     Error(Error),
 }
 
+/// a message, returned by [drain_received](Socket::drain_received)
+///
+/// the same as [SocketEvent::Received]
+#[derive(Debug)]
+pub struct Received {
+    pub addr: SocketAddr,
+    pub sequence: u16,
+    pub tag: Option<u8>,
+    pub data: Box<[u8]>,
+}
+
+/// part of a streamed message, returned by [drain_received_chunks](Socket::drain_received_chunks)
+///
+/// the same as [SocketEvent::ReceivedChunk]
+#[derive(Debug)]
+pub struct ReceivedChunk {
+    pub addr: SocketAddr,
+    pub sequence: u16,
+    pub offset: usize,
+    pub total_size: usize,
+    pub data: Box<[u8]>,
+}
+
+/// a change to the connections of a socket, returned by [drain_connection_changes](Socket::drain_connection_changes)
+///
+/// each variant is the same as the [OwnedSocketEvent] with the same name
+#[derive(Debug)]
+pub enum ConnectionChange {
+    NewConnection {
+        addr: SocketAddr,
+        id: ConnectionId,
+        token: Box<[u8]>,
+    },
+    ConnectionRequest {
+        addr: SocketAddr,
+        token: Box<[u8]>,
+    },
+    QualityChanged {
+        addr: SocketAddr,
+        quality: ConnectionQuality,
+    },
+    ConnectionStalled {
+        addr: SocketAddr,
+    },
+    SendBufferFull {
+        addr: SocketAddr,
+    },
+    ConnectionRejected {
+        addr: SocketAddr,
+        reason: Option<Box<str>>,
+    },
+    ConnectionMigrated {
+        addr: SocketAddr,
+        previous_addr: SocketAddr,
+    },
+    MessageDropped {
+        addr: SocketAddr,
+        id: u16,
+    },
+    HandshakeIgnored {
+        addr: SocketAddr,
+        reason: HandshakeIgnoredReason,
+    },
+    ClosedConnection {
+        addr: SocketAddr,
+        id: ConnectionId,
+    },
+}
+
+/// the same as a [SocketEvent] but owns all of its data,
+/// returned by [poll_events](Socket::poll_events)
+#[derive(Debug)]
+pub enum OwnedSocketEvent {
+    /// see [SocketEvent::Received]
+    Received {
+        addr: SocketAddr,
+        sequence: u16,
+        tag: Option<u8>,
+        data: Box<[u8]>,
+    },
+    /// see [SocketEvent::ReceivedChunk]
+    ReceivedChunk {
+        addr: SocketAddr,
+        sequence: u16,
+        offset: usize,
+        total_size: usize,
+        data: Box<[u8]>,
+    },
+    /// see [SocketEvent::NewConnection]
+    NewConnection {
+        addr: SocketAddr,
+        id: ConnectionId,
+        token: Box<[u8]>,
+    },
+    /// received a request from an address to open a connection
+    ///
+    /// the request stays pending until [accept_connection](Socket::accept_connection)
+    /// or [reject_connection](Socket::reject_connection) is called,
+    /// or the address stops sending handshakes
+    ConnectionRequest {
+        addr: SocketAddr,
+        token: Box<[u8]>,
+    },
+    /// see [SocketEvent::QualityChanged]
+    QualityChanged {
+        addr: SocketAddr,
+        quality: ConnectionQuality,
+    },
+    /// see [SocketEvent::ConnectionStalled]
+    ConnectionStalled {
+        addr: SocketAddr,
+    },
+    /// see [SocketEvent::SendBufferFull]
+    SendBufferFull {
+        addr: SocketAddr,
+    },
+    /// see [SocketEvent::ConnectionRejected]
+    ConnectionRejected {
+        addr: SocketAddr,
+        reason: Option<Box<str>>,
+    },
+    /// see [SocketEvent::ConnectionMigrated]
+    ConnectionMigrated {
+        addr: SocketAddr,
+        previous_addr: SocketAddr,
+    },
+    /// see [SocketEvent::MessageDropped]
+    MessageDropped {
+        addr: SocketAddr,
+        id: u16,
+    },
+    /// see [SocketEvent::HandshakeIgnored]
+    HandshakeIgnored {
+        addr: SocketAddr,
+        reason: HandshakeIgnoredReason,
+    },
+    /// see [SocketEvent::ClosedConnection]
+    ClosedConnection {
+        addr: SocketAddr,
+        id: ConnectionId,
+    },
+    /// see [SocketEvent::Error]
+    Error(Error),
+}
 
 
 impl Socket {
     /// binds to a port and creates a new socket
     pub fn bind(addr: SocketAddr, config: Config) -> Result<Self, std::io::Error> {
-        let udp_socket = UdpSocket::bind(addr)?;
+        Self::from_udp_socket(UdpSocket::bind(addr)?, config)
+    }
+
+    /// like [bind](Socket::bind), but if the address is still in use, such as by a server that
+    /// is restarting, it tries again up to `retries` times, waiting `delay` before the first retry
+    /// and doubling it after each one
+    ///
+    /// this blocks the thread whilst waiting. any other error is returned straight away,
+    /// and the last [AddrInUse](ErrorKind::AddrInUse) error is returned if every retry fails
+    ///
+    /// std can't set `SO_REUSEADDR`, if you need it bind the socket yourself with a crate like `socket2`
+    /// and pass it to [from_udp_socket](Socket::from_udp_socket)
+    pub fn bind_with_retry(addr: SocketAddr, config: Config, retries: u32, mut delay: Duration) -> Result<Self, std::io::Error> {
+        let mut attempt = 0;
 
+        loop {
+            match UdpSocket::bind(addr) {
+                Ok(udp_socket) => return Self::from_udp_socket(udp_socket, config),
+                Err(err) if attempt < retries && matches!(err.kind(), ErrorKind::AddrInUse | ErrorKind::WouldBlock) => {
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                },
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// creates a new socket from an already bound udp socket, so that options std
+    /// doesn't expose, like `SO_REUSEADDR`, can be set before binding
+    ///
+    /// the socket is set to non blocking
+    pub fn from_udp_socket(udp_socket: UdpSocket, config: Config) -> Result<Self, std::io::Error> {
         udp_socket.set_nonblocking(true)?;
 
         Ok(Socket {
-            config,
-            udp_socket,
+            udp_socket: FilteredSocket::new(udp_socket),
             receive_buffer: None,
-            connections: Connections::new(),
+            connections: Connections::with_capacity(config.expected_connections),
+            connection_requests: HashMap::new(),
+            handle_queue: Arc::new(Mutex::new(Vec::new())),
+            buffered_events: BufferedEvents::default(),
+            buffer_errors: false,
+            shutting_down: false,
+            report_ignored_handshakes: false,
+            deferred_events: VecDeque::new(),
+            closing_packets: VecDeque::new(),
+            last_updated_connection: None,
+            closed_sessions: Vec::new(),
+            flushed_messages: Vec::new(),
+            flushed_chunks: Vec::new(),
+            wrong_protocol_handshakes: 0,
+            malformed_packets: 0,
+            packets_from_unknown_peers: 0,
+            refused_connections: 0,
+            dropped_connection_requests: 0,
+            config,
         })
     }
 
+    /// the address the socket is bound to
+    pub fn local_addr(&self) -> Result<SocketAddr, std::io::Error> {
+        self.udp_socket.local_addr()
+    }
+
+    /// sets a function that changes the raw bytes of every datagram just before it's sent,
+    /// including handshakes, for things like obfuscation or custom framing
+    ///
+    /// the peer needs a matching [inbound filter](Socket::set_inbound_filter) to undo any changes.
+    /// datagrams that grow past the [mtu](Config::mtu) may not arrive, lower it to leave space if needed.
+    /// with the `recvmmsg` feature a socket with an inbound filter receives one datagram per syscall,
+    /// as it can't know how large the peer's filter made them
+    pub fn set_outbound_filter(&mut self, filter: impl FnMut(&mut Vec<u8>) + Send + 'static) {
+        self.udp_socket.set_outbound_filter(Some(Box::new(filter)));
+    }
+
+    /// sets a function that changes the raw bytes of every datagram as soon as it's received,
+    /// before anything is read from them, see [set_outbound_filter](Socket::set_outbound_filter)
+    pub fn set_inbound_filter(&mut self, filter: impl FnMut(&mut Vec<u8>) + Send + 'static) {
+        self.udp_socket.set_inbound_filter(Some(Box::new(filter)));
+    }
+
+    /// removes the inbound and outbound filters, so datagrams are sent and received unchanged
+    pub fn clear_filters(&mut self) {
+        self.udp_socket.set_outbound_filter(None);
+        self.udp_socket.set_inbound_filter(None);
+    }
+
+    /// starts recording every datagram sent and received to `sink`, as [CaptureRecord](crate::capture::CaptureRecord)s
+    /// that can be read back with [read_from](crate::capture::CaptureRecord::read_from)
+    ///
+    /// datagrams are recorded as they are on the wire, after the [outbound filter](Socket::set_outbound_filter)
+    /// and before the [inbound filter](Socket::set_inbound_filter), with the time passed to the update.
+    /// writing is unbuffered so consider wrapping files in a `BufWriter`
+    ///
+    /// if writing fails the capture stops and the error is reported in the next update.
+    /// replaces any capture already running
+    pub fn start_capture(&mut self, sink: impl Write + Send + 'static) {
+        self.udp_socket.set_capture(Some(Box::new(sink)));
+    }
+
+    /// stops recording datagrams and flushes the sink
+    ///
+    /// returns `Ok` if there was no capture running
+    pub fn stop_capture(&mut self) -> Result<(), std::io::Error> {
+        match self.udp_socket.set_capture(None) {
+            Some(mut sink) => sink.flush(),
+            None => Ok(()),
+        }
+    }
+
     /// receives packets and updates internal state
     ///
     /// pass in a closure to handle events produced by the socket
+    ///
+    /// the same as calling [process_timers](Socket::process_timers) then [process_incoming](Socket::process_incoming)
     pub fn update(&mut self, time: Duration, mut event_handler: impl FnMut(SocketEvent)) {
+        self.process_timers(time, &mut event_handler);
+        self.process_incoming(time, &mut event_handler);
+    }
 
-        // update individual connections
-        let mut connections_to_drop = Vec::new();
-
-        for connection in self.connections.iter_mut() {
-            if let Err(err) = connection.update(time, &self.config, &self.udp_socket) {
-                event_handler(SocketEvent::Error(err));
-            }
+    /// updates connections without receiving any packets, sending queued messages,
+    /// heartbeats, resends and acknowledgements and timing out connections
+    ///
+    /// useful when driven by an external event loop, call this when a timer fires
+    pub fn process_timers(&mut self, time: Duration, event_handler: impl FnMut(SocketEvent)) {
+        self.with_buffered_errors(event_handler, |socket, event_handler| socket.run_timers(time, event_handler));
+    }
 
-            if connection.should_drop() {
-                connections_to_drop.push(connection.address());
-            }
+    fn run_timers(&mut self, time: Duration, mut event_handler: impl FnMut(SocketEvent)) {
+        self.udp_socket.set_time(time);
 
-            if connection.just_connected() {
-                event_handler(SocketEvent::NewConnection { addr: connection.address() })
-            }
+        if let Some(err) = self.udp_socket.take_capture_error() {
+            event_handler(SocketEvent::Error(Error::IoError(err)));
         }
 
-        for addr in connections_to_drop {
-            self.connections.remove_connection(addr);
-            event_handler(SocketEvent::ClosedConnection { addr });
+        // queue messages sent from handles
+        let queued_messages = std::mem::take(&mut *self.handle_queue.lock().unwrap_or_else(|err| err.into_inner()));
+        for message in queued_messages {
+            let _ = self.send_with_reliability(message.addr, message.reliability, message.priority, message.data);
         }
 
 
-        // receive and process messages from the `UdpSocket`
+        // forget connection requests that have stopped sending handshakes
+        self.connection_requests.retain(|_, request| request.last_handshake + self.config.timeout_delay >= time);
 
-        // remove for ownership, reinitialize if it was dropped due to an error
-        let mut receive_buffer = self.receive_buffer.take().unwrap_or_else(|| [0; RECV_BUFFER_SIZE].into());
 
-        loop {
-            let event = self.udp_socket.recv_from(receive_buffer.as_mut());
+        // retry the last packets of closed connections, giving up after the timeout delay
+        self.closing_packets.retain(|(closed, _, _)| *closed + self.config.timeout_delay >= time);
+        let session_resume_delay = self.config.session_resume_delay.unwrap_or_default();
+        self.closed_sessions.retain(|session| session.time + session_resume_delay >= time);
 
-            match event {
+        while let Some((_, addr, bytes)) = self.closing_packets.front() {
+            match send_datagram(bytes, *addr, &self.udp_socket) {
+                // still full
+                Ok(None) => break,
+                Ok(Some(_)) => (),
+                Err(err) => event_handler(SocketEvent::Error(Error::IoError(err))),
+            }
 
-                // received a packet
-                Ok((received_bytes, addr)) => {
+            self.closing_packets.pop_front();
+        }
 
-                    let bytes = receive_buffer.get(0..received_bytes).unwrap();
-                    // handle in case of handshake
-                    if let Some(handshake) = Handshake::deserialize_handshake(bytes) {
-                        if handshake.protocol_id != self.config.protocol_id {
-                            // ignore wrong protocol id's
-                            continue;
-                        }
-
-                        if self.connections.get_connection(addr).is_some() {
-                            // ignore duplicate handshakes
-                            continue;
-                        }
-
-                        let mut accept_connection = false;
-                        event_handler(SocketEvent::ConnectionRequest {
-                            addr,
-                            accept_connection: &mut accept_connection,
-                        });
-
-                        if accept_connection {
-                            // unwrap is safe, connection doesn't exist
-                            self.connections.new_connection(Connection::new(time, addr, false)).unwrap();
-                        }
 
-                        continue;
-                    }
+        // update individual connections
+        let mut connections_to_drop = Vec::new();
 
-                    let Some(connection) = self.connections.get_connection_mut(addr) else {
-                        // message is from an address without a connection
-                        continue;
-                    };
+        // everything sent from here until the connections are updated goes out together
+        self.udp_socket.start_batch();
 
-                    // parse the packet
-                    let Some(packet) = Packet::deserialize(bytes) else {
-                        event_handler(SocketEvent::Error(Error::MalformedPacket { addr }));
-                        continue;
-                    };
+        let connections: Box<dyn Iterator<Item = &mut Connection>> = if let Some(max_connection_updates) = self.config.max_connection_updates {
+            // take turns in order of id, starting after the last connection updated
+            let mut connections = self.connections.iter_mut().collect::<Vec<_>>();
+            connections.sort_unstable_by_key(|connection| connection.id());
+            let start = connections.partition_point(|connection| Some(connection.id()) <= self.last_updated_connection);
+            connections.rotate_left(start);
+            connections.truncate(max_connection_updates.get());
 
-                    // handle the packet with the connection
-                    if let Err(()) = connection.receive(time, &self.config, packet) {
-                        event_handler(SocketEvent::Error(Error::MalformedPacket { addr }));
-                    }
-                },
+            if let Some(connection) = connections.last() {
+                self.last_updated_connection = Some(connection.id());
+            }
+
+            Box::new(connections.into_iter())
+        } else {
+            Box::new(self.connections.iter_mut())
+        };
+
+        for connection in connections {
+            if let Err(err) = connection.update(time, &self.config, &self.udp_socket) {
+                event_handler(SocketEvent::Error(err));
+            }
 
-                // some other event
-                Err(err) => match err.kind() {
+            if connection.should_drop() {
+                connections_to_drop.push((connection.address(), connection.id()));
+            }
 
-                    // nothing in queue
-                    ErrorKind::WouldBlock => break,
+            if connection.just_connected() {
+                event_handler(SocketEvent::NewConnection { addr: connection.address(), id: connection.id(), token: connection.token() })
+            }
 
-                    // errors to ignore
-                    ErrorKind::ConnectionReset |
-                    ErrorKind::ConnectionRefused |
-                    ErrorKind::ConnectionAborted => (),
+            if let Some(quality) = connection.quality_changed(&self.config) {
+                event_handler(SocketEvent::QualityChanged { addr: connection.address(), quality })
+            }
 
-                    // unhandled
-                    _ => {
-                        event_handler(SocketEvent::Error(Error::IoError(err)));
-                        break;
-                    },
-                }
+            if connection.just_stalled() {
+                event_handler(SocketEvent::ConnectionStalled { addr: connection.address() })
             }
-        }
 
-        // put allocated buffer back
-        self.receive_buffer = Some(receive_buffer);
+            if connection.just_blocked() {
+                event_handler(SocketEvent::SendBufferFull { addr: connection.address() })
+            }
 
+            if let Some(reason) = connection.just_rejected() {
+                event_handler(SocketEvent::ConnectionRejected { addr: connection.address(), reason: reason.as_deref() })
+            }
 
-        // flush complete messages
-        for connection in self.connections.iter_mut() {
             let addr = connection.address();
-            connection.flush_messages(time, |data| {
-                event_handler(SocketEvent::Received { addr, data });
-            });
+            for id in connection.drain_dropped_messages() {
+                event_handler(SocketEvent::MessageDropped { addr, id })
+            }
         }
 
-    }
+        // anything that didn't fit in the send buffer is held back by it's connection, as if it had been full when sent
+        let mut blocked = Vec::new();
+        let connections = &mut self.connections;
+        let result = self.udp_socket.flush_batch(|addr, bytes, sent_bytes| {
+            if let Some(connection) = connections.get_connection_mut(addr) {
+                connection.unsend_packet(bytes, sent_bytes);
+                blocked.push(addr);
+            }
+        });
 
-    /// opens a new connection with an address
-    ///
-    /// fails if there is already a connection to that address
-    ///
-    /// will cause a [NewConnection](SocketEvent::NewConnection) event to be fired once
-    /// a response is heard, or a [ClosedConnection](SocketEvent::ClosedConnection)
-    /// event if the timeout is reached first
-    pub fn open_connection(&mut self, time: Duration, addr: SocketAddr) -> Result<(), ()> {
-        let Ok(_) = self.connections.new_connection(Connection::new(time, addr, true)) else {
-            return Err(());
-        };
+        if let Err(err) = result {
+            event_handler(SocketEvent::Error(Error::IoError(err)));
+        }
 
-        Ok(())
+        for addr in blocked {
+            if let Some(connection) = self.connections.get_connection_mut(addr) {
+                if connection.just_blocked() {
+                    event_handler(SocketEvent::SendBufferFull { addr })
+                }
+            }
+        }
+
+        for (addr, id) in connections_to_drop {
+            if let Some(mut connection) = self.connections.take_connection(addr) {
+                // the disconnect and anything queued before it still reach the peer
+                for bytes in connection.take_blocked_packets() {
+                    self.closing_packets.push_back((time, addr, bytes));
+                }
+
+                if self.config.session_resume_delay.is_some() && !connection.token().is_empty() {
+                    let (send, receive) = connection.ordered_sequences();
+                    self.closed_sessions.retain(|session| *session.token != *connection.token());
+                    self.closed_sessions.push(ClosedSession {
+                        time,
+                        token: connection.token().into(),
+                        sequences: OrderedSequences { send, receive },
+                        messages: connection.take_unacknowledged_ordered(),
+                    });
+                }
+            }
+            event_handler(SocketEvent::ClosedConnection { addr, id });
+        }
     }
 
-    /// sends a message to an address
+    /// receives and processes packets waiting on the socket, then passes on completed messages
     ///
-    /// fails if there is no connection with that address, see [open_connection](Socket::open_connection)
-    pub fn send(&mut self, addr: SocketAddr, reliable: bool, data: Box<[u8]>) -> Result<(), ()> {
-        let Some(connection) = self.connections.get_connection_mut(addr) else {
-            return Err(());
-        };
+    /// useful when driven by an external event loop, call this when the socket is readable.
+    /// anything that needs sending in response, such as acknowledgements, is sent in the next
+    /// call to [process_timers](Socket::process_timers)
+    pub fn process_incoming(&mut self, time: Duration, event_handler: impl FnMut(SocketEvent)) {
+        // remove for ownership, reinitialize if it was dropped due to an error
+        let mut receive_buffer = self.receive_buffer.take().unwrap_or_else(|| [0; RECV_BUFFER_SIZE].into());
 
-        connection.send(reliable, data);
+        self.with_buffered_errors(event_handler, |socket, event_handler| socket.receive_into(time, &mut receive_buffer, event_handler));
 
-        Ok(())
+        // put allocated buffer back
+        self.receive_buffer = Some(receive_buffer);
     }
 
-    /// drops the connection with an address
+    /// the same as [update](Socket::update) but receives packets into a [ReceiveBuffer] that can be shared
+    /// with other sockets, instead of a buffer allocated for and kept by this socket
+    pub fn update_with_buffer(&mut self, time: Duration, receive_buffer: &mut ReceiveBuffer, mut event_handler: impl FnMut(SocketEvent)) {
+        self.process_timers(time, &mut event_handler);
+        self.process_incoming_with_buffer(time, receive_buffer, &mut event_handler);
+    }
+
+    /// the same as [process_incoming](Socket::process_incoming) but receives packets into a [ReceiveBuffer]
+    /// that can be shared with other sockets
+    pub fn process_incoming_with_buffer(&mut self, time: Duration, receive_buffer: &mut ReceiveBuffer, event_handler: impl FnMut(SocketEvent)) {
+        self.with_buffered_errors(event_handler, |socket, event_handler| socket.receive_into(time, &mut receive_buffer.0, event_handler));
+    }
+
+    /// keeps errors for [drain_errors](Socket::drain_errors) instead of passing them to the event handler,
+    /// if [set_buffer_errors](Socket::set_buffer_errors) is set
+    fn with_buffered_errors(
+        &mut self,
+        mut event_handler: impl FnMut(SocketEvent),
+        f: impl FnOnce(&mut Self, &mut dyn FnMut(SocketEvent)),
+    ) {
+        let buffer_errors = self.buffer_errors;
+        let mut errors = std::mem::take(&mut self.buffered_events.errors);
+
+        f(self, &mut |event| match event {
+            SocketEvent::Error(err) if buffer_errors => errors.push(err),
+            event => event_handler(event),
+        });
+
+        self.buffered_events.errors = errors;
+    }
+
+    fn receive_into(&mut self, time: Duration, receive_buffer: &mut [u8; RECV_BUFFER_SIZE], mut event_handler: impl FnMut(SocketEvent)) {
+        self.udp_socket.set_time(time);
+
+        // receive and process messages from the `UdpSocket`
+
+        // datagrams are copied here to be changed by the inbound filter, if there is one
+        let mut filtered = Vec::new();
+
+        // the peer's outbound filter can make datagrams larger than the mtu,
+        // so when there is a filter to undo it each datagram can take the whole buffer
+        #[cfg(all(feature = "recvmmsg", target_os = "linux"))]
+        let slot_size = if self.udp_socket.has_inbound_filter() {
+            RECV_BUFFER_SIZE
+        } else {
+            usize::from(self.config.mtu)
+        };
+
+        #[cfg(all(feature = "recvmmsg", target_os = "linux"))]
+        loop {
+            match crate::batch::receive_batch(&self.udp_socket, receive_buffer.as_mut(), slot_size) {
+                Ok(datagrams) => for (addr, range, truncated) in datagrams {
+                    if truncated {
+                        // larger than the slot it was received into, the rest of it is lost
+                        self.malformed_packets += 1;
+                        event_handler(SocketEvent::Error(Error::MalformedPacket { addr }));
+                        continue;
+                    }
+
+                    self.receive_datagram(time, addr, &receive_buffer[range], &mut filtered, &mut event_handler);
+                },
+                Err(err) => if !Self::receive_error(err, &mut event_handler) {
+                    break;
+                },
+            }
+        }
+
+        #[cfg(not(all(feature = "recvmmsg", target_os = "linux")))]
+        loop {
+            match self.udp_socket.recv_from(receive_buffer.as_mut()) {
+                Ok((received_bytes, addr)) => {
+                    self.receive_datagram(time, addr, &receive_buffer[..received_bytes], &mut filtered, &mut event_handler);
+                },
+                Err(err) => if !Self::receive_error(err, &mut event_handler) {
+                    break;
+                },
+            }
+        }
+
+        // flush complete messages
+        let mut flushed_messages = std::mem::take(&mut self.flushed_messages);
+        let mut flushed_chunks = std::mem::take(&mut self.flushed_chunks);
+        for connection in self.connections.iter_mut() {
+            let addr = connection.address();
+
+            // streamed messages have to pass on their last chunk before they are removed as complete
+            connection.flush_chunks_into(&mut flushed_chunks);
+            for (sequence, offset, total_size, data) in flushed_chunks.drain(..) {
+                event_handler(SocketEvent::ReceivedChunk { addr, sequence, offset, total_size, data });
+            }
+
+            connection.flush_into(time, &mut flushed_messages);
+
+            for (sequence, tag, data) in flushed_messages.drain(..) {
+                event_handler(SocketEvent::Received { addr, sequence, tag, data });
+            }
+        }
+        self.flushed_messages = flushed_messages;
+        self.flushed_chunks = flushed_chunks;
+
+    }
+
+    /// processes a single datagram received from `addr`
+    fn receive_datagram(&mut self, time: Duration, addr: SocketAddr, received: &[u8], filtered: &mut Vec<u8>, event_handler: &mut impl FnMut(SocketEvent)) {
+        let bytes = self.udp_socket.filter_inbound(addr, received, filtered);
+        // handle in case of handshake
+        if let Some(handshake) = Handshake::deserialize_handshake(bytes) {
+            if handshake.protocol_id != self.config.protocol_id {
+                // ignore wrong protocol id's
+                self.wrong_protocol_handshakes += 1;
+
+                if self.report_ignored_handshakes {
+                    event_handler(SocketEvent::HandshakeIgnored {
+                        addr,
+                        reason: HandshakeIgnoredReason::ProtocolMismatch {
+                            received: handshake.protocol_id,
+                            expected: self.config.protocol_id,
+                        },
+                    });
+                }
+                return;
+            }
+
+            if self.connections.get_connection(addr).is_some() {
+                // ignore duplicate handshakes
+                if self.report_ignored_handshakes {
+                    event_handler(SocketEvent::HandshakeIgnored { addr, reason: HandshakeIgnoredReason::AlreadyConnected });
+                }
+                return;
+            }
+
+            if let Some(request) = self.connection_requests.get_mut(&addr) {
+                // request is already pending or was rejected
+                request.last_handshake = time;
+
+                // keep telling rejected addresses in case the previous rejection was lost
+                if request.rejected {
+                    if let Err(err) = Self::send_rejection(&self.udp_socket, addr, request.rejection_reason.clone()) {
+                        event_handler(SocketEvent::Error(err));
+                    }
+                }
+
+                return;
+            }
+
+            if self.shutting_down {
+                if let Err(err) = Self::send_rejection(&self.udp_socket, addr, Some(SHUTDOWN_REJECTION_REASON.into())) {
+                    event_handler(SocketEvent::Error(err));
+                }
+
+                self.refused_connections += 1;
+                self.insert_connection_request(addr, ConnectionRequest {
+                    last_handshake: time,
+                    rejected: true,
+                    rejection_reason: Some(SHUTDOWN_REJECTION_REASON.into()),
+                    token: handshake.token,
+                    mtu: handshake.mtu,
+                });
+                return;
+            }
+
+            let mut accept_connection = false;
+            let mut config = None;
+            event_handler(SocketEvent::ConnectionRequest {
+                addr,
+                token: &handshake.token,
+                accept_connection: &mut accept_connection,
+                config: &mut config,
+            });
+
+            if accept_connection {
+                // unwrap is safe, connection doesn't exist
+                self.new_accepted_connection(time, addr, config, handshake.token, handshake.mtu).unwrap();
+            } else {
+                self.insert_connection_request(addr, ConnectionRequest {
+                    last_handshake: time,
+                    rejected: false,
+                    rejection_reason: None,
+                    token: handshake.token,
+                    mtu: handshake.mtu,
+                });
+            }
+
+            return;
+        }
+
+        if self.connections.get_connection(addr).is_none() {
+            // a peer that handed it's connection off to another socket announces the address we knew it by
+            let handoff = Packet::deserialize(bytes).and_then(|packet| packet.handoff());
+
+            // the key proves the sender was the peer of the connection, not just someone who knows it's address
+            let Some((previous_addr, _)) = handoff.filter(|&(previous_addr, key)| {
+                self.connections.get_connection(previous_addr).is_some_and(|connection| connection.handoff_key() == key) &&
+                self.connections.migrate_connection(previous_addr, addr).is_ok()
+            }) else {
+                // message is from an address without a connection
+                self.packets_from_unknown_peers += 1;
+                return;
+            };
+
+            event_handler(SocketEvent::ConnectionMigrated { addr, previous_addr });
+        }
+
+        // unwrap is safe, connection exists or was just migrated
+        let connection = self.connections.get_connection_mut(addr).unwrap();
+
+        connection.record_received(received.len());
+
+        // parse the packet
+        let Some(packet) = Packet::deserialize(bytes) else {
+            self.malformed_packets += 1;
+            event_handler(SocketEvent::Error(Error::MalformedPacket { addr }));
+            return;
+        };
+
+        // handle the packet with the connection
+        if let Err(()) = connection.receive(time, &self.config, packet) {
+            self.malformed_packets += 1;
+            event_handler(SocketEvent::Error(Error::MalformedPacket { addr }));
+        }
+    }
+
+    /// handles an error from receiving datagrams, returns if there could be more waiting
+    fn receive_error(err: std::io::Error, event_handler: &mut impl FnMut(SocketEvent)) -> bool {
+        match err.kind() {
+
+            // nothing in queue
+            ErrorKind::WouldBlock => false,
+
+            // errors to ignore
+            ErrorKind::ConnectionReset |
+            ErrorKind::ConnectionRefused |
+            ErrorKind::ConnectionAborted => true,
+
+            // unhandled
+            _ => {
+                event_handler(SocketEvent::Error(Error::IoError(err)));
+                false
+            },
+        }
+    }
+
+    /// creates a [SocketHandle] that can queue messages to be sent from other threads
+    pub fn handle(&self) -> SocketHandle {
+        SocketHandle {
+            queue: self.handle_queue.clone(),
+        }
+    }
+
+    /// the same as [update](Socket::update) but returns the events instead of passing them to a closure
+    ///
+    /// connection requests are left pending, see [accept_connection](Socket::accept_connection)
+    pub fn poll_events(&mut self, time: Duration) -> Vec<OwnedSocketEvent> {
+        let mut events = Vec::new();
+
+        self.update(time, |event| {
+            events.push(match event {
+                SocketEvent::Received { addr, sequence, tag, data } => OwnedSocketEvent::Received { addr, sequence, tag, data },
+                SocketEvent::ReceivedChunk { addr, sequence, offset, total_size, data } => OwnedSocketEvent::ReceivedChunk { addr, sequence, offset, total_size, data },
+                SocketEvent::NewConnection { addr, id, token } => OwnedSocketEvent::NewConnection { addr, id, token: token.into() },
+                SocketEvent::ConnectionRequest { addr, token, .. } => OwnedSocketEvent::ConnectionRequest { addr, token: token.into() },
+                SocketEvent::QualityChanged { addr, quality } => OwnedSocketEvent::QualityChanged { addr, quality },
+                SocketEvent::ConnectionStalled { addr } => OwnedSocketEvent::ConnectionStalled { addr },
+                SocketEvent::SendBufferFull { addr } => OwnedSocketEvent::SendBufferFull { addr },
+                SocketEvent::ConnectionRejected { addr, reason } => OwnedSocketEvent::ConnectionRejected { addr, reason: reason.map(Into::into) },
+                SocketEvent::ConnectionMigrated { addr, previous_addr } => OwnedSocketEvent::ConnectionMigrated { addr, previous_addr },
+                SocketEvent::MessageDropped { addr, id } => OwnedSocketEvent::MessageDropped { addr, id },
+                SocketEvent::HandshakeIgnored { addr, reason } => OwnedSocketEvent::HandshakeIgnored { addr, reason },
+                SocketEvent::ClosedConnection { addr, id } => OwnedSocketEvent::ClosedConnection { addr, id },
+                SocketEvent::Error(err) => OwnedSocketEvent::Error(err),
+            });
+        });
+
+        events
+    }
+
+    /// the same as [poll_events](Socket::poll_events) but returns at most `max_events` events,
+    /// the rest are kept and returned first by the next call to this method
+    ///
+    /// the socket is only updated once fewer than `max_events` events are held back,
+    /// so they can't pile up faster than they're handled. call this often enough that
+    /// connections are still updated regularly
+    pub fn poll_events_with_limit(&mut self, time: Duration, max_events: usize) -> Vec<OwnedSocketEvent> {
+        if self.deferred_events.len() < max_events {
+            let events = self.poll_events(time);
+            self.deferred_events.extend(events);
+        }
+
+        let count = max_events.min(self.deferred_events.len());
+        self.deferred_events.drain(..count).collect()
+    }
+
+    /// how many events are being held back by [poll_events_with_limit](Socket::poll_events_with_limit)
+    pub fn deferred_event_count(&self) -> usize {
+        self.deferred_events.len()
+    }
+
+    /// the same as [update](Socket::update) but keeps the events in the socket,
+    /// to be taken out afterwards with [drain_received](Socket::drain_received),
+    /// [drain_connection_changes](Socket::drain_connection_changes) and [drain_errors](Socket::drain_errors)
+    ///
+    /// events are kept until they are drained, so make sure to drain all of them after every update
+    ///
+    /// connection requests are left pending, see [accept_connection](Socket::accept_connection)
+    pub fn update_buffered(&mut self, time: Duration) {
+        let mut buffered = std::mem::take(&mut self.buffered_events);
+
+        self.update(time, |event| match event {
+            SocketEvent::Received { addr, sequence, tag, data } => buffered.received.push(Received { addr, sequence, tag, data }),
+            SocketEvent::ReceivedChunk { addr, sequence, offset, total_size, data } => buffered.received_chunks.push(ReceivedChunk { addr, sequence, offset, total_size, data }),
+            SocketEvent::NewConnection { addr, id, token } => buffered.connection_changes.push(ConnectionChange::NewConnection { addr, id, token: token.into() }),
+            SocketEvent::ConnectionRequest { addr, token, .. } => buffered.connection_changes.push(ConnectionChange::ConnectionRequest { addr, token: token.into() }),
+            SocketEvent::QualityChanged { addr, quality } => buffered.connection_changes.push(ConnectionChange::QualityChanged { addr, quality }),
+            SocketEvent::ConnectionStalled { addr } => buffered.connection_changes.push(ConnectionChange::ConnectionStalled { addr }),
+            SocketEvent::SendBufferFull { addr } => buffered.connection_changes.push(ConnectionChange::SendBufferFull { addr }),
+            SocketEvent::ConnectionRejected { addr, reason } => buffered.connection_changes.push(ConnectionChange::ConnectionRejected { addr, reason: reason.map(Into::into) }),
+            SocketEvent::ConnectionMigrated { addr, previous_addr } => buffered.connection_changes.push(ConnectionChange::ConnectionMigrated { addr, previous_addr }),
+            SocketEvent::MessageDropped { addr, id } => buffered.connection_changes.push(ConnectionChange::MessageDropped { addr, id }),
+            SocketEvent::HandshakeIgnored { addr, reason } => buffered.connection_changes.push(ConnectionChange::HandshakeIgnored { addr, reason }),
+            SocketEvent::ClosedConnection { addr, id } => buffered.connection_changes.push(ConnectionChange::ClosedConnection { addr, id }),
+            SocketEvent::Error(err) => buffered.errors.push(err),
+        });
+
+        // errors kept by `with_buffered_errors` whilst the buffered events were taken
+        buffered.errors.append(&mut self.buffered_events.errors);
+        self.buffered_events = buffered;
+    }
+
+    /// takes the messages received in [update_buffered](Socket::update_buffered), in the order they were received
+    pub fn drain_received(&mut self) -> impl Iterator<Item = Received> + '_ {
+        self.buffered_events.received.drain(..)
+    }
+
+    /// takes the chunks of streamed messages received in [update_buffered](Socket::update_buffered), in the order they were received
+    pub fn drain_received_chunks(&mut self) -> impl Iterator<Item = ReceivedChunk> + '_ {
+        self.buffered_events.received_chunks.drain(..)
+    }
+
+    /// takes the [ConnectionChange]s from [update_buffered](Socket::update_buffered), in the order they happened
+    pub fn drain_connection_changes(&mut self) -> impl Iterator<Item = ConnectionChange> + '_ {
+        self.buffered_events.connection_changes.drain(..)
+    }
+
+    /// takes the errors that occurred in [update_buffered](Socket::update_buffered),
+    /// or in any update if [set_buffer_errors](Socket::set_buffer_errors) is set, in the order they occurred
+    pub fn drain_errors(&mut self) -> impl Iterator<Item = Error> + '_ {
+        self.buffered_events.errors.drain(..)
+    }
+
+    /// sets if errors are kept for [drain_errors](Socket::drain_errors)
+    /// instead of being passed to the event handler as [SocketEvent::Error], defaults to `false`
+    ///
+    /// separates handling errors from processing received messages and connection changes,
+    /// such as logging them all after every update
+    pub fn set_buffer_errors(&mut self, buffer_errors: bool) {
+        self.buffer_errors = buffer_errors;
+    }
+
+    /// sets if handshakes that are ignored fire a [HandshakeIgnored](SocketEvent::HandshakeIgnored) event
+    /// saying why, defaults to `false`
+    ///
+    /// useful whilst working out why a peer can't connect, but peers resend handshakes until
+    /// they are connected so it can be noisy
+    pub fn set_report_ignored_handshakes(&mut self, report_ignored_handshakes: bool) {
+        self.report_ignored_handshakes = report_ignored_handshakes;
+    }
+
+    /// accepts a pending connection request from an address
+    ///
+    /// will cause a [NewConnection](SocketEvent::NewConnection) event to be fired in the next update
+    ///
+    /// returns the id of the new connection, fails if there is no pending request from that address
+    pub fn accept_connection(&mut self, time: Duration, addr: SocketAddr, config: Option<Config>) -> Result<ConnectionId, ()> {
+        match self.connection_requests.get(&addr) {
+            Some(request) if !request.rejected => (),
+            _ => return Err(()),
+        }
+
+        if self.connections.get_connection(addr).is_some() {
+            return Err(());
+        }
+
+        // unwrap is safe, checked above
+        let request = self.connection_requests.remove(&addr).unwrap();
+        self.new_accepted_connection(time, addr, config, request.token, request.mtu)
+    }
+
+    /// creates the connection for an accepted connection request, carrying on the session of a closed
+    /// connection with the same token if one is remembered
+    ///
+    /// fails if there is already a connection with the address
+    fn new_accepted_connection(&mut self, time: Duration, addr: SocketAddr, config: Option<Config>, token: Box<[u8]>, mtu: u16) -> Result<ConnectionId, ()> {
+        let id = self.connections.next_id();
+        let connection = self.connections.new_connection(
+            Connection::new(time, addr, id, false, config.as_ref().unwrap_or(&self.config))
+        )?;
+
+        connection.set_config(config);
+        connection.set_token(token);
+        connection.set_peer_mtu(mtu, &self.config);
+
+        resume_session(&mut self.closed_sessions, connection);
+
+        Ok(id)
+    }
+
+    /// keeps a new connection request, forgetting the one that last sent a handshake the longest ago
+    /// if there are already [max_connection_requests](Config::max_connection_requests)
+    fn insert_connection_request(&mut self, addr: SocketAddr, request: ConnectionRequest) {
+        while self.connection_requests.len() >= self.config.max_connection_requests {
+            let Some(oldest) = self.connection_requests.iter()
+            .min_by_key(|(_, request)| request.last_handshake)
+            .map(|(&addr, _)| addr) else {
+                // no space for any requests
+                self.dropped_connection_requests += 1;
+                return;
+            };
+
+            self.connection_requests.remove(&oldest);
+            self.dropped_connection_requests += 1;
+        }
+
+        self.connection_requests.insert(addr, request);
+    }
+
+    /// returns the addresses of pending connection requests that haven't been rejected
+    pub fn connection_requests(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.connection_requests.iter()
+        .filter(|(_, request)| !request.rejected)
+        .map(|(&addr, _)| addr)
+    }
+
+    /// rejects a pending connection request from an address
+    ///
+    /// the address is told so that it gives up straight away with a
+    /// [ConnectionRejected](SocketEvent::ConnectionRejected) event,
+    /// and is told again for any further handshakes until it stops sending them
+    ///
+    /// fails if there is no pending request from that address
+    pub fn reject_connection(&mut self, addr: SocketAddr) -> Result<(), ()> {
+        self.reject_connection_inner(addr, None)
+    }
+
+    /// the same as [reject_connection](Socket::reject_connection) but tells the address why,
+    /// such as `"server full"`, which it gets in the [ConnectionRejected](SocketEvent::ConnectionRejected) event
+    ///
+    /// the reason is cut short to at most [MAX_REJECTION_REASON_SIZE] bytes
+    pub fn reject_connection_with_reason(&mut self, addr: SocketAddr, reason: &str) -> Result<(), ()> {
+        let mut len = reason.len().min(MAX_REJECTION_REASON_SIZE);
+        while !reason.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        self.reject_connection_inner(addr, Some(reason[..len].into()))
+    }
+
+    fn reject_connection_inner(&mut self, addr: SocketAddr, reason: Option<Box<str>>) -> Result<(), ()> {
+        let Some(request) = self.connection_requests.get_mut(&addr) else {
+            return Err(());
+        };
+
+        if !request.rejected {
+            request.rejected = true;
+            self.refused_connections += 1;
+        }
+
+        request.rejection_reason = reason;
+
+        // if this fails it is sent again in response to the next handshake
+        let _ = Self::send_rejection(&self.udp_socket, addr, request.rejection_reason.clone());
+
+        Ok(())
+    }
+
+    /// tells an address that it's connection request was rejected
+    fn send_rejection(udp_socket: &FilteredSocket, addr: SocketAddr, reason: Option<Box<str>>) -> Result<(), Error> {
+        let mut packet = Packet::new();
+        packet.push(Blob::HandshakeRejected(reason));
+
+        let mut bytes = Vec::new();
+        packet.serialize_into(&mut bytes);
+
+        send_datagram(&bytes, addr, udp_socket).map_err(Error::IoError)?;
+
+        Ok(())
+    }
+
+    /// opens a new connection with an address
+    ///
+    /// returns the id of the new connection, fails if there is already a connection to that address,
+    /// distinguishing one that's still connecting from an established one,
+    /// or the address is a different family to the one the socket is bound to, see [ConnectError]
+    ///
+    /// will cause a [NewConnection](SocketEvent::NewConnection) event to be fired once
+    /// a response is heard, or a [ClosedConnection](SocketEvent::ClosedConnection)
+    /// event if the timeout is reached first
+    pub fn open_connection(&mut self, time: Duration, addr: SocketAddr) -> Result<ConnectionId, ConnectError> {
+        self.check_address_family(addr)?;
+        self.check_existing_connection(addr)?;
+
+        let id = self.connections.next_id();
+        let Ok(_) = self.connections.new_connection(Connection::new(time, addr, id, true, &self.config)) else {
+            return Err(ConnectError::AlreadyConnected);
+        };
+
+        Ok(id)
+    }
+
+    /// the same as [open_connection](Socket::open_connection)
+    /// but the connection uses a different [Config] than the socket's
+    ///
+    /// the protocol id of the given config is the one sent in handshakes
+    pub fn open_connection_with_config(&mut self, time: Duration, addr: SocketAddr, config: Config) -> Result<ConnectionId, ConnectError> {
+        self.check_address_family(addr)?;
+        self.check_existing_connection(addr)?;
+
+        let id = self.connections.next_id();
+        let Ok(connection) = self.connections.new_connection(Connection::new(time, addr, id, true, &config)) else {
+            return Err(ConnectError::AlreadyConnected);
+        };
+
+        connection.set_config(Some(config));
+
+        Ok(id)
+    }
+
+    /// errors if an address can't be sent to from the family of address the socket is bound to
+    fn check_address_family(&self, addr: SocketAddr) -> Result<(), ConnectError> {
+        let Ok(local_addr) = self.udp_socket.local_addr() else {
+            // let sending report the problem
+            return Ok(());
+        };
+
+        if local_addr.is_ipv4() != addr.is_ipv4() {
+            return Err(ConnectError::AddressFamilyMismatch { local_addr, addr });
+        }
+
+        Ok(())
+    }
+
+    /// errors if there is already a connection with an address, saying whether it's still connecting
+    fn check_existing_connection(&self, addr: SocketAddr) -> Result<(), ConnectError> {
+        match self.connections.get_connection(addr) {
+            Some(connection) if connection.connecting() => Err(ConnectError::AlreadyConnecting),
+            Some(_) => Err(ConnectError::AlreadyConnected),
+            None => Ok(()),
+        }
+    }
+
+    /// the same as [open_connection](Socket::open_connection) but sends a token with the handshakes,
+    /// such as a session id, which the other party gets in the
+    /// [ConnectionRequest](SocketEvent::ConnectionRequest) to decide whether to accept it
+    ///
+    /// both parties can get the token later with [connection_token](Socket::connection_token).
+    /// with [session_resume_delay](Config::session_resume_delay) set the token also identifies the session,
+    /// so it must be unique to it
+    pub fn open_connection_with_token(&mut self, time: Duration, addr: SocketAddr, token: Box<[u8]>) -> Result<ConnectionId, ConnectError> {
+        if token.len() > MAX_TOKEN_SIZE {
+            return Err(ConnectError::TokenTooLarge { size: token.len() });
+        }
+
+        let id = self.open_connection(time, addr)?;
+
+        // unwrap is safe, the connection was just opened
+        let connection = self.connections.get_connection_mut(addr).unwrap();
+        connection.set_token(token);
+
+        resume_session(&mut self.closed_sessions, connection);
+
+        Ok(id)
+    }
+
+    /// gets the token sent in the handshake of a connection if it exists,
+    /// empty if no token was sent
+    pub fn connection_token(&self, addr: SocketAddr) -> Option<&[u8]> {
+        self.connections.get_connection(addr).map(Connection::token)
+    }
+
+    /// gets the token the peer sent in the handshake of a connection it opened,
+    /// such as a session id to map the connection to a logged in account for it's whole life
+    ///
+    /// `None` if there is no connection or this socket opened it, see [is_opening_party](Socket::is_opening_party).
+    /// empty if the peer didn't send a token. tokens are at most [MAX_TOKEN_SIZE] bytes
+    pub fn peer_token(&self, addr: SocketAddr) -> Option<&[u8]> {
+        self.connections.get_connection(addr)
+        .filter(|connection| !connection.opening_party())
+        .map(Connection::token)
+    }
+
+    /// if this socket opened the connection with an address with [open_connection](Socket::open_connection),
+    /// rather than accepting it, `None` if the connection doesn't exist
+    pub fn is_opening_party(&self, addr: SocketAddr) -> Option<bool> {
+        self.connections.get_connection(addr).map(Connection::opening_party)
+    }
+
+    /// stores an application defined value with a connection, replacing any previous one
+    ///
+    /// the value is dropped along with the connection
+    ///
+    /// fails if there is no connection with that address
+    pub fn set_connection_user_data(&mut self, addr: SocketAddr, data: impl Any + Send + Sync) -> Result<(), ()> {
+        self.connections.get_connection_mut(addr).ok_or(())?.set_user_data(data);
+        Ok(())
+    }
+
+    /// gets the value stored with a connection by [set_connection_user_data](Socket::set_connection_user_data),
+    /// `None` if there is no connection, no value or it isn't a `T`
+    pub fn connection_user_data<T: Any>(&self, addr: SocketAddr) -> Option<&T> {
+        self.connections.get_connection(addr)?.user_data()
+    }
+
+    /// mutably gets the value stored with a connection by [set_connection_user_data](Socket::set_connection_user_data),
+    /// `None` if there is no connection, no value or it isn't a `T`
+    pub fn connection_user_data_mut<T: Any>(&mut self, addr: SocketAddr) -> Option<&mut T> {
+        self.connections.get_connection_mut(addr)?.user_data_mut()
+    }
+
+    /// if there is a connection with an address that has finished handshaking
+    pub fn is_connected(&self, addr: SocketAddr) -> bool {
+        self.connections.get_connection(addr).is_some_and(|connection| !connection.connecting())
+    }
+
+    /// if there is a connection opened with [open_connection](Socket::open_connection)
+    /// to an address that is still waiting for the handshake to be answered
+    pub fn is_connecting(&self, addr: SocketAddr) -> bool {
+        self.connections.get_connection(addr).is_some_and(|connection| connection.connecting())
+    }
+
+    /// the [ReliableOrdered](Reliability::ReliableOrdered) sequence numbers of a connection,
+    /// which can be stored to carry on from with [resume_ordered_sequences](Socket::resume_ordered_sequences)
+    ///
+    /// ordered messages the peer hasn't acknowledged aren't carried on by these, unlike with
+    /// [session_resume_delay](Config::session_resume_delay), so wait for them to be delivered before closing
+    pub fn ordered_sequences(&self, addr: SocketAddr) -> Option<OrderedSequences> {
+        let (send, receive) = self.connections.get_connection(addr)?.ordered_sequences();
+        Some(OrderedSequences { send, receive })
+    }
+
+    /// carries on the [ReliableOrdered](Reliability::ReliableOrdered) sequence numbers of a previous connection,
+    /// for when sessions are resumed some other way than [session_resume_delay](Config::session_resume_delay)
+    ///
+    /// both parties have to resume before any ordered messages are sent, using the sequences from the same session
     ///
     /// returns `Err` if the connection didn't exist
-    pub fn close_connection(&mut self, addr: SocketAddr) -> Result<(), ()> {
-        if let Some(connection) = self.connections.get_connection_mut(addr) {
-            connection.drop();
-            Ok(())
-        } else {
-            Err(())
+    pub fn resume_ordered_sequences(&mut self, addr: SocketAddr, sequences: OrderedSequences) -> Result<(), ()> {
+        let Some(connection) = self.connections.get_connection_mut(addr) else {
+            return Err(());
+        };
+
+        connection.resume_ordered_sequences(sequences.send, sequences.receive);
+        Ok(())
+    }
+
+    /// gets the id of the connection with an address if it exists
+    pub fn connection_id(&self, addr: SocketAddr) -> Option<ConnectionId> {
+        self.connections.get_connection(addr).map(|connection| connection.id())
+    }
+
+    /// gets the address of the connection with an id if it exists
+    pub fn connection_addr(&self, id: ConnectionId) -> Option<SocketAddr> {
+        self.connections.get_addr(id)
+    }
+
+    /// iterates over every connection, for bulk operations such as broadcasting a message
+    /// or closing every connection without knowing each address
+    ///
+    /// includes connections still waiting for their handshake to be answered, see [ConnectionMut::is_connecting]
+    pub fn connections_mut(&mut self) -> impl Iterator<Item = ConnectionMut<'_>> + '_ {
+        let config = &self.config;
+        self.connections.iter_mut().map(move |connection| ConnectionMut { connection, config })
+    }
+
+    /// sends a message to an address
+    ///
+    /// fails if there is no connection with that address, see [open_connection](Socket::open_connection),
+    /// or if the message is too large, see [max_message_fragments](Config::max_message_fragments)
+    pub fn send(&mut self, addr: SocketAddr, reliable: bool, data: Box<[u8]>) -> Result<(), SendError> {
+        self.send_with_priority(addr, reliable, 0, data)
+    }
+
+    /// the same as [send](Socket::send) but the message is made of several parts joined together,
+    /// such as a header and a body, copying each part once instead of having to join them first
+    pub fn send_vectored(&mut self, addr: SocketAddr, reliable: bool, parts: &[&[u8]]) -> Result<(), SendError> {
+        if self.connections.get_connection(addr).is_none() {
+            return Err(SendError::NoConnection);
         }
+
+        self.send(addr, reliable, parts.concat().into())
     }
 
-    /// gets the [ConnectionMetrics] for a connection if it exists
-    pub fn connection_metrics(&self, addr: SocketAddr) -> Option<ConnectionMetrics> {
-        self.connections.get_connection(addr).map(|connection| connection.metrics())
+    /// the same as [send](Socket::send) but with a priority
+    ///
+    /// messages with a higher priority are sent before messages with a lower one,
+    /// [send](Socket::send) uses a priority of `0`
+    pub fn send_with_priority(&mut self, addr: SocketAddr, reliable: bool, priority: u8, data: Box<[u8]>) -> Result<(), SendError> {
+        self.send_with_reliability(addr, reliable.into(), priority, data)
+    }
+
+    /// the same as [send_with_priority](Socket::send_with_priority) but with any [Reliability],
+    /// such as sequenced or ordered messages
+    ///
+    /// ordered messages are only held back by other ordered messages, not by messages sent with other reliabilities
+    pub fn send_with_reliability(&mut self, addr: SocketAddr, reliability: Reliability, priority: u8, data: Box<[u8]>) -> Result<(), SendError> {
+        let Some(connection) = self.connections.get_connection_mut(addr) else {
+            return Err(SendError::NoConnection);
+        };
+
+        send_checked(connection, &self.config, reliability, priority, None, false, data)
+    }
+
+    /// the same as [send_with_reliability](Socket::send_with_reliability) but with a small tag,
+    /// received alongside the message in [Received](SocketEvent::Received)
+    ///
+    /// a lightweight way to route messages, such as by their type, without a serialization layer.
+    /// the tag takes up one byte of the message's [max size](SendError::MessageTooLarge)
+    pub fn send_with_tag(&mut self, addr: SocketAddr, reliability: Reliability, priority: u8, tag: u8, data: Box<[u8]>) -> Result<(), SendError> {
+        let Some(connection) = self.connections.get_connection_mut(addr) else {
+            return Err(SendError::NoConnection);
+        };
+
+        send_checked(connection, &self.config, reliability, priority, Some(tag), false, data)
+    }
+
+    /// sends a reliable message with it's first transmission sent twice, for latency critical messages
+    /// such as a hit confirmation, where waiting for a resend if the first packet is lost would be too slow
+    ///
+    /// the copies share a fragmentation id so the peer receives the message exactly once, from whichever copy arrives first.
+    /// the second copy is sent in a datagram of it's own after everything else in the update, so it's less likely to be lost with the first
+    ///
+    /// fails for the same reasons as [send](Socket::send)
+    pub fn send_redundant(&mut self, addr: SocketAddr, priority: u8, data: Box<[u8]>) -> Result<(), SendError> {
+        let Some(connection) = self.connections.get_connection_mut(addr) else {
+            return Err(SendError::NoConnection);
+        };
+
+        send_checked(connection, &self.config, Reliability::Reliable, priority, None, true, data)
+    }
+
+    /// sets a small unreliable message to send to an address with the connection's next heartbeat,
+    /// replacing one that hasn't been sent yet
+    ///
+    /// on a mostly idle connection this saves sending a packet just for the message,
+    /// such as for presence or other low rate updates. it is received like any other message
+    ///
+    /// fails if there is no connection with that address, or the message is larger than [MAX_HEARTBEAT_PAYLOAD_SIZE]
+    pub fn set_heartbeat_payload(&mut self, addr: SocketAddr, data: Box<[u8]>) -> Result<(), SendError> {
+        let Some(connection) = self.connections.get_connection_mut(addr) else {
+            return Err(SendError::NoConnection);
+        };
+
+        if data.len() > MAX_HEARTBEAT_PAYLOAD_SIZE {
+            return Err(SendError::MessageTooLarge {
+                size: data.len(),
+                max_size: MAX_HEARTBEAT_PAYLOAD_SIZE,
+            });
+        }
+
+        connection.set_heartbeat_payload(data);
+
+        Ok(())
+    }
+
+    /// sends a reliable message to an address, unless the connection has more than
+    /// [max_pending_reliable_bytes](Config::max_pending_reliable_bytes) waiting to be acknowledged
+    ///
+    /// use this instead of [send](Socket::send) to stop queued messages growing without bound
+    /// when sending faster than the connection can deliver, slowing down or shedding load when it fails
+    ///
+    /// returns the fragmentation id of the message
+    pub fn try_send_reliable(&mut self, addr: SocketAddr, data: Box<[u8]>) -> Result<u16, SendError> {
+        let Some(connection) = self.connections.get_connection_mut(addr) else {
+            return Err(SendError::NoConnection);
+        };
+
+        let max_size = connection.max_message_size(&self.config);
+        if data.len() > max_size {
+            return Err(SendError::MessageTooLarge {
+                size: data.len(),
+                max_size,
+            });
+        }
+
+        let pending_bytes = connection.pending_reliable_bytes();
+        let max_pending_bytes = connection.max_pending_reliable_bytes(&self.config);
+        if pending_bytes > max_pending_bytes {
+            return Err(SendError::TooMuchPending {
+                pending_bytes,
+                max_pending_bytes,
+            });
+        }
+
+        Ok(connection.send(true, 0, data))
+    }
+
+    /// sends a reliable message to an address that is given up on if it has to be resent more than `max_resends` times,
+    /// firing a [MessageDropped](SocketEvent::MessageDropped) event when it is
+    ///
+    /// useful for messages worth retrying but not worth retrying forever,
+    /// with `None` this is the same as a reliable [send_with_priority](Socket::send_with_priority)
+    ///
+    /// returns the fragmentation id of the message
+    pub fn send_with_max_resends(&mut self, addr: SocketAddr, priority: u8, max_resends: Option<u32>, data: Box<[u8]>) -> Result<u16, SendError> {
+        let Some(connection) = self.connections.get_connection_mut(addr) else {
+            return Err(SendError::NoConnection);
+        };
+
+        let max_size = connection.max_message_size(&self.config);
+        if data.len() > max_size {
+            return Err(SendError::MessageTooLarge {
+                size: data.len(),
+                max_size,
+            });
+        }
+
+        Ok(connection.send_with_max_resends(priority, max_resends, data))
+    }
+
+    /// drops the connection with an address
+    ///
+    /// messages already queued are sent once more in the next update, ahead of the disconnect
+    /// and in the same datagram when they fit, so a final unreliable message is received before the connection closes.
+    /// if the send buffer is full these packets are kept and retried for up to the
+    /// [timeout_delay](Config::timeout_delay) after the connection is removed
+    ///
+    /// returns `Err` if the connection didn't exist
+    pub fn close_connection(&mut self, addr: SocketAddr) -> Result<(), ()> {
+        if let Some(connection) = self.connections.get_connection_mut(addr) {
+            connection.drop();
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    /// shuts the socket down without dropping reliable messages that are still in transit,
+    /// blocking until they have all been acknowledged or the `timeout` passes,
+    /// then closes every connection
+    ///
+    /// connection requests, pending or new, are rejected with [SHUTDOWN_REJECTION_REASON] from now on.
+    /// whilst waiting the socket keeps being updated, starting at `time`, so events are still passed to the event handler.
+    /// the disconnects aren't acknowledged, like [close_connection](Socket::close_connection) they're sent once
+    /// and retried whilst the send buffer is full
+    ///
+    /// returns `true` if every connection finished sending before the timeout
+    pub fn shutdown_graceful(&mut self, time: Duration, timeout: Duration, mut event_handler: impl FnMut(SocketEvent)) -> bool {
+        self.shutting_down = true;
+
+        let pending_requests = self.connection_requests.keys().copied().collect::<Vec<_>>();
+        for addr in pending_requests {
+            let _ = self.reject_connection_with_reason(addr, SHUTDOWN_REJECTION_REASON);
+        }
+
+        let start = std::time::Instant::now();
+        let drained = loop {
+            self.update(time + start.elapsed(), &mut event_handler);
+
+            if self.connections.iter().all(|connection| connection.pending_reliable_bytes() == 0) {
+                break true;
+            }
+
+            if start.elapsed() >= timeout {
+                break false;
+            }
+
+            std::thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        };
+
+        for connection in self.connections.iter_mut() {
+            connection.drop();
+        }
+
+        // sends the disconnects and removes the connections, which can take a few updates
+        // if only some connections are updated at a time
+        while self.connections.iter().next().is_some() {
+            self.update(time + start.elapsed(), &mut event_handler);
+        }
+
+        drained
+    }
+
+    /// removes a connection without closing it, so that it can be handed off to another socket
+    /// with [insert_connection](Socket::insert_connection)
+    ///
+    /// no [ClosedConnection](SocketEvent::ClosedConnection) event is fired and the peer isn't told until
+    /// the other socket sends to it, see [DetachedConnection::set_handoff_from]
+    ///
+    /// returns `None` if the connection didn't exist
+    pub fn take_connection(&mut self, addr: SocketAddr) -> Option<DetachedConnection> {
+        let mut connection = self.connections.take_connection(addr)?;
+
+        if let Ok(local_addr) = self.local_addr() {
+            connection.set_handoff_from(local_addr);
+        }
+
+        Some(DetachedConnection { connection })
+    }
+
+    /// inserts a connection taken from another socket with [take_connection](Socket::take_connection)
+    ///
+    /// the connection is given a new [ConnectionId] from this socket and no [NewConnection](SocketEvent::NewConnection)
+    /// event is fired. the peer is told about the new address with every update until it replies,
+    /// after which it fires a [ConnectionMigrated](SocketEvent::ConnectionMigrated) event.
+    /// both sockets must be updated with times from the same clock
+    ///
+    /// returns the connection back if there is already a connection with it's address
+    pub fn insert_connection(&mut self, connection: DetachedConnection) -> Result<ConnectionId, DetachedConnection> {
+        match self.connections.insert_connection(connection.connection) {
+            Ok(connection) => Ok(connection.id()),
+            Err(connection) => Err(DetachedConnection { connection }),
+        }
+    }
+
+    /// pauses a connection, see [resume_connection](Socket::resume_connection)
+    ///
+    /// whilst paused nothing is sent to the connection and it can't time out,
+    /// the peer is told so that it doesn't time out either.
+    /// useful when the app is about to be suspended
+    ///
+    /// returns `Err` if the connection didn't exist
+    pub fn pause_connection(&mut self, time: Duration, addr: SocketAddr) -> Result<(), ()> {
+        let Some(connection) = self.connections.get_connection_mut(addr) else {
+            return Err(());
+        };
+
+        connection.pause(time);
+
+        Ok(())
+    }
+
+    /// resumes a paused connection, it's timers continue from where they were when it was paused
+    ///
+    /// returns `Err` if the connection didn't exist
+    pub fn resume_connection(&mut self, time: Duration, addr: SocketAddr) -> Result<(), ()> {
+        let Some(connection) = self.connections.get_connection_mut(addr) else {
+            return Err(());
+        };
+
+        connection.resume(time);
+
+        Ok(())
+    }
+
+    /// gets if a connection is paused, `None` if it doesn't exist
+    pub fn connection_paused(&self, addr: SocketAddr) -> Option<bool> {
+        self.connections.get_connection(addr).map(Connection::paused)
+    }
+
+    /// sends a heartbeat to a connection in the next update instead of waiting for the heartbeat interval,
+    /// getting a fresh round trip time sample once it's response is received
+    ///
+    /// returns `Err` if the connection didn't exist
+    pub fn ping(&mut self, addr: SocketAddr) -> Result<(), ()> {
+        let Some(connection) = self.connections.get_connection_mut(addr) else {
+            return Err(());
+        };
+
+        connection.ping();
+
+        Ok(())
+    }
+
+    /// gets the socket's [Config]
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// replaces the socket's [Config], taking effect from the next update
+    ///
+    /// connections with their own config keep using it, see [set_connection_config](Socket::set_connection_config)
+    ///
+    /// fails without changing anything if a message queued to a connection
+    /// would no longer fit with the new mtu
+    pub fn set_config(&mut self, config: Config) -> Result<(), ConfigError> {
+        for connection in self.connections.iter() {
+            let message_size = connection.largest_queued_message();
+            let max_message_size = connection.max_message_size(&config);
+
+            if message_size > max_message_size {
+                return Err(ConfigError::MtuTooSmall {
+                    addr: connection.address(),
+                    message_size,
+                    max_message_size,
+                });
+            }
+        }
+
+        self.config = config;
+
+        Ok(())
+    }
+
+    /// overrides the socket's [Config] for a connection, or removes the override if `None`
+    ///
+    /// returns `Err` if the connection didn't exist
+    pub fn set_connection_config(&mut self, addr: SocketAddr, config: Option<Config>) -> Result<(), ()> {
+        let Some(connection) = self.connections.get_connection_mut(addr) else {
+            return Err(());
+        };
+
+        connection.set_config(config);
+
+        Ok(())
+    }
+
+    /// gets the [Config] used by a connection if it exists
+    ///
+    /// this is the socket's config unless it has been overridden for that connection
+    pub fn connection_config(&self, addr: SocketAddr) -> Option<&Config> {
+        let connection = self.connections.get_connection(addr)?;
+        Some(connection.config().unwrap_or(&self.config))
+    }
+
+    /// overrides the mtu for a connection, or removes the override if `None`
+    ///
+    /// useful when different connections go over links with different mtus
+    ///
+    /// returns `Err` if the connection didn't exist
+    pub fn set_connection_mtu(&mut self, addr: SocketAddr, mtu: Option<u16>) -> Result<(), ()> {
+        let Some(connection) = self.connections.get_connection_mut(addr) else {
+            return Err(());
+        };
+
+        connection.set_mtu(mtu);
+
+        Ok(())
+    }
+
+    /// gets the mtu currently used by a connection if it exists
+    pub fn connection_mtu(&self, addr: SocketAddr) -> Option<u16> {
+        Some(self.connections.get_connection(addr)?.mtu(&self.config))
+    }
+
+    /// gets the [ConnectionMetrics] for a connection if it exists
+    pub fn connection_metrics(&self, addr: SocketAddr) -> Option<ConnectionMetrics> {
+        self.connections.get_connection(addr).map(|connection| connection.metrics(&self.config))
+    }
+
+    /// gets [SocketStats] totalling the metrics of all connections
+    pub fn stats(&self) -> SocketStats {
+        let mut stats = SocketStats {
+            wrong_protocol_handshakes: self.wrong_protocol_handshakes,
+            malformed_packets: self.malformed_packets,
+            packets_from_unknown_peers: self.packets_from_unknown_peers,
+            refused_connections: self.refused_connections,
+            dropped_connection_requests: self.dropped_connection_requests,
+            ..Default::default()
+        };
+
+        let mut rtt_total = Duration::ZERO;
+        let mut rtt_count = 0;
+
+        for connection in self.connections.iter() {
+            let metrics = connection.metrics(&self.config);
+
+            stats.connections += 1;
+            stats.sent_packets += metrics.sent_packets;
+            stats.sent_bytes += metrics.sent_bytes;
+            stats.received_packets += metrics.received_packets;
+            stats.received_bytes += metrics.received_bytes;
+
+            if let Some(rtt) = metrics.rtt {
+                rtt_total += rtt;
+                rtt_count += 1;
+            }
+        }
+
+        if rtt_count > 0 {
+            stats.average_rtt = Some(rtt_total / rtt_count);
+        }
+
+        stats
+    }
+
+    /// gets the [MetricsHistory] for a connection if it exists
+    ///
+    /// empty unless [metrics_history_length](Config::metrics_history_length) is set
+    pub fn connection_metrics_history(&self, addr: SocketAddr) -> Option<&MetricsHistory> {
+        self.connections.get_connection(addr).map(Connection::metrics_history)
+    }
+
+    /// lists the messages partially received from a connection, with their fragmentation ids
+    /// and the fraction of each that has been received between `0` and `1`, sorted by fragmentation id
+    ///
+    /// meant for debugging, such as seeing what a stalled transfer is waiting on
+    ///
+    /// returns `None` if the connection doesn't exist
+    pub fn incoming_progress(&self, addr: SocketAddr) -> Option<Vec<(u16, f32)>> {
+        let mut progress: Vec<_> = self.connections.get_connection(addr)?.incoming_progress().collect();
+        progress.sort_by_key(|&(fragmentation_id, _)| fragmentation_id);
+        Some(progress)
+    }
+
+    /// gets how long a connection has been established for
+    ///
+    /// returns `None` if the connection doesn't exist or hasn't been established yet
+    pub fn connection_uptime(&self, time: Duration, addr: SocketAddr) -> Option<Duration> {
+        self.connections.get_connection(addr)?.uptime(time)
+    }
+}
+
+/// gives access to the underlying `UdpSocket`'s file descriptor,
+/// for registering it with an external reactor or setting socket options
+#[cfg(unix)]
+impl std::os::fd::AsRawFd for Socket {
+    fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        self.udp_socket.as_raw_fd()
+    }
+}
+
+/// gives access to the underlying `UdpSocket`'s raw socket,
+/// for registering it with an external reactor or setting socket options
+#[cfg(windows)]
+impl std::os::windows::io::AsRawSocket for Socket {
+    fn as_raw_socket(&self) -> std::os::windows::io::RawSocket {
+        self.udp_socket.as_raw_socket()
+    }
+}
+
+impl DetachedConnection {
+    /// the address of the peer
+    pub fn address(&self) -> SocketAddr {
+        self.connection.address()
+    }
+
+    /// sets the address the peer knows the previous socket by, announced by the socket the connection is inserted into
+    ///
+    /// defaults to the local address of the previous socket,
+    /// which needs replacing if it was bound to an unspecified address such as `0.0.0.0` or is behind a NAT
+    pub fn set_handoff_from(&mut self, previous_addr: SocketAddr) {
+        self.connection.set_handoff_from(previous_addr);
+    }
+}
+
+impl ConnectionMut<'_> {
+    /// the address of the peer
+    pub fn address(&self) -> SocketAddr {
+        self.connection.address()
+    }
+
+    /// the id of the connection
+    pub fn id(&self) -> ConnectionId {
+        self.connection.id()
+    }
+
+    /// see [Socket::is_connecting]
+    pub fn is_connecting(&self) -> bool {
+        self.connection.connecting()
+    }
+
+    /// see [Socket::send]
+    pub fn send(&mut self, reliable: bool, data: Box<[u8]>) -> Result<(), SendError> {
+        self.send_with_priority(reliable, 0, data)
+    }
+
+    /// see [Socket::send_with_priority]
+    pub fn send_with_priority(&mut self, reliable: bool, priority: u8, data: Box<[u8]>) -> Result<(), SendError> {
+        self.send_with_reliability(reliable.into(), priority, data)
+    }
+
+    /// see [Socket::send_with_reliability]
+    pub fn send_with_reliability(&mut self, reliability: Reliability, priority: u8, data: Box<[u8]>) -> Result<(), SendError> {
+        send_checked(self.connection, self.config, reliability, priority, None, false, data)
+    }
+
+    /// see [Socket::send_with_tag]
+    pub fn send_with_tag(&mut self, reliability: Reliability, priority: u8, tag: u8, data: Box<[u8]>) -> Result<(), SendError> {
+        send_checked(self.connection, self.config, reliability, priority, Some(tag), false, data)
+    }
+
+    /// see [Socket::send_redundant]
+    pub fn send_redundant(&mut self, priority: u8, data: Box<[u8]>) -> Result<(), SendError> {
+        send_checked(self.connection, self.config, Reliability::Reliable, priority, None, true, data)
+    }
+
+    /// drops the connection, see [Socket::close_connection]
+    pub fn close(&mut self) {
+        self.connection.drop();
+    }
+
+    /// see [Socket::set_connection_user_data]
+    pub fn set_user_data(&mut self, data: impl Any + Send + Sync) {
+        self.connection.set_user_data(data);
+    }
+
+    /// see [Socket::connection_user_data]
+    pub fn user_data<T: Any>(&self) -> Option<&T> {
+        self.connection.user_data()
+    }
+
+    /// see [Socket::connection_user_data_mut]
+    pub fn user_data_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.connection.user_data_mut()
+    }
+}
+
+/// carries on from the sequence numbers of a closed connection with the same token if one is remembered,
+/// sending again the ordered messages it's peer hadn't acknowledged
+fn resume_session(closed_sessions: &mut Vec<ClosedSession>, connection: &mut Connection) {
+    if connection.token().is_empty() {
+        return;
+    }
+
+    if let Some(index) = closed_sessions.iter().position(|session| *session.token == *connection.token()) {
+        let session = closed_sessions.swap_remove(index);
+        connection.resume_ordered_sequences(session.sequences.send, session.sequences.receive);
+        connection.requeue_ordered(session.messages);
+    }
+}
+
+/// queues a message on a connection, checking it isn't too large
+///
+/// `redundant` messages are always [Reliable](Reliability::Reliable) and untagged, see [send_redundant](Socket::send_redundant)
+fn send_checked(connection: &mut Connection, config: &Config, reliability: Reliability, priority: u8, tag: Option<u8>, redundant: bool, data: Box<[u8]>) -> Result<(), SendError> {
+    // sequenced and ordered messages carry a sequence number
+    let mut max_size = if reliability.is_ordered() {
+        connection.max_message_size(config).saturating_sub(ORDERED_SEQUENCE_SIZE)
+    } else {
+        connection.max_message_size(config)
+    };
+
+    if tag.is_some() {
+        max_size = max_size.saturating_sub(TAG_SIZE);
+    }
+
+    if data.len() > max_size {
+        return Err(SendError::MessageTooLarge {
+            size: data.len(),
+            max_size,
+        });
+    }
+
+    if redundant {
+        connection.send_redundant(priority, data);
+    } else {
+        connection.send_with_tag(reliability, priority, tag, data);
+    }
+
+    Ok(())
+}
+
+impl SocketHandle {
+    /// queues a message to be sent to an address in the next update of the socket
+    ///
+    /// see [Socket::send]
+    pub fn send(&self, addr: SocketAddr, reliable: bool, data: Box<[u8]>) {
+        self.send_with_priority(addr, reliable, 0, data);
+    }
+
+    /// the same as [send](SocketHandle::send) but with a priority
+    ///
+    /// see [Socket::send_with_priority]
+    pub fn send_with_priority(&self, addr: SocketAddr, reliable: bool, priority: u8, data: Box<[u8]>) {
+        self.send_with_reliability(addr, reliable.into(), priority, data);
+    }
+
+    /// the same as [send_with_priority](SocketHandle::send_with_priority) but with any [Reliability]
+    ///
+    /// see [Socket::send_with_reliability]
+    pub fn send_with_reliability(&self, addr: SocketAddr, reliability: Reliability, priority: u8, data: Box<[u8]>) {
+        self.queue.lock().unwrap_or_else(|err| err.into_inner()).push(QueuedMessage {
+            addr,
+            reliability,
+            priority,
+            data,
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{CaptureRecord, Direction};
+    use std::num::NonZeroUsize;
+
+    fn bind() -> (Socket, SocketAddr) {
+        let socket = Socket::bind("127.0.0.1:0".parse().unwrap(), Config::default()).unwrap();
+        let addr = socket.local_addr().unwrap();
+        (socket, addr)
+    }
+
+    fn new_connections(events: &[OwnedSocketEvent]) -> Vec<SocketAddr> {
+        events.iter().filter_map(|event| match event {
+            &OwnedSocketEvent::NewConnection { addr, .. } => Some(addr),
+            _ => None,
+        }).collect()
+    }
+
+    fn connection_requests(events: &[OwnedSocketEvent]) -> Vec<SocketAddr> {
+        events.iter().filter_map(|event| match event {
+            &OwnedSocketEvent::ConnectionRequest { addr, .. } => Some(addr),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn deferred_accept() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        assert_eq!(client.open_connection(Duration::ZERO, server_addr), Err(ConnectError::AlreadyConnecting));
+
+        let events = server.poll_events(Duration::ZERO);
+        assert_eq!(connection_requests(&events), [client_addr]);
+
+        // further handshakes don't produce more requests
+        let time = Duration::from_millis(600);
+        client.poll_events(time);
+        assert!(server.poll_events(time).is_empty());
+
+        server.accept_connection(time, client_addr, None).unwrap();
+        assert!(server.accept_connection(time, client_addr, None).is_err());
+        assert_eq!(new_connections(&server.poll_events(time)), [client_addr]);
+
+        client.poll_events(time);
+        assert_eq!(new_connections(&client.poll_events(time)), [server_addr]);
+
+        assert_eq!(client.open_connection(time, server_addr), Err(ConnectError::AlreadyConnected));
+        assert_eq!(server.open_connection(time, client_addr), Err(ConnectError::AlreadyConnected));
+    }
+
+    #[test]
+    fn accept_sends_heartbeat() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+        server.poll_events(Duration::ZERO);
+
+        // connected without waiting for the heartbeat interval
+        assert!(client.is_connecting(server_addr));
+        client.poll_events(Duration::ZERO);
+        assert_eq!(new_connections(&client.poll_events(Duration::ZERO)), [server_addr]);
+        assert!(client.is_connected(server_addr));
+        assert!(!client.is_connecting(server_addr));
+        assert!(server.is_connected(client_addr));
+        assert!(!client.is_connected(client_addr));
+    }
+
+    #[test]
+    fn max_connection_requests() {
+        let (mut server, server_addr) = bind();
+        server.config.max_connection_requests = 2;
+
+        let mut clients: Vec<_> = (0..3).map(|_| bind()).collect();
+        for (index, (client, _)) in clients.iter_mut().enumerate() {
+            let time = Duration::from_millis(index as u64);
+            client.open_connection(time, server_addr).unwrap();
+            client.poll_events(time);
+            server.poll_events(time);
+        }
+
+        // the request that sent a handshake the longest ago is forgotten
+        let mut requests: Vec<_> = server.connection_requests().collect();
+        requests.sort();
+        let mut expected = [clients[1].1, clients[2].1];
+        expected.sort();
+        assert_eq!(requests, expected);
+        assert_eq!(server.stats().dropped_connection_requests, 1);
+        assert!(server.accept_connection(Duration::ZERO, clients[0].1, None).is_err());
+    }
+
+    #[test]
+    fn rejected_request() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+
+        let events = server.poll_events(Duration::ZERO);
+        assert_eq!(connection_requests(&events), [client_addr]);
+
+        server.reject_connection(client_addr).unwrap();
+        assert!(server.accept_connection(Duration::ZERO, client_addr, None).is_err());
+
+        // request is forgotten once handshakes stop
+        let time = Duration::from_millis(600);
+        client.close_connection(server_addr).unwrap();
+        client.poll_events(time);
+        server.poll_events(time);
+        assert!(server.reject_connection(client_addr).is_ok());
+
+        let time = time + server.config.timeout_delay + Duration::from_millis(1);
+        server.poll_events(time);
+        assert!(server.reject_connection(client_addr).is_err());
+    }
+
+    #[test]
+    fn rejection_fails_fast() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+
+        server.reject_connection_with_reason(client_addr, "server full").unwrap();
+        server.reject_connection_with_reason(client_addr, "server full").unwrap();
+        assert_eq!(server.stats().refused_connections, 1);
+
+        // receives the rejection, then gives up in the next update
+        let mut events = client.poll_events(Duration::ZERO);
+        events.extend(client.poll_events(Duration::ZERO));
+        assert!(matches!(
+            &events[..],
+            [
+                OwnedSocketEvent::ConnectionRejected { addr, reason: Some(reason) },
+                OwnedSocketEvent::ClosedConnection { addr: closed_addr, .. },
+            ] if *addr == server_addr && &**reason == "server full" && *closed_addr == server_addr
+        ));
+    }
+
+    #[test]
+    fn connection_ids() {
+        let (mut client, _) = bind();
+        let (_, server_addr) = bind();
+        let (_, other_addr) = bind();
+
+        let id = client.open_connection(Duration::ZERO, server_addr).unwrap();
+        let other_id = client.open_connection(Duration::ZERO, other_addr).unwrap();
+        assert_ne!(id, other_id);
+
+        assert_eq!(client.connection_id(server_addr), Some(id));
+        assert_eq!(client.connection_addr(id), Some(server_addr));
+
+        client.close_connection(server_addr).unwrap();
+        let events = client.poll_events(Duration::ZERO);
+        assert!(events.iter().any(|event| matches!(
+            event,
+            &OwnedSocketEvent::ClosedConnection { addr, id: closed_id } if addr == server_addr && closed_id == id
+        )));
+
+        assert_eq!(client.connection_id(server_addr), None);
+        assert_eq!(client.connection_addr(id), None);
+        assert_eq!(client.connection_addr(other_id), Some(other_addr));
+    }
+
+    #[test]
+    fn connection_mtu() {
+        let (mut client, _) = bind();
+        let (_, server_addr) = bind();
+
+        assert_eq!(client.connection_mtu(server_addr), None);
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        assert_eq!(client.connection_mtu(server_addr), Some(client.config.mtu));
+
+        client.set_connection_mtu(server_addr, Some(1300)).unwrap();
+        assert_eq!(client.connection_mtu(server_addr), Some(1300));
+
+        client.set_connection_config(server_addr, Some(Config { mtu: 1000, ..Default::default() })).unwrap();
+        assert_eq!(client.connection_mtu(server_addr), Some(1300));
+
+        client.set_connection_mtu(server_addr, None).unwrap();
+        assert_eq!(client.connection_mtu(server_addr), Some(1000));
+    }
+
+    #[test]
+    fn connection_user_data() {
+        let (mut client, _) = bind();
+        let (_, server_addr) = bind();
+
+        assert!(client.set_connection_user_data(server_addr, 1u32).is_err());
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        assert_eq!(client.connection_user_data::<u32>(server_addr), None);
+
+        client.set_connection_user_data(server_addr, 1u32).unwrap();
+        *client.connection_user_data_mut::<u32>(server_addr).unwrap() += 1;
+        assert_eq!(client.connection_user_data::<u32>(server_addr), Some(&2));
+
+        // the wrong type isn't returned
+        assert_eq!(client.connection_user_data::<u64>(server_addr), None);
+    }
+
+    #[test]
+    fn handle_send() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+
+        // the server's first heartbeat establishes the connection
+        let time = server.config.heartbeat_interval;
+        server.poll_events(time);
+        client.poll_events(time);
+        assert_eq!(new_connections(&client.poll_events(time)), [server_addr]);
+
+        let handle = client.handle();
+        std::thread::spawn(move || {
+            handle.send(server_addr, true, [1, 2, 3].into());
+        }).join().unwrap();
+
+        client.poll_events(time);
+        let events = server.poll_events(time);
+        assert!(events.iter().any(|event| matches!(
+            event,
+            OwnedSocketEvent::Received { addr, sequence, tag: None, data } if *addr == client_addr && *sequence == 0 && **data == [1, 2, 3]
+        )));
+    }
+
+    #[test]
+    fn bind_retries() {
+        let (socket, addr) = bind();
+
+        let result = Socket::bind_with_retry(addr, Config::default(), 2, Duration::from_millis(1));
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::AddrInUse);
+
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            drop(socket);
+        });
+
+        let socket = Socket::bind_with_retry(addr, Config::default(), 10, Duration::from_millis(10)).unwrap();
+        assert_eq!(socket.local_addr().unwrap(), addr);
+        release.join().unwrap();
+    }
+
+    #[test]
+    fn heartbeat_payload() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+
+        assert_eq!(client.set_heartbeat_payload(server_addr, [1].into()), Err(SendError::NoConnection));
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+
+        let time = server.config.heartbeat_interval;
+        server.poll_events(time);
+        client.poll_events(time);
+
+        assert_eq!(
+            client.set_heartbeat_payload(server_addr, [0; MAX_HEARTBEAT_PAYLOAD_SIZE + 1].into()),
+            Err(SendError::MessageTooLarge { size: MAX_HEARTBEAT_PAYLOAD_SIZE + 1, max_size: MAX_HEARTBEAT_PAYLOAD_SIZE }),
+        );
+        client.set_heartbeat_payload(server_addr, [1, 2, 3].into()).unwrap();
+
+        // only sent with the next heartbeat
+        let time = time * 2;
+        client.poll_events(time);
+        let events = server.poll_events(time);
+        assert_eq!(events.iter().filter(|event| matches!(
+            event,
+            OwnedSocketEvent::Received { addr, data, .. } if *addr == client_addr && **data == [1, 2, 3]
+        )).count(), 1);
+
+        let time = time * 2;
+        client.poll_events(time);
+        let events = server.poll_events(time);
+        assert!(!events.iter().any(|event| matches!(event, OwnedSocketEvent::Received { .. })));
+    }
+
+    #[test]
+    fn connection_handoff() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+        let (mut new_server, new_server_addr) = bind();
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+
+        let time = server.config.heartbeat_interval;
+        server.poll_events(time);
+        client.poll_events(time);
+        let client_id = client.connection_id(server_addr).unwrap();
+
+        // the server learns the client's handoff key once the client knows it's connected
+        client.poll_events(time);
+        server.poll_events(time);
+
+        // a message queued before the handoff is sent by the new socket
+        server.send(client_addr, true, [1, 2, 3].into()).unwrap();
+        let connection = server.take_connection(client_addr).unwrap();
+        assert!(server.take_connection(client_addr).is_none());
+        assert_eq!(connection.address(), client_addr);
+        assert!(new_server.insert_connection(connection).is_ok());
+
+        new_server.poll_events(time);
+        assert_eq!(server.connection_id(client_addr), None);
+        let events = client.poll_events(time);
+        assert!(events.iter().any(|event| matches!(
+            event,
+            OwnedSocketEvent::ConnectionMigrated { addr, previous_addr } if *addr == new_server_addr && *previous_addr == server_addr
+        )));
+        assert!(events.iter().any(|event| matches!(
+            event,
+            OwnedSocketEvent::Received { addr, data, .. } if *addr == new_server_addr && **data == [1, 2, 3]
+        )));
+        assert_eq!(client.connection_id(new_server_addr), Some(client_id));
+        assert_eq!(client.connection_id(server_addr), None);
+
+        client.send(new_server_addr, true, [4, 5, 6].into()).unwrap();
+        client.poll_events(time);
+        let events = new_server.poll_events(time);
+        assert!(events.iter().any(|event| matches!(
+            event,
+            OwnedSocketEvent::Received { addr, data, .. } if *addr == client_addr && **data == [4, 5, 6]
+        )));
+        assert_eq!(new_server.stats().packets_from_unknown_peers, 0);
+    }
+
+    #[test]
+    fn forged_handoff() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        let client_id = server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+
+        let time = server.config.heartbeat_interval;
+        server.poll_events(time);
+        client.poll_events(time);
+        client.poll_events(time);
+        server.poll_events(time);
+
+        // a third party claiming to be the client after a handoff, without it's key
+        let attacker = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let attacker_addr = attacker.local_addr().unwrap();
+        for key in [0, 1, u64::MAX] {
+            let mut packet = Packet::new();
+            packet.push(Blob::Handoff(client_addr, key));
+            packet.push(Blob::Disconnect);
+            attacker.send_to(&packet.serialize(), server_addr).unwrap();
+        }
+
+        let events = server.poll_events(time);
+        assert!(!events.iter().any(|event| matches!(event, OwnedSocketEvent::ConnectionMigrated { .. })));
+        assert!(!events.iter().any(|event| matches!(event, OwnedSocketEvent::ClosedConnection { .. })));
+        assert_eq!(server.connection_id(client_addr), Some(client_id));
+        assert_eq!(server.connection_id(attacker_addr), None);
+        assert_eq!(server.stats().packets_from_unknown_peers, 3);
+    }
+
+    #[test]
+    fn split_update() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+
+        let time = server.config.heartbeat_interval;
+        server.poll_events(time);
+        client.poll_events(time);
+        assert_eq!(new_connections(&client.poll_events(time)), [server_addr]);
+
+        let received = |server: &mut Socket| {
+            let mut received = Vec::new();
+            server.process_incoming(time, |event| if let SocketEvent::Received { data, .. } = event {
+                received.push(data);
+            });
+            received
+        };
+
+        // processing incoming packets doesn't send anything
+        client.send(server_addr, true, [1, 2, 3].into()).unwrap();
+        client.process_incoming(time, |_| ());
+        assert!(received(&mut server).is_empty());
+
+        client.process_timers(time, |_| ());
+        assert_eq!(received(&mut server), [[1, 2, 3].into()]);
+    }
+
+    #[test]
+    fn buffered_events() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.update_buffered(Duration::ZERO);
+
+        server.update_buffered(Duration::ZERO);
+        assert!(matches!(
+            server.drain_connection_changes().collect::<Vec<_>>()[..],
+            [ConnectionChange::ConnectionRequest { addr, .. }] if addr == client_addr
+        ));
+        server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+
+        let time = server.config.heartbeat_interval;
+        server.update_buffered(time);
+        assert!(matches!(
+            server.drain_connection_changes().next(),
+            Some(ConnectionChange::NewConnection { addr, .. }) if addr == client_addr
+        ));
+
+        server.send(client_addr, true, [1, 2, 3].into()).unwrap();
+        server.send_with_tag(client_addr, Reliability::Reliable, 0, 7, [4].into()).unwrap();
+        server.update_buffered(time);
+
+        client.update_buffered(time);
+        let mut received = client.drain_received().collect::<Vec<_>>();
+        received.sort_by_key(|received| received.sequence);
+        assert!(matches!(
+            &received[..],
+            [
+                Received { addr, tag: None, data, .. },
+                Received { tag: Some(7), data: tagged_data, .. },
+            ] if *addr == server_addr && **data == [1, 2, 3] && **tagged_data == [4]
+        ));
+        assert_eq!(client.drain_errors().count(), 0);
+    }
+
+    #[test]
+    fn buffered_errors() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+
+        server.set_buffer_errors(true);
+        for buffered in [false, true] {
+            client.udp_socket.send_to(&[0xff, 0xff, 0xff], server_addr).unwrap();
+
+            if buffered {
+                server.update_buffered(Duration::ZERO);
+            } else {
+                let events = server.poll_events(Duration::ZERO);
+                assert!(!events.iter().any(|event| matches!(event, OwnedSocketEvent::Error(_))));
+            }
+
+            assert!(matches!(
+                server.drain_errors().collect::<Vec<_>>()[..],
+                [Error::MalformedPacket { addr }] if addr == client_addr
+            ));
+        }
+    }
+
+    #[test]
+    fn message_too_large() {
+        let (mut client, _) = bind();
+        let (_, server_addr) = bind();
+
+        assert_eq!(client.send(server_addr, true, [0; 10].into()), Err(SendError::NoConnection));
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.set_connection_config(server_addr, Some(Config {
+            max_message_fragments: 4,
+            ..Default::default()
+        })).unwrap();
+        client.set_connection_mtu(server_addr, Some(20)).unwrap();
+
+        // 7 bytes of data per fragment
+        assert_eq!(client.send(server_addr, true, [0; 28].into()), Ok(()));
+        assert_eq!(
+            client.send(server_addr, true, [0; 29].into()),
+            Err(SendError::MessageTooLarge { size: 29, max_size: 28 }),
+        );
+        assert_eq!(
+            client.send_with_tag(server_addr, Reliability::Reliable, 0, 1, [0; 28].into()),
+            Err(SendError::MessageTooLarge { size: 28, max_size: 27 }),
+        );
+    }
+
+    #[test]
+    fn set_config() {
+        let (mut client, _) = bind();
+        let (_, server_addr) = bind();
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.send(server_addr, true, [0; 28].into()).unwrap();
+
+        // 7 bytes of data per fragment
+        assert_eq!(
+            client.set_config(Config {
+                mtu: 20,
+                max_message_fragments: 3,
+                ..Default::default()
+            }),
+            Err(ConfigError::MtuTooSmall { addr: server_addr, message_size: 28, max_message_size: 21 }),
+        );
+        assert_eq!(client.config().mtu, Config::default().mtu);
+
+        client.set_config(Config {
+            mtu: 20,
+            max_message_fragments: 4,
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(client.config().mtu, 20);
+        assert_eq!(client.connection_mtu(server_addr), Some(20));
+    }
+
+    #[test]
+    fn try_send_reliable() {
+        let (mut client, _) = bind();
+        let (_, server_addr) = bind();
+
+        assert_eq!(client.try_send_reliable(server_addr, [0; 10].into()), Err(SendError::NoConnection));
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.set_connection_config(server_addr, Some(Config {
+            max_pending_reliable_bytes: 10,
+            ..Default::default()
+        })).unwrap();
+
+        let id = client.try_send_reliable(server_addr, [0; 8].into()).unwrap();
+        assert_eq!(client.try_send_reliable(server_addr, [0; 8].into()), Ok(id.wrapping_add(1)));
+
+        assert_eq!(
+            client.try_send_reliable(server_addr, [0; 8].into()),
+            Err(SendError::TooMuchPending { pending_bytes: 16, max_pending_bytes: 10 }),
+        );
+
+        // regular sends aren't limited
+        assert_eq!(client.send(server_addr, true, [0; 8].into()), Ok(()));
+    }
+
+    #[test]
+    fn address_family_mismatch() {
+        let (mut client, client_addr) = bind();
+        let (_, server_addr) = bind();
+
+        let ipv6_addr = "[::1]:1234".parse().unwrap();
+        assert_eq!(
+            client.open_connection(Duration::ZERO, ipv6_addr),
+            Err(ConnectError::AddressFamilyMismatch { local_addr: client_addr, addr: ipv6_addr }),
+        );
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        assert_eq!(client.open_connection(Duration::ZERO, server_addr), Err(ConnectError::AlreadyConnecting));
+    }
+
+    #[test]
+    fn connection_token() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+
+        assert_eq!(
+            client.open_connection_with_token(Duration::ZERO, server_addr, [0; MAX_TOKEN_SIZE + 1].into()),
+            Err(ConnectError::TokenTooLarge { size: MAX_TOKEN_SIZE + 1 }),
+        );
+
+        client.open_connection_with_token(Duration::ZERO, server_addr, [1, 2, 3].into()).unwrap();
+        client.poll_events(Duration::ZERO);
+
+        let events = server.poll_events(Duration::ZERO);
+        assert!(matches!(
+            &events[..],
+            [OwnedSocketEvent::ConnectionRequest { addr, token }] if *addr == client_addr && **token == [1, 2, 3]
+        ));
+
+        server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+        assert_eq!(server.connection_token(client_addr), Some([1, 2, 3].as_slice()));
+
+        let events = server.poll_events(Duration::ZERO);
+        assert!(events.iter().any(|event| matches!(
+            event,
+            OwnedSocketEvent::NewConnection { token, .. } if **token == [1, 2, 3]
+        )));
+
+        assert_eq!(client.connection_token(server_addr), Some([1, 2, 3].as_slice()));
+
+        assert_eq!(server.peer_token(client_addr), Some([1, 2, 3].as_slice()));
+        assert_eq!(client.peer_token(server_addr), None);
+        assert_eq!(server.is_opening_party(client_addr), Some(false));
+        assert_eq!(client.is_opening_party(server_addr), Some(true));
+    }
+
+    #[test]
+    fn stats() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+
+        let time = server.config.heartbeat_interval;
+        server.poll_events(time);
+        client.poll_events(time);
+        client.poll_events(time);
+        server.poll_events(time);
+
+        let stats = server.stats();
+        let metrics = server.connection_metrics(client_addr).unwrap();
+        assert_eq!(stats.connections, 1);
+        assert_eq!(stats.sent_packets, metrics.sent_packets);
+        assert_eq!(stats.received_packets, metrics.received_packets);
+        assert!(stats.received_bytes > 0);
+        assert_eq!(stats.wrong_protocol_handshakes, 0);
+
+        // handshakes with the wrong protocol id are refused
+        let mut other = Socket::bind("127.0.0.1:0".parse().unwrap(), Config {
+            protocol_id: 1,
+            ..Default::default()
+        }).unwrap();
+        let other_addr = other.local_addr().unwrap();
+        other.open_connection(Duration::ZERO, server_addr).unwrap();
+        other.poll_events(Duration::ZERO);
+        server.set_report_ignored_handshakes(true);
+        assert!(server.poll_events(time).iter().any(|event| matches!(
+            event,
+            OwnedSocketEvent::HandshakeIgnored {
+                addr,
+                reason: HandshakeIgnoredReason::ProtocolMismatch { received: 1, expected: 0 },
+            } if *addr == other_addr
+        )));
+
+        // packets from addresses without a connection are counted too
+        let unknown = UdpSocket::bind("127.0.0.1:0").unwrap();
+        unknown.send_to(&[0, 1, 1], server_addr).unwrap();
+        server.poll_events(time);
+
+        let stats = server.stats();
+        assert_eq!(stats.connections, 1);
+        assert_eq!(stats.wrong_protocol_handshakes, 1);
+        assert_eq!(stats.packets_from_unknown_peers, 1);
+    }
+
+    #[test]
+    fn event_limit() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+        server.poll_events(Duration::ZERO);
+        client.poll_events(Duration::ZERO);
+        client.poll_events(Duration::ZERO);
+
+        for i in 0..5 {
+            client.send(server_addr, true, [i].into()).unwrap();
+        }
+        client.poll_events(Duration::ZERO);
+
+        let mut received = Vec::new();
+        let mut poll = |server: &mut Socket| {
+            let events = server.poll_events_with_limit(Duration::ZERO, 2);
+            assert!(events.len() <= 2);
+            received.extend(events.into_iter().filter_map(|event| match event {
+                OwnedSocketEvent::Received { data, .. } => Some(data[0]),
+                _ => None,
+            }));
+        };
+
+        poll(&mut server);
+        let deferred = server.deferred_event_count();
+        assert!(deferred >= 3);
+
+        // nothing new is received whilst the held back events fill a call
+        client.send(server_addr, true, [5].into()).unwrap();
+        client.poll_events(Duration::ZERO);
+        poll(&mut server);
+        assert_eq!(server.deferred_event_count(), deferred - 2);
+
+        while server.deferred_event_count() > 0 {
+            poll(&mut server);
+        }
+        poll(&mut server);
+
+        assert_eq!(received, [0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn bulk_connections() {
+        let (mut server, server_addr) = bind();
+        let mut clients: Vec<_> = (0..2).map(|_| bind()).collect();
+
+        for (client, _) in clients.iter_mut() {
+            client.open_connection(Duration::ZERO, server_addr).unwrap();
+            client.poll_events(Duration::ZERO);
+        }
+        server.poll_events(Duration::ZERO);
+        for (_, client_addr) in clients.iter() {
+            server.accept_connection(Duration::ZERO, *client_addr, None).unwrap();
+        }
+        server.poll_events(Duration::ZERO);
+
+        assert_eq!(server.connections_mut().count(), 2);
+        for mut connection in server.connections_mut() {
+            assert!(!connection.is_connecting());
+            connection.send(true, [7].into()).unwrap();
+        }
+        server.poll_events(Duration::ZERO);
+
+        for (client, _) in clients.iter_mut() {
+            let mut events = client.poll_events(Duration::ZERO);
+            events.extend(client.poll_events(Duration::ZERO));
+            assert!(events.iter().any(|event| matches!(event, OwnedSocketEvent::Received { data, .. } if **data == [7])));
+        }
+
+        server.connections_mut().for_each(|mut connection| connection.close());
+        let events = server.poll_events(Duration::ZERO);
+        assert_eq!(events.iter().filter(|event| matches!(event, OwnedSocketEvent::ClosedConnection { .. })).count(), 2);
+        assert_eq!(server.connections_mut().count(), 0);
+    }
+
+    #[test]
+    fn limited_connection_updates() {
+        let (mut server, server_addr) = bind();
+        let mut clients: Vec<_> = (0..3).map(|_| bind()).collect();
+
+        for (client, _) in clients.iter_mut() {
+            client.open_connection(Duration::ZERO, server_addr).unwrap();
+            client.poll_events(Duration::ZERO);
+        }
+        server.poll_events(Duration::ZERO);
+        let mut ids = Vec::new();
+        for (_, client_addr) in clients.iter() {
+            ids.push(server.accept_connection(Duration::ZERO, *client_addr, None).unwrap());
+        }
+
+        server.set_config(Config {
+            max_connection_updates: NonZeroUsize::new(1),
+            ..Default::default()
+        }).unwrap();
+
+        // updates the first connection
+        server.poll_events(Duration::ZERO);
+
+        server.connections_mut().for_each(|mut connection| connection.close());
+
+        // the rest take their turn before it comes round again
+        let closed: Vec<_> = (0..3).map(|_| {
+            let closed: Vec<_> = server.poll_events(Duration::ZERO).into_iter().filter_map(|event| match event {
+                OwnedSocketEvent::ClosedConnection { id, .. } => Some(id),
+                _ => None,
+            }).collect();
+            assert_eq!(closed.len(), 1);
+            closed[0]
+        }).collect();
+        assert_eq!(closed, [ids[1], ids[2], ids[0]]);
+    }
+
+    #[test]
+    fn resumed_sessions() {
+        let config = Config {
+            session_resume_delay: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        let mut client = Socket::bind("127.0.0.1:0".parse().unwrap(), config.clone()).unwrap();
+        let mut server = Socket::bind("127.0.0.1:0".parse().unwrap(), config).unwrap();
+        let (client_addr, server_addr) = (client.local_addr().unwrap(), server.local_addr().unwrap());
+
+        let connect = |client: &mut Socket, server: &mut Socket| {
+            client.open_connection_with_token(Duration::ZERO, server_addr, [1, 2, 3].into()).unwrap();
+            client.poll_events(Duration::ZERO);
+            server.poll_events(Duration::ZERO);
+            server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+            server.poll_events(Duration::ZERO);
+            client.poll_events(Duration::ZERO);
+        };
+
+        connect(&mut client, &mut server);
+        for i in 0..3 {
+            client.send_with_reliability(server_addr, Reliability::ReliableOrdered, 0, [i].into()).unwrap();
+        }
+        client.poll_events(Duration::ZERO);
+        assert_eq!(client.ordered_sequences(server_addr), Some(OrderedSequences { send: 3, receive: 0 }));
+        for _ in 0..2 {
+            server.poll_events(Duration::ZERO);
+            client.poll_events(Duration::ZERO);
+        }
+        assert_eq!(client.ordered_sequences(server_addr), Some(OrderedSequences { send: 3, receive: 0 }));
+        assert_eq!(server.ordered_sequences(client_addr), Some(OrderedSequences { send: 0, receive: 3 }));
+
+        client.close_connection(server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        assert!(!server.is_connected(client_addr));
+        // the server's disconnect would close the new connection
+        client.poll_events(Duration::ZERO);
+
+        connect(&mut client, &mut server);
+        assert_eq!(client.ordered_sequences(server_addr), Some(OrderedSequences { send: 3, receive: 0 }));
+        assert_eq!(server.ordered_sequences(client_addr), Some(OrderedSequences { send: 0, receive: 3 }));
+
+        client.send_with_reliability(server_addr, Reliability::ReliableOrdered, 0, [3].into()).unwrap();
+        client.poll_events(Duration::ZERO);
+        let events = server.poll_events(Duration::ZERO);
+        assert!(events.iter().any(|event| matches!(event, OwnedSocketEvent::Received { data, .. } if **data == [3])));
+    }
+
+    #[test]
+    fn resumed_sessions_accepted_in_update() {
+        let config = Config {
+            session_resume_delay: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        let mut client = Socket::bind("127.0.0.1:0".parse().unwrap(), config.clone()).unwrap();
+        let mut server = Socket::bind("127.0.0.1:0".parse().unwrap(), config).unwrap();
+        let (client_addr, server_addr) = (client.local_addr().unwrap(), server.local_addr().unwrap());
+
+        // the server accepts from within the event handler instead of with `accept_connection`
+        let connect = |client: &mut Socket, server: &mut Socket| {
+            client.open_connection_with_token(Duration::ZERO, server_addr, [1, 2, 3].into()).unwrap();
+            client.poll_events(Duration::ZERO);
+            server.update(Duration::ZERO, |event| if let SocketEvent::ConnectionRequest { accept_connection, .. } = event {
+                *accept_connection = true;
+            });
+            server.poll_events(Duration::ZERO);
+            client.poll_events(Duration::ZERO);
+        };
+
+        connect(&mut client, &mut server);
+        for i in 0..3 {
+            client.send_with_reliability(server_addr, Reliability::ReliableOrdered, 0, [i].into()).unwrap();
+        }
+        client.poll_events(Duration::ZERO);
+        for _ in 0..2 {
+            server.poll_events(Duration::ZERO);
+            client.poll_events(Duration::ZERO);
+        }
+
+        client.close_connection(server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        assert!(!server.is_connected(client_addr));
+        client.poll_events(Duration::ZERO);
+
+        connect(&mut client, &mut server);
+        assert_eq!(client.ordered_sequences(server_addr), Some(OrderedSequences { send: 3, receive: 0 }));
+        assert_eq!(server.ordered_sequences(client_addr), Some(OrderedSequences { send: 0, receive: 3 }));
+
+        client.send_with_reliability(server_addr, Reliability::ReliableOrdered, 0, [3].into()).unwrap();
+        client.poll_events(Duration::ZERO);
+        let events = server.poll_events(Duration::ZERO);
+        assert!(events.iter().any(|event| matches!(event, OwnedSocketEvent::Received { data, .. } if **data == [3])));
+    }
+
+    #[test]
+    fn resumed_sessions_resend_unacknowledged() {
+        let config = Config {
+            session_resume_delay: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        let mut client = Socket::bind("127.0.0.1:0".parse().unwrap(), config.clone()).unwrap();
+        let mut server = Socket::bind("127.0.0.1:0".parse().unwrap(), config).unwrap();
+        let (client_addr, server_addr) = (client.local_addr().unwrap(), server.local_addr().unwrap());
+
+        let connect = |client: &mut Socket, server: &mut Socket| {
+            client.open_connection_with_token(Duration::ZERO, server_addr, [1, 2, 3].into()).unwrap();
+            client.poll_events(Duration::ZERO);
+            server.poll_events(Duration::ZERO);
+            server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+            server.poll_events(Duration::ZERO);
+            client.poll_events(Duration::ZERO);
+        };
+        let received = |events: Vec<OwnedSocketEvent>| events.into_iter().filter_map(|event| match event {
+            OwnedSocketEvent::Received { data, .. } => Some(data[0]),
+            _ => None,
+        }).collect::<Vec<_>>();
+
+        connect(&mut client, &mut server);
+        client.send_with_reliability(server_addr, Reliability::ReliableOrdered, 0, [0].into()).unwrap();
+        client.poll_events(Duration::ZERO);
+        assert_eq!(received(server.poll_events(Duration::ZERO)), [0]);
+
+        // the acknowledgement of the message is lost, then the client closes the connection
+        client.set_inbound_filter(|bytes| bytes.clear());
+        client.close_connection(server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        client.poll_events(Duration::ZERO);
+        client.clear_filters();
+        assert!(!server.is_connected(client_addr));
+
+        // the message is sent again, but already received, the next one still gets through
+        connect(&mut client, &mut server);
+        client.send_with_reliability(server_addr, Reliability::ReliableOrdered, 0, [1].into()).unwrap();
+        client.poll_events(Duration::ZERO);
+        assert_eq!(received(server.poll_events(Duration::ZERO)), [1]);
+
+        // the messages themselves are lost this time, they arrive in the next connection ahead of the one after them,
+        // in the order they were sent even though the second has a higher priority
+        client.set_outbound_filter(|bytes| bytes.clear());
+        client.send_with_reliability(server_addr, Reliability::ReliableOrdered, 0, [2].into()).unwrap();
+        client.send_with_reliability(server_addr, Reliability::ReliableOrdered, 5, [3].into()).unwrap();
+        client.poll_events(Duration::ZERO);
+        client.clear_filters();
+        client.close_connection(server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        client.poll_events(Duration::ZERO);
+        assert!(!server.is_connected(client_addr));
+
+        connect(&mut client, &mut server);
+        client.send_with_reliability(server_addr, Reliability::ReliableOrdered, 0, [4].into()).unwrap();
+        client.poll_events(Duration::ZERO);
+        assert_eq!(received(server.poll_events(Duration::ZERO)), [2, 3, 4]);
+    }
+
+    #[test]
+    fn send_buffer_full() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+        server.poll_events(Duration::ZERO);
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+
+        let sent_packets = client.connection_metrics(server_addr).unwrap().sent_packets;
+        client.send(server_addr, true, [1, 2, 3].into()).unwrap();
+        client.udp_socket.fill_send_buffer_after(0);
+
+        // the packet is held back by the connection instead of being counted as sent
+        let events = client.poll_events(Duration::ZERO);
+        assert!(events.iter().any(|event| matches!(event, OwnedSocketEvent::SendBufferFull { addr } if *addr == server_addr)));
+        assert_eq!(client.connection_metrics(server_addr).unwrap().sent_packets, sent_packets);
+        assert!(server.poll_events(Duration::ZERO).iter().all(|event| !matches!(event, OwnedSocketEvent::Received { .. })));
+
+        // then sent once there is space
+        client.poll_events(Duration::ZERO);
+        assert!(client.connection_metrics(server_addr).unwrap().sent_packets > sent_packets);
+        let events = server.poll_events(Duration::ZERO);
+        assert!(events.iter().any(|event| matches!(event, OwnedSocketEvent::Received { data, .. } if **data == [1, 2, 3])));
+    }
+
+    #[test]
+    fn filter_grows_past_mtu() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+
+        // pads every datagram by more than the mtu
+        let padding = usize::from(Config::default().mtu) + 100;
+        client.set_outbound_filter(move |bytes| bytes.resize(bytes.len() + padding, 0xff));
+        server.set_inbound_filter(move |bytes| bytes.truncate(bytes.len().saturating_sub(padding)));
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+        server.poll_events(Duration::ZERO);
+        client.poll_events(Duration::ZERO);
+
+        client.send(server_addr, true, vec![7; 1000].into()).unwrap();
+        client.poll_events(Duration::ZERO);
+        let events = server.poll_events(Duration::ZERO);
+        assert!(events.iter().all(|event| !matches!(event, OwnedSocketEvent::Error(_))));
+        assert!(events.iter().any(|event| matches!(event, OwnedSocketEvent::Received { data, .. } if data.len() == 1000)));
+    }
+
+    #[test]
+    fn tagged_messages() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+        server.poll_events(Duration::ZERO);
+        client.poll_events(Duration::ZERO);
+
+        let reliabilities = [Reliability::Unreliable, Reliability::UnreliableSequenced, Reliability::Reliable, Reliability::ReliableOrdered];
+        for (i, reliability) in reliabilities.into_iter().enumerate() {
+            client.send_with_tag(server_addr, reliability, 0, i as u8 + 10, [i as u8].into()).unwrap();
+        }
+        client.send(server_addr, true, [4].into()).unwrap();
+        client.poll_events(Duration::ZERO);
+
+        let mut received: Vec<_> = server.poll_events(Duration::ZERO).into_iter().filter_map(|event| match event {
+            OwnedSocketEvent::Received { tag, data, .. } => Some((data[0], tag)),
+            _ => None,
+        }).collect();
+        received.sort();
+
+        assert_eq!(received, [(0, Some(10)), (1, Some(11)), (2, Some(12)), (3, Some(13)), (4, None)]);
+    }
+
+    #[test]
+    fn vectored_send() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+
+        assert_eq!(client.send_vectored(server_addr, true, &[&[1]]), Err(SendError::NoConnection));
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+        server.poll_events(Duration::ZERO);
+        client.poll_events(Duration::ZERO);
+
+        let header = [1, 2];
+        let body = vec![3, 4, 5];
+        client.send_vectored(server_addr, true, &[&header, &[], &body]).unwrap();
+        client.poll_events(Duration::ZERO);
+
+        let events = server.poll_events(Duration::ZERO);
+        assert!(events.iter().any(|event| matches!(
+            event,
+            OwnedSocketEvent::Received { data, .. } if **data == [1, 2, 3, 4, 5]
+        )));
+    }
+
+    #[test]
+    fn graceful_shutdown() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+        server.poll_events(Duration::ZERO);
+
+        server.send(client_addr, true, vec![7; 10_000].into()).unwrap();
+
+        let client = std::thread::spawn(move || {
+            let start = std::time::Instant::now();
+            let mut received = false;
+
+            while start.elapsed() < Duration::from_secs(5) {
+                for event in client.poll_events(start.elapsed()) {
+                    match event {
+                        OwnedSocketEvent::Received { data, .. } => received = data.len() == 10_000,
+                        OwnedSocketEvent::ClosedConnection { .. } => return received,
+                        _ => (),
+                    }
+                }
+
+                std::thread::sleep(Duration::from_millis(1));
+            }
+
+            panic!("the server didn't close the connection");
+        });
+
+        let mut closed = 0;
+        let drained = server.shutdown_graceful(Duration::ZERO, Duration::from_secs(5), |event| {
+            if let SocketEvent::ClosedConnection { .. } = event {
+                closed += 1;
+            }
+        });
+        assert!(drained);
+        assert_eq!(closed, 1);
+        assert!(client.join().unwrap());
+
+        // new connections are turned away
+        let (mut client, _) = bind();
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        let mut events = client.poll_events(Duration::ZERO);
+        events.extend(client.poll_events(Duration::ZERO));
+        assert!(events.iter().any(|event| matches!(
+            event,
+            OwnedSocketEvent::ConnectionRejected { reason: Some(reason), .. } if **reason == *SHUTDOWN_REJECTION_REASON
+        )));
+    }
+
+    #[test]
+    fn goodbye_before_close() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+        server.poll_events(Duration::ZERO);
+        client.poll_events(Duration::ZERO);
+        client.poll_events(Duration::ZERO);
+
+        client.send(server_addr, false, [1, 2, 3].into()).unwrap();
+        client.close_connection(server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+
+        let mut events = server.poll_events(Duration::ZERO);
+        events.extend(server.poll_events(Duration::ZERO));
+        let received = events.iter().position(|event| matches!(event, OwnedSocketEvent::Received { data, .. } if **data == [1, 2, 3]));
+        let closed = events.iter().position(|event| matches!(event, OwnedSocketEvent::ClosedConnection { .. }));
+        assert!(received.unwrap() < closed.unwrap());
+    }
+
+    #[test]
+    fn closing_packets_retried() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+        server.poll_events(Duration::ZERO);
+
+        // as if the send buffer was full when the connection was closed
+        let mut packet = Packet::new();
+        packet.push(Blob::Disconnect);
+        client.connections.take_connection(server_addr);
+        client.closing_packets.push_back((Duration::ZERO, server_addr, packet.serialize().into()));
+
+        client.poll_events(Duration::ZERO);
+        assert!(client.closing_packets.is_empty());
+
+        let mut events = server.poll_events(Duration::ZERO);
+        events.extend(server.poll_events(Duration::ZERO));
+        assert!(events.iter().any(|event| matches!(event, OwnedSocketEvent::ClosedConnection { addr, .. } if *addr == client_addr)));
+
+        // given up on after the timeout delay
+        client.closing_packets.push_back((Duration::ZERO, server_addr, [].into()));
+        client.poll_events(client.config.timeout_delay + Duration::from_millis(1));
+        assert!(client.closing_packets.is_empty());
+    }
+
+    #[test]
+    fn shared_receive_buffer() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+        let mut receive_buffer = ReceiveBuffer::new();
+
+        let mut poll = |socket: &mut Socket| {
+            let mut events = Vec::new();
+            socket.update_with_buffer(Duration::ZERO, &mut receive_buffer, |event| {
+                if let SocketEvent::Received { data, .. } = event {
+                    events.push(data);
+                }
+            });
+            events
+        };
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        poll(&mut client);
+        poll(&mut server);
+        server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+        poll(&mut server);
+        poll(&mut client);
+        poll(&mut client);
+
+        client.send(server_addr, true, [1, 2, 3].into()).unwrap();
+        poll(&mut client);
+        assert_eq!(poll(&mut server), [[1, 2, 3].into()]);
+
+        // neither socket allocated a buffer of it's own
+        assert!(client.receive_buffer.is_none());
+        assert!(server.receive_buffer.is_none());
+    }
+
+    #[test]
+    fn packet_filters() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+
+        let xor = |bytes: &mut Vec<u8>| bytes.iter_mut().for_each(|byte| *byte ^= 0x5a);
+        for socket in [&mut client, &mut server] {
+            socket.set_outbound_filter(xor);
+            socket.set_inbound_filter(xor);
+        }
+
+        // an unfiltered socket can't make sense of the handshake
+        let unfiltered = UdpSocket::bind("127.0.0.1:0").unwrap();
+        unfiltered.set_nonblocking(true).unwrap();
+        client.open_connection(Duration::ZERO, unfiltered.local_addr().unwrap()).unwrap();
+        client.poll_events(Duration::ZERO);
+        let mut buffer = [0; 1500];
+        let (len, _) = unfiltered.recv_from(&mut buffer).unwrap();
+        assert!(Handshake::deserialize_handshake(&buffer[..len]).is_none());
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+        server.poll_events(Duration::ZERO);
+        client.poll_events(Duration::ZERO);
+        assert_eq!(new_connections(&client.poll_events(Duration::ZERO)), [server_addr]);
+
+        client.send(server_addr, true, [1, 2, 3].into()).unwrap();
+        client.poll_events(Duration::ZERO);
+        assert!(server.poll_events(Duration::ZERO).iter().any(|event| matches!(
+            event,
+            OwnedSocketEvent::Received { data, .. } if **data == [1, 2, 3]
+        )));
+    }
+
+    /// a capture sink that can still be read after being given to a socket
+    #[derive(Clone, Default)]
+    struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn packet_capture() {
+        let (mut client, client_addr) = bind();
+        let (mut server, server_addr) = bind();
+
+        let sink = SharedSink::default();
+        client.start_capture(sink.clone());
+
+        let time = Duration::from_millis(5);
+        client.open_connection(time, server_addr).unwrap();
+        client.poll_events(time);
+        server.poll_events(time);
+        server.accept_connection(time, client_addr, None).unwrap();
+        server.poll_events(time);
+        client.poll_events(time);
+        client.stop_capture().unwrap();
+
+        // not recorded after stopping
+        client.send(server_addr, false, [1, 2, 3].into()).unwrap();
+        client.poll_events(time);
+
+        let capture = sink.0.lock().unwrap();
+        let mut source = capture.as_slice();
+        let mut records = Vec::new();
+        while let Some(record) = CaptureRecord::read_from(&mut source).unwrap() {
+            assert_eq!(record.time, time);
+            assert_eq!(record.addr, server_addr);
+            records.push(record);
+        }
+
+        // the handshake then the server's reply
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, Direction::Sent);
+        assert!(Handshake::deserialize_handshake(&records[0].bytes).is_some());
+        assert_eq!(records[1].direction, Direction::Received);
+    }
+
+    #[test]
+    fn peer_mtu() {
+        let mut client = Socket::bind("127.0.0.1:0".parse().unwrap(), Config {
+            mtu: 600,
+            ..Default::default()
+        }).unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let (mut server, server_addr) = bind();
+        assert!(server.config.mtu > 600);
+
+        let sink = SharedSink::default();
+        server.start_capture(sink.clone());
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+
+        // known from the handshake straight away
+        let metrics = server.connection_metrics(client_addr).unwrap();
+        assert_eq!((metrics.mtu, metrics.peer_mtu), (600, Some(600)));
+
+        server.send(client_addr, true, vec![0; 5000].into()).unwrap();
+        server.poll_events(Duration::ZERO);
+        client.poll_events(Duration::ZERO);
+        client.poll_events(Duration::ZERO);
+
+        let metrics = client.connection_metrics(server_addr).unwrap();
+        assert_eq!((metrics.mtu, metrics.peer_mtu), (600, Some(server.config.mtu)));
+
+        let capture = sink.0.lock().unwrap();
+        let mut source = capture.as_slice();
+        while let Some(record) = CaptureRecord::read_from(&mut source).unwrap() {
+            if record.direction == Direction::Sent {
+                assert!(record.bytes.len() <= 600);
+            }
+        }
+    }
+
+    #[test]
+    fn streamed_messages() {
+        let (mut client, client_addr) = bind();
+        let mut server = Socket::bind("127.0.0.1:0".parse().unwrap(), Config {
+            min_streamed_message_size: Some(1000),
+            ..Default::default()
+        }).unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        client.open_connection(Duration::ZERO, server_addr).unwrap();
+        client.poll_events(Duration::ZERO);
+        server.poll_events(Duration::ZERO);
+        server.accept_connection(Duration::ZERO, client_addr, None).unwrap();
+        server.poll_events(Duration::ZERO);
+        client.poll_events(Duration::ZERO);
+        client.poll_events(Duration::ZERO);
+
+        let data = (0..5000).map(|i| i as u8).collect::<Vec<u8>>();
+        client.send(server_addr, true, data.clone().into()).unwrap();
+        client.send(server_addr, true, [1, 2, 3].into()).unwrap();
+        client.poll_events(Duration::ZERO);
+
+        let mut streamed = Vec::new();
+        let mut received = Vec::new();
+        for event in server.poll_events(Duration::ZERO) {
+            match event {
+                OwnedSocketEvent::ReceivedChunk { addr, sequence, offset, total_size, data } => {
+                    assert_eq!((addr, sequence, offset, total_size), (client_addr, 0, streamed.len(), 5000));
+                    streamed.extend_from_slice(&data);
+                },
+                OwnedSocketEvent::Received { data, .. } => received.push(data),
+                _ => (),
+            }
+        }
+
+        assert_eq!(streamed, data);
+        // small messages are still received whole
+        assert_eq!(received, [[1, 2, 3].into()]);
+        assert_eq!(server.connection_metrics(client_addr).unwrap().active_receive_id_count, 2);
     }
 }
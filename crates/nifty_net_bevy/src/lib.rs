@@ -6,8 +6,8 @@ pub mod net_socket;
 pub mod typed;
 
 pub mod prelude {
-    pub use nifty_net::Config;
-    pub use nifty_net::metrics::ConnectionMetrics;
+    pub use nifty_net::{Config, ConnectionId, Reliability, socket::{ConfigError, SendError}};
+    pub use nifty_net::metrics::{ConnectionMetrics, ConnectionQuality, MetricsHistory, SocketStats};
 
     pub use crate::net_socket::{
         NetSocket,
@@ -17,13 +17,25 @@ pub mod prelude {
         Connected,
         Disconnected,
         FailedConnection,
+        ConnectionRejected,
+        ConnectionMigrated,
+        ConnectionRequest,
+        QualityChanged,
+        ConnectionStalled,
+        SendBufferFull,
+        ReceivedChunk,
         UpdateSockets,
     };
 
+    pub use crate::define_messages;
+
     pub use crate::typed::{
         TypedMessagePlugin,
         TypedMessages,
+        TypedMessageError,
         TypedSocket,
         Connections,
+        MessageSerializer,
+        BincodeSerializer,
     };
 }
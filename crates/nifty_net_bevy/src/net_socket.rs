@@ -1,12 +1,12 @@
 
-use std::{collections::VecDeque, net::SocketAddr};
+use std::{any::Any, collections::VecDeque, net::SocketAddr, time::Duration};
 
-use bevy::{prelude::*, utils::HashMap};
+use bevy::{ecs::schedule::{InternedScheduleLabel, ScheduleLabel}, prelude::*, utils::HashMap};
 use nifty_net::prelude::*;
 
 
 
-/// sockets are updated in this set in [PreUpdate]
+/// sockets are updated in this set in the schedule given to the [NetworkingPlugin], [PreUpdate] by default
 #[derive(Hash, Debug, PartialEq, Eq, Clone, SystemSet)]
 pub struct UpdateSockets;
 
@@ -14,15 +14,46 @@ pub struct UpdateSockets;
 /// provides basic functionality for [NetSocket]s,
 /// have them make an break [Connection]s
 /// and send and receive messages
-pub struct NetworkingPlugin;
+///
+/// sockets are updated in [PreUpdate] by default,
+/// use [in_schedule](NetworkingPlugin::in_schedule) to update them in a different schedule
+/// such as [FixedUpdate] to decouple networking from the frame rate
+pub struct NetworkingPlugin {
+    /// the schedule to update sockets in
+    pub schedule: InternedScheduleLabel,
+}
+
+impl Default for NetworkingPlugin {
+    fn default() -> Self {
+        NetworkingPlugin {
+            schedule: PreUpdate.intern(),
+        }
+    }
+}
+
+impl NetworkingPlugin {
+    /// creates the plugin with sockets updated in a given schedule
+    pub fn in_schedule(schedule: impl ScheduleLabel) -> Self {
+        NetworkingPlugin {
+            schedule: schedule.intern(),
+        }
+    }
+}
 
 impl Plugin for NetworkingPlugin {
     fn build(&self, app: &mut App) {
         app.add_event::<Connected>();
         app.add_event::<Disconnected>();
         app.add_event::<FailedConnection>();
-
-        app.add_systems(PreUpdate, update_sockets.in_set(UpdateSockets));
+        app.add_event::<ConnectionRejected>();
+        app.add_event::<ConnectionMigrated>();
+        app.add_event::<ConnectionRequest>();
+        app.add_event::<QualityChanged>();
+        app.add_event::<ConnectionStalled>();
+        app.add_event::<SendBufferFull>();
+        app.add_event::<ReceivedChunk>();
+
+        app.add_systems(self.schedule, update_sockets.in_set(UpdateSockets));
     }
 }
 
@@ -35,7 +66,10 @@ impl Plugin for NetworkingPlugin {
 pub struct NetSocketConfig {
     /// configuration for the underlying [Socket]
     pub socket_config: Config,
-    /// when `true` the socket will accept incoming connections, else it will deny them
+    /// when `true` the socket will accept incoming connections
+    ///
+    /// else a [ConnectionRequest] event is fired and the connection can be accepted later
+    /// with [accept_connection](NetSocket::accept_connection)
     pub accept_incoming: bool,
 }
 
@@ -52,8 +86,12 @@ pub struct NetSocket {
     accept_incoming: bool,
     /// map of connected addresses to child connection entities
     connections: HashMap<SocketAddr, Entity>,
-    /// queue of addresses to connect to
-    connect_queue: VecDeque<SocketAddr>,
+    /// queue of addresses to connect to and the tokens to send them
+    connect_queue: VecDeque<(SocketAddr, Box<[u8]>)>,
+    /// queue of connection requests to accept
+    accept_queue: VecDeque<SocketAddr>,
+    /// queue of connection requests to reject and the reasons to give them
+    reject_queue: VecDeque<(SocketAddr, Option<Box<str>>)>,
 }
 
 /// represents a connection on it's parent entity [NetSocket]
@@ -67,16 +105,30 @@ pub struct NetSocket {
 pub struct Connection {
     /// the peer address of the connection
     addr: SocketAddr,
+    /// the id of the connection on the socket
+    id: ConnectionId,
+    /// the token sent in the handshake that opened the connection
+    token: Box<[u8]>,
+    /// an application defined value stored with the connection
+    user_data: Option<Box<dyn Any + Send + Sync>>,
     /// messages that have been received and not read yet
     receive_queue: VecDeque<Box<[u8]>>,
     /// messages that have been sent and need to be pushed to the [NetSocket]
     ///
-    /// the `bool` is if the message is reliable and the `Box` is the data
-    send_queue: VecDeque<(bool, Box<[u8]>)>,
+    /// the [Reliability] of the message, it's priority and it's data
+    send_queue: VecDeque<(Reliability, u8, Box<[u8]>)>,
     /// marker to disconnect this connection
     disconnect: bool,
+    /// marker to ping this connection
+    ping: bool,
+    /// if the connection should be paused, applied to the socket connection when it changes
+    paused: bool,
     /// metrics extracted from the socket connection
     metrics: Option<ConnectionMetrics>,
+    /// metrics history extracted from the socket connection
+    metrics_history: MetricsHistory,
+    /// how long the connection has been established for, extracted from the socket connection
+    uptime: Duration,
 }
 
 /// event fired when a new [Connection] is made on a [NetSocket]
@@ -105,6 +157,95 @@ pub struct Disconnected {
     pub connection_addr: SocketAddr,
 }
 
+/// event fired when a [NetSocket] that doesn't accept incoming connections receives a connection request
+///
+/// the request can be accepted with [accept_connection](NetSocket::accept_connection)
+/// or rejected with [reject_connection](NetSocket::reject_connection) at any time
+/// until the address stops trying to connect
+#[derive(Event)]
+pub struct ConnectionRequest {
+    /// the entity of the [NetSocket]
+    pub socket_entity: Entity,
+    /// the address of the socket
+    pub socket_addr: SocketAddr,
+    /// the address trying to connect
+    pub connection_addr: SocketAddr,
+    /// the token sent with the request, see [open_connection_with_token](NetSocket::open_connection_with_token)
+    pub token: Box<[u8]>,
+}
+
+/// event fired when the [quality](ConnectionMetrics::quality) of a [Connection] changes
+///
+/// fired once the first round trip time is known, and then whenever the quality changes
+#[derive(Event)]
+pub struct QualityChanged {
+    /// the entity of the [NetSocket]
+    pub socket_entity: Entity,
+    /// the address of the socket
+    pub socket_addr: SocketAddr,
+    /// the entity of the [Connection]
+    pub connection_entity: Entity,
+    /// the address of the connection
+    pub connection_addr: SocketAddr,
+    /// the new quality of the connection
+    pub quality: ConnectionQuality,
+}
+
+/// event fired when a reliable message to a [Connection] has been resent more than the
+/// [stall_resend_threshold](Config::stall_resend_threshold) without any of it being acknowledged
+///
+/// the connection may have silently died, [disconnect](Connection::disconnect) it to give up early
+#[derive(Event)]
+pub struct ConnectionStalled {
+    /// the entity of the [NetSocket]
+    pub socket_entity: Entity,
+    /// the address of the socket
+    pub socket_addr: SocketAddr,
+    /// the entity of the [Connection]
+    pub connection_entity: Entity,
+    /// the address of the connection
+    pub connection_addr: SocketAddr,
+}
+
+/// event fired with part of a message being streamed from a [Connection],
+/// see [min_streamed_message_size](nifty_net::Config::min_streamed_message_size)
+///
+/// streamed messages don't go through the connection's receive queue, their chunks are passed on in order
+/// and the message is complete once `offset + data.len()` reaches `total_size`
+#[derive(Event)]
+pub struct ReceivedChunk {
+    /// the entity of the [NetSocket]
+    pub socket_entity: Entity,
+    /// the address of the socket
+    pub socket_addr: SocketAddr,
+    /// the entity of the [Connection]
+    pub connection_entity: Entity,
+    /// the address of the connection
+    pub connection_addr: SocketAddr,
+    /// the fragmentation id of the message, to tell apart chunks of messages streamed at the same time
+    pub sequence: u16,
+    /// where the chunk starts in the message
+    pub offset: usize,
+    /// the size of the whole message
+    pub total_size: usize,
+    pub data: Box<[u8]>,
+}
+
+/// event fired when the operating system's send buffer was full while sending to a [Connection]
+///
+/// held back packets are retried in the next update, throttle what gets sent before they start being dropped
+#[derive(Event)]
+pub struct SendBufferFull {
+    /// the entity of the [NetSocket]
+    pub socket_entity: Entity,
+    /// the address of the socket
+    pub socket_addr: SocketAddr,
+    /// the entity of the [Connection]
+    pub connection_entity: Entity,
+    /// the address of the connection
+    pub connection_addr: SocketAddr,
+}
+
 /// event fired when a [NetSocket] closed a connection before it was established
 ///
 /// this happens when it opens a connection but never gets a response
@@ -118,6 +259,38 @@ pub struct FailedConnection {
     pub connection_addr: SocketAddr,
 }
 
+/// event fired when the other party rejected a connection opened by a [NetSocket]
+///
+/// this is fired before the matching [FailedConnection]
+#[derive(Event)]
+pub struct ConnectionRejected {
+    /// the entity of the [NetSocket]
+    pub socket_entity: Entity,
+    /// the address of the socket
+    pub socket_addr: SocketAddr,
+    /// the address of the rejected connection
+    pub connection_addr: SocketAddr,
+    /// the reason given by the other party, see [reject_connection_with_reason](NetSocket::reject_connection_with_reason)
+    pub reason: Option<Box<str>>,
+}
+
+/// event fired when the peer of a [Connection] handed it off to another of it's sockets
+///
+/// the [Connection] keeps it's entity and state, only it's address changes
+#[derive(Event)]
+pub struct ConnectionMigrated {
+    /// the entity of the [NetSocket]
+    pub socket_entity: Entity,
+    /// the address of the socket
+    pub socket_addr: SocketAddr,
+    /// the entity of the [Connection]
+    pub connection_entity: Entity,
+    /// the new address of the connection
+    pub connection_addr: SocketAddr,
+    /// the address of the connection before the handoff
+    pub previous_addr: SocketAddr,
+}
+
 
 impl NetSocket {
     /// binds to an address, returns a [NetSocket] if successful
@@ -128,6 +301,8 @@ impl NetSocket {
             accept_incoming: config.accept_incoming,
             connections: HashMap::new(),
             connect_queue: VecDeque::new(),
+            accept_queue: VecDeque::new(),
+            reject_queue: VecDeque::new(),
         })
     }
 
@@ -141,22 +316,82 @@ impl NetSocket {
         self.addr
     }
 
+    /// gets the socket's [Config]
+    pub fn config(&self) -> &Config {
+        self.socket.config()
+    }
+
+    /// replaces the socket's [Config], see [Socket::set_config]
+    pub fn set_config(&mut self, config: Config) -> Result<(), ConfigError> {
+        self.socket.set_config(config)
+    }
+
+    /// sets if ignored handshakes are logged at the debug level, see [Socket::set_report_ignored_handshakes]
+    pub fn set_report_ignored_handshakes(&mut self, report_ignored_handshakes: bool) {
+        self.socket.set_report_ignored_handshakes(report_ignored_handshakes);
+    }
+
+    /// sets a small unreliable message to send to a connection with it's next heartbeat,
+    /// see [Socket::set_heartbeat_payload]
+    pub fn set_heartbeat_payload(&mut self, addr: SocketAddr, data: Box<[u8]>) -> Result<(), SendError> {
+        self.socket.set_heartbeat_payload(addr, data)
+    }
+
+    /// gets [SocketStats] totalling the metrics of all of the socket's connections
+    pub fn stats(&self) -> SocketStats {
+        self.socket.stats()
+    }
+
     /// makes the socket connect to an address in the next update
     ///
-    /// will fire a warning if already connected to that address
+    /// will fire a warning if already connected to that address,
+    /// but calling it again whilst still connecting does nothing
     pub fn open_connection(&mut self, addr: SocketAddr) {
-        self.connect_queue.push_back(addr);
+        self.open_connection_with_token(addr, Box::new([]));
+    }
+
+    /// the same as [open_connection](NetSocket::open_connection) but sends a token with the handshakes,
+    /// see [Socket::open_connection_with_token]
+    pub fn open_connection_with_token(&mut self, addr: SocketAddr, token: Box<[u8]>) {
+        self.connect_queue.push_back((addr, token));
+    }
+
+    /// accepts a pending [ConnectionRequest] in the next update
+    ///
+    /// will fire a warning if there is no pending request from that address
+    pub fn accept_connection(&mut self, addr: SocketAddr) {
+        self.accept_queue.push_back(addr);
+    }
+
+    /// rejects a pending [ConnectionRequest] in the next update
+    ///
+    /// will fire a warning if there is no pending request from that address
+    pub fn reject_connection(&mut self, addr: SocketAddr) {
+        self.reject_queue.push_back((addr, None));
+    }
+
+    /// the same as [reject_connection](NetSocket::reject_connection) but tells the address why,
+    /// see [Socket::reject_connection_with_reason]
+    pub fn reject_connection_with_reason(&mut self, addr: SocketAddr, reason: &str) {
+        self.reject_queue.push_back((addr, Some(reason.into())));
     }
 }
 
 impl Connection {
-    fn new(addr: SocketAddr) -> Self {
+    fn new(addr: SocketAddr, id: ConnectionId, token: Box<[u8]>) -> Self {
         Connection {
             addr,
+            id,
+            token,
+            user_data: None,
             receive_queue: VecDeque::new(),
             send_queue: VecDeque::new(),
             disconnect: false,
+            ping: false,
+            paused: false,
             metrics: None,
+            metrics_history: MetricsHistory::default(),
+            uptime: Duration::ZERO,
         }
     }
 
@@ -165,6 +400,33 @@ impl Connection {
         self.addr
     }
 
+    /// gets the id of the connection on it's [NetSocket]
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    /// gets the token sent in the handshake that opened the connection, empty if there wasn't one
+    pub fn token(&self) -> &[u8] {
+        &self.token
+    }
+
+    /// stores an application defined value with the connection, replacing any previous one
+    pub fn set_user_data(&mut self, data: impl Any + Send + Sync) {
+        self.user_data = Some(Box::new(data));
+    }
+
+    /// gets the value stored with [set_user_data](Connection::set_user_data),
+    /// `None` if there isn't one or it isn't a `T`
+    pub fn user_data<T: Any>(&self) -> Option<&T> {
+        self.user_data.as_ref()?.downcast_ref()
+    }
+
+    /// mutably gets the value stored with [set_user_data](Connection::set_user_data),
+    /// `None` if there isn't one or it isn't a `T`
+    pub fn user_data_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.user_data.as_mut()?.downcast_mut()
+    }
+
     /// drains the receive message queue
     ///
     /// if you don't continuously call this messages will fill up forever resulting in a memory leak
@@ -172,9 +434,27 @@ impl Connection {
         self.receive_queue.drain(..)
     }
 
+    /// puts a message back in the receive queue, used to give back messages that weren't typed
+    pub(crate) fn requeue_message(&mut self, data: Box<[u8]>) {
+        self.receive_queue.push_back(data);
+    }
+
     /// send a message through the connection
     pub fn send(&mut self, reliable: bool, data: Box<[u8]>) {
-        self.send_queue.push_back((reliable, data));
+        self.send_with_priority(reliable, 0, data);
+    }
+
+    /// send a message through the connection with a priority
+    ///
+    /// messages with a higher priority are sent before messages with a lower one,
+    /// [send](Connection::send) uses a priority of `0`
+    pub fn send_with_priority(&mut self, reliable: bool, priority: u8, data: Box<[u8]>) {
+        self.send_with_reliability(reliable.into(), priority, data);
+    }
+
+    /// send a message through the connection with any [Reliability], such as sequenced or ordered messages
+    pub fn send_with_reliability(&mut self, reliability: Reliability, priority: u8, data: Box<[u8]>) {
+        self.send_queue.push_back((reliability, priority, data));
     }
 
     /// disconnect the connection in the next update
@@ -182,13 +462,46 @@ impl Connection {
         self.disconnect = true;
     }
 
+    /// send a heartbeat in the next update to get a fresh round trip time sample
+    pub fn ping(&mut self) {
+        self.ping = true;
+    }
+
+    /// pauses the connection in the next update, see [Socket::pause_connection]
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// resumes the connection in the next update, see [Socket::resume_connection]
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    /// returns true if the connection is paused or will be in the next update
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
     /// returns the most recently measured metrics, if any have been
     pub fn metrics(&self) -> Option<&ConnectionMetrics> {
         self.metrics.as_ref()
     }
+
+    /// returns the recent history of the connection's metrics, as of the last socket update
+    ///
+    /// empty unless [metrics_history_length](Config::metrics_history_length) is set
+    pub fn metrics_history(&self) -> &MetricsHistory {
+        &self.metrics_history
+    }
+
+    /// returns how long the connection has been established for, as of the last socket update
+    pub fn uptime(&self) -> Duration {
+        self.uptime
+    }
 }
 
 
+#[allow(clippy::too_many_arguments)]
 fn update_sockets(
     mut commands: Commands,
     mut socket_q: Query<(Entity, &mut NetSocket, Option<&Children>)>,
@@ -196,6 +509,15 @@ fn update_sockets(
     mut connected_w: EventWriter<Connected>,
     mut disconnected_w: EventWriter<Disconnected>,
     mut failed_connection_w: EventWriter<FailedConnection>,
+    mut connection_rejected_w: EventWriter<ConnectionRejected>,
+    mut connection_migrated_w: EventWriter<ConnectionMigrated>,
+    mut connection_request_w: EventWriter<ConnectionRequest>,
+    mut quality_changed_w: EventWriter<QualityChanged>,
+    mut connection_stalled_w: EventWriter<ConnectionStalled>,
+    mut send_buffer_full_w: EventWriter<SendBufferFull>,
+    mut received_chunk_w: EventWriter<ReceivedChunk>,
+    // shared by every socket rather than each keeping their own
+    mut receive_buffer: Local<ReceiveBuffer>,
     time: Res<Time>,
 ) {
     for (socket_entity, mut socket, socket_children) in socket_q.iter_mut() {
@@ -203,9 +525,28 @@ fn update_sockets(
         let socket = socket.as_mut();
 
 
-        for addr in socket.connect_queue.drain(..) {
-            if let Err(()) = socket.socket.open_connection(time.elapsed(), addr) {
-                warn!("tried to connect to {} on {:?} {} but already connected", addr, socket_entity, socket.addr);
+        for (addr, token) in socket.connect_queue.drain(..) {
+            match socket.socket.open_connection_with_token(time.elapsed(), addr, token) {
+                // connecting again whilst still waiting for a response is harmless
+                Ok(_) | Err(ConnectError::AlreadyConnecting) => (),
+                Err(err) => warn!("tried to connect to {} on {:?} {} but failed {:?}", addr, socket_entity, socket.addr, err),
+            }
+        }
+
+        for addr in socket.accept_queue.drain(..) {
+            if let Err(()) = socket.socket.accept_connection(time.elapsed(), addr, None) {
+                warn!("tried to accept a connection from {} on {:?} {} but there was no pending request", addr, socket_entity, socket.addr);
+            }
+        }
+
+        for (addr, reason) in socket.reject_queue.drain(..) {
+            let result = match reason {
+                Some(reason) => socket.socket.reject_connection_with_reason(addr, &reason),
+                None => socket.socket.reject_connection(addr),
+            };
+
+            if let Err(()) = result {
+                warn!("tried to reject a connection from {} on {:?} {} but there was no pending request", addr, socket_entity, socket.addr);
             }
         }
 
@@ -219,9 +560,9 @@ fn update_sockets(
 
                 let addr = connection.addr;
 
-                for (reliable, data) in connection.send_queue.drain(..) {
-                    if let Err(()) = socket.socket.send(addr, reliable, data) {
-                        error!("tried to send a message to {} on {:?} {} but the connection didn't exist", addr, socket_entity, socket.addr);
+                for (reliability, priority, data) in connection.send_queue.drain(..) {
+                    if let Err(err) = socket.socket.send_with_reliability(addr, reliability, priority, data) {
+                        error!("tried to send a message to {} on {:?} {} but failed: {:?}", addr, socket_entity, socket.addr, err);
                     }
                 }
 
@@ -231,11 +572,36 @@ fn update_sockets(
                     }
                 }
 
+                if socket.socket.connection_paused(addr) == Some(!connection.paused) {
+                    // unwraps are safe, the connection exists
+                    if connection.paused {
+                        socket.socket.pause_connection(time.elapsed(), addr).unwrap();
+                    } else {
+                        socket.socket.resume_connection(time.elapsed(), addr).unwrap();
+                    }
+                }
+
+                if connection.ping {
+                    connection.ping = false;
+
+                    if let Err(()) = socket.socket.ping(addr) {
+                        error!("tried to ping connection {} on {:?} {} but the connection didn't exist", addr, socket_entity, socket.addr);
+                    }
+                }
+
                 if let Some(metrics) = socket.socket.connection_metrics(addr) {
                     connection.metrics = Some(metrics);
                 } else {
                     error!("tried to get connection metrics for {} {:?} from socket {} {:?} but failed", addr, connection_entity, socket.addr, socket_entity);
                 }
+
+                if let Some(history) = socket.socket.connection_metrics_history(addr) {
+                    connection.metrics_history.clone_from(history);
+                }
+
+                if let Some(uptime) = socket.socket.connection_uptime(time.elapsed(), addr) {
+                    connection.uptime = uptime;
+                }
             }
         }
 
@@ -243,22 +609,31 @@ fn update_sockets(
         // keep connections added this tick here until they can be flushed to the ecs between updates
         let mut new_connections = HashMap::new();
 
-        socket.socket.update(time.elapsed(), |event| {
+        socket.socket.update_with_buffer(time.elapsed(), &mut receive_buffer, |event| {
             match event {
                 SocketEvent::Error(err) => {
                     error!("Socket Error: {:?}", err);
                 },
 
-                SocketEvent::ConnectionRequest { accept_connection, .. } => {
+                SocketEvent::ConnectionRequest { addr, token, accept_connection, .. } => {
                     *accept_connection = socket.accept_incoming;
+
+                    if !socket.accept_incoming {
+                        connection_request_w.send(ConnectionRequest {
+                            socket_entity,
+                            socket_addr: socket.addr,
+                            connection_addr: addr,
+                            token: token.into(),
+                        });
+                    }
                 },
 
-                SocketEvent::NewConnection { addr } => {
+                SocketEvent::NewConnection { addr, id, token } => {
                     let connection_entity = commands.spawn_empty().set_parent(socket_entity).id();
 
                     socket.connections.insert(addr, connection_entity);
 
-                    new_connections.insert(connection_entity, Connection::new(addr));
+                    new_connections.insert(connection_entity, Connection::new(addr, id, token.into()));
 
                     connected_w.send(Connected {
                         socket_entity,
@@ -268,7 +643,93 @@ fn update_sockets(
                     });
                 },
 
-                SocketEvent::ClosedConnection { addr } => {
+                SocketEvent::QualityChanged { addr, quality } => {
+                    let Some(&connection_entity) = socket.connections.get(&addr) else {
+                        error!("connection quality changed for {} but it wasn't connected", addr);
+                        return;
+                    };
+
+                    quality_changed_w.send(QualityChanged {
+                        socket_entity,
+                        socket_addr: socket.addr,
+                        connection_entity,
+                        connection_addr: addr,
+                        quality,
+                    });
+                },
+
+                SocketEvent::ConnectionStalled { addr } => {
+                    let Some(&connection_entity) = socket.connections.get(&addr) else {
+                        error!("connection {} stalled but it wasn't connected", addr);
+                        return;
+                    };
+
+                    connection_stalled_w.send(ConnectionStalled {
+                        socket_entity,
+                        socket_addr: socket.addr,
+                        connection_entity,
+                        connection_addr: addr,
+                    });
+                },
+
+                SocketEvent::ConnectionRejected { addr, reason } => {
+                    connection_rejected_w.send(ConnectionRejected {
+                        socket_entity,
+                        socket_addr: socket.addr,
+                        connection_addr: addr,
+                        reason: reason.map(Into::into),
+                    });
+                },
+
+                SocketEvent::ConnectionMigrated { addr, previous_addr } => {
+                    let Some(connection_entity) = socket.connections.remove(&previous_addr) else {
+                        error!("connection {} migrated to {} but it wasn't connected", previous_addr, addr);
+                        return;
+                    };
+
+                    socket.connections.insert(addr, connection_entity);
+
+                    if let Ok(mut connection) = connection_q.get_mut(connection_entity) {
+                        connection.addr = addr;
+
+                    } else if let Some(connection) = new_connections.get_mut(&connection_entity) {
+                        connection.addr = addr;
+
+                    } else {
+                        error!("connection {} migrated to {} but couldn't find it's entity {:?}", previous_addr, addr, connection_entity);
+                    }
+
+                    connection_migrated_w.send(ConnectionMigrated {
+                        socket_entity,
+                        socket_addr: socket.addr,
+                        connection_entity,
+                        connection_addr: addr,
+                        previous_addr,
+                    });
+                },
+
+                // messages with a maximum number of resends can't be sent through a [Connection]
+                SocketEvent::MessageDropped { .. } => (),
+
+                SocketEvent::HandshakeIgnored { addr, reason } => {
+                    debug!("Ignored handshake from {}: {:?}", addr, reason);
+                },
+
+                SocketEvent::SendBufferFull { addr } => {
+                    // handshakes to connections that aren't established yet have no entity to report
+                    let Some(&connection_entity) = socket.connections.get(&addr) else {
+                        return;
+                    };
+
+                    send_buffer_full_w.send(SendBufferFull {
+                        socket_entity,
+                        socket_addr: socket.addr,
+                        connection_entity,
+                        connection_addr: addr,
+                    });
+                },
+
+                SocketEvent::ClosedConnection { addr, .. } => {
                     let Some(connection_entity) = socket.connections.remove(&addr) else {
                         failed_connection_w.send(FailedConnection {
                             socket_addr: socket.addr,
@@ -293,7 +754,25 @@ fn update_sockets(
                     });
                 },
 
-                SocketEvent::Received { addr, data } => {
+                SocketEvent::ReceivedChunk { addr, sequence, offset, total_size, data } => {
+                    let Some(&connection_entity) = socket.connections.get(&addr) else {
+                        error!("tried to receive data from {} but it wasn't connected", addr);
+                        return;
+                    };
+
+                    received_chunk_w.send(ReceivedChunk {
+                        socket_entity,
+                        socket_addr: socket.addr,
+                        connection_entity,
+                        connection_addr: addr,
+                        sequence,
+                        offset,
+                        total_size,
+                        data,
+                    });
+                },
+
+                SocketEvent::Received { addr, data, .. } => {
                     let Some(&connection_entity) = socket.connections.get(&addr) else {
                         error!("tried to receive data from {} but it wasn't connected", addr);
                         return;
@@ -1,7 +1,8 @@
 
-use std::collections::VecDeque;
+use std::{collections::VecDeque, net::SocketAddr};
 
-use bevy::prelude::*;
+use bevy::{ecs::schedule::{InternedScheduleLabel, ScheduleLabel}, prelude::*, utils::HashMap};
+use nifty_net::Reliability;
 use serde::{Serialize, Deserialize};
 
 use crate::{net_socket::{
@@ -9,14 +10,43 @@ use crate::{net_socket::{
     UpdateSockets,
 }, prelude::Connected};
 
-/// typed messages are sent in this set in [PreUpdate]
+/// typed messages are sent in this set in the schedule given to the [TypedMessagePlugin], [PreUpdate] by default
 #[derive(Hash, Debug, PartialEq, Eq, Clone, SystemSet)]
 pub struct SendTypedMessages;
 
-/// typed messages are received in this set in [PreUpdate]
+/// typed messages are received in this set in the schedule given to the [TypedMessagePlugin], [PreUpdate] by default
+///
+/// messages that aren't typed are put back on their [Connection] by the end of this set,
+/// so they can still be read with [drain_messages](Connection::drain_messages) after it
 #[derive(Hash, Debug, PartialEq, Eq, Clone, SystemSet)]
 pub struct ReadTypedMessages;
 
+/// each message type is deserialized in this set, within [ReadTypedMessages]
+#[derive(Hash, Debug, PartialEq, Eq, Clone, SystemSet)]
+struct DeserializeTypedMessages;
+
+/// event fired when a typed message couldn't be queued to be sent, fired in [SendTypedMessages]
+#[derive(Event, Debug)]
+pub enum TypedMessageError {
+    /// the message's [MessageSerializer] failed, so nothing was queued
+    SerializeFailed {
+        /// the name of the message's type
+        message_type: &'static str,
+    },
+    /// the message was sent to an entity without a [Connection], such as one that has disconnected,
+    /// and was dropped
+    ConnectionGone {
+        /// the entity the message was sent to
+        connection_entity: Entity,
+        /// the name of the message's type
+        message_type: &'static str,
+        reliability: Reliability,
+        /// the serialized message, starting with it's version,
+        /// which can be passed to [forward](TypedMessages::forward) to send it somewhere else
+        bytes: Box<[u8]>,
+    },
+}
+
 
 /// replaces how messages are sent and received in the application
 /// with a typed message system
@@ -31,17 +61,60 @@ pub struct ReadTypedMessages;
 ///
 /// the order you add the plugins in defines how they are serialized,
 /// and should be the same for any apps that talk to each other.
-/// to ensure that this is the case it is best done in a shared function
-#[derive(Default)]
+/// to ensure that this is the case it is best done in a shared function,
+/// which [define_messages](crate::define_messages) generates from a single list
+///
+/// messages are serialized with [bincode] by default,
+/// a different [MessageSerializer] can be chosen per message with
+/// [add_message_with_serializer](TypedMessagePlugin::add_message_with_serializer) or
+/// [with_message_serializer](TypedMessagePlugin::with_message_serializer)
+///
+/// messages sent to the same connection in the same tick can be grouped into one underlying message,
+/// see [with_batching](TypedMessagePlugin::with_batching)
+///
+/// each message carries a version byte after it's id, `0` unless set with
+/// [add_versioned_message](TypedMessagePlugin::add_versioned_message).
+/// when a message type changes, bump it's version and keep reading the previous layout with
+/// [add_message_migration](TypedMessagePlugin::add_message_migration),
+/// so apps that haven't updated yet can still be understood
+///
+/// received messages that don't start with the id of a typed message are left on their [Connection],
+/// so typed and raw messages can be mixed on the same socket.
+/// raw messages must not start with the id of a typed message, which is it's index as a big endian `u16`,
+/// or with `u16::MAX` which is reserved for batches
+///
+/// messages are sent and received in [PreUpdate] by default,
+/// this should be the same schedule given to the [NetworkingPlugin](crate::net_socket::NetworkingPlugin),
+/// see [with_schedule](TypedMessagePlugin::with_schedule)
 pub struct TypedMessagePlugin {
+    /// the schedule to send and receive messages in
+    schedule: InternedScheduleLabel,
     /// a list of functions to call to add messages to the app
-    messages: Vec<Box<dyn Fn(&mut App, u16) + Send + Sync + 'static>>,
+    messages: Vec<Box<dyn Fn(&mut App, InternedScheduleLabel, u16) + Send + Sync + 'static>>,
+    /// a list of functions to call to add older versions of messages once they have been added
+    migrations: Vec<AddMigration>,
+    /// the maximum size of a batch of messages, `None` if batching is disabled
+    max_batch_size: Option<usize>,
+}
+
+/// adds an older version of a message to the app
+type AddMigration = Box<dyn Fn(&mut App) + Send + Sync + 'static>;
+
+impl Default for TypedMessagePlugin {
+    fn default() -> Self {
+        TypedMessagePlugin {
+            schedule: PreUpdate.intern(),
+            messages: Vec::new(),
+            migrations: Vec::new(),
+            max_batch_size: None,
+        }
+    }
 }
 
 impl TypedMessagePlugin {
     /// adds a message to the plugin
     pub fn add_message<T: Serialize + for<'a> Deserialize<'a> + Send + Sync + 'static>(&mut self) {
-        self.messages.push(Box::new(build_message::<T>));
+        self.add_message_with_serializer::<T, BincodeSerializer>();
     }
 
     /// adds a message to the plugin
@@ -49,34 +122,197 @@ impl TypedMessagePlugin {
         self.add_message::<T>();
         self
     }
+
+    /// adds a message to the plugin that is serialized with a [MessageSerializer]
+    pub fn add_message_with_serializer<T: Serialize + for<'a> Deserialize<'a> + Send + Sync + 'static, S: MessageSerializer>(&mut self) {
+        self.add_versioned_message::<T, S>(0);
+    }
+
+    /// adds a message to the plugin that is serialized with a [MessageSerializer]
+    pub fn with_message_serializer<T: Serialize + for<'a> Deserialize<'a> + Send + Sync + 'static, S: MessageSerializer>(mut self) -> Self {
+        self.add_message_with_serializer::<T, S>();
+        self
+    }
+
+    /// adds a message to the plugin that is sent with a version and serialized with a [MessageSerializer]
+    ///
+    /// older versions of the message can still be received if added with
+    /// [add_message_migration](TypedMessagePlugin::add_message_migration)
+    pub fn add_versioned_message<T: Serialize + for<'a> Deserialize<'a> + Send + Sync + 'static, S: MessageSerializer>(&mut self, version: u8) {
+        self.messages.push(Box::new(move |app, schedule, message_id| build_message::<T, S>(app, schedule, message_id, version)));
+    }
+
+    /// adds a message to the plugin that is sent with a version, see [add_versioned_message](TypedMessagePlugin::add_versioned_message)
+    pub fn with_versioned_message<T: Serialize + for<'a> Deserialize<'a> + Send + Sync + 'static, S: MessageSerializer>(mut self, version: u8) -> Self {
+        self.add_versioned_message::<T, S>(version);
+        self
+    }
+
+    /// lets an older `version` of a message still be received, deserialized as `Old` and converted into the message
+    ///
+    /// the message itself must also be added to the plugin, and `version` must be different from it's current one
+    pub fn add_message_migration<T: Send + Sync + 'static, Old: for<'a> Deserialize<'a> + Into<T>, S: MessageSerializer>(&mut self, version: u8) {
+        self.migrations.push(Box::new(move |app| {
+            let Some(mut messages) = app.world.get_resource_mut::<TypedMessages<T>>() else {
+                panic!("added a migration for the typed message \"{}\" without adding the message", std::any::type_name::<T>());
+            };
+
+            assert!(version != messages.version, "a migration can't have the same version as it's typed message");
+            messages.migrations.push((version, deserialize_migration::<T, Old, S>));
+        }));
+    }
+
+    /// lets an older version of a message still be received, see [add_message_migration](TypedMessagePlugin::add_message_migration)
+    pub fn with_message_migration<T: Send + Sync + 'static, Old: for<'a> Deserialize<'a> + Into<T>, S: MessageSerializer>(mut self, version: u8) -> Self {
+        self.add_message_migration::<T, Old, S>(version);
+        self
+    }
+
+    /// sets whether messages should be batched
+    ///
+    /// when `Some`, messages sent to the same connection with the same reliability in one tick
+    /// are grouped together into underlying messages of up to `max_batch_size` bytes,
+    /// reducing the overhead of sending lots of small messages.
+    /// messages larger than this are sent on their own.
+    ///
+    /// keep in mind that if a batch of unreliable messages gets fragmented
+    /// then losing one fragment loses the whole batch,
+    /// so for unreliable messages it is best kept below the mtu
+    ///
+    /// batched messages can always be received, even if batching isn't enabled
+    pub fn set_batching(&mut self, max_batch_size: Option<usize>) {
+        self.max_batch_size = max_batch_size;
+    }
+
+    /// enables batching, see [set_batching](TypedMessagePlugin::set_batching)
+    pub fn with_batching(mut self, max_batch_size: usize) -> Self {
+        self.set_batching(Some(max_batch_size));
+        self
+    }
+
+    /// sets the schedule to send and receive messages in
+    ///
+    /// messages received are only kept until the next time the schedule runs,
+    /// so they should be read in the same schedule
+    pub fn set_schedule(&mut self, schedule: impl ScheduleLabel) {
+        self.schedule = schedule.intern();
+    }
+
+    /// sets the schedule to send and receive messages in, see [set_schedule](TypedMessagePlugin::set_schedule)
+    pub fn with_schedule(mut self, schedule: impl ScheduleLabel) -> Self {
+        self.set_schedule(schedule);
+        self
+    }
+}
+
+/// defines a function that creates a [TypedMessagePlugin] with a list of messages
+///
+/// each message's id is it's position in the list, so calling the same function
+/// from every app that talks to each other guarantees they agree on the ids.
+/// a [MessageSerializer] can be given for a message after a `=>`
+///
+/// ```ignore
+/// define_messages! {
+///     pub fn typed_plugin {
+///         Ping,
+///         Pong,
+///         Chat => JsonSerializer,
+///     }
+/// }
+///
+/// app.add_plugins(typed_plugin().with_batching(1024));
+/// ```
+#[macro_export]
+macro_rules! define_messages {
+    ($vis:vis fn $name:ident { $($message:ty $(=> $serializer:ty)?),* $(,)? }) => {
+        $vis fn $name() -> $crate::typed::TypedMessagePlugin {
+            let mut plugin = $crate::typed::TypedMessagePlugin::default();
+            $($crate::define_messages!(@add plugin, $message $(, $serializer)?);)*
+            plugin
+        }
+    };
+
+    (@add $plugin:ident, $message:ty) => {
+        $plugin.add_message::<$message>();
+    };
+
+    (@add $plugin:ident, $message:ty, $serializer:ty) => {
+        $plugin.add_message_with_serializer::<$message, $serializer>();
+    };
+}
+
+/// a serialization format for typed messages
+///
+/// implement this to use a format other than [bincode],
+/// both ends of a connection must use the same serializer for a message
+pub trait MessageSerializer: 'static {
+    /// serializes a message, returns `None` if it failed
+    fn serialize<T: Serialize>(message: &T) -> Option<Vec<u8>>;
+
+    /// deserializes a message, returns `None` if it failed
+    fn deserialize<T: for<'a> Deserialize<'a>>(bytes: &[u8]) -> Option<T>;
+}
+
+/// the default [MessageSerializer], uses [bincode]
+pub struct BincodeSerializer;
+
+impl MessageSerializer for BincodeSerializer {
+    fn serialize<T: Serialize>(message: &T) -> Option<Vec<u8>> {
+        bincode::serialize(message).ok()
+    }
+
+    fn deserialize<T: for<'a> Deserialize<'a>>(bytes: &[u8]) -> Option<T> {
+        bincode::deserialize(bytes).ok()
+    }
 }
 
 impl Plugin for TypedMessagePlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<BufferedMessages>();
+        app.add_event::<TypedMessageError>();
+        app.insert_resource(OutgoingMessages {
+            max_batch_size: self.max_batch_size,
+            messages: Vec::new(),
+        });
 
-        app.add_systems(PreUpdate, (
+        app.add_systems(self.schedule, (
             insert_typed_connections.after(UpdateSockets),
             apply_deferred, // make sure connections have been inserted and marked as typed before reading messages
-            buffer_messages.in_set(ReadTypedMessages),
+            buffer_messages.in_set(ReadTypedMessages).before(DeserializeTypedMessages),
         ).chain());
 
+        app.add_systems(self.schedule, requeue_untyped_messages.in_set(ReadTypedMessages).after(DeserializeTypedMessages));
+
+        app.add_systems(self.schedule, send_outgoing_messages.after(SendTypedMessages).before(UpdateSockets));
+
+        // the last id is reserved for batches
+        assert!(self.messages.len() < BATCH_MESSAGE_ID as usize, "too many typed messages");
+
         for (i, build) in self.messages.iter().enumerate() {
-            build(app, i as u16);
+            build(app, self.schedule, i as u16);
+        }
+
+        for build in self.migrations.iter() {
+            build(app);
         }
     }
 }
 
-fn build_message<T: Serialize + for<'a> Deserialize<'a> + Send + Sync + 'static>(app: &mut App, message_id: u16) {
+fn build_message<T: Serialize + for<'a> Deserialize<'a> + Send + Sync + 'static, S: MessageSerializer>(app: &mut App, schedule: InternedScheduleLabel, message_id: u16, version: u8) {
     app.insert_resource(TypedMessages::<T> {
         message_id,
+        version,
+        serialize: S::serialize::<T>,
+        deserialize: S::deserialize::<T>,
+        migrations: Vec::new(),
         received: VecDeque::new(),
         send: VecDeque::new(),
+        serialize_failures: 0,
     });
 
-    app.add_systems(PreUpdate, (
+    app.add_systems(schedule, (
         serialize_typed_messages::<T>.in_set(SendTypedMessages).before(UpdateSockets),
-        deserialize_typed_messages::<T>.in_set(ReadTypedMessages).after(buffer_messages),
+        deserialize_typed_messages::<T>.in_set(ReadTypedMessages).in_set(DeserializeTypedMessages),
     ));
 }
 
@@ -102,41 +338,212 @@ fn insert_typed_connections(
 }
 
 
+/// the message id used for a batch of typed messages
+///
+/// batch layout:
+/// - 2 bytes: [BATCH_MESSAGE_ID]
+/// - 4 bytes: length of the next message
+/// - then as many bytes as needed for that message, including it's id
+/// - repeat, starting with the length of the next message
+const BATCH_MESSAGE_ID: u16 = u16::MAX;
+
+/// how many bytes every typed message starts with, the two byte message id followed by it's version
+const TYPED_HEADER_SIZE: usize = 3;
+
+/// messages received by typed connections this tick
+///
+/// messages are taken out by [deserialize_typed_messages] for the type they belong to,
+/// any left over are given back to their connection by [requeue_untyped_messages]
 #[derive(Resource, Default)]
 struct BufferedMessages {
-    messages: Vec<(Entity, Box<[u8]>)>,
+    /// the entity and address of the connection each message came from,
+    /// the bytes are `None` once taken by a message type
+    messages: Vec<BufferedMessage>,
 }
 
+type BufferedMessage = (Entity, SocketAddr, Option<Box<[u8]>>);
+
 fn buffer_messages(
     mut connection_q: Query<(Entity, &mut Connection), With<TypedConnection>>,
     mut buffer: ResMut<BufferedMessages>,
 ) {
-    buffer.messages.clear();
     for (connection_entity, mut connection) in connection_q.iter_mut() {
-        buffer.messages.extend(
-            connection.drain_messages().map(|bytes| (connection_entity, bytes))
-        );
+        let connection_addr = connection.address();
+
+        for bytes in connection.drain_messages() {
+            if bytes.get(0..2) != Some(&BATCH_MESSAGE_ID.to_be_bytes()) {
+                buffer.messages.push((connection_entity, connection_addr, Some(bytes)));
+                continue;
+            }
+
+            // unwrap is safe, contains at least two bytes
+            let mut batch = bytes.get(2..).unwrap();
+
+            while !batch.is_empty() {
+                let Some(message) = split_batched_message(&mut batch) else {
+                    warn!("couldn't parse batch of typed messages from connection {:?}", connection_entity);
+                    break;
+                };
+
+                buffer.messages.push((connection_entity, connection_addr, Some(message.into())));
+            }
+        }
+    }
+}
+
+/// runs after every message type has been deserialized and puts messages that weren't typed back on their connection
+fn requeue_untyped_messages(
+    mut connection_q: Query<&mut Connection>,
+    mut buffer: ResMut<BufferedMessages>,
+) {
+    for (connection_entity, _, bytes) in buffer.messages.drain(..) {
+        let Some(bytes) = bytes else {
+            // taken by a message type
+            continue;
+        };
+
+        if let Ok(mut connection) = connection_q.get_mut(connection_entity) {
+            connection.requeue_message(bytes);
+        }
+    }
+}
+
+/// splits the next message off the front of a batch
+fn split_batched_message<'a>(batch: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = u32::from_be_bytes(batch.get(0..4)?.try_into().unwrap()) as usize;
+    let message = batch.get(4..4 + len)?;
+    *batch = batch.get(4 + len..)?;
+    Some(message)
+}
+
+
+/// serialized messages waiting to be sent to connections in [send_outgoing_messages]
+#[derive(Resource)]
+struct OutgoingMessages {
+    /// the maximum size of a batch, `None` if batching is disabled
+    max_batch_size: Option<usize>,
+    messages: Vec<(Entity, Reliability, Box<[u8]>)>,
+}
+
+/// runs after [SendTypedMessages] and sends serialized messages to connections, batching them if enabled
+fn send_outgoing_messages(
+    mut outgoing: ResMut<OutgoingMessages>,
+    mut connection_q: Query<&mut Connection>,
+) {
+    let outgoing = outgoing.as_mut();
+
+    let Some(max_batch_size) = outgoing.max_batch_size else {
+        for (connection_entity, reliability, bytes) in outgoing.messages.drain(..) {
+            if let Ok(mut connection) = connection_q.get_mut(connection_entity) {
+                connection.send_with_reliability(reliability, 0, bytes);
+            }
+        }
+
+        return;
+    };
+
+    // group messages by connection and reliability, keeping their order
+    let mut groups: HashMap<(Entity, Reliability), Vec<Box<[u8]>>> = HashMap::new();
+    for (connection_entity, reliability, bytes) in outgoing.messages.drain(..) {
+        groups.entry((connection_entity, reliability)).or_default().push(bytes);
+    }
+
+    for ((connection_entity, reliability), messages) in groups {
+        let Ok(mut connection) = connection_q.get_mut(connection_entity) else {
+            continue;
+        };
+
+        let mut batch = Vec::new();
+        for bytes in messages {
+            if batch.len() + 4 + bytes.len() > max_batch_size {
+                if let Some(batch) = finish_batch(&mut batch) {
+                    connection.send_with_reliability(reliability, 0, batch);
+                }
+            }
+
+            if batch.is_empty() && 2 + 4 + bytes.len() > max_batch_size {
+                // too big to be batched
+                connection.send_with_reliability(reliability, 0, bytes);
+                continue;
+            }
+
+            if batch.is_empty() {
+                batch.extend_from_slice(&BATCH_MESSAGE_ID.to_be_bytes());
+            }
+
+            batch.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+            batch.extend_from_slice(&bytes);
+        }
+
+        if let Some(batch) = finish_batch(&mut batch) {
+            connection.send_with_reliability(reliability, 0, batch);
+        }
+    }
+}
+
+/// takes a batch being built, returning `None` if it was empty
+///
+/// a batch of only one message is unwrapped and sent as a regular message
+fn finish_batch(batch: &mut Vec<u8>) -> Option<Box<[u8]>> {
+    if batch.is_empty() {
+        return None;
+    }
+
+    let batch = std::mem::take(batch);
+
+    let mut messages = batch.get(2..).unwrap();
+    let first = split_batched_message(&mut messages).unwrap();
+
+    if messages.is_empty() {
+        Some(first.into())
+    } else {
+        Some(batch.into_boxed_slice())
     }
 }
 
 
+type DeserializeFn<T> = fn(&[u8]) -> Option<T>;
+
+/// deserializes an older version of a message and converts it to the current one
+fn deserialize_migration<T, Old: for<'a> Deserialize<'a> + Into<T>, S: MessageSerializer>(bytes: &[u8]) -> Option<T> {
+    S::deserialize::<Old>(bytes).map(Into::into)
+}
+
+
 #[derive(Resource)]
 pub struct TypedMessages<T> {
     message_id: u16,
-    received: VecDeque<(Entity, T)>,
-    send: VecDeque<(Entity, bool, Box<[u8]>)>,
+    /// the version sent after the message id
+    version: u8,
+    /// the serialize function of the [MessageSerializer] for this message
+    serialize: fn(&T) -> Option<Vec<u8>>,
+    /// the deserialize function of the [MessageSerializer] for this message
+    deserialize: fn(&[u8]) -> Option<T>,
+    /// deserialize functions for older versions of the message
+    migrations: Vec<(u8, DeserializeFn<T>)>,
+    /// received messages along with the address of their connection
+    /// and the bytes they were deserialized from, including the message id and version
+    received: VecDeque<(Entity, SocketAddr, T, Box<[u8]>)>,
+    send: VecDeque<(Entity, Reliability, Box<[u8]>)>,
+    /// how many messages failed to serialize since the last [TypedMessageError]s were fired
+    serialize_failures: usize,
 }
 
 /// runs after [buffer_messages] and deserializes messages into their appropriate [TypedMessages]
-fn deserialize_typed_messages<T: for<'a> Deserialize<'a> + Send + Sync + 'static>(
-    buffer: Res<BufferedMessages>,
+fn deserialize_typed_messages<T: Send + Sync + 'static>(
+    mut buffer: ResMut<BufferedMessages>,
     mut messages: ResMut<TypedMessages<T>>
 ) {
     messages.received.clear();
 
-    for (connection_entity, bytes) in buffer.messages.iter() {
+    for (connection_entity, connection_addr, buffered_bytes) in buffer.messages.iter_mut() {
+        let Some(bytes) = buffered_bytes else {
+            // already taken by another message type
+            continue;
+        };
+
         let Some(message_id) = bytes.get(0..2) else {
-            warn!("couldn't parse message from connection {:?} as typed", connection_entity);
+            // too short to be typed
             continue;
         };
 
@@ -146,30 +553,65 @@ fn deserialize_typed_messages<T: for<'a> Deserialize<'a> + Send + Sync + 'static
             continue;
         }
 
-        // unwrap is safe, contains at least two bytes
-        let bytes = bytes.get(2..).unwrap();
+        let Some(&version) = bytes.get(2) else {
+            warn!("message from {:?} marked as a \"{}\" had no version", connection_entity, std::any::type_name::<T>());
+            *buffered_bytes = None;
+            continue;
+        };
 
-        let Ok(message) = bincode::deserialize(bytes) else {
+        let deserialize = if version == messages.version {
+            messages.deserialize
+        } else if let Some(&(_, deserialize)) = messages.migrations.iter().find(|&&(migration_version, _)| migration_version == version) {
+            deserialize
+        } else {
+            warn!("message from {:?} marked as a \"{}\" had unknown version {}", connection_entity, std::any::type_name::<T>(), version);
+            *buffered_bytes = None;
+            continue;
+        };
+
+        // unwrap is safe, contains at least the header
+        let bytes = bytes.get(TYPED_HEADER_SIZE..).unwrap();
+
+        let Some(message) = deserialize(bytes) else {
             warn!("couldn't deserialize message from {:?} marked as a \"{}\"", connection_entity, std::any::type_name::<T>());
+            // drop it rather than giving it back as an untyped message
+            *buffered_bytes = None;
             continue;
         };
 
-        messages.received.push_back((*connection_entity, message));
+        // unwrap is safe, checked above
+        messages.received.push_back((*connection_entity, *connection_addr, message, buffered_bytes.take().unwrap()));
     }
 }
 
-/// runs just before the sockets update in [UpdateSockets] and serializes typed messages to be sent
-fn serialize_typed_messages<T: Serialize + Send + Sync + 'static>(
+/// runs just before the sockets update in [UpdateSockets] and queues serialized typed messages to be sent
+fn serialize_typed_messages<T: Send + Sync + 'static>(
     mut messages: ResMut<TypedMessages<T>>,
-    mut connection_q: Query<&mut Connection>
+    mut outgoing: ResMut<OutgoingMessages>,
+    mut typed_message_error_w: EventWriter<TypedMessageError>,
+    connection_q: Query<(), With<Connection>>,
 ) {
-    for (connection_entity, reliable, message) in messages.send.drain(..) {
-        let Ok(mut connection) = connection_q.get_mut(connection_entity) else {
+    for _ in 0..std::mem::take(&mut messages.serialize_failures) {
+        typed_message_error_w.send(TypedMessageError::SerializeFailed {
+            message_type: std::any::type_name::<T>(),
+        });
+    }
+
+    for (connection_entity, reliability, message) in messages.send.drain(..) {
+        if !connection_q.contains(connection_entity) {
             error!("tried to send a typed message to {:?} but that connection doesn't exist. type was \"{}\"", connection_entity, std::any::type_name::<T>());
+
+            typed_message_error_w.send(TypedMessageError::ConnectionGone {
+                connection_entity,
+                message_type: std::any::type_name::<T>(),
+                reliability,
+                // unwrap is safe, starts with the two byte message id
+                bytes: message.get(2..).unwrap().into(),
+            });
             continue;
         };
 
-        connection.send(reliable, message);
+        outgoing.messages.push((connection_entity, reliability, message));
     }
 }
 
@@ -181,7 +623,16 @@ impl<T> TypedMessages<T> {
     ///
     /// if you need ownership of the message see [take](TypedMessages::take)
     pub fn iter(&self) -> impl Iterator<Item = (Entity, &T)> + '_ {
-        self.received.iter().map(|(entity, message)| (*entity, message))
+        self.received.iter().map(|(entity, _, message, _)| (*entity, message))
+    }
+
+    /// the same as [iter](TypedMessages::iter) but also gives the serialized bytes of each message
+    ///
+    /// these bytes start with the message's version and can be passed to [forward](TypedMessages::forward)
+    /// to relay a message without serializing it again
+    pub fn iter_with_bytes(&self) -> impl Iterator<Item = (Entity, &T, &[u8])> + '_ {
+        // unwrap is safe, contains at least two bytes
+        self.received.iter().map(|(entity, _, message, bytes)| (*entity, message, bytes.get(2..).unwrap()))
     }
 
     /// returns an iterator of all the messages received this tick,
@@ -190,11 +641,23 @@ impl<T> TypedMessages<T> {
     /// this means that for large messages you dont have to copy them,
     /// but only one system can read the messages
     pub fn take(&mut self) -> impl Iterator<Item = (Entity, T)> + '_ {
-        self.received.drain(..)
+        self.received.drain(..).map(|(entity, _, message, _)| (entity, message))
     }
 
     /// the same as [take](TypedMessages::take) except it will only drain items from [Entity]s specified by a predicate
-    pub fn take_from<P: FnMut(Entity) -> bool>(&mut self, predicate: P) -> TakeFromIter<T, P> {
+    pub fn take_from<P: FnMut(Entity) -> bool>(&mut self, mut predicate: P) -> TakeFromIter<T, impl FnMut(Entity, SocketAddr) -> bool> {
+        self.take_where(move |entity, _| predicate(entity))
+    }
+
+    /// the same as [take](TypedMessages::take) except it will only drain items from connections
+    /// whose address is specified by a predicate
+    pub fn take_from_addr<P: FnMut(SocketAddr) -> bool>(&mut self, mut predicate: P) -> TakeFromIter<'_, T, impl FnMut(Entity, SocketAddr) -> bool> {
+        self.take_where(move |_, addr| predicate(addr))
+    }
+
+    /// the same as [take](TypedMessages::take) except it will only drain items from connections
+    /// specified by a predicate over their [Entity] and address
+    pub fn take_where<P: FnMut(Entity, SocketAddr) -> bool>(&mut self, predicate: P) -> TakeFromIter<'_, T, P> {
         TakeFromIter {
             predicate,
             position: 0,
@@ -202,19 +665,57 @@ impl<T> TypedMessages<T> {
         }
     }
 
+    /// how many bytes a message will be when sent, including the two byte message id and it's version,
+    /// without sending it. returns `None` if the message can't be serialized
+    ///
+    /// this doesn't include the length prefix added when batching or the overhead of the packets it's sent in
+    pub fn serialized_size(&self, message: &T) -> Option<usize> {
+        (self.serialize)(message).map(|message_bytes| TYPED_HEADER_SIZE + message_bytes.len())
+    }
+
     /// queues a typed message to be sent in the next socket update
-    pub fn send(&mut self, connections: Connections, reliable: bool, message: &T) where T: Serialize {
+    ///
+    /// if the message can't be serialized or one of the connections no longer exists
+    /// a [TypedMessageError] event is fired
+    pub fn send(&mut self, connections: Connections, reliable: bool, message: &T) {
+        self.send_with_reliability(connections, reliable.into(), message);
+    }
 
-        let Ok(mut message_bytes) = bincode::serialize(message) else {
+    /// the same as [send](TypedMessages::send) but with any [Reliability], such as sequenced or ordered messages
+    ///
+    /// messages of different types share the same ordering, since they're sent over the same connection
+    pub fn send_with_reliability(&mut self, connections: Connections, reliability: Reliability, message: &T) {
+
+        let Some(message_bytes) = (self.serialize)(message) else {
             error!("failed to serialize typed message \"{}\"", std::any::type_name::<T>());
+            self.serialize_failures += 1;
             return;
         };
 
-        let mut bytes = Vec::from(self.message_id.to_be_bytes());
-        bytes.append(&mut message_bytes);
+        let mut versioned_bytes = Vec::with_capacity(1 + message_bytes.len());
+        versioned_bytes.push(self.version);
+        versioned_bytes.extend_from_slice(&message_bytes);
+
+        self.forward_with_reliability(connections, reliability, &versioned_bytes);
+    }
+
+    /// queues an already serialized message to be sent in the next socket update
+    ///
+    /// `bytes` should be a message of this type as given by [iter_with_bytes](TypedMessages::iter_with_bytes),
+    /// this is useful for relaying messages without deserializing and serializing them again
+    ///
+    /// the bytes are not checked, so forwarding anything else will cause deserialization to fail on the other end
+    pub fn forward(&mut self, connections: Connections, reliable: bool, bytes: &[u8]) {
+        self.forward_with_reliability(connections, reliable.into(), bytes);
+    }
+
+    /// the same as [forward](TypedMessages::forward) but with any [Reliability]
+    pub fn forward_with_reliability(&mut self, connections: Connections, reliability: Reliability, bytes: &[u8]) {
+        let mut message_bytes = Vec::from(self.message_id.to_be_bytes());
+        message_bytes.extend_from_slice(bytes);
 
         for entity in connections {
-            self.send.push_back((entity, reliable, bytes.clone().into_boxed_slice()));
+            self.send.push_back((entity, reliability, message_bytes.clone().into_boxed_slice()));
         }
     }
 }
@@ -226,17 +727,18 @@ pub struct TakeFromIter<'a, T, P> {
     messages: &'a mut TypedMessages<T>,
 }
 
-impl<'a, T, P: FnMut(Entity) -> bool> Iterator for TakeFromIter<'a, T, P> {
+impl<'a, T, P: FnMut(Entity, SocketAddr) -> bool> Iterator for TakeFromIter<'a, T, P> {
     type Item = (Entity, T);
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            let Some(&(entity, _)) = self.messages.received.get(self.position) else {
+            let Some(&(entity, addr, _, _)) = self.messages.received.get(self.position) else {
                 return None;
             };
 
-            if (self.predicate)(entity) {
-                return Some(self.messages.received.remove(self.position).unwrap());
+            if (self.predicate)(entity, addr) {
+                let (entity, _, message, _) = self.messages.received.remove(self.position).unwrap();
+                return Some((entity, message));
             }
 
             self.position += 1;
@@ -274,3 +776,124 @@ impl<'a> Connections<'a> {
         Connections::Iter(Box::new(iter))
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct TestMessage {
+        value: u32,
+        name: String,
+    }
+
+    /// an app with [TestMessage] added as message id `5` and version `2`
+    fn app() -> App {
+        let mut app = App::new();
+        build_message::<TestMessage, BincodeSerializer>(&mut app, PreUpdate.intern(), 5, 2);
+        app
+    }
+
+    #[test]
+    fn serialized_size_round_trip() {
+        let mut app = app();
+        let connection_entity = app.world.spawn_empty().id();
+        let message = TestMessage {
+            value: 7,
+            name: "nifty".into(),
+        };
+
+        let mut messages = app.world.resource_mut::<TypedMessages<TestMessage>>();
+        let size = messages.serialized_size(&message).unwrap();
+        messages.send(Connections::One(connection_entity), true, &message);
+
+        let (_, _, bytes) = messages.send.pop_front().unwrap();
+        assert_eq!(bytes.len(), size);
+        assert_eq!(bytes[..TYPED_HEADER_SIZE], [0, 5, 2]);
+
+        // and it's received as the same message
+        app.world.insert_resource(BufferedMessages {
+            messages: vec![(connection_entity, "127.0.0.1:0".parse().unwrap(), Some(bytes))],
+        });
+        app.world.run_system_once(deserialize_typed_messages::<TestMessage>);
+
+        let messages = app.world.resource::<TypedMessages<TestMessage>>();
+        assert_eq!(messages.iter().collect::<Vec<_>>(), [(connection_entity, &message)]);
+    }
+
+    /// a serializer that always fails
+    struct FailingSerializer;
+
+    impl MessageSerializer for FailingSerializer {
+        fn serialize<T: Serialize>(_: &T) -> Option<Vec<u8>> {
+            None
+        }
+
+        fn deserialize<T: for<'a> Deserialize<'a>>(_: &[u8]) -> Option<T> {
+            None
+        }
+    }
+
+    /// runs [serialize_typed_messages] for `T` and returns the errors it fired
+    fn serialize_errors<T: Send + Sync + 'static>(app: &mut App) -> Vec<TypedMessageError> {
+        app.add_event::<TypedMessageError>();
+        app.world.insert_resource(OutgoingMessages {
+            max_batch_size: None,
+            messages: Vec::new(),
+        });
+
+        app.world.run_system_once(serialize_typed_messages::<T>);
+        app.world.resource_mut::<Events<TypedMessageError>>().drain().collect()
+    }
+
+    #[test]
+    fn connection_gone_error() {
+        let mut app = app();
+        // no connection component, as if it disconnected
+        let connection_entity = app.world.spawn_empty().id();
+
+        app.world.resource_mut::<TypedMessages<TestMessage>>().send(Connections::One(connection_entity), false, &TestMessage {
+            value: 7,
+            name: "nifty".into(),
+        });
+
+        let errors = serialize_errors::<TestMessage>(&mut app);
+        assert_eq!(errors.len(), 1);
+        let TypedMessageError::ConnectionGone { connection_entity: entity, reliability, bytes, .. } = &errors[0] else {
+            panic!("expected a connection gone error, got {:?}", errors[0]);
+        };
+        assert_eq!(*entity, connection_entity);
+        assert_eq!(*reliability, Reliability::Unreliable);
+
+        // starts with the version, ready to be forwarded
+        assert_eq!(bytes[0], 2);
+        assert_eq!(BincodeSerializer::deserialize::<TestMessage>(&bytes[1..]).unwrap().value, 7);
+        assert!(app.world.resource::<OutgoingMessages>().messages.is_empty());
+    }
+
+    #[test]
+    fn serialize_failed_error() {
+        let mut app = App::new();
+        build_message::<TestMessage, FailingSerializer>(&mut app, PreUpdate.intern(), 5, 0);
+        let connection_entity = app.world.spawn_empty().id();
+
+        let message = TestMessage {
+            value: 7,
+            name: "nifty".into(),
+        };
+
+        let mut messages = app.world.resource_mut::<TypedMessages<TestMessage>>();
+        assert_eq!(messages.serialized_size(&message), None);
+        messages.send(Connections::One(connection_entity), true, &message);
+        assert!(messages.send.is_empty());
+
+        let errors = serialize_errors::<TestMessage>(&mut app);
+        assert!(matches!(errors[..], [TypedMessageError::SerializeFailed { message_type }] if message_type == std::any::type_name::<TestMessage>()));
+
+        // only fired once
+        assert!(serialize_errors::<TestMessage>(&mut app).is_empty());
+    }
+}